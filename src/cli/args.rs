@@ -2,17 +2,24 @@
 //!
 //! Defines command-line argument parsing using clap.
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
+use crate::protocol::EncryptionPolicy;
+use crate::dht::rate_limit::{DEFAULT_QUERY_LIMIT, DEFAULT_QUERY_LIMIT_PER_IP};
 
 /// CLI arguments for the torrent downloader
 #[derive(Debug, Parser)]
 #[command(name = "rust-torrent-downloader")]
 #[command(about = "A full-featured BitTorrent CLI downloader", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 pub struct CliArgs {
+    /// Command to run instead of downloading
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Path to the .torrent file
-    #[arg(value_name = "TORRENT_FILE")]
-    pub torrent_file: PathBuf,
+    #[arg(value_name = "TORRENT_FILE", required_unless_present = "crawl")]
+    pub torrent_file: Option<PathBuf>,
 
     /// Download directory
     #[arg(short, long, value_name = "DIR")]
@@ -42,21 +49,249 @@ pub struct CliArgs {
     #[arg(long, default_value_t = true)]
     pub use_dht: bool,
 
+    /// File to keep the DHT node ID and known nodes in between runs
+    /// (default: .dht_state.json in the download directory)
+    #[arg(long, value_name = "FILE")]
+    pub dht_state: Option<PathBuf>,
+
+    /// Query the DHT without answering other nodes, e.g. on metered links
+    #[arg(long)]
+    pub dht_read_only: bool,
+
+    /// Inbound DHT queries answered per second (0 = unlimited)
+    #[arg(long, value_name = "QUERIES_S", default_value_t = DEFAULT_QUERY_LIMIT)]
+    pub dht_query_limit: u32,
+
+    /// Inbound DHT queries answered per second from one address (0 = unlimited)
+    #[arg(long, value_name = "QUERIES_S", default_value_t = DEFAULT_QUERY_LIMIT_PER_IP)]
+    pub dht_query_limit_per_ip: u32,
+
+    /// Crawl the DHT instead of downloading, appending discovered info
+    /// hashes to FILE as JSON lines
+    #[arg(long, value_name = "FILE")]
+    pub crawl: Option<PathBuf>,
+
+    /// Stop crawling after this many minutes (0 = until interrupted)
+    #[arg(long, value_name = "MINUTES", default_value_t = 0)]
+    pub crawl_time: u64,
+
+    /// Enable uTP peer connections (UDP socket shared with DHT)
+    #[arg(long, default_value_t = false)]
+    pub utp: bool,
+
+    /// Only listen and run the DHT on IPv4
+    #[arg(long)]
+    pub no_ipv6: bool,
+
     /// Enable tracker communication
     #[arg(long, default_value_t = true)]
     pub use_tracker: bool,
 
     /// Verbose output
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub verbose: bool,
 
     /// Quiet mode (no output except errors)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub quiet: bool,
 
     /// Resume from checkpoint
     #[arg(long)]
     pub resume: bool,
+
+    /// Peer connection encryption: disabled, prefer or require
+    #[arg(long, value_name = "POLICY", default_value_t = EncryptionPolicy::Prefer)]
+    pub encryption: EncryptionPolicy,
+
+    /// Global download limit in KiB/s (0 = unlimited)
+    #[arg(long, value_name = "KIB_S", default_value_t = 0)]
+    pub download_limit: u64,
+
+    /// Global upload limit in KiB/s (0 = unlimited)
+    #[arg(long, value_name = "KIB_S", default_value_t = 0)]
+    pub upload_limit: u64,
+
+    /// Download limit for this torrent in KiB/s (0 = unlimited)
+    #[arg(long, value_name = "KIB_S", default_value_t = 0)]
+    pub torrent_download_limit: u64,
+
+    /// Upload limit for this torrent in KiB/s (0 = unlimited)
+    #[arg(long, value_name = "KIB_S", default_value_t = 0)]
+    pub torrent_upload_limit: u64,
+
+    /// Download limit per peer in KiB/s (0 = unlimited)
+    #[arg(long, value_name = "KIB_S", default_value_t = 0)]
+    pub peer_download_limit: u64,
+
+    /// Upload limit per peer in KiB/s (0 = unlimited)
+    #[arg(long, value_name = "KIB_S", default_value_t = 0)]
+    pub peer_upload_limit: u64,
+
+    /// Count protocol overhead, not just piece data, against the rate limits
+    #[arg(long, default_value_t = false)]
+    pub limit_overhead: bool,
+
+    /// Only download files whose path matches this glob; repeat for more
+    #[arg(long, value_name = "GLOB")]
+    pub only: Vec<String>,
+
+    /// Don't download files whose path matches this glob; repeat for more
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+}
+
+/// Commands other than downloading
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create a .torrent file from a file or directory
+    Create(CreateArgs),
+    /// Show everything a .torrent file contains
+    Info(InfoArgs),
+    /// Change trackers, comment and other keys of .torrent files
+    Edit(EditArgs),
+}
+
+/// Arguments of the `info` command
+#[derive(Debug, Args)]
+pub struct InfoArgs {
+    /// The .torrent file to inspect
+    #[arg(value_name = "TORRENT_FILE")]
+    pub torrent_file: PathBuf,
+
+    /// Print JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Arguments of the `create` command
+#[derive(Debug, Args)]
+pub struct CreateArgs {
+    /// File or directory to share
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// Where to write the .torrent file (default: <name>.torrent)
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Piece length in KiB, a power of two from 16 to 16384 (default: chosen from the total size)
+    #[arg(long, value_name = "KIB")]
+    pub piece_length: Option<u64>,
+
+    /// Tracker URL; repeat for more tiers, separate URLs in one tier with commas
+    #[arg(short, long = "tracker", value_name = "URL[,URL...]")]
+    pub trackers: Vec<String>,
+
+    /// Web seed URL; repeat for more
+    #[arg(short, long = "web-seed", value_name = "URL")]
+    pub web_seeds: Vec<String>,
+
+    /// Comment stored in the torrent
+    #[arg(short, long)]
+    pub comment: Option<String>,
+
+    /// Program named as the creator
+    #[arg(long, value_name = "NAME", default_value = concat!("rust-torrent-downloader/", env!("CARGO_PKG_VERSION")))]
+    pub created_by: String,
+
+    /// Creation date as a Unix timestamp (default: now)
+    #[arg(long, value_name = "UNIX_TIME", conflicts_with = "no_date")]
+    pub creation_date: Option<i64>,
+
+    /// Leave out the creation date, so the same files give the same .torrent
+    #[arg(long)]
+    pub no_date: bool,
+
+    /// Mark the torrent private, so clients only use its trackers
+    #[arg(long)]
+    pub private: bool,
+
+    /// Source tag, giving the torrent a distinct info hash per site
+    #[arg(long, value_name = "TAG")]
+    pub source: Option<String>,
+
+    /// Number of hashing threads (default: one per CPU)
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+}
+
+/// Arguments of the `edit` command
+#[derive(Debug, Args)]
+pub struct EditArgs {
+    /// The .torrent files to edit, in place unless --output is given
+    #[arg(value_name = "TORRENT_FILE", required = true)]
+    pub torrent_files: Vec<PathBuf>,
+
+    /// Where to write the edited torrent (only with a single file)
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Replace all trackers; repeat for more tiers, separate URLs in one tier with commas
+    #[arg(short, long = "tracker", value_name = "URL[,URL...]", conflicts_with = "clear_trackers")]
+    pub trackers: Vec<String>,
+
+    /// Remove all trackers
+    #[arg(long)]
+    pub clear_trackers: bool,
+
+    /// Replace text in every tracker URL, e.g. an old passkey with a new one
+    #[arg(long, num_args = 2, value_names = ["FROM", "TO"])]
+    pub replace_tracker: Vec<String>,
+
+    /// Set the comment
+    #[arg(short, long, conflicts_with = "clear_comment")]
+    pub comment: Option<String>,
+
+    /// Remove the comment
+    #[arg(long)]
+    pub clear_comment: bool,
+
+    /// Replace all web seeds; repeat for more
+    #[arg(short, long = "web-seed", value_name = "URL", conflicts_with = "clear_web_seeds")]
+    pub web_seeds: Vec<String>,
+
+    /// Remove all web seeds
+    #[arg(long)]
+    pub clear_web_seeds: bool,
+
+    /// Mark the torrent private or public; changes the info hash
+    #[arg(long, value_name = "BOOL")]
+    pub private: Option<bool>,
+
+    /// Set the source tag; changes the info hash
+    #[arg(long, value_name = "TAG", conflicts_with = "clear_source")]
+    pub source: Option<String>,
+
+    /// Remove the source tag; changes the info hash
+    #[arg(long)]
+    pub clear_source: bool,
+}
+
+impl EditArgs {
+    /// Get the new tracker tiers, one per `--tracker`
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        split_tiers(&self.trackers)
+    }
+
+    /// Get the `--replace-tracker` pairs
+    pub fn tracker_replacements(&self) -> Vec<(&str, &str)> {
+        self.replace_tracker.chunks_exact(2).map(|pair| (pair[0].as_str(), pair[1].as_str())).collect()
+    }
+}
+
+/// Split `--tracker` values into tiers of comma-separated URLs
+fn split_tiers(trackers: &[String]) -> Vec<Vec<String>> {
+    trackers.iter()
+        .map(|tier| tier.split(',').map(str::trim).filter(|url| !url.is_empty()).map(String::from).collect())
+        .filter(|tier: &Vec<String>| !tier.is_empty())
+        .collect()
+}
+
+impl CreateArgs {
+    /// Get the tracker tiers, one per `--tracker`
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        split_tiers(&self.trackers)
+    }
 }
 
 impl CliArgs {
@@ -94,7 +329,8 @@ mod tests {
     #[test]
     fn test_default_values() {
         let args = CliArgs {
-            torrent_file: PathBuf::from("test.torrent"),
+            command: None,
+            torrent_file: Some(PathBuf::from("test.torrent")),
             output_dir: None,
             port: 6881,
             max_connections: 50,
@@ -102,10 +338,28 @@ mod tests {
             seed_ratio: 1.0,
             seed_time: 0,
             use_dht: true,
+            dht_state: None,
+            dht_read_only: false,
+            dht_query_limit: DEFAULT_QUERY_LIMIT,
+            dht_query_limit_per_ip: DEFAULT_QUERY_LIMIT_PER_IP,
+            crawl: None,
+            crawl_time: 0,
+            utp: false,
+            no_ipv6: false,
             use_tracker: true,
             verbose: false,
             quiet: false,
             resume: false,
+            encryption: EncryptionPolicy::Prefer,
+            download_limit: 0,
+            upload_limit: 0,
+            torrent_download_limit: 0,
+            torrent_upload_limit: 0,
+            peer_download_limit: 0,
+            peer_upload_limit: 0,
+            limit_overhead: false,
+            only: vec![],
+            exclude: vec![],
         };
 
         assert_eq!(args.port, 6881);
@@ -115,5 +369,58 @@ mod tests {
         assert_eq!(args.seed_time, 0);
         assert!(args.use_dht);
         assert!(args.use_tracker);
+        assert!(!args.utp);
+        assert!(!args.no_ipv6);
+        assert!(!args.dht_read_only);
+        assert_eq!(args.encryption, EncryptionPolicy::Prefer);
+    }
+
+    #[test]
+    fn test_create_command() {
+        let args = CliArgs::try_parse_from([
+            "rust-torrent-downloader", "-v", "create", "build/", "-t", "http://a/announce, http://b/announce",
+            "-t", "udp://c:6969", "--private", "--no-date",
+        ]).unwrap();
+        assert!(args.verbose);
+        let Some(Command::Create(create)) = args.command else {
+            panic!("expected the create command");
+        };
+        assert_eq!(create.path, PathBuf::from("build/"));
+        assert_eq!(create.tracker_tiers(), vec![
+            vec!["http://a/announce".to_string(), "http://b/announce".to_string()],
+            vec!["udp://c:6969".to_string()],
+        ]);
+        assert!(create.private && create.no_date);
+
+        let args = CliArgs::try_parse_from([
+            "rust-torrent-downloader", "file.torrent", "--only", "*.mkv", "--only", "subs/**", "--exclude", "*sample*",
+        ]).unwrap();
+        assert_eq!(args.only, vec!["*.mkv".to_string(), "subs/**".to_string()]);
+        assert_eq!(args.exclude, vec!["*sample*".to_string()]);
+
+        let args = CliArgs::try_parse_from(["rust-torrent-downloader", "info", "file.torrent", "--json"]).unwrap();
+        let Some(Command::Info(info)) = args.command else {
+            panic!("expected the info command");
+        };
+        assert_eq!(info.torrent_file, PathBuf::from("file.torrent"));
+        assert!(info.json);
+
+        let args = CliArgs::try_parse_from([
+            "rust-torrent-downloader", "edit", "a.torrent", "b.torrent", "--replace-tracker", "old", "new",
+            "--replace-tracker", "http:", "https:", "--clear-comment", "--private", "false",
+        ]).unwrap();
+        let Some(Command::Edit(edit)) = args.command else {
+            panic!("expected the edit command");
+        };
+        assert_eq!(edit.torrent_files.len(), 2);
+        assert_eq!(edit.tracker_replacements(), vec![("old", "new"), ("http:", "https:")]);
+        assert!(edit.clear_comment);
+        assert_eq!(edit.private, Some(false));
+        assert!(CliArgs::try_parse_from(["rust-torrent-downloader", "edit", "a.torrent", "-c", "x", "--clear-comment"]).is_err());
+
+        let args = CliArgs::try_parse_from(["rust-torrent-downloader", "file.torrent"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.torrent_file, Some(PathBuf::from("file.torrent")));
+        assert!(CliArgs::try_parse_from(["rust-torrent-downloader"]).is_err());
     }
 }
@@ -3,11 +3,18 @@
 //! Manages configuration for the CLI application.
 
 use crate::cli::args::CliArgs;
+use crate::protocol::EncryptionPolicy;
 use crate::torrent::TorrentInfo;
 use std::path::PathBuf;
 use std::time::Duration;
 use anyhow::Result;
 
+/// DHT state file name used inside the download directory
+pub const DEFAULT_DHT_STATE_FILE: &str = ".dht_state.json";
+
+/// Suffix added to the DHT state file name for the IPv6 DHT
+const DHT6_STATE_SUFFIX: &str = "6";
+
 /// Configuration for the torrent downloader
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -27,12 +34,44 @@ pub struct Config {
     pub seed_time: Duration,
     /// Enable DHT
     pub use_dht: bool,
+    /// DHT state file
+    pub dht_state_file: PathBuf,
+    /// Query the DHT without answering (BEP 43)
+    pub dht_read_only: bool,
+    /// Inbound DHT queries answered per second (0 = unlimited)
+    pub dht_query_limit: u32,
+    /// Inbound DHT queries answered per second from one address (0 = unlimited)
+    pub dht_query_limit_per_ip: u32,
+    /// Enable uTP connections
+    pub use_utp: bool,
+    /// Listen and run the DHT on IPv6 as well as IPv4
+    pub use_ipv6: bool,
     /// Enable tracker
     pub use_tracker: bool,
     /// Verbose output
     pub verbose: bool,
     /// Quiet mode
     pub quiet: bool,
+    /// Peer connection encryption policy
+    pub encryption: EncryptionPolicy,
+    /// Global download limit in bytes per second (0 = unlimited)
+    pub download_limit: u64,
+    /// Global upload limit in bytes per second (0 = unlimited)
+    pub upload_limit: u64,
+    /// Torrent download limit in bytes per second (0 = unlimited)
+    pub torrent_download_limit: u64,
+    /// Torrent upload limit in bytes per second (0 = unlimited)
+    pub torrent_upload_limit: u64,
+    /// Per-peer download limit in bytes per second (0 = unlimited)
+    pub peer_download_limit: u64,
+    /// Per-peer upload limit in bytes per second (0 = unlimited)
+    pub peer_upload_limit: u64,
+    /// Count protocol overhead against the rate limits
+    pub limit_overhead: bool,
+    /// Only download files matching these globs (empty = all files)
+    pub only_files: Vec<String>,
+    /// Don't download files matching these globs
+    pub exclude_files: Vec<String>,
 }
 
 impl Config {
@@ -44,16 +83,34 @@ impl Config {
 
         Self {
             torrent_info,
-            output_dir,
+            output_dir: output_dir.clone(),
             port: args.port,
             max_connections: args.max_connections,
             seed: args.seed,
             seed_ratio: args.seed_ratio,
             seed_time: Duration::from_secs(args.seed_time * 60),
             use_dht: args.use_dht,
+            dht_state_file: args.dht_state
+                .clone()
+                .unwrap_or_else(|| output_dir.join(DEFAULT_DHT_STATE_FILE)),
+            dht_read_only: args.dht_read_only,
+            dht_query_limit: args.dht_query_limit,
+            dht_query_limit_per_ip: args.dht_query_limit_per_ip,
+            use_utp: args.utp,
+            use_ipv6: !args.no_ipv6,
             use_tracker: args.use_tracker,
             verbose: args.verbose,
             quiet: args.quiet,
+            encryption: args.encryption,
+            download_limit: args.download_limit.saturating_mul(1024),
+            upload_limit: args.upload_limit.saturating_mul(1024),
+            torrent_download_limit: args.torrent_download_limit.saturating_mul(1024),
+            torrent_upload_limit: args.torrent_upload_limit.saturating_mul(1024),
+            peer_download_limit: args.peer_download_limit.saturating_mul(1024),
+            peer_upload_limit: args.peer_upload_limit.saturating_mul(1024),
+            limit_overhead: args.limit_overhead,
+            only_files: args.only.clone(),
+            exclude_files: args.exclude.clone(),
         }
     }
 
@@ -93,11 +150,41 @@ impl Config {
         format!("0.0.0.0:{}", self.port)
     }
 
+    /// Get the IPv6 listen address for incoming connections
+    pub fn get_listen_addr6(&self) -> String {
+        format!("[::]:{}", self.port)
+    }
+
+    /// Check if IPv6 should be enabled
+    pub fn is_ipv6_enabled(&self) -> bool {
+        self.use_ipv6
+    }
+
+    /// Get the state file of the IPv6 DHT, next to the IPv4 one
+    pub fn dht6_state_file(&self) -> PathBuf {
+        let stem = self.dht_state_file.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!("{}{}", stem, DHT6_STATE_SUFFIX);
+        if let Some(extension) = self.dht_state_file.extension() {
+            name = format!("{}.{}", name, extension.to_string_lossy());
+        }
+        self.dht_state_file.with_file_name(name)
+    }
+
+    /// Check if only some files should be downloaded
+    pub fn has_file_selection(&self) -> bool {
+        !self.only_files.is_empty() || !self.exclude_files.is_empty()
+    }
+
     /// Check if DHT should be enabled
     pub fn is_dht_enabled(&self) -> bool {
         self.use_dht
     }
 
+    /// Check if uTP should be enabled
+    pub fn is_utp_enabled(&self) -> bool {
+        self.use_utp
+    }
+
     /// Check if tracker should be enabled
     pub fn is_tracker_enabled(&self) -> bool {
         self.use_tracker
@@ -126,6 +213,18 @@ impl Config {
         }
     }
 
+    /// Check if any rate limit is set
+    pub fn is_rate_limited(&self) -> bool {
+        [
+            self.download_limit,
+            self.upload_limit,
+            self.torrent_download_limit,
+            self.torrent_upload_limit,
+            self.peer_download_limit,
+            self.peer_upload_limit,
+        ].iter().any(|&limit| limit > 0)
+    }
+
     /// Check if verbose mode is enabled
     pub fn is_verbose(&self) -> bool {
         self.verbose
@@ -144,7 +243,8 @@ mod tests {
     #[test]
     fn test_config_from_args() {
         let args = CliArgs {
-            torrent_file: PathBuf::from("test.torrent"),
+            command: None,
+            torrent_file: Some(PathBuf::from("test.torrent")),
             output_dir: Some(PathBuf::from("/tmp/downloads")),
             port: 6882,
             max_connections: 100,
@@ -152,10 +252,28 @@ mod tests {
             seed_ratio: 2.0,
             seed_time: 60,
             use_dht: false,
+            dht_state: None,
+            dht_read_only: true,
+            dht_query_limit: 100,
+            dht_query_limit_per_ip: 2,
+            crawl: None,
+            crawl_time: 0,
+            utp: true,
+            no_ipv6: true,
             use_tracker: true,
             verbose: true,
             quiet: false,
             resume: false,
+            encryption: EncryptionPolicy::Require,
+            download_limit: 1000,
+            upload_limit: 100,
+            torrent_download_limit: 0,
+            torrent_upload_limit: 50,
+            peer_download_limit: u64::MAX,
+            peer_upload_limit: 0,
+            limit_overhead: true,
+            only: vec!["*.mkv".to_string()],
+            exclude: vec![],
         };
 
         let torrent_info = TorrentInfo {
@@ -167,17 +285,36 @@ mod tests {
             name: "test_torrent".to_string(),
             length: Some(1048576),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         let config = Config::from_args(&args, torrent_info);
 
         assert_eq!(config.output_dir, PathBuf::from("/tmp/downloads"));
+        assert_eq!(config.dht_state_file, PathBuf::from("/tmp/downloads/.dht_state.json"));
         assert_eq!(config.port, 6882);
         assert_eq!(config.max_connections, 100);
         assert!(config.seed);
         assert_eq!(config.seed_ratio, 2.0);
         assert_eq!(config.seed_time, Duration::from_secs(3600));
         assert!(!config.use_dht);
+        assert!(config.dht_read_only);
+        assert_eq!((config.dht_query_limit, config.dht_query_limit_per_ip), (100, 2));
+        assert!(config.use_utp);
+        assert!(!config.is_ipv6_enabled());
+        assert_eq!(config.dht6_state_file(), PathBuf::from("/tmp/downloads/.dht_state6.json"));
+        assert_eq!(config.encryption, EncryptionPolicy::Require);
+        assert_eq!(config.download_limit, 1000 * 1024);
+        assert_eq!(config.upload_limit, 100 * 1024);
+        assert_eq!(config.torrent_upload_limit, 50 * 1024);
+        // Huge limits saturate instead of overflowing
+        assert_eq!(config.peer_download_limit, u64::MAX);
+        assert!(config.is_rate_limited());
+        assert!(config.limit_overhead);
+        assert_eq!(config.only_files, vec!["*.mkv".to_string()]);
+        assert!(config.has_file_selection());
         assert!(config.use_tracker);
         assert!(config.verbose);
         assert!(!config.quiet);
@@ -194,20 +331,39 @@ mod tests {
             name: "test_torrent".to_string(),
             length: Some(1048576),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         let config = Config {
             torrent_info,
             output_dir: PathBuf::from("./downloads"),
+            dht_state_file: PathBuf::from("./downloads/.dht_state.json"),
+            dht_read_only: false,
+            dht_query_limit: 250,
+            dht_query_limit_per_ip: 5,
             port: 6881,
             max_connections: 50,
             seed: true,
             seed_ratio: 1.0,
             seed_time: Duration::ZERO,
             use_dht: true,
+            use_utp: false,
+            use_ipv6: true,
             use_tracker: true,
             verbose: false,
             quiet: false,
+            encryption: EncryptionPolicy::Prefer,
+            download_limit: 0,
+            upload_limit: 0,
+            torrent_download_limit: 0,
+            torrent_upload_limit: 0,
+            peer_download_limit: 0,
+            peer_upload_limit: 0,
+            limit_overhead: false,
+            only_files: vec![],
+            exclude_files: vec![],
         };
 
         assert!(config.validate().is_ok());
@@ -224,20 +380,39 @@ mod tests {
             name: "test_torrent".to_string(),
             length: Some(1048576),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         let config = Config {
             torrent_info,
             output_dir: PathBuf::from("./downloads"),
+            dht_state_file: PathBuf::from("./downloads/.dht_state.json"),
+            dht_read_only: false,
+            dht_query_limit: 250,
+            dht_query_limit_per_ip: 5,
             port: 0,
             max_connections: 50,
             seed: true,
             seed_ratio: 1.0,
             seed_time: Duration::ZERO,
             use_dht: true,
+            use_utp: false,
+            use_ipv6: true,
             use_tracker: true,
             verbose: false,
             quiet: false,
+            encryption: EncryptionPolicy::Prefer,
+            download_limit: 0,
+            upload_limit: 0,
+            torrent_download_limit: 0,
+            torrent_upload_limit: 0,
+            peer_download_limit: 0,
+            peer_upload_limit: 0,
+            limit_overhead: false,
+            only_files: vec![],
+            exclude_files: vec![],
         };
 
         assert!(config.validate().is_err());
@@ -254,20 +429,39 @@ mod tests {
             name: "test_torrent".to_string(),
             length: Some(1048576),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         let config = Config {
             torrent_info,
             output_dir: PathBuf::from("./downloads"),
+            dht_state_file: PathBuf::from("./downloads/.dht_state.json"),
+            dht_read_only: false,
+            dht_query_limit: 250,
+            dht_query_limit_per_ip: 5,
             port: 6881,
             max_connections: 50,
             seed: true,
             seed_ratio: 1.0,
             seed_time: Duration::ZERO,
             use_dht: true,
+            use_utp: false,
+            use_ipv6: true,
             use_tracker: true,
             verbose: false,
             quiet: false,
+            encryption: EncryptionPolicy::Prefer,
+            download_limit: 0,
+            upload_limit: 0,
+            torrent_download_limit: 0,
+            torrent_upload_limit: 0,
+            peer_download_limit: 0,
+            peer_upload_limit: 0,
+            limit_overhead: false,
+            only_files: vec![],
+            exclude_files: vec![],
         };
 
         assert_eq!(config.get_listen_addr(), "0.0.0.0:6881");
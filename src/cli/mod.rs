@@ -6,6 +6,6 @@ pub mod args;
 pub mod config;
 pub mod progress;
 
-pub use args::CliArgs;
+pub use args::{CliArgs, Command, CreateArgs, EditArgs, InfoArgs};
 pub use config::Config;
 pub use progress::{ProgressDisplay, DownloadStats};
@@ -2,7 +2,8 @@
 //!
 //! Handles bootstrapping the DHT network and discovering peers.
 
-use crate::dht::message::{generate_transaction_id, parse_compact_peers, DHTMessage};
+use crate::dht::lookup::{announce_to, run, Lookup, LookupKind, LookupResult, SocketQuerier};
+use crate::dht::message::{generate_transaction_id, DHTMessage, Want};
 use crate::dht::node::{Node, NodeId};
 use crate::dht::routing::RoutingTable;
 use anyhow::Result;
@@ -11,6 +12,13 @@ use std::time::Duration;
 use tokio::net::UdpSocket;
 use tokio::time::sleep;
 
+/// Well-known routers used to join the DHT
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
 /// Bootstrap configuration
 #[derive(Debug, Clone)]
 pub struct BootstrapConfig {
@@ -39,18 +47,35 @@ impl BootstrapConfig {
     }
 
     /// Get default bootstrap nodes
+    ///
+    /// Only numeric addresses are returned; use [`resolve_bootstrap_nodes`]
+    /// to look up the default routers by name.
     pub fn get_default_bootstrap_nodes() -> Vec<SocketAddr> {
-        vec![
-            "router.bittorrent.com:6881".parse::<SocketAddr>().unwrap_or_else(|_| "0.0.0.0:0".parse::<SocketAddr>().unwrap()),
-            "dht.transmissionbt.com:6881".parse::<SocketAddr>().unwrap_or_else(|_| "0.0.0.0:0".parse::<SocketAddr>().unwrap()),
-            "router.utorrent.com:6881".parse::<SocketAddr>().unwrap_or_else(|_| "0.0.0.0:0".parse::<SocketAddr>().unwrap()),
-        ]
-        .into_iter()
-        .filter(|addr| addr.port() != 0)
-        .collect()
+        DEFAULT_BOOTSTRAP_NODES.iter()
+            .filter_map(|host| host.parse::<SocketAddr>().ok())
+            .collect()
     }
 }
 
+/// Resolve bootstrap hosts to addresses of one family, skipping hosts that fail
+pub async fn resolve_bootstrap_nodes(hosts: &[&str], family: Want) -> Vec<SocketAddr> {
+    let mut nodes = Vec::new();
+    for host in hosts {
+        match tokio::net::lookup_host(host).await {
+            Ok(addrs) => {
+                for addr in addrs.filter(|addr| family.matches(addr)) {
+                    if !nodes.contains(&addr) {
+                        tracing::debug!("Resolved bootstrap node {} to {}", host, addr);
+                        nodes.push(addr);
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("Failed to resolve bootstrap node {}: {}", host, e),
+        }
+    }
+    nodes
+}
+
 /// Bootstrap the DHT network
 pub async fn bootstrap(
     socket: &UdpSocket,
@@ -81,6 +106,19 @@ pub async fn bootstrap(
     Ok(())
 }
 
+/// Run a `get_peers` lookup on a socket nobody else is reading
+async fn lookup_peers(
+    querier: &SocketQuerier<'_>,
+    our_id: NodeId,
+    routing_table: &RoutingTable,
+    info_hash: [u8; 20],
+) -> Result<LookupResult> {
+    let target = NodeId::new(info_hash);
+    let seeds = routing_table.find_closest_nodes(&target);
+    let lookup = Lookup::new(target, LookupKind::GetPeers, our_id, seeds);
+    querier.drive(run(lookup, querier)).await
+}
+
 /// Discover peers for a torrent
 pub async fn discover_peers(
     socket: &UdpSocket,
@@ -90,27 +128,16 @@ pub async fn discover_peers(
 ) -> Result<Vec<SocketAddr>> {
     tracing::info!("Discovering peers for torrent...");
 
-    let mut discovered_peers = Vec::new();
-    let closest_nodes = routing_table.find_closest_nodes(&NodeId::new(info_hash));
-
-    // Query closest nodes for peers
-    for node in closest_nodes {
-        let transaction_id = generate_transaction_id();
-        let get_peers_query =
-            DHTMessage::create_get_peers_query(transaction_id, our_id, info_hash);
-        let serialized = get_peers_query.serialize()?;
-
-        socket.send_to(&serialized, node.addr).await?;
-        tracing::debug!("Sent get_peers to node: {}", node.addr);
-
-        // Wait for response (simplified - in real implementation, use async channels)
-        sleep(Duration::from_millis(100)).await;
-    }
+    let querier = SocketQuerier::new(socket);
+    let result = lookup_peers(&querier, our_id, routing_table, info_hash).await?;
+    tracing::info!("Discovered {} peers after {} queries", result.peers.len(), result.queries);
 
-    Ok(discovered_peers)
+    Ok(result.peers)
 }
 
 /// Announce ourselves to the DHT
+///
+/// Looks up the closest nodes first to get their write tokens.
 pub async fn announce(
     socket: &UdpSocket,
     our_id: NodeId,
@@ -120,37 +147,16 @@ pub async fn announce(
 ) -> Result<()> {
     tracing::info!("Announcing to DHT...");
 
-    let closest_nodes = routing_table.find_closest_nodes(&NodeId::new(info_hash));
-
-    // In a real implementation, we would:
-    // 1. First do get_peers to get tokens
-    // 2. Then announce_peer with those tokens
-
-    for node in closest_nodes {
-        let transaction_id = generate_transaction_id();
-        // Note: This would require a valid token from get_peers response
-        // For now, we'll use a placeholder token
-        let token = "placeholder_token".to_string();
-
-        let announce_query = DHTMessage::create_announce_peer_query(
-            transaction_id,
-            our_id,
-            info_hash,
-            port,
-            token,
-        );
-        let serialized = announce_query.serialize()?;
-
-        socket.send_to(&serialized, node.addr).await?;
-        tracing::debug!("Sent announce_peer to node: {}", node.addr);
-    }
+    let querier = SocketQuerier::new(socket);
+    let result = lookup_peers(&querier, our_id, routing_table, info_hash).await?;
+    let accepted = querier.drive(announce_to(&querier, our_id, info_hash, port, &result)).await?;
 
-    tracing::info!("Announcement complete");
+    tracing::info!("Announcement complete, accepted by {} nodes", accepted);
     Ok(())
 }
 
 /// Generate a deterministic node ID for a bootstrap node
-fn generate_bootstrap_node_id(addr: &SocketAddr) -> NodeId {
+pub(crate) fn generate_bootstrap_node_id(addr: &SocketAddr) -> NodeId {
     use sha1::{Digest, Sha1};
     let mut hasher = Sha1::new();
     hasher.update(addr.to_string().as_bytes());
@@ -175,10 +181,14 @@ pub async fn bootstrap_and_discover(
     // Wait a bit for responses
     sleep(Duration::from_secs(2)).await;
     
-    // Discover peers
-    let peers = discover_peers(socket, our_id, routing_table, info_hash).await?;
-    
-    Ok(peers)
+    // Discover peers, keeping the nodes that answered
+    let querier = SocketQuerier::new(socket);
+    let result = lookup_peers(&querier, our_id, routing_table, info_hash).await?;
+    for (node, _) in result.closest {
+        routing_table.add_node(node);
+    }
+
+    Ok(result.peers)
 }
 
 #[cfg(test)]
@@ -213,6 +223,15 @@ mod tests {
         assert_eq!(node_id, node_id2);
     }
 
+    #[tokio::test]
+    async fn test_resolve_bootstrap_nodes() {
+        let hosts = ["127.0.0.1:6881", "127.0.0.1:6881", "[::1]:6881", "not a host"];
+        let nodes = resolve_bootstrap_nodes(&hosts, Want::N4).await;
+        assert_eq!(nodes, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+        let nodes = resolve_bootstrap_nodes(&hosts, Want::N6).await;
+        assert_eq!(nodes, vec!["[::1]:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
     #[tokio::test]
     async fn test_bootstrap() {
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
@@ -0,0 +1,271 @@
+//! DHT crawler (BEP 51)
+//!
+//! Walks the keyspace with `sample_infohashes` queries for random targets,
+//! collecting the info hashes other nodes store. Every node that answers is
+//! only sampled again after the `interval` it asked for.
+
+use crate::dht::dht::DHT;
+use crate::dht::message::{
+    generate_transaction_id, get_bytes, parse_compact_nodes_of, parse_samples, BencodeValue, DHTMessage,
+};
+use crate::dht::node::{Node, NodeId};
+use anyhow::Result;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, trace};
+
+/// Number of nodes sampled at the same time
+pub const CRAWL_CONCURRENCY: usize = 8;
+
+/// Shortest wait before sampling a node again, whatever interval it asks for
+pub const MIN_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest interval a node may ask for (BEP 51)
+pub const MAX_SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Maximum number of nodes the crawler keeps track of
+const MAX_CRAWL_NODES: usize = 10_000;
+
+/// How long the crawler waits when no node is due
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// An info hash found while crawling, written as one JSON line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscoveredInfoHash {
+    /// Hex encoded info hash
+    pub info_hash: String,
+    /// Node that sampled it
+    pub source: SocketAddr,
+    /// Unix time it was first seen
+    pub discovered_at: u64,
+}
+
+/// Crawls the DHT through a running node
+pub struct Crawler {
+    dht: Arc<DHT>,
+    /// Known nodes and when each may be sampled again
+    nodes: HashMap<SocketAddr, (NodeId, Instant)>,
+    /// Info hashes found so far
+    seen: HashSet<[u8; 20]>,
+    concurrency: usize,
+}
+
+/// What a node told us
+struct Sample {
+    info_hashes: Vec<[u8; 20]>,
+    nodes: Vec<(NodeId, SocketAddr)>,
+    interval: Duration,
+}
+
+impl Crawler {
+    /// Create a crawler; the DHT's event loop must be running
+    pub fn new(dht: Arc<DHT>) -> Self {
+        Self {
+            dht,
+            nodes: HashMap::new(),
+            seen: HashSet::new(),
+            concurrency: CRAWL_CONCURRENCY,
+        }
+    }
+
+    /// Sample a custom number of nodes at the same time
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Get the number of info hashes found so far
+    pub fn discovered_count(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Get the number of nodes the crawler knows
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Add nodes to sample, due immediately
+    pub fn add_nodes(&mut self, nodes: impl IntoIterator<Item = (NodeId, SocketAddr)>) {
+        let now = Instant::now();
+        for (id, addr) in nodes {
+            if self.nodes.len() >= MAX_CRAWL_NODES {
+                break;
+            }
+            self.nodes.entry(addr).or_insert((id, now));
+        }
+    }
+
+    /// Sample every node that is due, returning the newly found info hashes
+    pub async fn crawl_once(&mut self) -> Vec<DiscoveredInfoHash> {
+        let now = Instant::now();
+        let due: Vec<Node> = self.nodes.iter()
+            .filter(|(_, (_, next))| *next <= now)
+            .map(|(addr, (id, _))| Node::new(*id, *addr))
+            .collect();
+        if due.is_empty() {
+            return Vec::new();
+        }
+        trace!("Sampling {} of {} nodes", due.len(), self.nodes.len());
+
+        let dht = &self.dht;
+        let mut responses = stream::iter(due)
+            .map(|node| async move {
+                let sample = sample_node(dht, &node).await;
+                (node, sample)
+            })
+            .buffer_unordered(self.concurrency);
+
+        let mut discovered = Vec::new();
+        let mut found_nodes = Vec::new();
+        while let Some((node, sample)) = responses.next().await {
+            // Nodes that fail or don't support BEP 51 are kept, but left alone for a long time
+            let interval = match sample {
+                Some(sample) => {
+                    for info_hash in sample.info_hashes {
+                        if self.seen.insert(info_hash) {
+                            discovered.push(DiscoveredInfoHash {
+                                info_hash: hex::encode(info_hash),
+                                source: node.addr,
+                                discovered_at: unix_time(),
+                            });
+                        }
+                    }
+                    found_nodes.extend(sample.nodes);
+                    sample.interval
+                }
+                None => MAX_SAMPLE_INTERVAL,
+            };
+            self.nodes.insert(node.addr, (node.id, Instant::now() + interval));
+        }
+        drop(responses);
+
+        self.add_nodes(found_nodes);
+        if !discovered.is_empty() {
+            debug!("Discovered {} new info hashes ({} total)", discovered.len(), self.seen.len());
+        }
+        discovered
+    }
+
+    /// Crawl until the DHT stops or `duration` passes, writing each new info hash as a JSON line
+    ///
+    /// Starts from the DHT's routing table. Returns the number of info hashes written.
+    pub async fn run<W: Write>(&mut self, mut out: W, duration: Option<Duration>) -> Result<usize> {
+        let started = Instant::now();
+        let nodes = self.dht.get_all_nodes().await;
+        self.add_nodes(nodes.into_iter().map(|node| (node.id, node.addr)));
+        info!("Crawling the DHT from {} nodes", self.nodes.len());
+
+        let mut written = 0;
+        while *self.dht.running.read().await && duration.is_none_or(|duration| started.elapsed() < duration) {
+            let discovered = self.crawl_once().await;
+            if discovered.is_empty() {
+                if self.nodes.is_empty() {
+                    let nodes = self.dht.get_all_nodes().await;
+                    self.add_nodes(nodes.into_iter().map(|node| (node.id, node.addr)));
+                }
+                tokio::time::sleep(IDLE_WAIT).await;
+                continue;
+            }
+
+            for info_hash in &discovered {
+                serde_json::to_writer(&mut out, info_hash)?;
+                out.write_all(b"\n")?;
+            }
+            out.flush()?;
+            written += discovered.len();
+        }
+
+        info!("Crawl finished: {} info hashes from {} nodes", written, self.nodes.len());
+        Ok(written)
+    }
+}
+
+/// Send a sample_infohashes query for a random target
+async fn sample_node(dht: &DHT, node: &Node) -> Option<Sample> {
+    let query = DHTMessage::create_sample_infohashes_query(generate_transaction_id(), dht.our_id(), NodeId::random().0);
+    let response = match dht.query(node, query).await {
+        Ok(response) => response,
+        Err(e) => {
+            trace!("sample_infohashes to {} failed: {}", node.addr, e);
+            return None;
+        }
+    };
+    let args = response.args()?;
+
+    let nodes = get_bytes(args, dht.family().nodes_key())
+        .and_then(|nodes| parse_compact_nodes_of(dht.family(), nodes).ok())
+        .unwrap_or_default();
+    let info_hashes = match get_bytes(args, "samples").map(parse_samples) {
+        Some(Ok(info_hashes)) => info_hashes,
+        Some(Err(e)) => {
+            debug!("Invalid samples from {}: {}", node.addr, e);
+            Vec::new()
+        }
+        None => {
+            trace!("{} does not support sample_infohashes", node.addr);
+            return Some(Sample { info_hashes: Vec::new(), nodes, interval: MAX_SAMPLE_INTERVAL });
+        }
+    };
+    let interval = args.get("interval")
+        .and_then(BencodeValue::as_integer)
+        .map(|secs| Duration::from_secs(secs.max(0) as u64))
+        .unwrap_or(MIN_SAMPLE_INTERVAL)
+        .clamp(MIN_SAMPLE_INTERVAL, MAX_SAMPLE_INTERVAL);
+
+    Some(Sample { info_hashes, nodes, interval })
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerManager;
+
+    #[tokio::test]
+    async fn test_crawl_local_network() {
+        let mut dhts = Vec::new();
+        for _ in 0..3 {
+            let dht = Arc::new(DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap());
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+            dhts.push(dht);
+        }
+        // The crawler only knows the first node, which knows the second
+        dhts[1].routing_table.write().await.add_node(Node::new(dhts[2].our_id(), dhts[2].local_addr));
+        dhts[2].routing_table.write().await.add_node(Node::new(dhts[1].our_id(), dhts[1].local_addr));
+        dhts[0].routing_table.write().await.add_node(Node::new(dhts[1].our_id(), dhts[1].local_addr));
+        dhts[1].peer_store.write().await.announce([1u8; 20], "10.0.0.1:6881".parse().unwrap());
+        dhts[2].peer_store.write().await.announce([2u8; 20], "10.0.0.2:6881".parse().unwrap());
+        dhts[2].peer_store.write().await.announce([1u8; 20], "10.0.0.3:6881".parse().unwrap());
+
+        let mut crawler = Crawler::new(dhts[0].clone());
+        let mut out = Vec::new();
+        let written = crawler.run(&mut out, Some(Duration::from_millis(1500))).await.unwrap();
+        assert_eq!(written, 2);
+        assert_eq!(crawler.discovered_count(), 2);
+
+        let lines: Vec<DiscoveredInfoHash> = String::from_utf8(out).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let mut hashes: Vec<&str> = lines.iter().map(|line| line.info_hash.as_str()).collect();
+        hashes.sort();
+        assert_eq!(hashes, vec![hex::encode([1u8; 20]), hex::encode([2u8; 20])]);
+
+        // Sampled nodes are not due again before their interval
+        assert!(crawler.crawl_once().await.is_empty());
+
+        for dht in &dhts {
+            dht.stop().await;
+        }
+    }
+}
@@ -2,33 +2,69 @@
 //!
 //! Main DHT implementation for peer discovery.
 
-use crate::dht::bootstrap::{bootstrap, discover_peers, announce, BootstrapConfig};
+use crate::dht::bootstrap::{generate_bootstrap_node_id, resolve_bootstrap_nodes, DEFAULT_BOOTSTRAP_NODES};
+use crate::dht::lookup::{self, Lookup, LookupKind, LookupResult, Querier, QUERY_TIMEOUT};
 use crate::dht::message::{
-    parse_compact_nodes, parse_compact_peers, DHTMessage, QueryType,
-    Transaction,
+    error_code, generate_transaction_id, get_bytes, get_node_id, get_want, serialize_compact_nodes_of,
+    serialize_compact_peer, BencodeDict, BencodeValue, DHTMessage, QueryType, ResponseType, Transaction, Want,
 };
-use crate::dht::node::{Node, NodeId};
-use crate::dht::routing::RoutingTable;
-use crate::peer::PeerManager;
+use crate::dht::node::{Node, NodeId, GOOD_NODE_TIMEOUT};
+use crate::dht::item::{immutable_target, mutable_target, Item, ItemError, MutableItem};
+use crate::dht::item_store::ItemStore;
+use crate::dht::peer_store::{PeerStore, MAX_SAMPLES, MAX_VALUES};
+use crate::dht::rate_limit::QueryLimiter;
+use crate::dht::routing::{InsertOutcome, RoutingTable};
+use crate::dht::security::{is_valid_node_id, secure_node_id, ExternalIpVotes};
+use crate::dht::state::DhtState;
+use crate::dht::token::TokenManager;
+use crate::peer::{PeerManager, PeerSource};
 use crate::error::TorrentError;
+use crate::transport::{bind_udp, Datagram, UdpDemux};
 use anyhow::Result;
+use async_trait::async_trait;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
-use tokio::time::{interval};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tokio::time::{interval, timeout};
 use tracing::{debug, error, info, trace, warn};
 
+/// Time after which an unanswered query counts as failed
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Why a query was rejected, with the KRPC error code to reply with
+struct QueryError {
+    code: u32,
+    message: String,
+}
+
+impl From<&str> for QueryError {
+    fn from(message: &str) -> Self {
+        Self { code: error_code::PROTOCOL, message: message.to_string() }
+    }
+}
+
+impl From<ItemError> for QueryError {
+    fn from(error: ItemError) -> Self {
+        Self { code: error.code, message: error.message }
+    }
+}
+
+/// How often a tracked torrent is looked up and announced again
+pub const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(300);
+
 /// Main DHT struct
 pub struct DHT {
     /// Routing table
     pub routing_table: Arc<RwLock<RoutingTable>>,
-    /// UDP socket for DHT communication
-    pub socket: UdpSocket,
-    /// Our node ID
-    pub our_id: NodeId,
+    /// UDP socket for DHT communication, possibly shared with uTP
+    pub socket: Arc<UdpSocket>,
+    /// Our node ID, regenerated when our external address changes
+    our_id: std::sync::RwLock<NodeId>,
     /// Transaction tracking
     pub transactions: Arc<RwLock<HashMap<String, Transaction>>>,
     /// Peer manager for discovered peers
@@ -37,6 +73,26 @@ pub struct DHT {
     pub local_addr: SocketAddr,
     /// Running state
     pub running: Arc<RwLock<bool>>,
+    /// Peers announced to us
+    pub peer_store: Arc<RwLock<PeerStore>>,
+    /// Items put to us (BEP 44)
+    pub item_store: Arc<RwLock<ItemStore>>,
+    /// Announce tokens we hand out
+    tokens: std::sync::Mutex<TokenManager>,
+    /// Datagrams routed to us when the socket is shared
+    incoming: Option<Mutex<mpsc::Receiver<Datagram>>>,
+    /// Queries waiting for a response, by transaction ID
+    responders: std::sync::Mutex<HashMap<String, oneshot::Sender<DHTMessage>>>,
+    /// Routers to join through, or `None` for the default routers
+    bootstrap_nodes: std::sync::Mutex<Option<Vec<SocketAddr>>>,
+    /// Routing table of the DHT instance for the other address family
+    sibling_table: Option<Arc<RwLock<RoutingTable>>>,
+    /// Reports from other nodes of our external address
+    external_ip: std::sync::Mutex<ExternalIpVotes>,
+    /// Only query, never answer (BEP 43)
+    read_only: AtomicBool,
+    /// Limits on inbound queries
+    query_limiter: std::sync::Mutex<QueryLimiter>,
 }
 
 impl DHT {
@@ -47,7 +103,7 @@ impl DHT {
     ) -> Result<Self> {
         info!("Creating DHT instance on {}", bind_addr);
         
-        let socket = UdpSocket::bind(bind_addr).await
+        let socket = bind_udp(bind_addr)
             .map_err(|e| {
                 error!("Failed to bind UDP socket to {}: {}", bind_addr, e);
                 TorrentError::network_error_full("Failed to bind UDP socket", bind_addr.to_string(), e.to_string())
@@ -57,6 +113,28 @@ impl DHT {
                 error!("Failed to get local address: {}", e);
                 TorrentError::network_error_full("Failed to get local address", "unknown".to_string(), e.to_string())
             })?;
+
+        Ok(Self::with_socket(Arc::new(socket), local_addr, None, peer_manager))
+    }
+
+    /// Create a DHT instance on a UDP socket shared through a demultiplexer
+    pub fn with_demux(demux: &UdpDemux, peer_manager: Arc<PeerManager>) -> Result<Self> {
+        info!("Creating DHT instance on shared socket {}", demux.local_addr());
+        let incoming = demux.take_dht_receiver()
+            .ok_or_else(|| TorrentError::dht_error_full(
+                "DHT receiver already taken",
+                demux.local_addr().to_string(),
+                "the shared socket already serves a DHT instance".to_string(),
+            ))?;
+        Ok(Self::with_socket(demux.socket(), demux.local_addr(), Some(incoming), peer_manager))
+    }
+
+    fn with_socket(
+        socket: Arc<UdpSocket>,
+        local_addr: SocketAddr,
+        incoming: Option<mpsc::Receiver<Datagram>>,
+        peer_manager: Arc<PeerManager>,
+    ) -> Self {
         let our_id = NodeId::random();
         let routing_table = Arc::new(RwLock::new(RoutingTable::new(our_id)));
         let transactions = Arc::new(RwLock::new(HashMap::new()));
@@ -65,15 +143,124 @@ impl DHT {
         info!("DHT initialized with ID: {}", our_id.to_hex());
         info!("DHT listening on: {}", local_addr);
 
-        Ok(Self {
+        Self {
             routing_table,
             socket,
-            our_id,
+            our_id: std::sync::RwLock::new(our_id),
             transactions,
             peer_manager,
             local_addr,
             running,
-        })
+            peer_store: Arc::new(RwLock::new(PeerStore::new())),
+            item_store: Arc::new(RwLock::new(ItemStore::new())),
+            tokens: std::sync::Mutex::new(TokenManager::new()),
+            incoming: incoming.map(Mutex::new),
+            responders: std::sync::Mutex::new(HashMap::new()),
+            bootstrap_nodes: std::sync::Mutex::new(None),
+            sibling_table: None,
+            external_ip: std::sync::Mutex::new(ExternalIpVotes::new()),
+            read_only: AtomicBool::new(false),
+            query_limiter: std::sync::Mutex::new(QueryLimiter::new()),
+        }
+    }
+
+    /// Stop answering queries and mark ours read-only (BEP 43)
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    /// Check if we only query and never answer
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Limit inbound queries per second, in total and per address (0 = unlimited)
+    pub fn set_query_limits(&self, limit: u32, limit_per_ip: u32) {
+        *self.query_limiter.lock().unwrap() = QueryLimiter::with_limits(limit, limit_per_ip);
+    }
+
+    /// Get our node ID
+    pub fn our_id(&self) -> NodeId {
+        *self.our_id.read().unwrap()
+    }
+
+    /// Get our external address, once enough nodes agree on it
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip.lock().unwrap().current()
+    }
+
+    /// Get the address family this instance runs on
+    pub fn family(&self) -> Want {
+        Want::for_addr(&self.local_addr)
+    }
+
+    /// Let an IPv4 and an IPv6 instance answer `want` queries from each other's routing table
+    ///
+    /// Call after loading saved state, which replaces the routing tables.
+    pub fn pair(&mut self, other: &mut DHT) {
+        info!("Pairing {:?} DHT on {} with {:?} DHT on {}", self.family(), self.local_addr, other.family(), other.local_addr);
+        self.sibling_table = Some(other.routing_table.clone());
+        other.sibling_table = Some(self.routing_table.clone());
+    }
+
+    /// Join the DHT through these routers instead of the default ones
+    pub fn set_bootstrap_nodes(&self, nodes: Vec<SocketAddr>) {
+        *self.bootstrap_nodes.lock().unwrap() = Some(nodes);
+    }
+
+    /// Get our node ID and a sample of good nodes for saving
+    pub async fn state(&self) -> DhtState {
+        DhtState::from_routing_table(&*self.routing_table.read().await)
+    }
+
+    /// Take over a saved node ID and nodes
+    ///
+    /// Must be called before the DHT is started. Returns the number of nodes
+    /// restored.
+    pub fn restore_state(&mut self, state: &DhtState) -> usize {
+        if let Some(our_id) = state.node_id() {
+            *self.our_id.get_mut().unwrap() = our_id;
+        }
+
+        let mut routing_table = RoutingTable::new(self.our_id());
+        let restored = state.nodes()
+            .into_iter()
+            .filter(|node| routing_table.add_node(node.clone()))
+            .count();
+        self.routing_table = Arc::new(RwLock::new(routing_table));
+
+        info!("Restored DHT ID {} with {} nodes", self.our_id().to_hex(), restored);
+        restored
+    }
+
+    /// Save our node ID and known nodes to a state file
+    pub async fn save_state(&self, path: &Path) -> Result<()> {
+        let state = self.state().await;
+        state.save(path).await
+            .map_err(|e| {
+                error!("Failed to save DHT state to {}: {}", path.display(), e);
+                TorrentError::dht_error_full("Failed to save DHT state", path.display().to_string(), e.to_string())
+            })?;
+        debug!("Saved DHT state with {} nodes to {}", state.nodes.len(), path.display());
+        Ok(())
+    }
+
+    /// Restore state from a state file, if it exists
+    ///
+    /// Returns the number of nodes restored.
+    pub async fn load_state(&mut self, path: &Path) -> Result<usize> {
+        let state = DhtState::load(path).await
+            .map_err(|e| {
+                error!("Failed to load DHT state from {}: {}", path.display(), e);
+                TorrentError::dht_error_full("Failed to load DHT state", path.display().to_string(), e.to_string())
+            })?;
+        match state {
+            Some(state) => Ok(self.restore_state(&state)),
+            None => {
+                debug!("No DHT state at {}", path.display());
+                Ok(0)
+            }
+        }
     }
 
     /// Start DHT service
@@ -88,46 +275,109 @@ impl DHT {
 
         info!("Starting DHT service...");
 
-        // Bootstrap DHT
-        let info_hash = [0u8; 20]; // Placeholder - will be set when downloading
-        let config = BootstrapConfig::with_defaults(info_hash);
-        let mut routing_table = self.routing_table.write().await;
-        bootstrap(&self.socket, self.our_id, &mut routing_table, &config).await
-            .map_err(|e| {
-                error!("Failed to bootstrap DHT: {}", e);
-                TorrentError::dht_error_full("Failed to bootstrap DHT", "unknown".to_string(), e.to_string())
-            })?;
-        drop(routing_table);
+        // Check the nodes we already knew, e.g. from a saved state
+        let known_nodes = self.routing_table.read().await.get_nodes();
+        for node in &known_nodes {
+            let ping = DHTMessage::create_ping_query(generate_transaction_id(), self.our_id());
+            if let Err(e) = self.send_query(node, ping).await {
+                debug!("Failed to ping {}: {}", node.addr, e);
+            }
+        }
 
         info!("DHT service started");
         Ok(())
     }
 
+    /// Join the network by looking up our own ID through the routers
+    ///
+    /// Needs the event loop running. Returns the number of nodes we know afterwards.
+    pub async fn bootstrap(&self) -> usize {
+        let configured = self.bootstrap_nodes.lock().unwrap().clone();
+        let routers = match configured {
+            Some(nodes) => nodes,
+            None => resolve_bootstrap_nodes(DEFAULT_BOOTSTRAP_NODES, self.family()).await,
+        };
+        info!("Bootstrapping DHT through {} routers", routers.len());
+
+        let mut seeds = self.routing_table.read().await.find_closest_nodes(&self.our_id());
+        seeds.extend(routers.iter().map(|addr| Node::new(generate_bootstrap_node_id(addr), *addr)));
+        let lookup = Lookup::new(self.our_id(), LookupKind::FindNode, self.our_id(), seeds).with_family(self.family());
+        let result = lookup::run(lookup, self).await;
+
+        let count = self.node_count().await;
+        if count == 0 {
+            warn!("DHT bootstrap found no nodes after {} queries", result.queries);
+        } else {
+            info!("DHT bootstrap complete: {} nodes after {} queries", count, result.queries);
+        }
+        count
+    }
+
     /// Send a query to a node
-    pub async fn send_query(&self, node: &Node, message: DHTMessage) -> Result<()> {
-        let transaction_id = message.get_transaction_id().unwrap_or_default();
-        let serialized = message.serialize()
-            .map_err(|e| {
-                error!("Failed to serialize DHT message: {}", e);
-                TorrentError::dht_error_full("Failed to serialize DHT message", "unknown".to_string(), e.to_string())
-            })?;
+    pub async fn send_query(&self, node: &Node, mut message: DHTMessage) -> Result<()> {
+        if self.is_read_only() {
+            message = message.with_read_only();
+        }
 
         // Track transaction
-        if let DHTMessage::Query { id, query_type, .. } = &message {
-            let transaction = Transaction::new(transaction_id.clone(), *id, query_type.clone());
+        if let DHTMessage::Query { query_type, .. } = &message {
+            let transaction_id = message.get_transaction_id().unwrap_or_default();
+            let transaction = Transaction::to_addr(transaction_id.clone(), node.id, query_type.clone(), node.addr);
             self.transactions.write().await.insert(transaction_id, transaction);
         }
 
-        self.socket.send_to(&serialized, node.addr).await
-            .map_err(|e| {
-                error!("Failed to send query to {}: {}", node.addr, e);
-                TorrentError::network_error_full("Failed to send query", node.addr.to_string(), e.to_string())
-            })?;
+        self.send_message(node.addr, &message).await?;
         debug!("Sent query to {}: {:?}", node.addr, message.message_type());
 
         Ok(())
     }
 
+    /// Query a node and wait for its response
+    ///
+    /// Responses are matched by `run_loop`, which must be running.
+    pub async fn query(&self, node: &Node, message: DHTMessage) -> Result<DHTMessage> {
+        let transaction_id = message.get_transaction_id()
+            .ok_or_else(|| TorrentError::dht_error("Query has no transaction ID"))?;
+        let (tx, rx) = oneshot::channel();
+        self.responders.lock().unwrap().insert(transaction_id.clone(), tx);
+
+        if let Err(e) = self.send_query(node, message).await {
+            self.responders.lock().unwrap().remove(&transaction_id);
+            return Err(e);
+        }
+
+        let response = timeout(QUERY_TIMEOUT, rx).await;
+        self.responders.lock().unwrap().remove(&transaction_id);
+        match response {
+            Ok(Ok(DHTMessage::Error { code, message, .. })) => Err(TorrentError::dht_error_full(
+                "Node returned an error",
+                node.addr.to_string(),
+                format!("{} {}", code, message),
+            ).into()),
+            Ok(Ok(response)) => Ok(response),
+            _ => {
+                self.transactions.write().await.remove(&transaction_id);
+                self.routing_table.write().await.node_failed(&node.id);
+                Err(TorrentError::dht_error_with_node("Query timed out", node.addr.to_string()).into())
+            }
+        }
+    }
+
+    /// Serialize and send a message
+    async fn send_message(&self, addr: SocketAddr, message: &DHTMessage) -> Result<()> {
+        let serialized = message.serialize()
+            .map_err(|e| {
+                error!("Failed to serialize DHT message: {}", e);
+                TorrentError::dht_error_full("Failed to serialize DHT message", addr.to_string(), e.to_string())
+            })?;
+        self.socket.send_to(&serialized, addr).await
+            .map_err(|e| {
+                error!("Failed to send DHT message to {}: {}", addr, e);
+                TorrentError::network_error_full("Failed to send DHT message", addr.to_string(), e.to_string())
+            })?;
+        Ok(())
+    }
+
     /// Handle incoming DHT message
     pub async fn handle_message(&self, data: &[u8], from: SocketAddr) -> Result<()> {
         trace!("Handling message from {} ({} bytes)", from, data.len());
@@ -138,14 +388,14 @@ impl DHT {
             })?;
 
         match message {
-            DHTMessage::Query { id, query_type, args } => {
-                self.handle_query(id, query_type, args, from).await?;
+            DHTMessage::Query { transaction_id, query_type, args, read_only } => {
+                self.handle_query(transaction_id, query_type, args, read_only, from).await?;
             }
-            DHTMessage::Response { id, response_type, args } => {
-                self.handle_response(id, response_type, args, from).await?;
+            response @ DHTMessage::Response { .. } => {
+                self.handle_response(response, from).await?;
             }
-            DHTMessage::Error { id, code, message: msg } => {
-                self.handle_error(id, code, msg, from).await?;
+            error @ DHTMessage::Error { .. } => {
+                self.handle_error(error, from).await?;
             }
         }
 
@@ -155,243 +405,395 @@ impl DHT {
     /// Handle incoming query
     async fn handle_query(
         &self,
-        id: NodeId,
+        transaction_id: Vec<u8>,
         query_type: QueryType,
-        args: HashMap<String, crate::dht::message::BencodeValue>,
+        args: BencodeDict,
+        read_only: bool,
         from: SocketAddr,
     ) -> Result<()> {
         debug!("Received {} query from {}", query_type, from);
+        if self.is_read_only() {
+            trace!("Read-only, not answering {} query from {}", query_type, from);
+            return Ok(());
+        }
+        if !self.query_limiter.lock().unwrap().allow(from.ip()) {
+            trace!("Dropping {} query from {}: rate limited", query_type, from);
+            return Ok(());
+        }
+
+        let response = match self.answer_query(&query_type, &args, read_only, from).await {
+            Ok(response_args) => DHTMessage::create_response(transaction_id, self.our_id(), response_args).with_ip(from),
+            Err(error) => {
+                debug!("Rejecting {} query from {}: {}", query_type, from, error.message);
+                DHTMessage::create_error(transaction_id, error.code, error.message)
+            }
+        };
+        self.send_message(from, &response).await
+    }
 
-        // Add querying node to our routing table
-        let node = Node::new(id, from);
-        self.routing_table.write().await.add_node(node);
+    /// Build the response values for a query, or the reason to reject it
+    async fn answer_query(
+        &self,
+        query_type: &QueryType,
+        args: &BencodeDict,
+        read_only: bool,
+        from: SocketAddr,
+    ) -> std::result::Result<BencodeDict, QueryError> {
+        let id = get_node_id(args, "id").ok_or("missing id")?;
+
+        // Add querying node to our routing table, unless it won't answer our queries
+        if !read_only {
+            self.add_node(Node::new(id, from)).await;
+        }
+
+        // Without `want`, answer with nodes of the querying node's family
+        let mut wants = get_want(args);
+        if wants.is_empty() {
+            wants.push(Want::for_addr(&from));
+        }
 
-        // Handle different query types
+        let mut response_args = BencodeDict::new();
         match query_type {
-            QueryType::Ping => {
-                // Send ping response
-                let response = DHTMessage::Response {
-                    id: self.our_id,
-                    response_type: crate::dht::message::ResponseType::Ping,
-                    args: args,
-                };
-                let serialized = response.serialize()
-                    .map_err(|e| {
-                        error!("Failed to serialize ping response: {}", e);
-                        TorrentError::dht_error_full("Failed to serialize ping response", "unknown".to_string(), e.to_string())
-                    })?;
-                self.socket.send_to(&serialized, from).await
-                    .map_err(|e| {
-                        error!("Failed to send ping response to {}: {}", from, e);
-                        TorrentError::network_error_full("Failed to send ping response", from.to_string(), e.to_string())
-                    })?;
-            }
+            QueryType::Ping => {}
             QueryType::FindNode => {
-                // Find closest nodes to target
-                if let Some(target_hex) = args.get("target") {
-                    if let crate::dht::message::BencodeValue::String(target_str) = target_hex {
-                        if let Some(target_id) = NodeId::from_hex(target_str) {
-                            let closest = self.routing_table.read().await.find_closest_nodes(&target_id);
-                            let mut response_args = HashMap::new();
-                            response_args.insert(
-                                "id".to_string(),
-                                crate::dht::message::BencodeValue::String(self.our_id.to_hex()),
-                            );
-                            // In a real implementation, serialize nodes to compact format
-                            response_args.insert(
-                                "nodes".to_string(),
-                                crate::dht::message::BencodeValue::String("".to_string()),
-                            );
-
-                            let response = DHTMessage::Response {
-                                id: self.our_id,
-                                response_type: crate::dht::message::ResponseType::FindNode,
-                                args: response_args,
-                            };
-                            let serialized = response.serialize()
-                                .map_err(|e| {
-                                    error!("Failed to serialize find_node response: {}", e);
-                                    TorrentError::dht_error_full("Failed to serialize find_node response", "unknown".to_string(), e.to_string())
-                                })?;
-                            self.socket.send_to(&serialized, from).await
-                                .map_err(|e| {
-                                    error!("Failed to send find_node response to {}: {}", from, e);
-                                    TorrentError::network_error_full("Failed to send find_node response", from.to_string(), e.to_string())
-                                })?;
-                        }
-                    }
-                }
+                let target = get_node_id(args, "target").ok_or("missing target")?;
+                self.insert_closest_nodes(&mut response_args, &target, &wants).await;
             }
             QueryType::GetPeers => {
-                // Return peers if we have them, otherwise return closest nodes
-                let mut response_args = HashMap::new();
-                response_args.insert(
-                    "id".to_string(),
-                    crate::dht::message::BencodeValue::String(self.our_id.to_hex()),
-                );
-                response_args.insert(
-                    "token".to_string(),
-                    crate::dht::message::BencodeValue::String("token".to_string()),
-                );
-
-                let response = DHTMessage::Response {
-                    id: self.our_id,
-                    response_type: crate::dht::message::ResponseType::GetPeers,
-                    args: response_args,
-                };
-                let serialized = response.serialize()
-                    .map_err(|e| {
-                        error!("Failed to serialize get_peers response: {}", e);
-                        TorrentError::dht_error_full("Failed to serialize get_peers response", "unknown".to_string(), e.to_string())
-                    })?;
-                self.socket.send_to(&serialized, from).await
-                    .map_err(|e| {
-                        error!("Failed to send get_peers response to {}: {}", from, e);
-                        TorrentError::network_error_full("Failed to send get_peers response", from.to_string(), e.to_string())
-                    })?;
+                let info_hash = get_node_id(args, "info_hash").ok_or("missing info_hash")?;
+                self.insert_closest_nodes(&mut response_args, &info_hash, &wants).await;
+                let token = self.tokens.lock().unwrap().generate(from.ip());
+                response_args.insert("token".to_string(), BencodeValue::Bytes(token));
+
+                let values: Vec<BencodeValue> = self.peer_store.read().await
+                    .get_peers(&info_hash.0, MAX_VALUES)
+                    .iter()
+                    .filter(|peer| wants.contains(&Want::for_addr(peer)))
+                    .map(|peer| BencodeValue::Bytes(serialize_compact_peer(peer)))
+                    .collect();
+                if !values.is_empty() {
+                    trace!("Returning {} peers to {}", values.len(), from);
+                    response_args.insert("values".to_string(), BencodeValue::List(values));
+                }
             }
             QueryType::AnnouncePeer => {
-                // Acknowledge announce
-                let response = DHTMessage::Response {
-                    id: self.our_id,
-                    response_type: crate::dht::message::ResponseType::AnnouncePeer,
-                    args: args,
+                let info_hash = get_node_id(args, "info_hash").ok_or("missing info_hash")?;
+                self.check_token(args, from)?;
+
+                let implied_port = args.get("implied_port").and_then(BencodeValue::as_integer) == Some(1);
+                let port = if implied_port {
+                    from.port()
+                } else {
+                    args.get("port")
+                        .and_then(BencodeValue::as_integer)
+                        .and_then(|port| u16::try_from(port).ok())
+                        .filter(|port| *port != 0)
+                        .ok_or("invalid port")?
                 };
-                let serialized = response.serialize()
-                    .map_err(|e| {
-                        error!("Failed to serialize announce_peer response: {}", e);
-                        TorrentError::dht_error_full("Failed to serialize announce_peer response", "unknown".to_string(), e.to_string())
-                    })?;
-                self.socket.send_to(&serialized, from).await
-                    .map_err(|e| {
-                        error!("Failed to send announce_peer response to {}: {}", from, e);
-                        TorrentError::network_error_full("Failed to send announce_peer response", from.to_string(), e.to_string())
-                    })?;
+
+                let peer = SocketAddr::new(from.ip(), port);
+                if self.peer_store.write().await.announce(info_hash.0, peer) {
+                    debug!("Stored peer {} for {}", peer, info_hash.to_hex());
+                } else {
+                    warn!("Peer store full, dropping announce from {}", peer);
+                }
+            }
+            QueryType::SampleInfohashes => {
+                let target = get_node_id(args, "target").ok_or("missing target")?;
+                self.insert_closest_nodes(&mut response_args, &target, &wants).await;
+
+                let mut peer_store = self.peer_store.write().await;
+                let samples = peer_store.sample(MAX_SAMPLES);
+                trace!("Returning {} info hash samples to {}", samples.len(), from);
+                response_args.insert("interval".to_string(), BencodeValue::Integer(peer_store.sample_interval().as_secs() as i64));
+                response_args.insert("num".to_string(), BencodeValue::Integer(peer_store.torrent_count() as i64));
+                response_args.insert("samples".to_string(), BencodeValue::Bytes(samples.concat()));
+            }
+            QueryType::Get => {
+                let target = get_node_id(args, "target").ok_or("missing target")?;
+                self.insert_closest_nodes(&mut response_args, &target, &wants).await;
+                let token = self.tokens.lock().unwrap().generate(from.ip());
+                response_args.insert("token".to_string(), BencodeValue::Bytes(token));
+
+                let seq = args.get("seq").and_then(BencodeValue::as_integer);
+                match self.item_store.read().await.get(&target.0) {
+                    Some(Item::Immutable(value)) => {
+                        response_args.insert("v".to_string(), value.clone());
+                    }
+                    // The querying node already has this version or a newer one
+                    Some(Item::Mutable(item)) if seq.is_some_and(|seq| item.seq <= seq) => {
+                        response_args.insert("seq".to_string(), BencodeValue::Integer(item.seq));
+                    }
+                    Some(Item::Mutable(item)) => item.insert_into(&mut response_args),
+                    None => {}
+                }
+            }
+            QueryType::Put => {
+                self.check_token(args, from)?;
+                let item = Item::from_put_args(args)?;
+                item.verify()?;
+                let target = item.target();
+                let cas = args.get("cas").and_then(BencodeValue::as_integer);
+                self.item_store.write().await.put(item, cas)?;
+                debug!("Stored item {} from {}", hex::encode(target), from);
             }
         }
 
+        Ok(response_args)
+    }
+
+    /// Check the write token a node got from us earlier
+    fn check_token(&self, args: &BencodeDict, from: SocketAddr) -> std::result::Result<(), QueryError> {
+        let token = get_bytes(args, "token").ok_or("missing token")?;
+        if !self.tokens.lock().unwrap().validate(from.ip(), token) {
+            return Err("bad token".into());
+        }
         Ok(())
     }
 
-    /// Handle incoming response
-    async fn handle_response(
-        &self,
-        id: NodeId,
-        response_type: crate::dht::message::ResponseType,
-        args: HashMap<String, crate::dht::message::BencodeValue>,
-        from: SocketAddr,
-    ) -> Result<()> {
-        debug!("Received {:?} response from {}", response_type, from);
+    /// Add our closest nodes to a target for each wanted address family
+    async fn insert_closest_nodes(&self, response_args: &mut BencodeDict, target: &NodeId, wants: &[Want]) {
+        for want in wants {
+            let table = if *want == self.family() {
+                &self.routing_table
+            } else if let Some(sibling) = &self.sibling_table {
+                sibling
+            } else {
+                continue;
+            };
+            let closest: Vec<(NodeId, SocketAddr)> = table.read().await
+                .find_closest_nodes(target)
+                .into_iter()
+                .filter(|node| want.matches(&node.addr))
+                .map(|node| (node.id, node.addr))
+                .collect();
+            let compact = serialize_compact_nodes_of(*want, &closest).unwrap_or_default();
+            response_args.insert(want.nodes_key().to_string(), BencodeValue::Bytes(compact));
+        }
+    }
 
-        // Update node in routing table
-        let node = Node::new(id, from);
-        self.routing_table.write().await.add_node(node);
-
-        // Process response based on type
-        match response_type {
-            crate::dht::message::ResponseType::FindNode => {
-                // Parse and add nodes from response
-                if let Some(nodes_str) = args.get("nodes") {
-                    if let crate::dht::message::BencodeValue::String(nodes_data) = nodes_str {
-                        if let Ok(nodes_bytes) = hex::decode(nodes_data) {
-                            if let Ok(parsed_nodes) = parse_compact_nodes(&nodes_bytes) {
-                                let mut table = self.routing_table.write().await;
-                                for (node_id, addr) in &parsed_nodes {
-                                    table.add_node(Node::new(*node_id, *addr));
-                                }
-                                debug!("Added {} nodes from find_node response", parsed_nodes.len());
-                            }
-                        }
-                    }
-                }
+    /// Take the transaction a response or error answers, if it came from the queried node
+    async fn complete_transaction(&self, message: &DHTMessage, from: SocketAddr) -> Option<Transaction> {
+        let transaction_id = String::from_utf8_lossy(message.transaction_id()).into_owned();
+        let mut transactions = self.transactions.write().await;
+        match transactions.get(&transaction_id) {
+            Some(transaction) if transaction.addr.is_none_or(|addr| addr == from) => {
+                transactions.remove(&transaction_id)
             }
-            crate::dht::message::ResponseType::GetPeers => {
-                // Parse peers from response
-                if let Some(values) = args.get("values") {
-                    if let crate::dht::message::BencodeValue::String(peers_data) = values {
-                        if let Ok(peers_bytes) = hex::decode(peers_data) {
-                            if let Ok(parsed_peers) = parse_compact_peers(&peers_bytes) {
-                                for peer_addr in &parsed_peers {
-                                    if let Err(e) = self.peer_manager.add_peer(*peer_addr).await {
-                                        warn!("Failed to add peer {}: {}", peer_addr, e);
-                                    }
-                                }
-                                debug!("Added {} peers from get_peers response", parsed_peers.len());
-                            }
-                        }
-                    }
-                }
+            Some(_) => {
+                warn!("Ignoring {} for transaction {} from unexpected address {}", message.message_type(), transaction_id, from);
+                None
+            }
+            None => {
+                debug!("Ignoring {} for unknown transaction {} from {}", message.message_type(), transaction_id, from);
+                None
             }
-            _ => {}
         }
+    }
+
+    /// Hand a response or error to the query waiting for it
+    fn respond(&self, transaction: &Transaction, message: DHTMessage) {
+        if let Some(responder) = self.responders.lock().unwrap().remove(&transaction.transaction_id) {
+            let _ = responder.send(message);
+        }
+    }
+
+    /// Handle incoming response
+    async fn handle_response(&self, response: DHTMessage, from: SocketAddr) -> Result<()> {
+        let Some(transaction) = self.complete_transaction(&response, from).await else {
+            return Ok(());
+        };
+        debug!("Received {:?} response from {}", ResponseType::from(&transaction.query_type), from);
 
+        // Update node in routing table
+        if let Some(id) = response.sender_id() {
+            self.add_node(Node::new(id, from)).await;
+            self.routing_table.write().await.node_responded(&id);
+        }
+        if let Some(reported) = response.reported_ip() {
+            self.record_external_ip(from.ip(), reported.ip()).await;
+        }
+
+        self.respond(&transaction, response);
         Ok(())
     }
 
+    /// Count a node's report of our external address, switching to a matching ID once most agree
+    async fn record_external_ip(&self, voter: IpAddr, reported: IpAddr) {
+        if reported.is_ipv6() != self.local_addr.is_ipv6() {
+            return;
+        }
+        let Some(ip) = self.external_ip.lock().unwrap().vote(voter, reported) else {
+            return;
+        };
+        if is_valid_node_id(&self.our_id(), &ip) {
+            debug!("DHT ID {} is already valid for {}", self.our_id().to_hex(), ip);
+            return;
+        }
+
+        let our_id = secure_node_id(&ip);
+        info!("Regenerating DHT ID for external address {}: {}", ip, our_id.to_hex());
+        *self.our_id.write().unwrap() = our_id;
+        self.routing_table.write().await.set_our_id(our_id);
+    }
+
     /// Handle incoming error
-    async fn handle_error(
-        &self,
-        id: NodeId,
-        code: u32,
-        message: String,
-        from: SocketAddr,
-    ) -> Result<()> {
-        warn!("Received error from {}: code={}, message={}", from, code, message);
+    async fn handle_error(&self, error: DHTMessage, from: SocketAddr) -> Result<()> {
+        if let DHTMessage::Error { code, message, .. } = &error {
+            warn!("Received error from {}: code={}, message={}", from, code, message);
+        }
 
-        // Remove transaction
-        let transaction_id = id.to_hex();
-        self.transactions.write().await.remove(&transaction_id);
+        if let Some(transaction) = self.complete_transaction(&error, from).await {
+            self.respond(&transaction, error);
+        }
 
         Ok(())
     }
 
+    /// Run a lookup seeded from our routing table
+    async fn lookup(&self, target: NodeId, kind: LookupKind) -> LookupResult {
+        let seeds = self.routing_table.read().await.find_closest_nodes(&target);
+        lookup::run(Lookup::new(target, kind, self.our_id(), seeds).with_family(self.family()), self).await
+    }
+
+    /// Find the nodes closest to a target ID
+    pub async fn find_node(&self, target: NodeId) -> Vec<Node> {
+        self.lookup(target, LookupKind::FindNode).await
+            .closest
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect()
+    }
+
+    /// Look up peers and announce tokens for an info hash
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> LookupResult {
+        self.lookup(NodeId::new(info_hash), LookupKind::GetPeers).await
+    }
+
     /// Find peers for a given info hash
     pub async fn find_peers(&self, info_hash: [u8; 20]) -> Result<Vec<SocketAddr>> {
         info!("Finding peers for info_hash: {}", hex::encode(info_hash));
-        
-        let routing_table = self.routing_table.read().await;
-        let peers = discover_peers(
-            &self.socket,
-            self.our_id,
-            &routing_table,
-            info_hash,
-        ).await
-            .map_err(|e| {
-                error!("Failed to discover peers: {}", e);
-                TorrentError::dht_error_full("Failed to discover peers", "unknown".to_string(), e.to_string())
-            })?;
 
-        info!("Found {} peers", peers.len());
-        Ok(peers)
+        let result = self.get_peers(info_hash).await;
+        self.peer_manager.add_peers_from(result.peers.clone(), PeerSource::DHT).await;
+
+        info!("Found {} peers", result.peers.len());
+        Ok(result.peers)
+    }
+
+    /// Look up peers for a torrent, hand them to the peer manager and announce our port
+    ///
+    /// Returns the number of new peers.
+    pub async fn refresh_torrent(&self, info_hash: [u8; 20], port: u16) -> usize {
+        debug!("Refreshing DHT peers for info_hash: {}", hex::encode(info_hash));
+
+        let result = self.get_peers(info_hash).await;
+        let added = self.peer_manager.add_peers_from(result.peers.clone(), PeerSource::DHT).await;
+        let accepted = lookup::announce_to(self, self.our_id(), info_hash, port, &result).await;
+        if accepted == 0 {
+            warn!("No DHT node accepted our announce for {}", hex::encode(info_hash));
+        }
+
+        info!("DHT lookup found {} peers ({} new), announced to {} nodes", result.peers.len(), added, accepted);
+        added
+    }
+
+    /// Refresh a torrent's peers and announcement periodically until stopped
+    pub async fn track_torrent(&self, info_hash: [u8; 20], port: u16, every: Duration) {
+        info!("Tracking torrent {} in the DHT every {:?}", hex::encode(info_hash), every);
+
+        let mut ticker = interval(every);
+        loop {
+            ticker.tick().await;
+            if !*self.running.read().await {
+                break;
+            }
+            self.refresh_torrent(info_hash, port).await;
+        }
     }
 
     /// Announce ourselves to DHT
-    pub async fn announce_peer(&self, info_hash: [u8; 20], port: u16) -> Result<()> {
+    ///
+    /// Returns the number of nodes that accepted the announce.
+    pub async fn announce_peer(&self, info_hash: [u8; 20], port: u16) -> Result<usize> {
         info!("Announcing to DHT for info_hash: {}", hex::encode(info_hash));
-        
-        let routing_table = self.routing_table.read().await;
-        announce(
-            &self.socket,
-            self.our_id,
-            &routing_table,
-            info_hash,
-            port,
-        ).await
+
+        let result = self.get_peers(info_hash).await;
+        let accepted = lookup::announce_to(self, self.our_id(), info_hash, port, &result).await;
+        if accepted == 0 {
+            error!("No DHT node accepted our announce");
+            return Err(TorrentError::dht_error("No DHT node accepted the announce").into());
+        }
+
+        info!("Announced to {} DHT nodes", accepted);
+        Ok(accepted)
+    }
+
+    /// Store an item on the nodes closest to its target (BEP 44)
+    ///
+    /// With `cas`, nodes only replace a mutable item whose sequence number
+    /// is `cas`, so concurrent publishers don't overwrite each other. It is
+    /// ignored for immutable items. Returns the number of nodes that stored
+    /// the item.
+    pub async fn put(&self, item: &Item, cas: Option<i64>) -> Result<usize> {
+        let target = item.target();
+        item.verify()
             .map_err(|e| {
-                error!("Failed to announce to DHT: {}", e);
-                TorrentError::dht_error_full("Failed to announce to DHT", "unknown".to_string(), e.to_string())
+                error!("Refusing to put invalid item {}: {}", hex::encode(target), e);
+                TorrentError::dht_error_full("Invalid DHT item", hex::encode(target), e.to_string())
             })?;
+        info!("Putting DHT item {}", hex::encode(target));
 
-        Ok(())
+        let result = self.lookup(NodeId::new(target), LookupKind::Get).await;
+        let stored = lookup::put_to(self, self.our_id(), item, cas, &result).await;
+        if stored == 0 {
+            error!("No DHT node stored item {}", hex::encode(target));
+            return Err(TorrentError::dht_error("No DHT node stored the item").into());
+        }
+
+        info!("Stored DHT item {} on {} nodes", hex::encode(target), stored);
+        Ok(stored)
+    }
+
+    /// Fetch an immutable item by the SHA-1 of its value
+    pub async fn get_immutable(&self, target: [u8; 20]) -> Option<BencodeValue> {
+        debug!("Getting immutable DHT item {}", hex::encode(target));
+        let result = self.lookup(NodeId::new(target), LookupKind::Get).await;
+        result.items.iter()
+            .filter_map(|args| args.get("v"))
+            .find(|value| immutable_target(value) == target)
+            .cloned()
+    }
+
+    /// Fetch the newest validly signed version of a mutable item
+    pub async fn get_mutable(&self, public_key: &[u8; 32], salt: &[u8]) -> Option<MutableItem> {
+        let target = mutable_target(public_key, salt);
+        debug!("Getting mutable DHT item {}", hex::encode(target));
+        let result = self.lookup(NodeId::new(target), LookupKind::Get).await;
+        result.items.iter()
+            .filter_map(|args| MutableItem::from_args(args, salt.to_vec()))
+            .filter(|item| item.public_key == *public_key && item.verify().is_ok())
+            .max_by_key(|item| item.seq)
     }
 
     /// Main DHT event loop
+    ///
+    /// Handles incoming messages while running maintenance, so maintenance
+    /// can wait for responses to its own queries.
     pub async fn run_loop(&self) -> Result<()> {
         info!("Starting DHT event loop");
 
+        tokio::select! {
+            _ = self.receive_loop() => {}
+            _ = self.maintenance_loop() => {}
+        }
+
+        Ok(())
+    }
+
+    /// Handle incoming messages until stopped
+    async fn receive_loop(&self) {
         let mut buffer = [0u8; 4096];
-        let mut cleanup_interval = interval(Duration::from_secs(60));
-        let mut refresh_interval = interval(Duration::from_secs(300));
 
         loop {
             let running = *self.running.read().await;
@@ -400,23 +802,36 @@ impl DHT {
                 break;
             }
 
-            tokio::select! {
-                // Handle incoming messages
-                result = self.socket.recv_from(&mut buffer) => {
-                    match result {
-                        Ok((len, from)) => {
-                            if let Err(e) = self.handle_message(&buffer[..len], from).await {
-                                error!("Error handling message from {}: {}", from, e);
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error receiving message: {}", e);
-                        }
+            match self.recv_datagram(&mut buffer).await {
+                Ok((len, from)) => {
+                    if let Err(e) = self.handle_message(&buffer[..len], from).await {
+                        error!("Error handling message from {}: {}", from, e);
                     }
                 }
-                // Cleanup expired transactions
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    warn!("DHT datagram source closed, stopping event loop");
+                    break;
+                }
+                Err(e) => {
+                    error!("Error receiving message: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Periodic cleanup and bucket refresh
+    async fn maintenance_loop(&self) {
+        let mut cleanup_interval = interval(Duration::from_secs(15));
+        let mut refresh_interval = interval(Duration::from_secs(300));
+
+        loop {
+            tokio::select! {
+                // Cleanup expired transactions, announced peers and items
                 _ = cleanup_interval.tick() => {
                     self.cleanup_transactions().await;
+                    self.cleanup_peers().await;
+                    self.cleanup_items().await;
+                    self.query_limiter.lock().unwrap().cleanup();
                 }
                 // Refresh routing table buckets
                 _ = refresh_interval.tick() => {
@@ -424,40 +839,113 @@ impl DHT {
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Receive the next datagram, from the socket or the demultiplexer
+    async fn recv_datagram(&self, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match &self.incoming {
+            Some(incoming) => {
+                let (data, from) = incoming.lock().await.recv().await
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "demultiplexer stopped"))?;
+                let len = data.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&data[..len]);
+                Ok((len, from))
+            }
+            None => self.socket.recv_from(buffer).await,
+        }
     }
 
-    /// Clean up expired transactions
+    /// Clean up expired transactions, counting them as failed queries
     pub async fn cleanup_transactions(&self) {
-        let timeout = Duration::from_secs(60);
         let mut transactions = self.transactions.write().await;
-        let initial_count = transactions.len();
+        let mut failed = Vec::new();
+        transactions.retain(|_t, transaction| {
+            let expired = transaction.is_expired(TRANSACTION_TIMEOUT);
+            if expired {
+                failed.push(transaction.node_id);
+            }
+            !expired
+        });
+        drop(transactions);
+
+        if !failed.is_empty() {
+            debug!("Cleaned up {} expired transactions", failed.len());
+            let mut routing_table = self.routing_table.write().await;
+            for node_id in &failed {
+                routing_table.node_failed(node_id);
+            }
+        }
+    }
 
-        transactions.retain(|_t, transaction| !transaction.is_expired(timeout));
+    /// Drop expired peers from the peer store
+    pub async fn cleanup_peers(&self) {
+        let removed = self.peer_store.write().await.cleanup();
+        if removed > 0 {
+            debug!("Cleaned up {} expired peers", removed);
+        }
+    }
 
-        let removed = initial_count - transactions.len();
+    /// Cleanup expired items
+    pub async fn cleanup_items(&self) {
+        let removed = self.item_store.write().await.cleanup();
         if removed > 0 {
-            debug!("Cleaned up {} expired transactions", removed);
+            debug!("Cleaned up {} expired items", removed);
         }
     }
 
     /// Refresh routing table buckets
+    ///
+    /// Buckets untouched for 15 minutes are refreshed with a `find_node` for
+    /// a random ID in their range.
     pub async fn refresh_buckets(&self) {
         debug!("Refreshing routing table buckets");
 
-        let stale_buckets = self.routing_table.read().await.get_stale_buckets(Duration::from_secs(900));
+        let targets: Vec<(usize, NodeId)> = {
+            let routing_table = self.routing_table.read().await;
+            routing_table.get_stale_buckets(GOOD_NODE_TIMEOUT)
+                .into_iter()
+                .map(|index| (index, routing_table.random_id_in_bucket(index)))
+                .collect()
+        };
 
-        if stale_buckets.is_empty() {
+        if targets.is_empty() {
             return;
         }
 
-        debug!("Found {} stale buckets to refresh", stale_buckets.len());
+        debug!("Found {} stale buckets to refresh", targets.len());
 
-        // In a real implementation, we would refresh each bucket by querying nodes
-        // For now, we just log stale buckets
-        for bucket_index in stale_buckets {
-            debug!("Bucket {} is stale", bucket_index);
+        for (bucket_index, target) in targets {
+            let found = self.find_node(target).await;
+            debug!("Refreshed bucket {}: {} nodes found", bucket_index, found.len());
+        }
+    }
+
+    /// Add a node we heard from, pinging questionable nodes if its bucket is full
+    async fn add_node(&self, node: Node) {
+        let questionable = {
+            let mut routing_table = self.routing_table.write().await;
+            match routing_table.insert(node.clone()) {
+                InsertOutcome::Cached => routing_table.questionable_nodes(&node.id),
+                InsertOutcome::Rejected => {
+                    debug!("Not adding {} at {}: ID does not match address", node.id.to_hex(), node.addr);
+                    return;
+                }
+                _ => return,
+            }
+        };
+
+        for node in questionable {
+            let already_pinged = self.transactions.read().await
+                .values()
+                .any(|t| t.node_id == node.id);
+            if already_pinged {
+                continue;
+            }
+            trace!("Pinging questionable node {}", node.addr);
+            let ping = DHTMessage::create_ping_query(generate_transaction_id(), self.our_id());
+            if let Err(e) = self.send_query(&node, ping).await {
+                debug!("Failed to ping {}: {}", node.addr, e);
+            }
         }
     }
 
@@ -479,9 +967,17 @@ impl DHT {
     }
 }
 
+#[async_trait]
+impl Querier for DHT {
+    async fn query(&self, node: &Node, message: DHTMessage) -> Result<DHTMessage> {
+        DHT::query(self, node, message).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ed25519_dalek::SigningKey;
 
     #[tokio::test]
     async fn test_dht_new() {
@@ -492,7 +988,17 @@ mod tests {
         assert!(dht.is_ok());
         
         let dht = dht.unwrap();
-        assert_eq!(dht.our_id.0.len(), 20);
+        assert_eq!(dht.our_id().0.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_dht_with_demux() {
+        let demux = UdpDemux::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let peer_manager = Arc::new(PeerManager::default());
+
+        let dht = DHT::with_demux(&demux, peer_manager.clone()).unwrap();
+        assert_eq!(dht.local_addr, demux.local_addr());
+        assert!(DHT::with_demux(&demux, peer_manager).is_err());
     }
 
     #[tokio::test]
@@ -526,6 +1032,45 @@ mod tests {
         assert_eq!(dht.transactions.read().await.len(), 1);
     }
 
+    #[tokio::test]
+    async fn test_expired_transactions_count_as_failures() {
+        let peer_manager = Arc::new(PeerManager::default());
+        let dht = DHT::new("127.0.0.1:0".parse().unwrap(), peer_manager).await.unwrap();
+        let node = Node::new(NodeId::random(), "127.0.0.1:6881".parse().unwrap());
+        dht.routing_table.write().await.add_node(node.clone());
+
+        let mut transaction = Transaction::to_addr("ping".to_string(), node.id, QueryType::Ping, node.addr);
+        transaction.created_at -= TRANSACTION_TIMEOUT;
+        dht.transactions.write().await.insert("ping".to_string(), transaction);
+
+        dht.cleanup_transactions().await;
+        assert!(dht.transactions.read().await.is_empty());
+        let routing_table = dht.routing_table.read().await;
+        assert_eq!(routing_table.find_node(&node.id).unwrap().state(), crate::dht::NodeState::Questionable);
+    }
+
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        let path = std::env::temp_dir().join("test_dht_restart").join("dht.json");
+        let node = Node::new(NodeId::random(), "127.0.0.1:6881".parse().unwrap());
+
+        let dht = DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap();
+        dht.routing_table.write().await.add_node(node.clone());
+        dht.save_state(&path).await.unwrap();
+
+        let mut restarted = DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap();
+        assert_eq!(restarted.load_state(&path).await.unwrap(), 1);
+        assert_eq!(restarted.our_id(), dht.our_id());
+        assert_eq!(restarted.routing_table.read().await.our_id, dht.our_id());
+        assert!(restarted.routing_table.read().await.find_node(&node.id).is_some());
+
+        // Restored nodes are pinged on start
+        restarted.start().await.unwrap();
+        assert_eq!(restarted.transactions.read().await.len(), 1);
+
+        let _ = tokio::fs::remove_dir_all(path.parent().unwrap()).await;
+    }
+
     #[tokio::test]
     async fn test_node_count() {
         let peer_manager = Arc::new(PeerManager::default());
@@ -535,4 +1080,262 @@ mod tests {
         let count = dht.node_count().await;
         assert_eq!(count, 0);
     }
+
+    #[tokio::test]
+    async fn test_lookups_walk_a_chain_of_nodes() {
+        let mut dhts = Vec::new();
+        for _ in 0..6 {
+            let peer_manager = Arc::new(PeerManager::default());
+            let dht = Arc::new(DHT::new("127.0.0.1:0".parse().unwrap(), peer_manager).await.unwrap());
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+            dhts.push(dht);
+        }
+        // Each node only knows the next one
+        for pair in dhts.windows(2) {
+            pair[0].routing_table.write().await.add_node(Node::new(pair[1].our_id(), pair[1].local_addr));
+        }
+
+        let last = dhts.last().unwrap();
+        let closest = dhts[0].find_node(last.our_id()).await;
+        assert_eq!(closest.len(), 5);
+        assert_eq!(closest[0].id, last.our_id());
+        // Queried nodes learn about us
+        assert!(dhts[3].routing_table.read().await.find_node(&dhts[0].our_id()).is_some());
+
+        let accepted = dhts[0].announce_peer([7u8; 20], 6881).await.unwrap();
+        assert_eq!(accepted, 5);
+        assert!(dhts[0].transactions.read().await.is_empty());
+        assert_eq!(dhts[5].peer_store.read().await.get_peers(&[7u8; 20], 10), vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        // Other nodes now find us through the nodes we announced to
+        let peers = dhts[1].find_peers([7u8; 20]).await.unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+
+        for dht in &dhts {
+            dht.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_and_refresh_torrent() {
+        let mut dhts = Vec::new();
+        for _ in 0..4 {
+            let dht = Arc::new(DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap());
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+            dhts.push(dht);
+        }
+        // The router knows the other nodes; everyone else only knows the router
+        for dht in &dhts[2..] {
+            dhts[1].routing_table.write().await.add_node(Node::new(dht.our_id(), dht.local_addr));
+            dht.set_bootstrap_nodes(vec![dhts[1].local_addr]);
+        }
+        dhts[0].set_bootstrap_nodes(vec![dhts[1].local_addr]);
+
+        assert_eq!(dhts[0].bootstrap().await, 3);
+        assert!(dhts[0].routing_table.read().await.find_node(&dhts[1].our_id()).is_some());
+
+        // Another node announces, then we pick it up as a DHT peer
+        dhts[2].bootstrap().await;
+        assert_eq!(dhts[2].refresh_torrent([9u8; 20], 7000).await, 0);
+        assert_eq!(dhts[0].refresh_torrent([9u8; 20], 6881).await, 1);
+        assert_eq!(dhts[0].peer_manager.peer_count_from(PeerSource::DHT).await, 1);
+        assert_eq!(dhts[0].peer_manager.peer_addresses().await, vec!["127.0.0.1:7000".parse().unwrap()]);
+
+        for dht in &dhts {
+            dht.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_dht_and_want() {
+        let mut dht4 = DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap();
+        let Ok(mut dht6) = DHT::new("[::1]:0".parse().unwrap(), Arc::new(PeerManager::default())).await else {
+            // No IPv6 on this host
+            return;
+        };
+        dht4.pair(&mut dht6);
+        assert_eq!(dht6.family(), Want::N6);
+
+        let mut others = Vec::new();
+        for _ in 0..2 {
+            let dht = Arc::new(DHT::new("[::1]:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap());
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+            others.push(dht);
+        }
+        others[0].routing_table.write().await.add_node(Node::new(others[1].our_id(), others[1].local_addr));
+        dht6.routing_table.write().await.add_node(Node::new(others[0].our_id(), others[0].local_addr));
+
+        let (dht4, dht6) = (Arc::new(dht4), Arc::new(dht6));
+        for dht in [&dht4, &dht6] {
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+        }
+
+        // An IPv6 lookup follows nodes6 and stores IPv6 peers
+        let closest = dht6.find_node(others[1].our_id()).await;
+        assert_eq!(closest.len(), 2);
+        assert_eq!(dht6.announce_peer([5u8; 20], 6881).await.unwrap(), 2);
+        assert_eq!(others[1].peer_store.read().await.get_peers(&[5u8; 20], 10), vec!["[::1]:6881".parse().unwrap()]);
+
+        // The IPv4 instance answers `want` with nodes from the IPv6 table
+        let querier = Arc::new(DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap());
+        querier.start().await.unwrap();
+        let event_loop = querier.clone();
+        tokio::spawn(async move { event_loop.run_loop().await });
+        let query = DHTMessage::create_find_node_query(generate_transaction_id(), querier.our_id(), others[1].our_id())
+            .with_want(&[Want::N4, Want::N6]);
+        let response = querier.query(&Node::new(dht4.our_id(), dht4.local_addr), query).await.unwrap();
+        let nodes6 = crate::dht::message::parse_compact_nodes6(get_bytes(response.args().unwrap(), "nodes6").unwrap()).unwrap();
+        assert!(nodes6.iter().any(|(id, _)| *id == others[1].our_id()));
+        assert!(get_bytes(response.args().unwrap(), "nodes").is_some());
+
+        for dht in others.iter().chain([&dht4, &dht6, &querier]) {
+            dht.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_id_follows_external_address() {
+        let dht = DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap();
+        let external: IpAddr = "124.31.75.21".parse().unwrap();
+
+        dht.record_external_ip("10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap()).await;
+        for n in 0..crate::dht::security::MIN_IP_VOTES as u8 {
+            assert!(dht.external_ip().is_none());
+            dht.record_external_ip(IpAddr::from([10, 0, 1, n]), external).await;
+        }
+
+        assert_eq!(dht.external_ip(), Some(external));
+        assert!(is_valid_node_id(&dht.our_id(), &external));
+        assert_eq!(dht.routing_table.read().await.our_id, dht.our_id());
+    }
+
+    #[tokio::test]
+    async fn test_announce_requires_valid_token() {
+        let mut dhts = Vec::new();
+        for _ in 0..2 {
+            let dht = Arc::new(DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap());
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+            dhts.push(dht);
+        }
+        let node = Node::new(dhts[1].our_id(), dhts[1].local_addr);
+
+        let announce = DHTMessage::create_announce_peer_query(
+            generate_transaction_id(), dhts[0].our_id(), [7u8; 20], 6881, b"forged".to_vec(),
+        );
+        assert!(dhts[0].query(&node, announce).await.is_err());
+
+        let get_peers = DHTMessage::create_get_peers_query(generate_transaction_id(), dhts[0].our_id(), [7u8; 20]);
+        let response = dhts[0].query(&node, get_peers).await.unwrap();
+        assert_eq!(response.reported_ip(), Some(dhts[0].local_addr));
+        let token = get_bytes(response.args().unwrap(), "token").unwrap().to_vec();
+        let mut announce = DHTMessage::create_announce_peer_query(
+            generate_transaction_id(), dhts[0].our_id(), [7u8; 20], 1, token,
+        );
+        if let DHTMessage::Query { args, .. } = &mut announce {
+            args.insert("implied_port".to_string(), BencodeValue::Integer(1));
+        }
+        dhts[0].query(&node, announce).await.unwrap();
+
+        let stored = dhts[1].peer_store.read().await.get_peers(&[7u8; 20], 10);
+        assert_eq!(stored, vec![dhts[0].local_addr]);
+
+        for dht in &dhts {
+            dht.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_and_get_items() {
+        let mut dhts = Vec::new();
+        for _ in 0..4 {
+            let dht = Arc::new(DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap());
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+            dhts.push(dht);
+        }
+        // The first node knows everyone; the last only knows the first
+        for i in 1..4 {
+            dhts[0].routing_table.write().await.add_node(Node::new(dhts[i].our_id(), dhts[i].local_addr));
+        }
+        dhts[3].routing_table.write().await.add_node(Node::new(dhts[0].our_id(), dhts[0].local_addr));
+
+        let value = BencodeValue::Bytes(b"Hello World!".to_vec());
+        let item = Item::Immutable(value.clone());
+        assert_eq!(dhts[0].put(&item, None).await.unwrap(), 3);
+        assert_eq!(dhts[3].get_immutable(item.target()).await, Some(value));
+        assert_eq!(dhts[3].get_immutable([1u8; 20]).await, None);
+
+        let key = SigningKey::from_bytes(&[5u8; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        for seq in 1..=2 {
+            let item = MutableItem::sign(&key, b"salt".to_vec(), seq, BencodeValue::Integer(seq)).unwrap();
+            dhts[0].put(&Item::Mutable(item), None).await.unwrap();
+        }
+        let item = dhts[3].get_mutable(&public_key, b"salt").await.unwrap();
+        assert_eq!((item.seq, item.value), (2, BencodeValue::Integer(2)));
+        assert!(dhts[3].get_mutable(&public_key, b"other").await.is_none());
+
+        // Every node refuses to go back to an older version
+        let old = MutableItem::sign(&key, b"salt".to_vec(), 1, BencodeValue::Integer(1)).unwrap();
+        assert!(dhts[0].put(&Item::Mutable(old), None).await.is_err());
+
+        // A stale cas is rejected, the current one lets the update through
+        let next = Item::Mutable(MutableItem::sign(&key, b"salt".to_vec(), 3, BencodeValue::Integer(3)).unwrap());
+        assert!(dhts[0].put(&next, Some(1)).await.is_err());
+        assert_eq!(dhts[3].get_mutable(&public_key, b"salt").await.unwrap().seq, 2);
+        assert_eq!(dhts[0].put(&next, Some(2)).await.unwrap(), 3);
+        assert_eq!(dhts[3].get_mutable(&public_key, b"salt").await.unwrap().seq, 3);
+
+        for dht in &dhts {
+            dht.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_and_rate_limited_queries() {
+        let mut dhts = Vec::new();
+        for _ in 0..2 {
+            let dht = Arc::new(DHT::new("127.0.0.1:0".parse().unwrap(), Arc::new(PeerManager::default())).await.unwrap());
+            dht.start().await.unwrap();
+            let event_loop = dht.clone();
+            tokio::spawn(async move { event_loop.run_loop().await });
+            dhts.push(dht);
+        }
+        let (reader, server) = (&dhts[0], &dhts[1]);
+        reader.set_read_only(true);
+        let server_node = Node::new(server.our_id(), server.local_addr);
+        let reader_node = Node::new(reader.our_id(), reader.local_addr);
+
+        // A read-only node can query, but is neither answered nor added to the routing table
+        let ping = DHTMessage::create_ping_query(generate_transaction_id(), reader.our_id());
+        assert!(reader.query(&server_node, ping).await.is_ok());
+        assert_eq!(server.node_count().await, 0);
+        let ping = DHTMessage::create_ping_query(generate_transaction_id(), server.our_id());
+        assert!(server.query(&reader_node, ping).await.is_err());
+
+        // Queries over the limit are dropped
+        reader.set_read_only(false);
+        server.set_query_limits(2, 0);
+        let pings = (0..4).map(|_| {
+            let ping = DHTMessage::create_ping_query(generate_transaction_id(), reader.our_id());
+            reader.query(&server_node, ping)
+        });
+        let answered = futures::future::join_all(pings).await.iter().filter(|result| result.is_ok()).count();
+        assert_eq!(answered, 2);
+
+        for dht in &dhts {
+            dht.stop().await;
+        }
+    }
 }
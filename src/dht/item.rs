@@ -0,0 +1,245 @@
+//! DHT items (BEP 44)
+//!
+//! Small bencoded values stored in the DHT. Immutable items are keyed by the
+//! SHA-1 of their value; mutable items are keyed by the SHA-1 of an ed25519
+//! public key and optional salt, and carry a signed sequence number.
+
+use crate::dht::message::{error_code, get_bytes, BencodeDict, BencodeValue};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+
+/// Largest bencoded value an item may hold
+pub const MAX_VALUE_SIZE: usize = 1000;
+
+/// Largest salt a mutable item may use
+pub const MAX_SALT_SIZE: usize = 64;
+
+/// Why an item was refused, with the KRPC error code to reply with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemError {
+    pub code: u32,
+    pub message: String,
+}
+
+impl ItemError {
+    fn new(code: u32, message: &str) -> Self {
+        Self { code, message: message.to_string() }
+    }
+}
+
+impl std::fmt::Display for ItemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+/// A mutable item signed by its owner
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutableItem {
+    /// ed25519 public key of the owner
+    pub public_key: [u8; 32],
+    /// Salt, letting one key own several items
+    pub salt: Vec<u8>,
+    /// Sequence number; newer versions have higher numbers
+    pub seq: i64,
+    /// The stored value
+    pub value: BencodeValue,
+    /// Signature over salt, seq and value
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    /// Create and sign a new version of an item
+    pub fn sign(key: &SigningKey, salt: Vec<u8>, seq: i64, value: BencodeValue) -> Result<Self, ItemError> {
+        check_value(&value)?;
+        check_salt(&salt)?;
+        let signature = key.sign(&signed_data(&salt, seq, &value)).to_bytes();
+        Ok(Self {
+            public_key: key.verifying_key().to_bytes(),
+            salt,
+            seq,
+            value,
+            signature,
+        })
+    }
+
+    /// Get the key the item is stored under
+    pub fn target(&self) -> [u8; 20] {
+        mutable_target(&self.public_key, &self.salt)
+    }
+
+    /// Check sizes and the signature
+    pub fn verify(&self) -> Result<(), ItemError> {
+        check_value(&self.value)?;
+        check_salt(&self.salt)?;
+        let key = VerifyingKey::from_bytes(&self.public_key)
+            .map_err(|_| ItemError::new(error_code::INVALID_SIGNATURE, "invalid public key"))?;
+        key.verify(&signed_data(&self.salt, self.seq, &self.value), &Signature::from_bytes(&self.signature))
+            .map_err(|_| ItemError::new(error_code::INVALID_SIGNATURE, "invalid signature"))
+    }
+
+    /// Read an item from `put` arguments or a `get` response, given its salt
+    pub fn from_args(args: &BencodeDict, salt: Vec<u8>) -> Option<Self> {
+        Some(Self {
+            public_key: get_bytes(args, "k")?.try_into().ok()?,
+            salt,
+            seq: args.get("seq").and_then(BencodeValue::as_integer)?,
+            value: args.get("v")?.clone(),
+            signature: get_bytes(args, "sig")?.try_into().ok()?,
+        })
+    }
+
+    /// Write the item's fields into `put` arguments or a `get` response
+    pub fn insert_into(&self, args: &mut BencodeDict) {
+        args.insert("k".to_string(), BencodeValue::Bytes(self.public_key.to_vec()));
+        args.insert("seq".to_string(), BencodeValue::Integer(self.seq));
+        args.insert("sig".to_string(), BencodeValue::Bytes(self.signature.to_vec()));
+        args.insert("v".to_string(), self.value.clone());
+    }
+}
+
+/// An item stored in the DHT
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    /// A value keyed by its own hash
+    Immutable(BencodeValue),
+    /// A signed value keyed by its owner's key and salt
+    Mutable(MutableItem),
+}
+
+impl Item {
+    /// Get the key the item is stored under
+    pub fn target(&self) -> [u8; 20] {
+        match self {
+            Item::Immutable(value) => immutable_target(value),
+            Item::Mutable(item) => item.target(),
+        }
+    }
+
+    /// Get the stored value
+    pub fn value(&self) -> &BencodeValue {
+        match self {
+            Item::Immutable(value) => value,
+            Item::Mutable(item) => &item.value,
+        }
+    }
+
+    /// Check sizes, and the signature of mutable items
+    pub fn verify(&self) -> Result<(), ItemError> {
+        match self {
+            Item::Immutable(value) => check_value(value),
+            Item::Mutable(item) => item.verify(),
+        }
+    }
+
+    /// Read an item from `put` arguments
+    pub fn from_put_args(args: &BencodeDict) -> Result<Self, ItemError> {
+        let value = args.get("v").ok_or_else(|| ItemError::new(error_code::PROTOCOL, "missing v"))?;
+        if !args.contains_key("k") {
+            return Ok(Item::Immutable(value.clone()));
+        }
+
+        let salt = get_bytes(args, "salt").unwrap_or_default().to_vec();
+        check_salt(&salt)?;
+        MutableItem::from_args(args, salt)
+            .map(Item::Mutable)
+            .ok_or_else(|| ItemError::new(error_code::PROTOCOL, "invalid mutable item"))
+    }
+}
+
+/// Get the key of an immutable item
+pub fn immutable_target(value: &BencodeValue) -> [u8; 20] {
+    Sha1::digest(value.to_bytes()).into()
+}
+
+/// Get the key of a mutable item
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(public_key);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+/// The bytes a mutable item's signature covers
+fn signed_data(salt: &[u8], seq: i64, value: &BencodeValue) -> Vec<u8> {
+    let mut data = Vec::new();
+    if !salt.is_empty() {
+        data.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        data.extend_from_slice(salt);
+    }
+    data.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    data.extend_from_slice(&value.to_bytes());
+    data
+}
+
+fn check_value(value: &BencodeValue) -> Result<(), ItemError> {
+    if value.to_bytes().len() > MAX_VALUE_SIZE {
+        return Err(ItemError::new(error_code::MESSAGE_TOO_BIG, "message (v field) too big"));
+    }
+    Ok(())
+}
+
+fn check_salt(salt: &[u8]) -> Result<(), ItemError> {
+    if salt.len() > MAX_SALT_SIZE {
+        return Err(ItemError::new(error_code::SALT_TOO_BIG, "salt (salt field) too big"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    #[test]
+    fn test_immutable_target() {
+        // BEP 44 test vector
+        let value = BencodeValue::String("Hello World!".to_string());
+        assert_eq!(hex::encode(immutable_target(&value)), "e5f96f6f38320f0f33959cb4d3d656452117aadb");
+    }
+
+    #[test]
+    fn test_mutable_item_signature() {
+        let item = MutableItem::sign(&key(), b"foobar".to_vec(), 1, BencodeValue::String("Hello World!".to_string())).unwrap();
+        assert!(item.verify().is_ok());
+        assert_eq!(item.target(), mutable_target(&key().verifying_key().to_bytes(), b"foobar"));
+
+        let mut tampered = item.clone();
+        tampered.seq = 2;
+        assert_eq!(tampered.verify().unwrap_err().code, error_code::INVALID_SIGNATURE);
+
+        let mut args = BencodeDict::new();
+        item.insert_into(&mut args);
+        assert_eq!(MutableItem::from_args(&args, b"foobar".to_vec()), Some(item.clone()));
+        assert!(MutableItem::from_args(&args, Vec::new()).unwrap().verify().is_err());
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let big = BencodeValue::Bytes(vec![0u8; MAX_VALUE_SIZE]);
+        assert_eq!(Item::Immutable(big.clone()).verify().unwrap_err().code, error_code::MESSAGE_TOO_BIG);
+        assert_eq!(MutableItem::sign(&key(), Vec::new(), 1, big).unwrap_err().code, error_code::MESSAGE_TOO_BIG);
+
+        let salt = vec![0u8; MAX_SALT_SIZE + 1];
+        let err = MutableItem::sign(&key(), salt, 1, BencodeValue::Integer(1)).unwrap_err();
+        assert_eq!(err.code, error_code::SALT_TOO_BIG);
+    }
+
+    #[test]
+    fn test_item_from_put_args() {
+        let mut args = BencodeDict::new();
+        args.insert("v".to_string(), BencodeValue::Integer(5));
+        assert_eq!(Item::from_put_args(&args).unwrap(), Item::Immutable(BencodeValue::Integer(5)));
+
+        let item = MutableItem::sign(&key(), b"s".to_vec(), 3, BencodeValue::Integer(5)).unwrap();
+        let mut args = BencodeDict::new();
+        item.insert_into(&mut args);
+        args.insert("salt".to_string(), BencodeValue::Bytes(b"s".to_vec()));
+        assert_eq!(Item::from_put_args(&args).unwrap(), Item::Mutable(item));
+
+        assert!(Item::from_put_args(&BencodeDict::new()).is_err());
+    }
+}
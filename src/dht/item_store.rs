@@ -0,0 +1,145 @@
+//! DHT item store
+//!
+//! Items stored with `put` (BEP 44) and returned by `get`. The store is
+//! bounded, and items expire unless they are put again.
+
+use crate::dht::item::{Item, ItemError};
+use crate::dht::message::error_code;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a stored item is kept
+pub const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Maximum number of items stored
+pub const MAX_ITEMS: usize = 1000;
+
+/// Bounded, expiring store of items by target
+#[derive(Debug)]
+pub struct ItemStore {
+    items: HashMap<[u8; 20], (Item, Instant)>,
+    max_items: usize,
+    ttl: Duration,
+}
+
+impl ItemStore {
+    /// Create an item store with the default limits
+    pub fn new() -> Self {
+        Self::with_limits(MAX_ITEMS, ITEM_TTL)
+    }
+
+    /// Create an item store with custom limits
+    pub fn with_limits(max_items: usize, ttl: Duration) -> Self {
+        Self {
+            items: HashMap::new(),
+            max_items,
+            ttl,
+        }
+    }
+
+    /// Store a verified item
+    ///
+    /// A mutable item only replaces one with a lower or equal sequence
+    /// number, and only if `cas` matches the stored sequence number when given.
+    pub fn put(&mut self, item: Item, cas: Option<i64>) -> Result<(), ItemError> {
+        let target = item.target();
+        if let (Some((Item::Mutable(stored), _)), Item::Mutable(new)) = (self.items.get(&target), &item) {
+            if cas.is_some_and(|cas| cas != stored.seq) {
+                return Err(ItemError { code: error_code::CAS_MISMATCH, message: "CAS mismatch".to_string() });
+            }
+            if new.seq < stored.seq {
+                return Err(ItemError { code: error_code::SEQ_TOO_LOW, message: "sequence number less than current".to_string() });
+            }
+        }
+
+        if !self.items.contains_key(&target) && self.items.len() >= self.max_items {
+            self.cleanup();
+            if self.items.len() >= self.max_items {
+                let oldest = self.items.iter().min_by_key(|(_, (_, stored))| *stored).map(|(target, _)| *target);
+                if let Some(oldest) = oldest {
+                    self.items.remove(&oldest);
+                }
+            }
+        }
+        self.items.insert(target, (item, Instant::now()));
+        Ok(())
+    }
+
+    /// Get a live item
+    pub fn get(&self, target: &[u8; 20]) -> Option<&Item> {
+        self.items.get(target)
+            .filter(|(_, stored)| stored.elapsed() < self.ttl)
+            .map(|(item, _)| item)
+    }
+
+    /// Drop expired items, returning how many were removed
+    pub fn cleanup(&mut self) -> usize {
+        let before = self.items.len();
+        let ttl = self.ttl;
+        self.items.retain(|_, (_, stored)| stored.elapsed() < ttl);
+        before - self.items.len()
+    }
+
+    /// Get the number of stored items
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Check if no items are stored
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl Default for ItemStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::item::MutableItem;
+    use crate::dht::message::BencodeValue;
+    use ed25519_dalek::SigningKey;
+
+    fn mutable(seq: i64) -> Item {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        Item::Mutable(MutableItem::sign(&key, Vec::new(), seq, BencodeValue::Integer(seq)).unwrap())
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let mut store = ItemStore::new();
+        let item = Item::Immutable(BencodeValue::Integer(1));
+        store.put(item.clone(), None).unwrap();
+        assert_eq!(store.get(&item.target()), Some(&item));
+        assert!(store.get(&[0u8; 20]).is_none());
+    }
+
+    #[test]
+    fn test_mutable_sequence_and_cas() {
+        let mut store = ItemStore::new();
+        store.put(mutable(2), None).unwrap();
+        assert_eq!(store.put(mutable(1), None).unwrap_err().code, error_code::SEQ_TOO_LOW);
+        assert_eq!(store.put(mutable(3), Some(1)).unwrap_err().code, error_code::CAS_MISMATCH);
+        store.put(mutable(3), Some(2)).unwrap();
+        assert_eq!(store.get(&mutable(3).target()), Some(&mutable(3)));
+    }
+
+    #[test]
+    fn test_limits_and_expiry() {
+        let mut store = ItemStore::with_limits(2, ITEM_TTL);
+        for i in 0..3 {
+            store.put(Item::Immutable(BencodeValue::Integer(i)), None).unwrap();
+        }
+        assert_eq!(store.len(), 2);
+
+        let mut store = ItemStore::with_limits(2, Duration::ZERO);
+        store.put(Item::Immutable(BencodeValue::Integer(1)), None).unwrap();
+        assert!(store.get(&Item::Immutable(BencodeValue::Integer(1)).target()).is_none());
+        assert_eq!(store.cleanup(), 1);
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,647 @@
+//! DHT lookup module
+//!
+//! Iterative Kademlia lookups. A `find_node` lookup walks towards a target ID;
+//! a `get_peers` lookup also collects peers and the write tokens needed for a
+//! later `announce_peer`.
+
+use crate::dht::message::{
+    generate_transaction_id, get_bytes, parse_compact_nodes_of, parse_compact_peer_value, BencodeDict,
+    BencodeValue, DHTMessage, Want,
+};
+use crate::dht::item::Item;
+use crate::dht::node::{Node, NodeId};
+use crate::dht::routing::K;
+use crate::error::TorrentError;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tracing::{debug, error, trace};
+
+/// Number of queries kept in flight during a lookup
+pub const ALPHA: usize = 3;
+
+/// Time to wait for a node to answer a query
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a lookup is looking for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupKind {
+    /// Nodes closest to the target
+    FindNode,
+    /// Peers for the target info hash
+    GetPeers,
+    /// An item stored under the target (BEP 44)
+    Get,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    Pending,
+    InFlight,
+    Responded,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    node: Node,
+    distance: [u8; 20],
+    state: CandidateState,
+    token: Option<Vec<u8>>,
+}
+
+/// State of an iterative lookup
+///
+/// Candidates are kept sorted by XOR distance to the target. The lookup is
+/// finished once the K closest nodes that did not fail have all responded.
+#[derive(Debug)]
+pub struct Lookup {
+    target: NodeId,
+    kind: LookupKind,
+    our_id: NodeId,
+    family: Want,
+    candidates: Vec<Candidate>,
+    peers: Vec<SocketAddr>,
+    seen_peers: HashSet<SocketAddr>,
+    items: Vec<BencodeDict>,
+    queries: usize,
+}
+
+/// Outcome of a lookup
+#[derive(Debug, Clone, Default)]
+pub struct LookupResult {
+    /// Closest responding nodes with their write tokens, closest first
+    pub closest: Vec<(Node, Option<Vec<u8>>)>,
+    /// Peers returned by `get_peers`
+    pub peers: Vec<SocketAddr>,
+    /// Responses to `get` that carried a value, unverified
+    pub items: Vec<BencodeDict>,
+    /// Number of queries sent
+    pub queries: usize,
+}
+
+impl Lookup {
+    /// Create a lookup starting from the given nodes
+    pub fn new(target: NodeId, kind: LookupKind, our_id: NodeId, seeds: impl IntoIterator<Item = Node>) -> Self {
+        let mut lookup = Self {
+            target,
+            kind,
+            our_id,
+            family: Want::N4,
+            candidates: Vec::new(),
+            peers: Vec::new(),
+            seen_peers: HashSet::new(),
+            items: Vec::new(),
+            queries: 0,
+        };
+        for node in seeds {
+            lookup.insert(node);
+        }
+        lookup
+    }
+
+    /// Walk nodes of another address family than IPv4, dropping other seeds
+    pub fn with_family(mut self, family: Want) -> Self {
+        self.family = family;
+        self.candidates.retain(|c| family.matches(&c.node.addr));
+        self
+    }
+
+    /// Get the lookup target
+    pub fn target(&self) -> NodeId {
+        self.target
+    }
+
+    /// Get the lookup kind
+    pub fn kind(&self) -> LookupKind {
+        self.kind
+    }
+
+    /// Get the peers found so far
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    fn insert(&mut self, node: Node) -> bool {
+        if node.id == self.our_id
+            || self.candidates.iter().any(|c| c.node.addr == node.addr || c.node.id == node.id)
+        {
+            return false;
+        }
+        let distance = node.distance_to(&self.target);
+        let index = self.candidates.partition_point(|c| c.distance < distance);
+        self.candidates.insert(index, Candidate {
+            node,
+            distance,
+            state: CandidateState::Pending,
+            token: None,
+        });
+        true
+    }
+
+    /// The K closest candidates that have not failed
+    fn closest(&self) -> impl Iterator<Item = &Candidate> + Clone {
+        self.candidates.iter().filter(|c| c.state != CandidateState::Failed).take(K)
+    }
+
+    /// Pick the next node to query, marking it in flight
+    pub fn next_query(&mut self) -> Option<Node> {
+        let addr = self.closest()
+            .find(|c| c.state == CandidateState::Pending)
+            .map(|c| c.node.addr)?;
+        let candidate = self.candidates.iter_mut().find(|c| c.node.addr == addr)?;
+        candidate.state = CandidateState::InFlight;
+        self.queries += 1;
+        Some(candidate.node.clone())
+    }
+
+    /// Build the query to send to the next node
+    pub fn query_message(&self, transaction_id: String) -> DHTMessage {
+        let message = match self.kind {
+            LookupKind::FindNode => DHTMessage::create_find_node_query(transaction_id, self.our_id, self.target),
+            LookupKind::GetPeers => DHTMessage::create_get_peers_query(transaction_id, self.our_id, self.target.0),
+            LookupKind::Get => DHTMessage::create_get_query(transaction_id, self.our_id, self.target.0, None),
+        };
+        message.with_want(&[self.family])
+    }
+
+    /// Record a node's response
+    pub fn on_response(&mut self, addr: SocketAddr, message: &DHTMessage) {
+        let Some(args) = message.args() else {
+            self.on_failure(addr);
+            return;
+        };
+        let Some(candidate) = self.candidates.iter_mut().find(|c| c.node.addr == addr) else {
+            trace!("Ignoring response from unknown node {}", addr);
+            return;
+        };
+        candidate.state = CandidateState::Responded;
+        candidate.node.update_last_seen();
+        candidate.token = get_bytes(args, "token").map(<[u8]>::to_vec);
+
+        // The node may not have the ID we were told about
+        if let Some(id) = message.sender_id() {
+            if id != candidate.node.id {
+                trace!("Node {} responded with ID {}", addr, id.to_hex());
+                candidate.node.id = id;
+                candidate.distance = candidate.node.distance_to(&self.target);
+                self.candidates.sort_by_key(|c| c.distance);
+            }
+        }
+
+        if let Some(nodes) = get_bytes(args, self.family.nodes_key()) {
+            match parse_compact_nodes_of(self.family, nodes) {
+                Ok(nodes) => {
+                    let added = nodes.into_iter()
+                        .filter(|(id, addr)| self.insert(Node::new(*id, *addr)))
+                        .count();
+                    trace!("{} new candidates from {}", added, addr);
+                }
+                Err(e) => debug!("Invalid nodes from {}: {}", addr, e),
+            }
+        }
+
+        if self.kind == LookupKind::Get && args.contains_key("v") {
+            self.items.push(args.clone());
+        }
+
+        if let Some(values) = args.get("values").and_then(BencodeValue::as_list) {
+            for peer in values.iter().filter_map(BencodeValue::as_bytes) {
+                match parse_compact_peer_value(peer) {
+                    Ok(peers) => {
+                        for peer in peers {
+                            if self.seen_peers.insert(peer) {
+                                self.peers.push(peer);
+                            }
+                        }
+                    }
+                    Err(e) => debug!("Invalid peer from {}: {}", addr, e),
+                }
+            }
+        }
+    }
+
+    /// Record that a node failed to respond
+    pub fn on_failure(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.node.addr == addr) {
+            candidate.state = CandidateState::Failed;
+        }
+    }
+
+    /// Check if the lookup is done
+    pub fn is_finished(&self) -> bool {
+        self.closest().all(|c| c.state == CandidateState::Responded)
+            || !self.candidates.iter()
+                .any(|c| matches!(c.state, CandidateState::Pending | CandidateState::InFlight))
+    }
+
+    /// Finish the lookup
+    pub fn into_result(self) -> LookupResult {
+        let closest = self.candidates.into_iter()
+            .filter(|c| c.state == CandidateState::Responded)
+            .take(K)
+            .map(|c| (c.node, c.token))
+            .collect();
+        LookupResult {
+            closest,
+            peers: self.peers,
+            items: self.items,
+            queries: self.queries,
+        }
+    }
+}
+
+/// Sends a query to a node and waits for its response
+#[async_trait]
+pub trait Querier: Send + Sync {
+    /// Query a node, failing on timeout or a KRPC error
+    async fn query(&self, node: &Node, message: DHTMessage) -> Result<DHTMessage>;
+}
+
+/// Run a lookup to completion with up to `ALPHA` queries in flight
+pub async fn run<Q: Querier + ?Sized>(mut lookup: Lookup, querier: &Q) -> LookupResult {
+    debug!("Starting {:?} lookup for {}", lookup.kind(), lookup.target().to_hex());
+    let mut in_flight = FuturesUnordered::new();
+
+    loop {
+        while in_flight.len() < ALPHA && !lookup.is_finished() {
+            let Some(node) = lookup.next_query() else { break };
+            let message = lookup.query_message(generate_transaction_id());
+            in_flight.push(async move {
+                let result = querier.query(&node, message).await;
+                (node.addr, result)
+            });
+        }
+
+        if lookup.is_finished() {
+            break;
+        }
+        match in_flight.next().await {
+            Some((addr, Ok(response))) => lookup.on_response(addr, &response),
+            Some((addr, Err(e))) => {
+                trace!("Lookup query to {} failed: {}", addr, e);
+                lookup.on_failure(addr);
+            }
+            None => break,
+        }
+    }
+
+    let result = lookup.into_result();
+    debug!(
+        "Lookup finished after {} queries: {} nodes, {} peers",
+        result.queries, result.closest.len(), result.peers.len(),
+    );
+    result
+}
+
+/// Announce to the nodes of a `get_peers` lookup that gave us a token
+///
+/// Returns the number of nodes that accepted the announce.
+pub async fn announce_to<Q: Querier + ?Sized>(
+    querier: &Q,
+    our_id: NodeId,
+    info_hash: [u8; 20],
+    port: u16,
+    result: &LookupResult,
+) -> usize {
+    let mut announces: FuturesUnordered<_> = result.closest.iter()
+        .filter_map(|(node, token)| token.clone().map(|token| (node, token)))
+        .map(|(node, token)| async move {
+            let message = DHTMessage::create_announce_peer_query(
+                generate_transaction_id(), our_id, info_hash, port, token,
+            );
+            let result = querier.query(node, message).await;
+            if let Err(e) = &result {
+                debug!("announce_peer to {} failed: {}", node.addr, e);
+            }
+            result.is_ok()
+        })
+        .collect();
+
+    let mut accepted = 0;
+    while let Some(ok) = announces.next().await {
+        accepted += ok as usize;
+    }
+    accepted
+}
+
+/// Put an item to the closest nodes of a finished `get` lookup
+///
+/// `cas` is sent with mutable items, see [`DHT::put`](crate::dht::DHT::put).
+/// Returns the number of nodes that stored the item.
+pub async fn put_to<Q: Querier + ?Sized>(
+    querier: &Q,
+    our_id: NodeId,
+    item: &Item,
+    cas: Option<i64>,
+    result: &LookupResult,
+) -> usize {
+    let mut puts: FuturesUnordered<_> = result.closest.iter()
+        .filter_map(|(node, token)| token.clone().map(|token| (node, token)))
+        .map(|(node, token)| async move {
+            let message = DHTMessage::create_put_query(generate_transaction_id(), our_id, token, item, cas);
+            let result = querier.query(node, message).await;
+            if let Err(e) = &result {
+                debug!("put to {} failed: {}", node.addr, e);
+            }
+            result.is_ok()
+        })
+        .collect();
+
+    let mut stored = 0;
+    while let Some(ok) = puts.next().await {
+        stored += ok as usize;
+    }
+    stored
+}
+
+/// Queries waiting for a response, by transaction ID, with the queried address
+type PendingQueries = HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<DHTMessage>)>;
+
+/// Querier owning a raw socket for the duration of a lookup
+///
+/// Used when no `DHT` event loop is reading the socket; [`SocketQuerier::drive`]
+/// receives responses while the lookup runs.
+pub struct SocketQuerier<'a> {
+    socket: &'a UdpSocket,
+    pending: std::sync::Mutex<PendingQueries>,
+}
+
+impl<'a> SocketQuerier<'a> {
+    /// Create a querier on a socket
+    pub fn new(socket: &'a UdpSocket) -> Self {
+        Self {
+            socket,
+            pending: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `future` while dispatching responses arriving on the socket
+    pub async fn drive<T>(&self, future: impl std::future::Future<Output = T>) -> Result<T> {
+        tokio::select! {
+            output = future => Ok(output),
+            Err(e) = self.receive_loop() => Err(e),
+        }
+    }
+
+    async fn receive_loop(&self) -> Result<()> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buffer).await
+                .map_err(|e| {
+                    error!("Failed to receive DHT message: {}", e);
+                    TorrentError::network_error_full("Failed to receive DHT message", "unknown".to_string(), e.to_string())
+                })?;
+            let message = match DHTMessage::deserialize(&buffer[..len]) {
+                Ok(message) => message,
+                Err(e) => {
+                    trace!("Ignoring invalid DHT message from {}: {}", from, e);
+                    continue;
+                }
+            };
+            if matches!(message, DHTMessage::Query { .. }) {
+                trace!("Ignoring query from {} during lookup", from);
+                continue;
+            }
+
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(message.transaction_id()) {
+                Some((addr, _)) if *addr == from => {
+                    if let Some((_, responder)) = pending.remove(message.transaction_id()) {
+                        let _ = responder.send(message);
+                    }
+                }
+                _ => trace!("Ignoring unexpected message from {}", from),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Querier for SocketQuerier<'_> {
+    async fn query(&self, node: &Node, message: DHTMessage) -> Result<DHTMessage> {
+        let transaction_id = message.transaction_id().to_vec();
+        let serialized = message.serialize()?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction_id.clone(), (node.addr, tx));
+
+        if let Err(e) = self.socket.send_to(&serialized, node.addr).await {
+            self.pending.lock().unwrap().remove(&transaction_id);
+            return Err(TorrentError::network_error_full("Failed to send query", node.addr.to_string(), e.to_string()).into());
+        }
+
+        let response = tokio::time::timeout(QUERY_TIMEOUT, rx).await;
+        self.pending.lock().unwrap().remove(&transaction_id);
+        match response {
+            Ok(Ok(DHTMessage::Error { code, message, .. })) => Err(TorrentError::dht_error_full(
+                "Node returned an error",
+                node.addr.to_string(),
+                format!("{} {}", code, message),
+            ).into()),
+            Ok(Ok(response)) => Ok(response),
+            _ => Err(TorrentError::dht_error_with_node("Query timed out", node.addr.to_string()).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::message::{serialize_compact_nodes, serialize_compact_peers};
+
+    fn id(first: u8) -> NodeId {
+        let mut id = [0u8; 20];
+        id[0] = first;
+        NodeId::new(id)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn response(from: NodeId, nodes: &[(NodeId, SocketAddr)], peers: &[SocketAddr], token: Option<&[u8]>) -> DHTMessage {
+        let mut args = BencodeDict::new();
+        if !nodes.is_empty() {
+            args.insert("nodes".to_string(), BencodeValue::Bytes(serialize_compact_nodes(nodes).unwrap()));
+        }
+        if !peers.is_empty() {
+            let values = peers.iter()
+                .map(|peer| BencodeValue::Bytes(serialize_compact_peers(&[*peer]).unwrap()))
+                .collect();
+            args.insert("values".to_string(), BencodeValue::List(values));
+        }
+        if let Some(token) = token {
+            args.insert("token".to_string(), BencodeValue::Bytes(token.to_vec()));
+        }
+        DHTMessage::create_response(b"aa".to_vec(), from, args)
+    }
+
+    #[test]
+    fn test_ipv6_lookup_follows_nodes6() {
+        let v6 = |port: u16| SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+        let seeds = vec![Node::new(id(1), addr(1)), Node::new(id(2), v6(2))];
+        let mut lookup = Lookup::new(id(0), LookupKind::GetPeers, id(0xff), seeds).with_family(Want::N6);
+
+        let node = lookup.next_query().unwrap();
+        assert_eq!(node.addr, v6(2));
+        let query = lookup.query_message("aa".to_string());
+        assert_eq!(crate::dht::message::get_want(query.args().unwrap()), vec![Want::N6]);
+
+        let mut args = BencodeDict::new();
+        args.insert("nodes".to_string(), BencodeValue::Bytes(serialize_compact_nodes(&[(id(3), addr(3))]).unwrap()));
+        args.insert("nodes6".to_string(), BencodeValue::Bytes(
+            crate::dht::message::serialize_compact_nodes6(&[(id(4), v6(4))]).unwrap(),
+        ));
+        args.insert("values".to_string(), BencodeValue::List(vec![
+            BencodeValue::Bytes(crate::dht::message::serialize_compact_peer(&v6(5))),
+        ]));
+        lookup.on_response(v6(2), &DHTMessage::create_response(b"aa".to_vec(), id(2), args));
+
+        assert_eq!(lookup.next_query().unwrap().addr, v6(4));
+        assert!(lookup.next_query().is_none());
+        assert_eq!(lookup.peers(), &[v6(5)]);
+    }
+
+    #[test]
+    fn test_lookup_queries_closest_first() {
+        let seeds = (1..=5).rev().map(|i| Node::new(id(i), addr(i as u16)));
+        let mut lookup = Lookup::new(id(0), LookupKind::FindNode, id(0xff), seeds);
+
+        assert_eq!(lookup.next_query().unwrap().id, id(1));
+        assert_eq!(lookup.next_query().unwrap().id, id(2));
+        assert!(!lookup.is_finished());
+
+        // A closer node learnt from a response is queried next
+        let closer = NodeId::new([0u8; 20]);
+        lookup.on_response(addr(1), &response(id(1), &[(closer, addr(100))], &[], None));
+        assert_eq!(lookup.next_query().unwrap().id, closer);
+    }
+
+    #[test]
+    fn test_lookup_collects_peers_and_tokens() {
+        let mut lookup = Lookup::new(id(0), LookupKind::GetPeers, id(0xff), [Node::new(id(1), addr(1))]);
+        assert!(matches!(
+            lookup.query_message("aa".to_string()),
+            DHTMessage::Query { query_type: crate::dht::QueryType::GetPeers, .. }
+        ));
+
+        let node = lookup.next_query().unwrap();
+        let peers = [addr(6881), addr(6882)];
+        lookup.on_response(node.addr, &response(id(1), &[], &peers, Some(b"secret")));
+        lookup.on_response(node.addr, &response(id(1), &[], &peers, Some(b"secret")));
+        assert!(lookup.is_finished());
+
+        let result = lookup.into_result();
+        assert_eq!(result.peers, peers);
+        assert_eq!(result.closest.len(), 1);
+        assert_eq!(result.closest[0].1.as_deref(), Some(&b"secret"[..]));
+        assert_eq!(result.queries, 1);
+    }
+
+    #[test]
+    fn test_lookup_finishes_when_k_closest_responded() {
+        let seeds: Vec<Node> = (1..=(K as u8 + 2)).map(|i| Node::new(id(i), addr(i as u16))).collect();
+        let mut lookup = Lookup::new(id(0), LookupKind::FindNode, id(0xff), seeds);
+
+        // One of the closest fails, so the K+1th node takes its place
+        let first = lookup.next_query().unwrap();
+        lookup.on_failure(first.addr);
+        for _ in 0..K {
+            let node = lookup.next_query().unwrap();
+            assert!(!lookup.is_finished());
+            lookup.on_response(node.addr, &response(node.id, &[], &[], None));
+        }
+
+        assert!(lookup.is_finished());
+        assert!(lookup.next_query().is_none());
+        let result = lookup.into_result();
+        assert_eq!(result.closest.len(), K);
+        assert_eq!(result.closest[0].0.id, id(2));
+        assert_eq!(result.queries, K + 1);
+    }
+
+    #[test]
+    fn test_lookup_updates_responding_node_id() {
+        let mut lookup = Lookup::new(id(0), LookupKind::FindNode, id(0xff), [
+            Node::new(id(0x80), addr(1)),
+            Node::new(id(0x40), addr(2)),
+        ]);
+        // Our own ID is never a candidate
+        lookup.on_response(addr(9), &response(id(9), &[(id(0xff), addr(3))], &[], None));
+
+        let node = lookup.next_query().unwrap();
+        assert_eq!(node.addr, addr(2));
+        lookup.on_response(node.addr, &response(id(1), &[(id(0xff), addr(3))], &[], None));
+
+        let node = lookup.next_query().unwrap();
+        lookup.on_response(node.addr, &response(node.id, &[], &[], None));
+        assert!(lookup.is_finished());
+        let result = lookup.into_result();
+        assert_eq!(result.closest[0].0.id, id(1));
+        assert_eq!(result.closest.len(), 2);
+    }
+
+    /// A simulated network where every node knows a few others
+    struct FakeNetwork {
+        nodes: HashMap<SocketAddr, (NodeId, Vec<(NodeId, SocketAddr)>)>,
+        peers: Vec<SocketAddr>,
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Querier for FakeNetwork {
+        async fn query(&self, node: &Node, message: DHTMessage) -> Result<DHTMessage> {
+            use std::sync::atomic::Ordering;
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            let (id, known) = self.nodes.get(&node.addr)
+                .ok_or_else(|| anyhow::anyhow!("unreachable"))?;
+            let DHTMessage::Query { transaction_id, .. } = message else { unreachable!() };
+            let peers = if id.0[0] < 4 { self.peers.as_slice() } else { &[] };
+            let mut reply = response(*id, known, peers, Some(b"token"));
+            if let DHTMessage::Response { transaction_id: t, .. } = &mut reply {
+                *t = transaction_id;
+            }
+            Ok(reply)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_converges_on_target() {
+        // Node i knows nodes at half its distance to the target, plus an unreachable one
+        let mut nodes = HashMap::new();
+        for i in 1..=128u8 {
+            let known = vec![
+                (id(i / 2), addr(i as u16 / 2)),
+                (id(i.saturating_sub(1)), addr(i as u16 - 1)),
+                (id(i / 2 + 1), addr(1000 + i as u16)),
+            ];
+            nodes.insert(addr(i as u16), (id(i), known));
+        }
+        let network = FakeNetwork {
+            nodes,
+            peers: vec![addr(6881)],
+            in_flight: Default::default(),
+            max_in_flight: Default::default(),
+        };
+
+        let lookup = Lookup::new(id(0), LookupKind::GetPeers, id(0xff), [Node::new(id(128), addr(128))]);
+        let result = run(lookup, &network).await;
+
+        assert_eq!(result.closest[0].0.id, id(1));
+        assert_eq!(result.peers, vec![addr(6881)]);
+        assert!(network.max_in_flight.load(std::sync::atomic::Ordering::SeqCst) <= ALPHA);
+
+        let accepted = announce_to(&network, id(0xff), id(0).0, 6881, &result).await;
+        assert_eq!(accepted, result.closest.len());
+    }
+}
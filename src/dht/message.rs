@@ -1,11 +1,14 @@
 //! DHT message module
 //!
-//! Defines DHT protocol messages for peer discovery.
+//! Defines DHT protocol messages for peer discovery, encoded as KRPC
+//! bencoded dictionaries (BEP 5).
 
+use crate::dht::item::Item;
 use crate::dht::node::NodeId;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 
 /// DHT query types
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -15,6 +18,9 @@ pub enum QueryType {
     FindNode,
     GetPeers,
     AnnouncePeer,
+    Get,
+    Put,
+    SampleInfohashes,
 }
 
 impl std::fmt::Display for QueryType {
@@ -24,6 +30,25 @@ impl std::fmt::Display for QueryType {
             QueryType::FindNode => write!(f, "find_node"),
             QueryType::GetPeers => write!(f, "get_peers"),
             QueryType::AnnouncePeer => write!(f, "announce_peer"),
+            QueryType::Get => write!(f, "get"),
+            QueryType::Put => write!(f, "put"),
+            QueryType::SampleInfohashes => write!(f, "sample_infohashes"),
+        }
+    }
+}
+
+impl QueryType {
+    /// Parse a query method name
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"ping" => Some(QueryType::Ping),
+            b"find_node" => Some(QueryType::FindNode),
+            b"get_peers" => Some(QueryType::GetPeers),
+            b"announce_peer" => Some(QueryType::AnnouncePeer),
+            b"get" => Some(QueryType::Get),
+            b"put" => Some(QueryType::Put),
+            b"sample_infohashes" => Some(QueryType::SampleInfohashes),
+            _ => None,
         }
     }
 }
@@ -36,37 +61,115 @@ pub enum ResponseType {
     FindNode,
     GetPeers,
     AnnouncePeer,
+    Get,
+    Put,
+    SampleInfohashes,
+}
+
+impl From<&QueryType> for ResponseType {
+    fn from(query_type: &QueryType) -> Self {
+        match query_type {
+            QueryType::Ping => ResponseType::Ping,
+            QueryType::FindNode => ResponseType::FindNode,
+            QueryType::GetPeers => ResponseType::GetPeers,
+            QueryType::AnnouncePeer => ResponseType::AnnouncePeer,
+            QueryType::Get => ResponseType::Get,
+            QueryType::Put => ResponseType::Put,
+            QueryType::SampleInfohashes => ResponseType::SampleInfohashes,
+        }
+    }
+}
+
+/// KRPC error codes
+pub mod error_code {
+    /// Generic error
+    pub const GENERIC: u32 = 201;
+    /// Server error
+    pub const SERVER: u32 = 202;
+    /// Protocol error, such as a malformed packet or a bad token
+    pub const PROTOCOL: u32 = 203;
+    /// Method unknown
+    pub const METHOD_UNKNOWN: u32 = 204;
+    /// Item value too big (BEP 44)
+    pub const MESSAGE_TOO_BIG: u32 = 205;
+    /// Invalid item signature (BEP 44)
+    pub const INVALID_SIGNATURE: u32 = 206;
+    /// Item salt too big (BEP 44)
+    pub const SALT_TOO_BIG: u32 = 207;
+    /// Stored sequence number differs from `cas` (BEP 44)
+    pub const CAS_MISMATCH: u32 = 301;
+    /// Sequence number lower than the stored one (BEP 44)
+    pub const SEQ_TOO_LOW: u32 = 302;
+}
+
+/// Address family of the nodes a query asks for (BEP 32)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Want {
+    /// IPv4 nodes, returned in `nodes`
+    N4,
+    /// IPv6 nodes, returned in `nodes6`
+    N6,
+}
+
+impl Want {
+    /// Get the address family of an address
+    pub fn for_addr(addr: &SocketAddr) -> Self {
+        if addr.is_ipv6() { Want::N6 } else { Want::N4 }
+    }
+
+    /// Check if an address belongs to this family
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        Self::for_addr(addr) == *self
+    }
+
+    /// Get the value used in the `want` list
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Want::N4 => b"n4",
+            Want::N6 => b"n6",
+        }
+    }
+
+    /// Parse a value from the `want` list
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        match value {
+            b"n4" => Some(Want::N4),
+            b"n6" => Some(Want::N6),
+            _ => None,
+        }
+    }
+
+    /// Get the response key holding compact nodes of this family
+    pub fn nodes_key(&self) -> &'static str {
+        match self {
+            Want::N4 => "nodes",
+            Want::N6 => "nodes6",
+        }
+    }
 }
 
-/// DHT message types
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "y", rename_all = "lowercase")]
+/// DHT message (KRPC)
+///
+/// Responses do not name their query type on the wire; it is recovered from
+/// the pending [`Transaction`] with the same transaction ID.
+#[derive(Debug, Clone)]
 pub enum DHTMessage {
-    #[serde(rename = "q")]
     Query {
-        #[serde(rename = "t")]
-        id: NodeId,
-        #[serde(rename = "q")]
+        transaction_id: Vec<u8>,
         query_type: QueryType,
-        #[serde(rename = "a")]
         args: BencodeDict,
+        /// The querying node does not answer queries (BEP 43)
+        read_only: bool,
     },
-    #[serde(rename = "r")]
     Response {
-        #[serde(rename = "t")]
-        id: NodeId,
-        #[serde(rename = "r")]
-        response_type: ResponseType,
-        #[serde(rename = "a")]
+        transaction_id: Vec<u8>,
         args: BencodeDict,
+        /// The querying node's address as we saw it (BEP 42)
+        ip: Option<SocketAddr>,
     },
-    #[serde(rename = "e")]
     Error {
-        #[serde(rename = "t")]
-        id: NodeId,
-        #[serde(rename = "e")]
+        transaction_id: Vec<u8>,
         code: u32,
-        #[serde(rename = "m")]
         message: String,
     },
 }
@@ -78,6 +181,8 @@ pub struct Transaction {
     pub node_id: NodeId,
     pub query_type: QueryType,
     pub created_at: std::time::Instant,
+    /// Address the query was sent to; responses from elsewhere are ignored
+    pub addr: Option<SocketAddr>,
 }
 
 impl Transaction {
@@ -87,9 +192,15 @@ impl Transaction {
             node_id,
             query_type,
             created_at: std::time::Instant::now(),
+            addr: None,
         }
     }
 
+    /// Create a transaction for a query sent to `addr`
+    pub fn to_addr(transaction_id: String, node_id: NodeId, query_type: QueryType, addr: SocketAddr) -> Self {
+        Self { addr: Some(addr), ..Self::new(transaction_id, node_id, query_type) }
+    }
+
     pub fn is_expired(&self, timeout: std::time::Duration) -> bool {
         self.created_at.elapsed() > timeout
     }
@@ -99,7 +210,10 @@ impl Transaction {
 pub type BencodeDict = HashMap<String, BencodeValue>;
 
 /// Bencode value types
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Byte strings are decoded as `Bytes`; `String` is a convenience for
+/// building messages with text values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum BencodeValue {
     String(String),
@@ -109,55 +223,285 @@ pub enum BencodeValue {
     Bytes(Vec<u8>),
 }
 
+/// Deepest nesting accepted when decoding
+const MAX_DEPTH: usize = 32;
+
+impl BencodeValue {
+    /// Get a byte string value
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(bytes) => Some(bytes),
+            BencodeValue::String(s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Get a byte string value as UTF-8 text
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    /// Get an integer value
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Get a list value
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    /// Get a dictionary value
+    pub fn as_dict(&self) -> Option<&BencodeDict> {
+        match self {
+            BencodeValue::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Bencode the value, with dictionary keys in sorted order
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            BencodeValue::String(s) => encode_bytes(s.as_bytes(), out),
+            BencodeValue::Bytes(bytes) => encode_bytes(bytes, out),
+            BencodeValue::Integer(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            }
+            BencodeValue::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+            BencodeValue::Dict(dict) => {
+                let mut keys: Vec<&String> = dict.keys().collect();
+                keys.sort();
+                out.push(b'd');
+                for key in keys {
+                    encode_bytes(key.as_bytes(), out);
+                    dict[key].encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// Bencode the value into a new buffer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    /// Decode a single bencoded value spanning all of `data`
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let value = Self::decode_at(data, &mut pos, 0)?;
+        if pos != data.len() {
+            return Err(anyhow::anyhow!("Trailing data after bencoded value"));
+        }
+        Ok(value)
+    }
+
+    fn decode_at(data: &[u8], pos: &mut usize, depth: usize) -> Result<Self> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow::anyhow!("Bencode nesting too deep"));
+        }
+        match data.get(*pos) {
+            Some(b'i') => {
+                *pos += 1;
+                let end = find_byte(data, *pos, b'e')?;
+                let value = std::str::from_utf8(&data[*pos..end])?.parse::<i64>()?;
+                *pos = end + 1;
+                Ok(BencodeValue::Integer(value))
+            }
+            Some(b'l') => {
+                *pos += 1;
+                let mut list = Vec::new();
+                while data.get(*pos) != Some(&b'e') {
+                    list.push(Self::decode_at(data, pos, depth + 1)?);
+                }
+                *pos += 1;
+                Ok(BencodeValue::List(list))
+            }
+            Some(b'd') => {
+                *pos += 1;
+                let mut dict = BencodeDict::new();
+                while data.get(*pos) != Some(&b'e') {
+                    let key = decode_bytes(data, pos)?;
+                    let key = String::from_utf8(key)
+                        .map_err(|_| anyhow::anyhow!("Dictionary key is not UTF-8"))?;
+                    let value = Self::decode_at(data, pos, depth + 1)?;
+                    dict.insert(key, value);
+                }
+                *pos += 1;
+                Ok(BencodeValue::Dict(dict))
+            }
+            Some(b'0'..=b'9') => Ok(BencodeValue::Bytes(decode_bytes(data, pos)?)),
+            Some(byte) => Err(anyhow::anyhow!("Unknown bencode type: {}", byte)),
+            None => Err(anyhow::anyhow!("Unexpected end of data")),
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+fn find_byte(data: &[u8], from: usize, byte: u8) -> Result<usize> {
+    data[from..].iter().position(|&b| b == byte)
+        .map(|offset| from + offset)
+        .ok_or_else(|| anyhow::anyhow!("Unexpected end of data"))
+}
+
+fn decode_bytes(data: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    let colon = find_byte(data, *pos, b':')?;
+    let length: usize = std::str::from_utf8(&data[*pos..colon])?.parse()?;
+    let start = colon + 1;
+    let end = start.checked_add(length)
+        .filter(|&end| end <= data.len())
+        .ok_or_else(|| anyhow::anyhow!("Byte string exceeds data"))?;
+    *pos = end;
+    Ok(data[start..end].to_vec())
+}
+
+/// Get a byte string argument
+pub fn get_bytes<'a>(args: &'a BencodeDict, key: &str) -> Option<&'a [u8]> {
+    args.get(key).and_then(BencodeValue::as_bytes)
+}
+
+/// Get the address families listed in a query's `want` argument
+pub fn get_want(args: &BencodeDict) -> Vec<Want> {
+    args.get("want")
+        .and_then(BencodeValue::as_list)
+        .map(|list| list.iter().filter_map(BencodeValue::as_bytes).filter_map(Want::from_bytes).collect())
+        .unwrap_or_default()
+}
+
+/// Get a 20-byte node ID or info hash argument
+pub fn get_node_id(args: &BencodeDict, key: &str) -> Option<NodeId> {
+    get_bytes(args, key).and_then(NodeId::from_slice)
+}
+
 impl DHTMessage {
     /// Serialize DHT message to bytes
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        serde_bencode::ser::to_bytes(self)
-            .map_err(|e| anyhow::anyhow!("Failed to serialize DHT message: {}", e))
+        let mut dict = BencodeDict::new();
+        dict.insert("t".to_string(), BencodeValue::Bytes(self.transaction_id().to_vec()));
+        match self {
+            DHTMessage::Query { query_type, args, read_only, .. } => {
+                dict.insert("y".to_string(), BencodeValue::String("q".to_string()));
+                dict.insert("q".to_string(), BencodeValue::String(query_type.to_string()));
+                dict.insert("a".to_string(), BencodeValue::Dict(args.clone()));
+                if *read_only {
+                    dict.insert("ro".to_string(), BencodeValue::Integer(1));
+                }
+            }
+            DHTMessage::Response { args, ip, .. } => {
+                dict.insert("y".to_string(), BencodeValue::String("r".to_string()));
+                dict.insert("r".to_string(), BencodeValue::Dict(args.clone()));
+                if let Some(ip) = ip {
+                    dict.insert("ip".to_string(), BencodeValue::Bytes(serialize_compact_peer(ip)));
+                }
+            }
+            DHTMessage::Error { code, message, .. } => {
+                dict.insert("y".to_string(), BencodeValue::String("e".to_string()));
+                dict.insert("e".to_string(), BencodeValue::List(vec![
+                    BencodeValue::Integer(*code as i64),
+                    BencodeValue::String(message.clone()),
+                ]));
+            }
+        }
+        Ok(BencodeValue::Dict(dict).to_bytes())
     }
 
     /// Deserialize DHT message from bytes
     pub fn deserialize(data: &[u8]) -> Result<Self> {
-        serde_bencode::de::from_bytes(data)
-            .map_err(|e| anyhow::anyhow!("Failed to deserialize DHT message: {}", e))
-    }
+        let value = BencodeValue::decode(data)
+            .map_err(|e| anyhow::anyhow!("Failed to deserialize DHT message: {}", e))?;
+        let BencodeValue::Dict(mut dict) = value else {
+            return Err(anyhow::anyhow!("DHT message is not a dictionary"));
+        };
 
-    /// Create a ping query
-    pub fn create_ping_query(transaction_id: String, our_id: NodeId) -> Self {
-        let mut args = BencodeDict::new();
-        args.insert("id".to_string(), BencodeValue::String(our_id.to_hex()));
+        let transaction_id = get_bytes(&dict, "t")
+            .ok_or_else(|| anyhow::anyhow!("DHT message has no transaction ID"))?
+            .to_vec();
+        let kind = get_bytes(&dict, "y")
+            .ok_or_else(|| anyhow::anyhow!("DHT message has no type"))?
+            .to_vec();
 
+        match kind.as_slice() {
+            b"q" => {
+                let name = get_bytes(&dict, "q")
+                    .ok_or_else(|| anyhow::anyhow!("DHT query has no method"))?;
+                let query_type = QueryType::from_name(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown DHT query: {}", String::from_utf8_lossy(name)))?;
+                let args = match dict.remove("a") {
+                    Some(BencodeValue::Dict(args)) => args,
+                    _ => return Err(anyhow::anyhow!("DHT query has no arguments")),
+                };
+                let read_only = dict.get("ro").and_then(BencodeValue::as_integer) == Some(1);
+                Ok(DHTMessage::Query { transaction_id, query_type, args, read_only })
+            }
+            b"r" => {
+                let args = match dict.remove("r") {
+                    Some(BencodeValue::Dict(args)) => args,
+                    _ => return Err(anyhow::anyhow!("DHT response has no values")),
+                };
+                let ip = get_bytes(&dict, "ip")
+                    .filter(|ip| ip.len() == 6 || ip.len() == 18)
+                    .and_then(|ip| parse_compact_peer_value(ip).ok())
+                    .and_then(|ips| ips.first().copied());
+                Ok(DHTMessage::Response { transaction_id, args, ip })
+            }
+            b"e" => {
+                let error = dict.get("e").and_then(BencodeValue::as_list).unwrap_or(&[]);
+                let code = error.first().and_then(BencodeValue::as_integer).unwrap_or(error_code::GENERIC as i64);
+                let message = error.get(1).and_then(BencodeValue::as_str).unwrap_or_default().to_string();
+                Ok(DHTMessage::Error { transaction_id, code: code as u32, message })
+            }
+            other => Err(anyhow::anyhow!("Unknown DHT message type: {}", String::from_utf8_lossy(other))),
+        }
+    }
+
+    fn query(transaction_id: String, query_type: QueryType, our_id: NodeId, mut args: BencodeDict) -> Self {
+        args.insert("id".to_string(), BencodeValue::Bytes(our_id.0.to_vec()));
         DHTMessage::Query {
-            id: our_id,
-            query_type: QueryType::Ping,
+            transaction_id: transaction_id.into_bytes(),
+            query_type,
             args,
+            read_only: false,
         }
     }
 
+    /// Create a ping query
+    pub fn create_ping_query(transaction_id: String, our_id: NodeId) -> Self {
+        Self::query(transaction_id, QueryType::Ping, our_id, BencodeDict::new())
+    }
+
     /// Create a find_node query
     pub fn create_find_node_query(transaction_id: String, our_id: NodeId, target: NodeId) -> Self {
         let mut args = BencodeDict::new();
-        args.insert("id".to_string(), BencodeValue::String(our_id.to_hex()));
-        args.insert("target".to_string(), BencodeValue::String(target.to_hex()));
-
-        DHTMessage::Query {
-            id: our_id,
-            query_type: QueryType::FindNode,
-            args,
-        }
+        args.insert("target".to_string(), BencodeValue::Bytes(target.0.to_vec()));
+        Self::query(transaction_id, QueryType::FindNode, our_id, args)
     }
 
     /// Create a get_peers query
     pub fn create_get_peers_query(transaction_id: String, our_id: NodeId, info_hash: [u8; 20]) -> Self {
         let mut args = BencodeDict::new();
-        args.insert("id".to_string(), BencodeValue::String(our_id.to_hex()));
-        args.insert("info_hash".to_string(), BencodeValue::String(hex::encode(info_hash)));
-
-        DHTMessage::Query {
-            id: our_id,
-            query_type: QueryType::GetPeers,
-            args,
-        }
+        args.insert("info_hash".to_string(), BencodeValue::Bytes(info_hash.to_vec()));
+        Self::query(transaction_id, QueryType::GetPeers, our_id, args)
     }
 
     /// Create an announce_peer query
@@ -166,19 +510,91 @@ impl DHTMessage {
         our_id: NodeId,
         info_hash: [u8; 20],
         port: u16,
-        token: String,
+        token: Vec<u8>,
     ) -> Self {
         let mut args = BencodeDict::new();
-        args.insert("id".to_string(), BencodeValue::String(our_id.to_hex()));
-        args.insert("info_hash".to_string(), BencodeValue::String(hex::encode(info_hash)));
+        args.insert("info_hash".to_string(), BencodeValue::Bytes(info_hash.to_vec()));
         args.insert("port".to_string(), BencodeValue::Integer(port as i64));
-        args.insert("token".to_string(), BencodeValue::String(token));
+        args.insert("token".to_string(), BencodeValue::Bytes(token));
+        Self::query(transaction_id, QueryType::AnnouncePeer, our_id, args)
+    }
 
-        DHTMessage::Query {
-            id: our_id,
-            query_type: QueryType::AnnouncePeer,
-            args,
+    /// Create a get query for an item, optionally only if newer than `seq`
+    pub fn create_get_query(transaction_id: String, our_id: NodeId, target: [u8; 20], seq: Option<i64>) -> Self {
+        let mut args = BencodeDict::new();
+        args.insert("target".to_string(), BencodeValue::Bytes(target.to_vec()));
+        if let Some(seq) = seq {
+            args.insert("seq".to_string(), BencodeValue::Integer(seq));
         }
+        Self::query(transaction_id, QueryType::Get, our_id, args)
+    }
+
+    /// Create a sample_infohashes query (BEP 51)
+    pub fn create_sample_infohashes_query(transaction_id: String, our_id: NodeId, target: [u8; 20]) -> Self {
+        let mut args = BencodeDict::new();
+        args.insert("target".to_string(), BencodeValue::Bytes(target.to_vec()));
+        Self::query(transaction_id, QueryType::SampleInfohashes, our_id, args)
+    }
+
+    /// Create a put query storing an item
+    pub fn create_put_query(transaction_id: String, our_id: NodeId, token: Vec<u8>, item: &Item, cas: Option<i64>) -> Self {
+        let mut args = BencodeDict::new();
+        args.insert("token".to_string(), BencodeValue::Bytes(token));
+        match item {
+            Item::Immutable(value) => {
+                args.insert("v".to_string(), value.clone());
+            }
+            Item::Mutable(item) => {
+                item.insert_into(&mut args);
+                if !item.salt.is_empty() {
+                    args.insert("salt".to_string(), BencodeValue::Bytes(item.salt.clone()));
+                }
+                if let Some(cas) = cas {
+                    args.insert("cas".to_string(), BencodeValue::Integer(cas));
+                }
+            }
+        }
+        Self::query(transaction_id, QueryType::Put, our_id, args)
+    }
+
+    /// Create a response carrying our ID
+    pub fn create_response(transaction_id: Vec<u8>, our_id: NodeId, mut args: BencodeDict) -> Self {
+        args.insert("id".to_string(), BencodeValue::Bytes(our_id.0.to_vec()));
+        DHTMessage::Response { transaction_id, args, ip: None }
+    }
+
+    /// Tell the querying node the address we saw it at
+    pub fn with_ip(mut self, addr: SocketAddr) -> Self {
+        if let DHTMessage::Response { ip, .. } = &mut self {
+            *ip = Some(addr);
+        }
+        self
+    }
+
+    /// Get the address the responding node saw us at
+    pub fn reported_ip(&self) -> Option<SocketAddr> {
+        match self {
+            DHTMessage::Response { ip, .. } => *ip,
+            _ => None,
+        }
+    }
+
+    /// Mark a query as coming from a read-only node (BEP 43)
+    pub fn with_read_only(mut self) -> Self {
+        if let DHTMessage::Query { read_only, .. } = &mut self {
+            *read_only = true;
+        }
+        self
+    }
+
+    /// Check if a query comes from a read-only node
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, DHTMessage::Query { read_only: true, .. })
+    }
+
+    /// Create an error reply
+    pub fn create_error(transaction_id: Vec<u8>, code: u32, message: impl Into<String>) -> Self {
+        DHTMessage::Error { transaction_id, code, message: message.into() }
     }
 
     /// Get the message type as a string
@@ -190,13 +606,40 @@ impl DHTMessage {
         }
     }
 
+    /// Get the raw transaction ID
+    pub fn transaction_id(&self) -> &[u8] {
+        match self {
+            DHTMessage::Query { transaction_id, .. }
+            | DHTMessage::Response { transaction_id, .. }
+            | DHTMessage::Error { transaction_id, .. } => transaction_id,
+        }
+    }
+
     /// Get the transaction ID from the message
     pub fn get_transaction_id(&self) -> Option<String> {
+        String::from_utf8(self.transaction_id().to_vec()).ok()
+    }
+
+    /// Get the query arguments or response values
+    pub fn args(&self) -> Option<&BencodeDict> {
         match self {
-            DHTMessage::Query { id, .. } => Some(id.to_hex()),
-            DHTMessage::Response { id, .. } => Some(id.to_hex()),
-            DHTMessage::Error { id, .. } => Some(id.to_hex()),
+            DHTMessage::Query { args, .. } | DHTMessage::Response { args, .. } => Some(args),
+            DHTMessage::Error { .. } => None,
+        }
+    }
+
+    /// Get the sender's node ID
+    pub fn sender_id(&self) -> Option<NodeId> {
+        self.args().and_then(|args| get_node_id(args, "id"))
+    }
+
+    /// Ask for nodes of the given address families in a query's response
+    pub fn with_want(mut self, wants: &[Want]) -> Self {
+        if let DHTMessage::Query { args, .. } = &mut self {
+            let list = wants.iter().map(|want| BencodeValue::Bytes(want.as_bytes().to_vec())).collect();
+            args.insert("want".to_string(), BencodeValue::List(list));
         }
+        self
     }
 }
 
@@ -251,6 +694,14 @@ pub fn serialize_compact_nodes(nodes: &[(NodeId, std::net::SocketAddr)]) -> Resu
     Ok(buffer)
 }
 
+/// Parse the `samples` of a sample_infohashes response (20 bytes per info hash)
+pub fn parse_samples(data: &[u8]) -> Result<Vec<[u8; 20]>> {
+    if !data.len().is_multiple_of(20) {
+        return Err(anyhow::anyhow!("Invalid samples data length"));
+    }
+    Ok(data.chunks_exact(20).map(|chunk| chunk.try_into().unwrap()).collect())
+}
+
 /// Parse peers from compact peer format (6 bytes per peer: 4 bytes IP + 2 bytes port)
 pub fn parse_compact_peers(data: &[u8]) -> Result<Vec<std::net::SocketAddr>> {
     let mut peers = Vec::new();
@@ -288,6 +739,117 @@ pub fn serialize_compact_peers(peers: &[std::net::SocketAddr]) -> Result<Vec<u8>
     Ok(buffer)
 }
 
+/// Parse nodes from compact IPv6 node format (20 bytes ID + 16 bytes IP + 2 bytes port)
+pub fn parse_compact_nodes6(data: &[u8]) -> Result<Vec<(NodeId, std::net::SocketAddr)>> {
+    let chunk_size = 38;
+
+    if !data.len().is_multiple_of(chunk_size) {
+        return Err(anyhow::anyhow!("Invalid compact IPv6 nodes data length"));
+    }
+
+    let mut nodes = Vec::with_capacity(data.len() / chunk_size);
+    for chunk in data.chunks(chunk_size) {
+        let mut id = [0u8; 20];
+        id.copy_from_slice(&chunk[0..20]);
+        nodes.push((NodeId::new(id), parse_compact_addr6(&chunk[20..])));
+    }
+
+    Ok(nodes)
+}
+
+/// Serialize nodes to compact IPv6 format
+pub fn serialize_compact_nodes6(nodes: &[(NodeId, std::net::SocketAddr)]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(nodes.len() * 38);
+
+    for (node_id, addr) in nodes {
+        buffer.extend_from_slice(node_id.as_bytes());
+        serialize_compact_addr6(addr, &mut buffer)?;
+    }
+
+    Ok(buffer)
+}
+
+/// Parse peers from compact IPv6 peer format (18 bytes per peer: 16 bytes IP + 2 bytes port)
+pub fn parse_compact_peers6(data: &[u8]) -> Result<Vec<std::net::SocketAddr>> {
+    let chunk_size = 18;
+
+    if !data.len().is_multiple_of(chunk_size) {
+        return Err(anyhow::anyhow!("Invalid compact IPv6 peers data length"));
+    }
+
+    Ok(data.chunks(chunk_size).map(parse_compact_addr6).collect())
+}
+
+/// Serialize peers to compact IPv6 format
+pub fn serialize_compact_peers6(peers: &[std::net::SocketAddr]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(peers.len() * 18);
+
+    for addr in peers {
+        serialize_compact_addr6(addr, &mut buffer)?;
+    }
+
+    Ok(buffer)
+}
+
+/// Parse nodes of an address family from a response
+pub fn parse_compact_nodes_of(family: Want, data: &[u8]) -> Result<Vec<(NodeId, std::net::SocketAddr)>> {
+    match family {
+        Want::N4 => parse_compact_nodes(data),
+        Want::N6 => parse_compact_nodes6(data),
+    }
+}
+
+/// Serialize nodes of an address family for a response
+pub fn serialize_compact_nodes_of(family: Want, nodes: &[(NodeId, std::net::SocketAddr)]) -> Result<Vec<u8>> {
+    match family {
+        Want::N4 => serialize_compact_nodes(nodes),
+        Want::N6 => serialize_compact_nodes6(nodes),
+    }
+}
+
+/// Parse one `values` entry of a `get_peers` response, IPv4 or IPv6
+pub fn parse_compact_peer_value(data: &[u8]) -> Result<Vec<std::net::SocketAddr>> {
+    if data.len() == 18 {
+        parse_compact_peers6(data)
+    } else {
+        parse_compact_peers(data)
+    }
+}
+
+/// Serialize one peer for the `values` of a `get_peers` response
+pub fn serialize_compact_peer(peer: &std::net::SocketAddr) -> Vec<u8> {
+    match peer {
+        std::net::SocketAddr::V4(addr_v4) => {
+            let mut buffer = addr_v4.ip().octets().to_vec();
+            buffer.extend_from_slice(&addr_v4.port().to_be_bytes());
+            buffer
+        }
+        std::net::SocketAddr::V6(addr_v6) => {
+            let mut buffer = addr_v6.ip().octets().to_vec();
+            buffer.extend_from_slice(&addr_v6.port().to_be_bytes());
+            buffer
+        }
+    }
+}
+
+fn parse_compact_addr6(chunk: &[u8]) -> std::net::SocketAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&chunk[0..16]);
+    let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+    std::net::SocketAddr::new(std::net::Ipv6Addr::from(octets).into(), port)
+}
+
+fn serialize_compact_addr6(addr: &std::net::SocketAddr, buffer: &mut Vec<u8>) -> Result<()> {
+    match addr {
+        std::net::SocketAddr::V6(addr_v6) => {
+            buffer.extend_from_slice(&addr_v6.ip().octets());
+            buffer.extend_from_slice(&addr_v6.port().to_be_bytes());
+            Ok(())
+        }
+        std::net::SocketAddr::V4(_) => Err(anyhow::anyhow!("IPv4 addresses not supported in compact IPv6 format")),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -331,21 +893,59 @@ mod tests {
             our_id,
             info_hash,
             6881,
-            "token".to_string(),
+            b"token".to_vec(),
         );
         assert!(matches!(query, DHTMessage::Query { query_type: QueryType::AnnouncePeer, .. }));
     }
 
     #[test]
     fn test_serialize_deserialize() {
-        // Note: Full serialize/deserialize test is skipped due to
-        // NodeId serialization format mismatch (byte array vs hex string).
-        // This is a known issue in the DHT message format.
         let our_id = NodeId::new([1u8; 20]);
-        let query = DHTMessage::create_ping_query("test".to_string(), our_id);
-        let serialized = query.serialize();
-        // We can serialize, but deserialization will fail due to the format mismatch
-        assert!(serialized.is_ok());
+        let query = DHTMessage::create_get_peers_query("aa".to_string(), our_id, [3u8; 20]);
+        let serialized = query.serialize().unwrap();
+        assert_eq!(
+            serialized,
+            [
+                &b"d1:ad2:id20:"[..], &[1u8; 20], b"9:info_hash20:", &[3u8; 20],
+                b"e1:q9:get_peers1:t2:aa1:y1:qe",
+            ].concat()
+        );
+
+        match DHTMessage::deserialize(&serialized).unwrap() {
+            DHTMessage::Query { transaction_id, query_type, args, .. } => {
+                assert_eq!(transaction_id, b"aa");
+                assert_eq!(query_type, QueryType::GetPeers);
+                assert_eq!(get_node_id(&args, "id"), Some(our_id));
+                assert_eq!(get_bytes(&args, "info_hash"), Some(&[3u8; 20][..]));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_response_and_error() {
+        let response = DHTMessage::deserialize(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re").unwrap();
+        assert_eq!(response.transaction_id(), b"aa");
+        assert_eq!(response.sender_id(), Some(NodeId::new(*b"mnopqrstuvwxyz123456")));
+        assert_eq!(response.reported_ip(), None);
+
+        let addr: SocketAddr = "124.31.75.21:6881".parse().unwrap();
+        let response = DHTMessage::create_response(b"aa".to_vec(), NodeId::new([1u8; 20]), BencodeDict::new()).with_ip(addr);
+        let decoded = DHTMessage::deserialize(&response.serialize().unwrap()).unwrap();
+        assert_eq!(decoded.reported_ip(), Some(addr));
+
+        let error = DHTMessage::deserialize(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        match error {
+            DHTMessage::Error { code, message, .. } => {
+                assert_eq!(code, error_code::GENERIC);
+                assert_eq!(message, "A Generic Error Ocurred");
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        assert!(DHTMessage::deserialize(b"d1:t2:aa1:y1:q1:q4:evil1:ade").is_err());
+        assert!(DHTMessage::deserialize(b"d1:t99:aae").is_err());
+        assert!(BencodeValue::decode(&[b'l'; 64]).is_err());
     }
 
     #[test]
@@ -386,6 +986,57 @@ mod tests {
         assert_eq!(data.len(), 6);
     }
 
+    #[test]
+    fn test_compact_ipv6_round_trip() {
+        let nodes = vec![(NodeId::new([1u8; 20]), "[2001:db8::1]:6881".parse().unwrap())];
+        let data = serialize_compact_nodes6(&nodes).unwrap();
+        assert_eq!(data.len(), 38);
+        assert_eq!(parse_compact_nodes6(&data).unwrap(), nodes);
+        assert!(serialize_compact_nodes6(&[(NodeId::new([1u8; 20]), "127.0.0.1:6881".parse().unwrap())]).is_err());
+
+        let peers = vec!["[2001:db8::2]:51413".parse().unwrap()];
+        let data = serialize_compact_peers6(&peers).unwrap();
+        assert_eq!(data.len(), 18);
+        assert_eq!(parse_compact_peers6(&data).unwrap(), peers);
+        assert_eq!(parse_compact_peer_value(&data).unwrap(), peers);
+        assert_eq!(serialize_compact_peer(&peers[0]), data);
+        assert!(parse_compact_peers6(&data[..17]).is_err());
+    }
+
+    #[test]
+    fn test_sample_infohashes_query() {
+        let query = DHTMessage::create_sample_infohashes_query("aa".to_string(), NodeId::new([1u8; 20]), [2u8; 20]);
+        let decoded = DHTMessage::deserialize(&query.serialize().unwrap()).unwrap();
+        assert!(matches!(decoded, DHTMessage::Query { query_type: QueryType::SampleInfohashes, .. }));
+        assert_eq!(get_bytes(decoded.args().unwrap(), "target"), Some(&[2u8; 20][..]));
+
+        let samples = [[3u8; 20], [4u8; 20]].concat();
+        assert_eq!(parse_samples(&samples).unwrap(), vec![[3u8; 20], [4u8; 20]]);
+        assert!(parse_samples(&samples[..30]).is_err());
+    }
+
+    #[test]
+    fn test_read_only_query() {
+        let query = DHTMessage::create_ping_query("aa".to_string(), NodeId::new([1u8; 20]));
+        assert!(!DHTMessage::deserialize(&query.serialize().unwrap()).unwrap().is_read_only());
+
+        let data = query.with_read_only().serialize().unwrap();
+        assert!(data.windows(7).any(|window| window == b"2:roi1e"));
+        assert!(DHTMessage::deserialize(&data).unwrap().is_read_only());
+    }
+
+    #[test]
+    fn test_want() {
+        let query = DHTMessage::create_find_node_query("aa".to_string(), NodeId::new([1u8; 20]), NodeId::new([2u8; 20]))
+            .with_want(&[Want::N4, Want::N6]);
+        let decoded = DHTMessage::deserialize(&query.serialize().unwrap()).unwrap();
+        assert_eq!(get_want(decoded.args().unwrap()), vec![Want::N4, Want::N6]);
+
+        assert_eq!(Want::for_addr(&"[::1]:1".parse().unwrap()), Want::N6);
+        assert!(Want::N4.matches(&"127.0.0.1:1".parse().unwrap()));
+        assert_eq!(Want::N6.nodes_key(), "nodes6");
+    }
+
     #[test]
     fn test_generate_transaction_id() {
         let id1 = generate_transaction_id();
@@ -6,15 +6,35 @@ pub mod node;
 pub mod routing;
 pub mod message;
 pub mod bootstrap;
+pub mod lookup;
+pub mod token;
+pub mod peer_store;
+pub mod item;
+pub mod item_store;
+pub mod state;
+pub mod security;
+pub mod rate_limit;
+pub mod crawler;
 pub mod dht;
 
 // Re-exports for convenience
-pub use node::{Node, NodeId};
-pub use routing::{KBucket, RoutingTable};
+pub use node::{Node, NodeId, NodeState};
+pub use routing::{InsertOutcome, KBucket, RoutingTable, K};
 pub use message::{
-    DHTMessage, QueryType, ResponseType, Transaction, BencodeDict, BencodeValue,
+    DHTMessage, QueryType, ResponseType, Transaction, BencodeDict, BencodeValue, Want,
     generate_transaction_id, parse_compact_nodes, parse_compact_peers,
     serialize_compact_nodes, serialize_compact_peers,
+    parse_compact_nodes6, parse_compact_peers6, serialize_compact_nodes6, serialize_compact_peers6,
 };
-pub use bootstrap::{BootstrapConfig, bootstrap, discover_peers, announce, bootstrap_and_discover};
-pub use dht::DHT;
+pub use lookup::{Lookup, LookupKind, LookupResult, Querier, SocketQuerier, ALPHA};
+pub use token::TokenManager;
+pub use peer_store::PeerStore;
+pub use item::{Item, ItemError, MutableItem};
+pub use item_store::ItemStore;
+pub use ed25519_dalek::SigningKey;
+pub use state::DhtState;
+pub use security::{ExternalIpVotes, is_valid_node_id, secure_node_id};
+pub use crawler::{Crawler, DiscoveredInfoHash};
+pub use rate_limit::QueryLimiter;
+pub use bootstrap::{BootstrapConfig, bootstrap, discover_peers, announce, bootstrap_and_discover, resolve_bootstrap_nodes, DEFAULT_BOOTSTRAP_NODES};
+pub use dht::{DHT, DHT_LOOKUP_INTERVAL};
@@ -35,6 +35,11 @@ impl NodeId {
         hex::encode(self.0)
     }
 
+    /// Create a NodeId from a 20-byte slice
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        <[u8; 20]>::try_from(bytes).ok().map(Self)
+    }
+
     /// Parse a NodeId from a hex string
     pub fn from_hex(hex_str: &str) -> Option<Self> {
         hex::decode(hex_str)
@@ -51,6 +56,23 @@ impl NodeId {
     }
 }
 
+/// How long a node stays good without contact
+pub const GOOD_NODE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(900);
+
+/// Consecutive failed queries after which a node is bad
+pub const MAX_FAILED_QUERIES: u32 = 3;
+
+/// Liveness of a node (BEP 5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    /// Seen within 15 minutes and answering our queries
+    Good,
+    /// Not seen for 15 minutes, or missed a query
+    Questionable,
+    /// Failed several queries in a row
+    Bad,
+}
+
 /// Represents a DHT node
 #[derive(Debug, Clone)]
 pub struct Node {
@@ -60,6 +82,8 @@ pub struct Node {
     pub addr: SocketAddr,
     /// When the node was last contacted
     pub last_seen: Instant,
+    /// Queries the node failed to answer since its last response
+    pub failed_queries: u32,
 }
 
 impl Node {
@@ -69,16 +93,13 @@ impl Node {
             id,
             addr,
             last_seen: Instant::now(),
+            failed_queries: 0,
         }
     }
 
     /// Create a node with a random ID
     pub fn with_random_id(addr: SocketAddr) -> Self {
-        Self {
-            id: NodeId::random(),
-            addr,
-            last_seen: Instant::now(),
-        }
+        Self::new(NodeId::random(), addr)
     }
 
     /// Calculate XOR distance to another node
@@ -90,9 +111,31 @@ impl Node {
         distance
     }
 
+    /// Get the node's liveness state
+    pub fn state(&self) -> NodeState {
+        if self.failed_queries >= MAX_FAILED_QUERIES {
+            NodeState::Bad
+        } else if self.failed_queries == 0 && self.last_seen.elapsed() < GOOD_NODE_TIMEOUT {
+            NodeState::Good
+        } else {
+            NodeState::Questionable
+        }
+    }
+
     /// Check if node is responsive (seen within 15 minutes)
     pub fn is_good(&self) -> bool {
-        self.last_seen.elapsed() < std::time::Duration::from_secs(900)
+        self.state() == NodeState::Good
+    }
+
+    /// Record a response from the node
+    pub fn mark_responded(&mut self) {
+        self.last_seen = Instant::now();
+        self.failed_queries = 0;
+    }
+
+    /// Record a query the node did not answer
+    pub fn mark_failed(&mut self) {
+        self.failed_queries += 1;
     }
 
     /// Update last seen timestamp
@@ -174,6 +217,28 @@ mod tests {
         assert_eq!(distance, [0xFFu8; 20]);
     }
 
+    #[test]
+    fn test_node_state() {
+        let mut node = Node::new(NodeId::new([1u8; 20]), "127.0.0.1:6881".parse().unwrap());
+        assert_eq!(node.state(), NodeState::Good);
+
+        node.mark_failed();
+        assert_eq!(node.state(), NodeState::Questionable);
+        node.mark_responded();
+        assert_eq!(node.state(), NodeState::Good);
+
+        for _ in 0..MAX_FAILED_QUERIES {
+            node.mark_failed();
+        }
+        assert_eq!(node.state(), NodeState::Bad);
+
+        node.mark_responded();
+        if let Some(long_ago) = Instant::now().checked_sub(GOOD_NODE_TIMEOUT) {
+            node.last_seen = long_ago;
+            assert_eq!(node.state(), NodeState::Questionable);
+        }
+    }
+
     #[test]
     fn test_node_update_last_seen() {
         let mut node = Node::new(NodeId::new([1u8; 20]), "127.0.0.1:6881".parse().unwrap());
@@ -0,0 +1,221 @@
+//! DHT peer store
+//!
+//! Peers announced to us with `announce_peer`, returned in `get_peers`
+//! responses. The store is bounded in torrents and peers per torrent, and
+//! peers expire unless they announce again.
+
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long an announced peer is kept
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Maximum number of torrents tracked
+pub const MAX_TORRENTS: usize = 2000;
+
+/// Maximum number of peers kept per torrent
+pub const MAX_PEERS_PER_TORRENT: usize = 500;
+
+/// Maximum number of peers returned in one `get_peers` response
+pub const MAX_VALUES: usize = 50;
+
+/// Maximum number of info hashes returned in one `sample_infohashes` response
+pub const MAX_SAMPLES: usize = 20;
+
+/// How long a sample of info hashes is handed out before a new one is drawn
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Bounded, expiring store of announced peers by info hash
+#[derive(Debug)]
+pub struct PeerStore {
+    torrents: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+    max_torrents: usize,
+    max_peers: usize,
+    ttl: Duration,
+    /// The current sample of info hashes and when it was drawn
+    sample: Option<(Instant, Vec<[u8; 20]>)>,
+    sample_interval: Duration,
+}
+
+impl PeerStore {
+    /// Create a peer store with the default limits
+    pub fn new() -> Self {
+        Self::with_limits(MAX_TORRENTS, MAX_PEERS_PER_TORRENT, PEER_TTL)
+    }
+
+    /// Create a peer store with custom limits
+    pub fn with_limits(max_torrents: usize, max_peers: usize, ttl: Duration) -> Self {
+        Self {
+            torrents: HashMap::new(),
+            max_torrents,
+            max_peers,
+            ttl,
+            sample: None,
+            sample_interval: SAMPLE_INTERVAL,
+        }
+    }
+
+    /// Use a custom interval between samples
+    pub fn with_sample_interval(mut self, interval: Duration) -> Self {
+        self.sample_interval = interval;
+        self
+    }
+
+    /// Store an announced peer
+    ///
+    /// Returns false if the store is full of other torrents. A torrent that
+    /// is full drops its oldest peer.
+    pub fn announce(&mut self, info_hash: [u8; 20], peer: SocketAddr) -> bool {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= self.max_torrents {
+            self.cleanup();
+            if self.torrents.len() >= self.max_torrents {
+                return false;
+            }
+        }
+
+        let now = Instant::now();
+        let peers = self.torrents.entry(info_hash).or_default();
+        if !peers.contains_key(&peer) && peers.len() >= self.max_peers {
+            if let Some(oldest) = peers.iter().min_by_key(|(_, seen)| **seen).map(|(addr, _)| *addr) {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer, now);
+        true
+    }
+
+    /// Get up to `max` random live peers for a torrent
+    pub fn get_peers(&self, info_hash: &[u8; 20], max: usize) -> Vec<SocketAddr> {
+        let Some(peers) = self.torrents.get(info_hash) else {
+            return Vec::new();
+        };
+        let mut live: Vec<SocketAddr> = peers.iter()
+            .filter(|(_, seen)| seen.elapsed() < self.ttl)
+            .map(|(addr, _)| *addr)
+            .collect();
+        live.shuffle(&mut rand::thread_rng());
+        live.truncate(max);
+        live
+    }
+
+    /// Get a random sample of up to `max` stored info hashes (BEP 51)
+    ///
+    /// The same sample is handed out until the sample interval passes, so
+    /// repeated queries cannot enumerate the whole store.
+    pub fn sample(&mut self, max: usize) -> Vec<[u8; 20]> {
+        if let Some((drawn, sample)) = &self.sample {
+            if drawn.elapsed() < self.sample_interval && sample.len() <= max {
+                return sample.clone();
+            }
+        }
+
+        let mut info_hashes: Vec<[u8; 20]> = self.torrents.keys().copied().collect();
+        info_hashes.shuffle(&mut rand::thread_rng());
+        info_hashes.truncate(max);
+        // An empty store should not hide torrents announced shortly after
+        if !info_hashes.is_empty() {
+            self.sample = Some((Instant::now(), info_hashes.clone()));
+        }
+        info_hashes
+    }
+
+    /// Get the interval between samples
+    pub fn sample_interval(&self) -> Duration {
+        self.sample_interval
+    }
+
+    /// Drop expired peers and empty torrents, returning the number of peers removed
+    pub fn cleanup(&mut self) -> usize {
+        let ttl = self.ttl;
+        let mut removed = 0;
+        self.torrents.retain(|_, peers| {
+            let before = peers.len();
+            peers.retain(|_, seen| seen.elapsed() < ttl);
+            removed += before - peers.len();
+            !peers.is_empty()
+        });
+        removed
+    }
+
+    /// Get the number of torrents with stored peers
+    pub fn torrent_count(&self) -> usize {
+        self.torrents.len()
+    }
+
+    /// Get the total number of stored peers
+    pub fn peer_count(&self) -> usize {
+        self.torrents.values().map(HashMap::len).sum()
+    }
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_announce_and_get_peers() {
+        let mut store = PeerStore::new();
+        for port in 1..=80 {
+            assert!(store.announce([1u8; 20], peer(port)));
+        }
+        store.announce([1u8; 20], peer(1));
+
+        assert_eq!(store.peer_count(), 80);
+        assert_eq!(store.get_peers(&[1u8; 20], MAX_VALUES).len(), MAX_VALUES);
+        assert!(store.get_peers(&[2u8; 20], MAX_VALUES).is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut store = PeerStore::with_limits(2, 3, PEER_TTL);
+        for port in 1..=4 {
+            store.announce([1u8; 20], peer(port));
+        }
+        assert_eq!(store.get_peers(&[1u8; 20], 10).len(), 3);
+
+        assert!(store.announce([2u8; 20], peer(1)));
+        assert!(!store.announce([3u8; 20], peer(1)));
+        assert_eq!(store.torrent_count(), 2);
+    }
+
+    #[test]
+    fn test_peers_expire() {
+        let mut store = PeerStore::with_limits(1, 10, Duration::from_millis(20));
+        store.announce([1u8; 20], peer(1));
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert!(store.get_peers(&[1u8; 20], 10).is_empty());
+        // Expired torrents make room for new ones
+        assert!(store.announce([2u8; 20], peer(1)));
+        assert_eq!(store.torrent_count(), 1);
+        assert_eq!(store.cleanup(), 0);
+    }
+
+    #[test]
+    fn test_sample_is_reused_until_interval() {
+        let mut store = PeerStore::new();
+        assert!(store.sample(MAX_SAMPLES).is_empty());
+        for n in 0..30 {
+            store.announce([n; 20], peer(1));
+        }
+        let sample = store.sample(MAX_SAMPLES);
+        assert_eq!(sample.len(), MAX_SAMPLES);
+        assert_eq!(store.sample(MAX_SAMPLES), sample);
+
+        let mut store = store.with_sample_interval(Duration::ZERO);
+        store.announce([99; 20], peer(1));
+        assert_eq!(store.sample(100).len(), 31);
+    }
+}
@@ -0,0 +1,147 @@
+//! DHT query rate limiting
+//!
+//! Inbound queries are limited per source address and in total, so a flood
+//! of queries cannot saturate our uplink. Queries over a limit are dropped
+//! without a response. Loopback addresses, where several local nodes share
+//! one address, only count against the global limit.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Default number of queries answered per second in total
+pub const DEFAULT_QUERY_LIMIT: u32 = 250;
+
+/// Default number of queries answered per second for one address
+pub const DEFAULT_QUERY_LIMIT_PER_IP: u32 = 5;
+
+/// Maximum number of addresses tracked at once
+const MAX_TRACKED_IPS: usize = 10_000;
+
+/// Token bucket holding up to one second of queries
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: u32, now: Instant) -> Self {
+        Self { tokens: rate as f64, updated: now }
+    }
+
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.updated = now;
+    }
+
+    fn take(&mut self, rate: u32, now: Instant) -> bool {
+        self.refill(rate, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Per-address and global limits on inbound queries
+#[derive(Debug)]
+pub struct QueryLimiter {
+    /// Queries per second in total, 0 for unlimited
+    limit: u32,
+    /// Queries per second per address, 0 for unlimited
+    limit_per_ip: u32,
+    global: Bucket,
+    per_ip: HashMap<IpAddr, Bucket>,
+}
+
+impl QueryLimiter {
+    /// Create a limiter with the default limits
+    pub fn new() -> Self {
+        Self::with_limits(DEFAULT_QUERY_LIMIT, DEFAULT_QUERY_LIMIT_PER_IP)
+    }
+
+    /// Create a limiter allowing `limit` queries per second in total and
+    /// `limit_per_ip` per address (0 = unlimited)
+    pub fn with_limits(limit: u32, limit_per_ip: u32) -> Self {
+        Self {
+            limit,
+            limit_per_ip,
+            global: Bucket::full(limit, Instant::now()),
+            per_ip: HashMap::new(),
+        }
+    }
+
+    /// Check if a query from an address may be answered, counting it if so
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.limit_per_ip > 0 && !ip.is_loopback() {
+            if !self.per_ip.contains_key(&ip) && self.per_ip.len() >= MAX_TRACKED_IPS {
+                self.cleanup();
+            }
+            // Past the tracking limit, new addresses only count against the global limit
+            if self.per_ip.contains_key(&ip) || self.per_ip.len() < MAX_TRACKED_IPS {
+                let limit_per_ip = self.limit_per_ip;
+                let bucket = self.per_ip.entry(ip).or_insert_with(|| Bucket::full(limit_per_ip, now));
+                if !bucket.take(limit_per_ip, now) {
+                    return false;
+                }
+            }
+        }
+        self.limit == 0 || self.global.take(self.limit, now)
+    }
+
+    /// Forget addresses that are back to a full allowance, returning how many were dropped
+    pub fn cleanup(&mut self) -> usize {
+        let now = Instant::now();
+        let limit_per_ip = self.limit_per_ip;
+        let before = self.per_ip.len();
+        self.per_ip.retain(|_, bucket| {
+            bucket.refill(limit_per_ip, now);
+            bucket.tokens < limit_per_ip as f64
+        });
+        before - self.per_ip.len()
+    }
+}
+
+impl Default for QueryLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_per_ip_limit() {
+        let mut limiter = QueryLimiter::with_limits(0, 3);
+        let flooder: IpAddr = "10.0.0.1".parse().unwrap();
+        let allowed = (0..10).filter(|_| limiter.allow(flooder)).count();
+        assert_eq!(allowed, 3);
+        // Other addresses are not affected
+        assert!(limiter.allow("10.0.0.2".parse().unwrap()));
+        assert!((0..10).all(|_| limiter.allow("127.0.0.1".parse().unwrap())));
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let mut limiter = QueryLimiter::with_limits(5, 0);
+        let allowed = (0..10u8).filter(|n| limiter.allow(IpAddr::from([10, 0, 0, *n]))).count();
+        assert_eq!(allowed, 5);
+
+        let mut unlimited = QueryLimiter::with_limits(0, 0);
+        assert!((0..1000).all(|_| unlimited.allow("10.0.0.1".parse().unwrap())));
+    }
+
+    #[test]
+    fn test_cleanup_forgets_idle_addresses() {
+        let mut limiter = QueryLimiter::with_limits(0, 1000);
+        limiter.allow("10.0.0.1".parse().unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(limiter.cleanup(), 1);
+    }
+}
@@ -1,17 +1,26 @@
 //! DHT routing table module
 //!
-//! Implements the Kademlia routing table for DHT.
+//! Implements the Kademlia routing table for DHT (BEP 5). The table starts with
+//! a single bucket covering the whole ID space; the bucket covering our own ID
+//! splits when it fills up. Full buckets keep a cache of replacement nodes.
+//! Nodes whose ID does not match their address (BEP 42) are not admitted.
 
-use crate::dht::node::{Node, NodeId};
+use crate::dht::node::{Node, NodeId, NodeState};
+use crate::dht::security::is_valid_node_id;
 use std::time::Instant;
 
-const K: usize = 8; // Kademlia constant - number of nodes per bucket
+pub const K: usize = 8; // Kademlia constant - number of nodes per bucket
+
+/// Number of bits in a node ID, and the most buckets a table can have
+const ID_BITS: usize = 160;
 
 /// A bucket in the routing table
 #[derive(Debug, Clone)]
 pub struct KBucket {
     /// Nodes in this bucket
     pub nodes: Vec<Node>,
+    /// Nodes waiting for a place in the bucket, most recently seen last
+    pub replacements: Vec<Node>,
     /// When this bucket was last modified
     pub last_changed: Instant,
     /// Bucket prefix (shared prefix of all nodes in this bucket)
@@ -23,12 +32,15 @@ impl KBucket {
     pub fn new(prefix: NodeId) -> Self {
         Self {
             nodes: Vec::with_capacity(K),
+            replacements: Vec::new(),
             last_changed: Instant::now(),
             prefix,
         }
     }
 
     /// Add a node to the bucket
+    ///
+    /// A full bucket keeps the node in its replacement cache and returns false.
     pub fn add_node(&mut self, node: Node) -> bool {
         // Check if node already exists
         if let Some(pos) = self.nodes.iter().position(|n| n.id == node.id) {
@@ -37,8 +49,9 @@ impl KBucket {
             return true;
         }
 
-        // If bucket is full, return false
+        // If bucket is full, remember the node for later
         if self.nodes.len() >= K {
+            self.add_replacement(node);
             return false;
         }
 
@@ -47,9 +60,25 @@ impl KBucket {
         true
     }
 
-    /// Remove a node from the bucket
+    /// Add a node to the replacement cache, dropping the oldest if it is full
+    pub fn add_replacement(&mut self, node: Node) {
+        self.replacements.retain(|n| n.id != node.id);
+        self.replacements.push(node);
+        if self.replacements.len() > K {
+            self.replacements.remove(0);
+        }
+    }
+
+    /// Remove a node from the bucket, promoting the newest replacement
     pub fn remove_node(&mut self, id: &NodeId) {
+        let before = self.nodes.len();
         self.nodes.retain(|n| n.id != *id);
+        self.replacements.retain(|n| n.id != *id);
+        if self.nodes.len() < before {
+            if let Some(replacement) = self.replacements.pop() {
+                self.nodes.push(replacement);
+            }
+        }
         self.last_changed = Instant::now();
     }
 
@@ -63,57 +92,183 @@ impl KBucket {
         self.nodes.len()
     }
 
+    /// Check if the bucket has no nodes
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
     /// Check if the bucket is full
     pub fn is_full(&self) -> bool {
         self.nodes.len() >= K
     }
 }
 
+/// Outcome of inserting a node into the routing table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The node was added to a bucket
+    Added,
+    /// The node was already known and was refreshed
+    Updated,
+    /// The node took the place of a bad node
+    Replaced(NodeId),
+    /// The bucket is full; the node went to the replacement cache
+    Cached,
+    /// The node was not added (our own ID, or a known ID at another address)
+    Ignored,
+    /// The node's ID is not valid for its address
+    Rejected,
+}
+
 /// Kademlia routing table
 #[derive(Debug)]
 pub struct RoutingTable {
     /// Our node ID
     pub our_id: NodeId,
-    /// K buckets (160 buckets for 160-bit IDs)
+    /// K buckets, the last one covering our own ID
     pub buckets: Vec<KBucket>,
 }
 
 impl RoutingTable {
     /// Create a new routing table
     pub fn new(our_id: NodeId) -> Self {
-        let mut buckets = Vec::with_capacity(160);
-        for i in 0..160 {
-            let prefix = Self::calculate_prefix(&our_id, i);
-            buckets.push(KBucket::new(prefix));
-        }
-
         Self {
             our_id,
-            buckets,
+            buckets: vec![KBucket::new(Self::calculate_prefix(&our_id, 0))],
         }
     }
 
     /// Add a node to the routing table
     pub fn add_node(&mut self, node: Node) -> bool {
-        let bucket_index = self.get_bucket_index(&node.id);
-        self.buckets[bucket_index].add_node(node)
+        matches!(
+            self.insert(node),
+            InsertOutcome::Added | InsertOutcome::Updated | InsertOutcome::Replaced(_)
+        )
+    }
+
+    /// Insert a node, splitting our bucket or evicting bad nodes as needed
+    pub fn insert(&mut self, node: Node) -> InsertOutcome {
+        if node.id == self.our_id {
+            return InsertOutcome::Ignored;
+        }
+        if !is_valid_node_id(&node.id, &node.addr.ip()) {
+            return InsertOutcome::Rejected;
+        }
+
+        loop {
+            let bucket_index = self.get_bucket_index(&node.id);
+            let can_split = bucket_index == self.buckets.len() - 1 && self.buckets.len() < ID_BITS;
+            let bucket = &mut self.buckets[bucket_index];
+
+            if let Some(existing) = bucket.nodes.iter_mut().find(|n| n.id == node.id) {
+                if existing.addr != node.addr {
+                    return InsertOutcome::Ignored;
+                }
+                existing.update_last_seen();
+                bucket.last_changed = Instant::now();
+                return InsertOutcome::Updated;
+            }
+
+            if !bucket.is_full() {
+                bucket.add_node(node);
+                return InsertOutcome::Added;
+            }
+
+            if let Some(pos) = bucket.nodes.iter().position(|n| n.state() == NodeState::Bad) {
+                let evicted = std::mem::replace(&mut bucket.nodes[pos], node);
+                bucket.last_changed = Instant::now();
+                return InsertOutcome::Replaced(evicted.id);
+            }
+
+            if !can_split {
+                bucket.add_replacement(node);
+                return InsertOutcome::Cached;
+            }
+            self.split_last_bucket();
+        }
+    }
+
+    /// Split the bucket covering our own ID in two
+    fn split_last_bucket(&mut self) {
+        let depth = self.buckets.len() - 1;
+        let our_id = self.our_id;
+        let mut new_bucket = KBucket::new(Self::calculate_prefix(&our_id, depth + 1));
+        let last = &mut self.buckets[depth];
+
+        let (stay, moved): (Vec<Node>, Vec<Node>) = last.nodes.drain(..)
+            .partition(|n| common_prefix_len(&our_id, &n.id) == depth);
+        last.nodes = stay;
+        new_bucket.nodes = moved;
+
+        let (stay, moved): (Vec<Node>, Vec<Node>) = last.replacements.drain(..)
+            .partition(|n| common_prefix_len(&our_id, &n.id) == depth);
+        last.replacements = stay;
+        new_bucket.replacements = moved;
+
+        // Fill freed places from the replacement caches
+        for bucket in [&mut *last, &mut new_bucket] {
+            while !bucket.is_full() {
+                match bucket.replacements.pop() {
+                    Some(node) => bucket.nodes.push(node),
+                    None => break,
+                }
+            }
+        }
+
+        self.buckets.push(new_bucket);
+    }
+
+    /// Record a response from a node
+    pub fn node_responded(&mut self, id: &NodeId) {
+        let bucket_index = self.get_bucket_index(id);
+        let bucket = &mut self.buckets[bucket_index];
+        if let Some(node) = bucket.nodes.iter_mut().find(|n| n.id == *id) {
+            node.mark_responded();
+            bucket.last_changed = Instant::now();
+        } else if let Some(node) = bucket.replacements.iter_mut().find(|n| n.id == *id) {
+            node.mark_responded();
+        }
+    }
+
+    /// Record a query a node did not answer
+    ///
+    /// The node is evicted as soon as it is no longer good if a replacement is
+    /// waiting; otherwise bad nodes stay until a new node takes their place.
+    /// Returns true if it was evicted.
+    pub fn node_failed(&mut self, id: &NodeId) -> bool {
+        let bucket_index = self.get_bucket_index(id);
+        let bucket = &mut self.buckets[bucket_index];
+        let Some(node) = bucket.nodes.iter_mut().find(|n| n.id == *id) else {
+            bucket.replacements.retain(|n| n.id != *id);
+            return false;
+        };
+        node.mark_failed();
+
+        if node.state() != NodeState::Good && !bucket.replacements.is_empty() {
+            bucket.remove_node(id);
+            return true;
+        }
+        false
+    }
+
+    /// Get the questionable nodes in the bucket a node ID belongs to
+    pub fn questionable_nodes(&self, id: &NodeId) -> Vec<Node> {
+        self.buckets[self.get_bucket_index(id)].nodes
+            .iter()
+            .filter(|n| n.state() == NodeState::Questionable)
+            .cloned()
+            .collect()
     }
 
     /// Find K closest nodes to a target ID
     pub fn find_closest_nodes(&self, target: &NodeId) -> Vec<Node> {
         let mut all_nodes: Vec<Node> = self.buckets
             .iter()
-            .flat_map(|b| b.nodes.clone())
+            .flat_map(|b| b.nodes.iter().filter(|n| n.state() != NodeState::Bad).cloned())
             .collect();
 
         // Sort by XOR distance to target
-        all_nodes.sort_by_key(|n| {
-            let mut distance = [0u8; 20];
-            for i in 0..20 {
-                distance[i] = n.id.0[i] ^ target.0[i];
-            }
-            distance
-        });
+        all_nodes.sort_by_key(|n| n.distance_to(target));
 
         all_nodes.into_iter().take(K).collect()
     }
@@ -124,6 +279,15 @@ impl RoutingTable {
         self.buckets[bucket_index].remove_node(id);
     }
 
+    /// Switch to a new node ID, re-inserting the nodes we know
+    pub fn set_our_id(&mut self, our_id: NodeId) {
+        let nodes = self.get_nodes();
+        *self = Self::new(our_id);
+        for node in nodes {
+            self.insert(node);
+        }
+    }
+
     /// Get all nodes in the routing table
     pub fn get_nodes(&self) -> Vec<Node> {
         self.buckets
@@ -143,25 +307,21 @@ impl RoutingTable {
         self.buckets.iter().map(|b| b.len()).sum()
     }
 
+    /// Get the number of buckets
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
     /// Get the bucket index for a node ID
     fn get_bucket_index(&self, id: &NodeId) -> usize {
-        // Find the first bit where the IDs differ
-        for i in 0..160 {
-            let byte_index = i / 8;
-            let bit_index = 7 - (i % 8);
-            let our_bit = (self.our_id.0[byte_index] >> bit_index) & 1;
-            let their_bit = (id.0[byte_index] >> bit_index) & 1;
-            if our_bit != their_bit {
-                return i;
-            }
-        }
-        159 // IDs are identical
+        // The first bit where the IDs differ, within the buckets we have
+        common_prefix_len(&self.our_id, id).min(self.buckets.len() - 1)
     }
 
     /// Calculate the prefix for a bucket index
     fn calculate_prefix(our_id: &NodeId, bucket_index: usize) -> NodeId {
         let mut prefix = our_id.0;
-        if bucket_index < 160 {
+        if bucket_index < ID_BITS {
             let byte_index = bucket_index / 8;
             let bit_index = 7 - (bucket_index % 8);
             // Flip the bit at this position
@@ -170,6 +330,20 @@ impl RoutingTable {
         NodeId(prefix)
     }
 
+    /// Generate a random ID falling into a bucket
+    pub fn random_id_in_bucket(&self, bucket_index: usize) -> NodeId {
+        let mut id = NodeId::random().0;
+        let shared_bits = bucket_index.min(ID_BITS);
+        for bit in 0..shared_bits {
+            set_bit(&mut id, bit, get_bit(&self.our_id.0, bit));
+        }
+        // Every bucket but ours differs from our ID at its index
+        if bucket_index < self.buckets.len() - 1 {
+            set_bit(&mut id, bucket_index, !get_bit(&self.our_id.0, bucket_index));
+        }
+        NodeId(id)
+    }
+
     /// Get all buckets that need refreshing
     pub fn get_stale_buckets(&self, timeout: std::time::Duration) -> Vec<usize> {
         self.buckets
@@ -181,16 +355,35 @@ impl RoutingTable {
     }
 }
 
+fn get_bit(id: &[u8; 20], bit: usize) -> bool {
+    (id[bit / 8] >> (7 - bit % 8)) & 1 == 1
+}
+
+fn set_bit(id: &mut [u8; 20], bit: usize, value: bool) {
+    let mask = 1 << (7 - bit % 8);
+    if value {
+        id[bit / 8] |= mask;
+    } else {
+        id[bit / 8] &= !mask;
+    }
+}
+
+/// Number of leading bits two IDs share
+fn common_prefix_len(a: &NodeId, b: &NodeId) -> usize {
+    (0..ID_BITS).find(|&bit| get_bit(&a.0, bit) != get_bit(&b.0, bit)).unwrap_or(ID_BITS)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::SocketAddr;
 
     #[test]
     fn test_routing_table_new() {
         let our_id = NodeId::new([1u8; 20]);
         let table = RoutingTable::new(our_id);
         assert_eq!(table.our_id, our_id);
-        assert_eq!(table.buckets.len(), 160);
+        assert_eq!(table.buckets.len(), 1);
     }
 
     #[test]
@@ -257,14 +450,112 @@ mod tests {
     #[test]
     fn test_bucket_index() {
         let our_id = NodeId::new([0x80u8; 20]);
-        let table = RoutingTable::new(our_id);
+        let mut table = RoutingTable::new(our_id);
+
+        // A single bucket covers everything
+        assert_eq!(table.get_bucket_index(&our_id), 0);
+        assert_eq!(table.get_bucket_index(&NodeId::new([0x00u8; 20])), 0);
 
-        // Same ID should go to last bucket
-        assert_eq!(table.get_bucket_index(&our_id), 159);
+        table.split_last_bucket();
+        table.split_last_bucket();
+        // Our own ID falls in the last bucket
+        assert_eq!(table.get_bucket_index(&our_id), 2);
 
         // First bit different should go to bucket 0
         let different_id = NodeId::new([0x00u8; 20]);
         assert_eq!(table.get_bucket_index(&different_id), 0);
+        assert_eq!(table.get_bucket_index(&NodeId::new([0xC0u8; 20])), 1);
+    }
+
+    fn node_with_prefix(first: u8, n: u8) -> Node {
+        let mut id = [0u8; 20];
+        id[0] = first;
+        id[19] = n;
+        Node::new(NodeId::new(id), SocketAddr::from(([10, 0, first, n], 6881)))
+    }
+
+    #[test]
+    fn test_bucket_splits_around_our_id() {
+        let mut table = RoutingTable::new(NodeId::new([0u8; 20]));
+
+        // Far nodes fill the first bucket, then force a split
+        for n in 0..K as u8 {
+            assert_eq!(table.insert(node_with_prefix(0x80, n)), InsertOutcome::Added);
+        }
+        assert_eq!(table.insert(node_with_prefix(0x40, 0)), InsertOutcome::Added);
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.buckets[0].len(), K);
+
+        // The far bucket no longer splits, so extra far nodes are cached
+        assert_eq!(table.insert(node_with_prefix(0x80, 100)), InsertOutcome::Cached);
+        assert_eq!(table.buckets[0].replacements.len(), 1);
+        assert_eq!(table.node_count(), K + 1);
+
+        // Nodes close to us keep splitting our bucket, down to their own bucket
+        for n in 0..(2 * K) as u8 {
+            table.insert(node_with_prefix(0x01, n));
+        }
+        assert_eq!(table.node_count(), 2 * K + 1);
+        assert_eq!(table.bucket_count(), 9);
+        assert_eq!(table.buckets[7].replacements.len(), K);
+        for (index, bucket) in table.buckets.iter().enumerate().take(table.bucket_count() - 1) {
+            assert!(bucket.nodes.iter().all(|n| common_prefix_len(&table.our_id, &n.id) == index));
+        }
+        assert_eq!(table.insert(Node::new(table.our_id, "10.0.0.1:1".parse().unwrap())), InsertOutcome::Ignored);
+    }
+
+    #[test]
+    fn test_failed_nodes_replaced_from_cache() {
+        let mut table = RoutingTable::new(NodeId::new([0u8; 20]));
+        table.split_last_bucket();
+        for n in 0..K as u8 {
+            table.insert(node_with_prefix(0x80, n));
+        }
+        let victim = node_with_prefix(0x80, 0).id;
+
+        // No replacement yet: the node only becomes bad after several failures
+        assert!(!table.node_failed(&victim));
+        assert_eq!(table.questionable_nodes(&victim).len(), 1);
+        table.node_responded(&victim);
+        assert!(table.questionable_nodes(&victim).is_empty());
+
+        // With a replacement waiting, a questionable node is evicted
+        let replacement = node_with_prefix(0x80, 200);
+        assert_eq!(table.insert(replacement.clone()), InsertOutcome::Cached);
+        assert!(table.node_failed(&victim));
+        assert!(table.find_node(&victim).is_none());
+        assert!(table.find_node(&replacement.id).is_some());
+        assert!(table.buckets[0].replacements.is_empty());
+    }
+
+    #[test]
+    fn test_bad_node_evicted_on_insert() {
+        let mut table = RoutingTable::new(NodeId::new([0u8; 20]));
+        table.split_last_bucket();
+        for n in 0..K as u8 {
+            table.insert(node_with_prefix(0x80, n));
+        }
+        let bad = node_with_prefix(0x80, 3).id;
+        for _ in 0..crate::dht::node::MAX_FAILED_QUERIES {
+            table.node_failed(&bad);
+        }
+        assert!(table.find_closest_nodes(&bad).iter().all(|n| n.id != bad));
+
+        let newcomer = node_with_prefix(0x80, 100);
+        assert_eq!(table.insert(newcomer.clone()), InsertOutcome::Replaced(bad));
+        assert!(table.find_node(&newcomer.id).is_some());
+    }
+
+    #[test]
+    fn test_random_id_in_bucket() {
+        let mut table = RoutingTable::new(NodeId::random());
+        for _ in 0..5 {
+            table.split_last_bucket();
+        }
+        for index in 0..table.bucket_count() {
+            let id = table.random_id_in_bucket(index);
+            assert_eq!(table.get_bucket_index(&id), index);
+        }
     }
 
     #[test]
@@ -390,7 +681,7 @@ mod tests {
 
         // With zero timeout, all buckets should be stale (they were just created)
         let stale = table.get_stale_buckets(std::time::Duration::from_secs(0));
-        assert_eq!(stale.len(), 160);
+        assert_eq!(stale.len(), table.bucket_count());
     }
 
     #[test]
@@ -417,4 +708,34 @@ mod tests {
         assert_eq!(closest.len(), 3);
         assert_eq!(closest[0].id, NodeId::new([0xFFu8; 20]));
     }
+
+    #[test]
+    fn test_rejects_ids_not_matching_address() {
+        use crate::dht::security::secure_node_id;
+
+        let mut table = RoutingTable::new(NodeId::random());
+        let public: std::net::SocketAddr = "124.31.75.21:6881".parse().unwrap();
+
+        let forged = Node::new(NodeId::new([0x5fu8; 20]), public);
+        assert_eq!(table.insert(forged), InsertOutcome::Rejected);
+        let valid = Node::new(secure_node_id(&public.ip()), public);
+        assert_eq!(table.insert(valid), InsertOutcome::Added);
+    }
+
+    #[test]
+    fn test_set_our_id_keeps_nodes() {
+        let mut table = RoutingTable::new(NodeId::new([0u8; 20]));
+        for i in 1..=K as u8 {
+            let mut id = [i; 20];
+            id[0] = i.wrapping_mul(29);
+            table.add_node(Node::new(NodeId::new(id), format!("127.0.0.1:{}", 7000 + i as u16).parse().unwrap()));
+        }
+        let count = table.node_count();
+        assert_eq!(count, K);
+
+        let new_id = NodeId::new([0xffu8; 20]);
+        table.set_our_id(new_id);
+        assert_eq!(table.our_id, new_id);
+        assert_eq!(table.node_count(), count);
+    }
 }
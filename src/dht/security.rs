@@ -0,0 +1,213 @@
+//! DHT security extension (BEP 42)
+//!
+//! Node IDs are tied to the node's IP address through a CRC32C of the
+//! masked address, which makes it expensive to pick IDs close to a target.
+//! We learn our own external address from the `ip` field other nodes put in
+//! their responses.
+
+use crate::dht::node::NodeId;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use tracing::{debug, info};
+
+/// Mask applied to IPv4 addresses before hashing
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+
+/// Mask applied to the first 8 bytes of IPv6 addresses before hashing
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// Number of distinct nodes that must report an address before we trust it
+pub const MIN_IP_VOTES: usize = 10;
+
+/// Number of recent reports kept when voting on our external address
+const MAX_IP_VOTES: usize = 50;
+
+/// CRC32C (Castagnoli) checksum
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Check if an address is exempt from the ID restriction (local and private networks)
+pub fn is_exempt(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+/// CRC32C of an address masked as BEP 42 describes, for a random value `r` (0-7)
+fn masked_crc(ip: &IpAddr, r: u8) -> u32 {
+    let mut masked = match ip {
+        IpAddr::V4(ip) => ip.octets().iter().zip(IPV4_MASK).map(|(byte, mask)| byte & mask).collect::<Vec<_>>(),
+        IpAddr::V6(ip) => ip.octets().iter().zip(IPV6_MASK).map(|(byte, mask)| byte & mask).collect::<Vec<_>>(),
+    };
+    masked[0] |= (r & 0x07) << 5;
+    crc32c(&masked)
+}
+
+/// Generate a node ID valid for an address, keeping `rand` in the last byte
+pub fn secure_node_id_with(ip: &IpAddr, rand: u8) -> NodeId {
+    let mut rng = rand::thread_rng();
+    let crc = masked_crc(ip, rand);
+    let mut id = [0u8; 20];
+    rng.fill(&mut id[..]);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id[19] = rand;
+    NodeId::new(id)
+}
+
+/// Generate a random node ID valid for an address
+pub fn secure_node_id(ip: &IpAddr) -> NodeId {
+    secure_node_id_with(ip, rand::random())
+}
+
+/// Check that a node ID matches the address it was seen at
+pub fn is_valid_node_id(id: &NodeId, ip: &IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+    let crc = masked_crc(ip, id.0[19]);
+    id.0[0] == (crc >> 24) as u8
+        && id.0[1] == (crc >> 16) as u8
+        && id.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+/// Votes from other nodes on what our external address is
+#[derive(Debug)]
+pub struct ExternalIpVotes {
+    /// Most recent report per voter, oldest first
+    votes: VecDeque<(IpAddr, IpAddr)>,
+    /// The address the majority agreed on last
+    current: Option<IpAddr>,
+    /// Reports needed before a majority counts
+    min_votes: usize,
+}
+
+impl ExternalIpVotes {
+    /// Create an empty vote
+    pub fn new() -> Self {
+        Self::with_min_votes(MIN_IP_VOTES)
+    }
+
+    /// Create an empty vote needing `min_votes` reports
+    pub fn with_min_votes(min_votes: usize) -> Self {
+        Self {
+            votes: VecDeque::new(),
+            current: None,
+            min_votes,
+        }
+    }
+
+    /// Get the external address the majority agreed on
+    pub fn current(&self) -> Option<IpAddr> {
+        self.current
+    }
+
+    /// Record a report, returning the new address if the majority changed its mind
+    pub fn vote(&mut self, voter: IpAddr, reported: IpAddr) -> Option<IpAddr> {
+        self.votes.retain(|(existing, _)| *existing != voter);
+        self.votes.push_back((voter, reported));
+        if self.votes.len() > MAX_IP_VOTES {
+            self.votes.pop_front();
+        }
+        if self.votes.len() < self.min_votes {
+            return None;
+        }
+
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for (_, ip) in &self.votes {
+            *counts.entry(*ip).or_default() += 1;
+        }
+        let (winner, count) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+        if count * 2 <= self.votes.len() || self.current == Some(winner) {
+            return None;
+        }
+
+        debug!("{} of {} nodes report our address as {}", count, self.votes.len(), winner);
+        info!("External address changed from {:?} to {}", self.current, winner);
+        self.current = Some(winner);
+        Some(winner)
+    }
+}
+
+impl Default for ExternalIpVotes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[]), 0);
+    }
+
+    #[test]
+    fn test_bep42_vectors() {
+        let vectors: [(&str, u8, [u8; 3]); 5] = [
+            ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, rand, prefix) in vectors {
+            let ip: IpAddr = ip.parse().unwrap();
+            let id = secure_node_id_with(&ip, rand);
+            assert_eq!(id.0[0..2], prefix[0..2], "{}", ip);
+            assert_eq!(id.0[2] & 0xf8, prefix[2] & 0xf8, "{}", ip);
+            assert_eq!(id.0[19], rand);
+            assert!(is_valid_node_id(&id, &ip));
+        }
+    }
+
+    #[test]
+    fn test_node_id_validation() {
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        let id = secure_node_id(&ip);
+        assert!(is_valid_node_id(&id, &ip));
+        assert!(!is_valid_node_id(&id, &"21.75.31.124".parse().unwrap()));
+
+        let ip6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(is_valid_node_id(&secure_node_id(&ip6), &ip6));
+
+        // Local addresses may use any ID
+        assert!(is_valid_node_id(&NodeId::new([0u8; 20]), &"192.168.1.5".parse().unwrap()));
+        assert!(is_valid_node_id(&NodeId::new([0u8; 20]), &"::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_external_ip_majority() {
+        let mut votes = ExternalIpVotes::with_min_votes(3);
+        let ours: IpAddr = "1.2.3.4".parse().unwrap();
+        let other: IpAddr = "5.6.7.8".parse().unwrap();
+        let voter = |n: u8| IpAddr::from([10, 0, 0, n]);
+
+        assert_eq!(votes.vote(voter(1), ours), None);
+        // Repeated reports from one node count once
+        assert_eq!(votes.vote(voter(1), ours), None);
+        assert_eq!(votes.vote(voter(2), other), None);
+        assert_eq!(votes.vote(voter(3), ours), Some(ours));
+        assert_eq!(votes.vote(voter(4), ours), None);
+        assert_eq!(votes.current(), Some(ours));
+
+        for n in 5..10 {
+            votes.vote(voter(n), other);
+        }
+        assert_eq!(votes.current(), Some(other));
+    }
+}
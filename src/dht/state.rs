@@ -0,0 +1,139 @@
+//! DHT state module
+//!
+//! Saves our node ID and a sample of good nodes across runs, so the DHT can
+//! bootstrap from known nodes instead of the public routers.
+
+use crate::dht::node::{Node, NodeId, NodeState};
+use crate::dht::routing::RoutingTable;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::fs;
+
+/// Maximum number of nodes saved
+pub const MAX_SAVED_NODES: usize = 200;
+
+/// A saved DHT node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedNode {
+    /// Node ID as hex string
+    pub id: String,
+    /// Node address
+    pub addr: SocketAddr,
+}
+
+/// Persistent DHT state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DhtState {
+    /// Our node ID as hex string
+    pub node_id: String,
+    /// Known nodes, best first
+    pub nodes: Vec<SavedNode>,
+}
+
+impl DhtState {
+    /// Capture the state of a routing table, preferring good nodes
+    pub fn from_routing_table(routing_table: &RoutingTable) -> Self {
+        let mut nodes: Vec<Node> = routing_table.get_nodes()
+            .into_iter()
+            .filter(|node| node.state() != NodeState::Bad)
+            .collect();
+        nodes.sort_by_key(|node| (node.state() != NodeState::Good, node.time_since_seen()));
+
+        Self {
+            node_id: routing_table.our_id.to_hex(),
+            nodes: nodes.into_iter()
+                .take(MAX_SAVED_NODES)
+                .map(|node| SavedNode { id: node.id.to_hex(), addr: node.addr })
+                .collect(),
+        }
+    }
+
+    /// Get the saved node ID
+    pub fn node_id(&self) -> Option<NodeId> {
+        NodeId::from_hex(&self.node_id)
+    }
+
+    /// Get the saved nodes, skipping malformed entries
+    pub fn nodes(&self) -> Vec<Node> {
+        self.nodes.iter()
+            .filter_map(|saved| NodeId::from_hex(&saved.id).map(|id| Node::new(id, saved.addr)))
+            .collect()
+    }
+
+    /// Serialize to bytes
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Deserialize from bytes
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    /// Save to file
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let data = self.serialize()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Write then rename so an interrupted save keeps the old state
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data).await?;
+        fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    /// Load from file
+    pub async fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(path).await?;
+        Ok(Some(Self::deserialize(&data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let our_id = NodeId::random();
+        let mut routing_table = RoutingTable::new(our_id);
+        let good = Node::new(NodeId::random(), "10.0.0.1:6881".parse().unwrap());
+        let flaky = Node::new(NodeId::random(), "10.0.0.2:6881".parse().unwrap());
+        routing_table.add_node(flaky.clone());
+        routing_table.add_node(good.clone());
+        routing_table.node_failed(&flaky.id);
+
+        let state = DhtState::from_routing_table(&routing_table);
+        assert_eq!(state.node_id(), Some(our_id));
+        assert_eq!(state.nodes[0].addr, good.addr);
+        assert_eq!(state.nodes.len(), 2);
+
+        let temp_dir = std::env::temp_dir().join("test_dht_state");
+        let path = temp_dir.join("dht").join("state.json");
+        let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+        assert!(DhtState::load(&path).await.unwrap().is_none());
+        state.save(&path).await.unwrap();
+
+        let loaded = DhtState::load(&path).await.unwrap().unwrap();
+        assert_eq!(loaded.node_id(), Some(our_id));
+        let nodes = loaded.nodes();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].id, good.id);
+
+        // Cleanup
+        let _ = tokio::fs::remove_dir_all(temp_dir).await;
+    }
+
+    #[test]
+    fn test_malformed_entries_skipped() {
+        let state = DhtState::deserialize(br#"{"node_id":"zz","nodes":[{"id":"00","addr":"10.0.0.1:1"}]}"#).unwrap();
+        assert!(state.node_id().is_none());
+        assert!(state.nodes().is_empty());
+    }
+}
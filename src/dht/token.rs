@@ -0,0 +1,140 @@
+//! DHT token module
+//!
+//! Write tokens handed out in `get_peers` responses and checked on
+//! `announce_peer` (BEP 5). A token is the SHA-1 of the querier's IP and a
+//! secret that rotates every five minutes; tokens made with the previous
+//! secret are still accepted.
+
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How often the secret rotates
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(300);
+
+/// Length of a token in bytes
+pub const TOKEN_LEN: usize = 8;
+
+/// Issues and validates announce tokens
+#[derive(Debug)]
+pub struct TokenManager {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+    interval: Duration,
+}
+
+impl TokenManager {
+    /// Create a token manager rotating every `TOKEN_ROTATION`
+    pub fn new() -> Self {
+        Self::with_interval(TOKEN_ROTATION)
+    }
+
+    /// Create a token manager with a custom rotation interval
+    pub fn with_interval(interval: Duration) -> Self {
+        let current = random_secret();
+        Self {
+            current,
+            previous: current,
+            rotated_at: Instant::now(),
+            interval,
+        }
+    }
+
+    /// Replace the secret, keeping the current one as the previous secret
+    pub fn rotate(&mut self) {
+        self.previous = self.current;
+        self.current = random_secret();
+        self.rotated_at = Instant::now();
+    }
+
+    fn rotate_if_due(&mut self) {
+        let elapsed = self.rotated_at.elapsed();
+        if elapsed >= self.interval * 2 {
+            // Both secrets are stale
+            self.rotate();
+            self.rotate();
+        } else if elapsed >= self.interval {
+            self.rotate();
+        }
+    }
+
+    /// Generate a token for a node at `ip`
+    pub fn generate(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate_if_due();
+        token_for(ip, &self.current)
+    }
+
+    /// Check a token presented by a node at `ip`
+    pub fn validate(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+        token == token_for(ip, &self.current).as_slice()
+            || token == token_for(ip, &self.previous).as_slice()
+    }
+}
+
+impl Default for TokenManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn random_secret() -> [u8; 20] {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill(&mut secret);
+    secret
+}
+
+fn token_for(ip: IpAddr, secret: &[u8; 20]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+    hasher.finalize()[..TOKEN_LEN].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bound_to_ip() {
+        let mut tokens = TokenManager::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = tokens.generate(ip);
+
+        assert_eq!(token.len(), TOKEN_LEN);
+        assert!(tokens.validate(ip, &token));
+        assert!(!tokens.validate("10.0.0.2".parse().unwrap(), &token));
+        assert!(!tokens.validate(ip, b"bogus"));
+    }
+
+    #[test]
+    fn test_previous_secret_accepted_once() {
+        let mut tokens = TokenManager::new();
+        let ip: IpAddr = "::1".parse().unwrap();
+        let token = tokens.generate(ip);
+
+        tokens.rotate();
+        assert!(tokens.validate(ip, &token));
+        assert_ne!(tokens.generate(ip), token);
+
+        tokens.rotate();
+        assert!(!tokens.validate(ip, &token));
+    }
+
+    #[test]
+    fn test_rotates_after_interval() {
+        let mut tokens = TokenManager::with_interval(Duration::from_millis(100));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = tokens.generate(ip);
+
+        std::thread::sleep(Duration::from_millis(110));
+        assert!(tokens.validate(ip, &token));
+        std::thread::sleep(Duration::from_millis(210));
+        assert!(!tokens.validate(ip, &token));
+    }
+}
@@ -5,6 +5,7 @@
 pub mod torrent;
 pub mod protocol;
 pub mod peer;
+pub mod transport;
 pub mod dht;
 pub mod storage;
 pub mod cli;
@@ -12,18 +13,22 @@ pub mod error;
 
 pub use error::TorrentError;
 
-pub use torrent::{TorrentParser, TorrentInfo, MagnetParser, MagnetInfo, TorrentFile};
+pub use torrent::{TorrentParser, TorrentInfo, MagnetParser, MagnetInfo, TorrentFile, TorrentCreator, TorrentDetails, TorrentEditor, InfoV2, TorrentFileV2, FileAttributes};
 pub use protocol::{Handshake, Message, MessageId};
 pub use peer::{PeerConnection, PeerManager, PeerInfo, PeerState};
+pub use transport::{Transport, BoxedTransport, TransportKind, MemoryTransport, RateLimiter, RateLimits, UdpDemux, UtpStream, bind_tcp, bind_udp};
 pub use dht::{
-    Node, NodeId, KBucket, RoutingTable, DHT, DHTMessage,
+    Node, NodeId, KBucket, RoutingTable, DHT, DHT_LOOKUP_INTERVAL, DHTMessage,
     QueryType, ResponseType, Transaction, BencodeDict, BencodeValue,
     BootstrapConfig, bootstrap, discover_peers, announce, bootstrap_and_discover,
+    Lookup, LookupKind, LookupResult, Item, MutableItem, Crawler, DiscoveredInfoHash,
     generate_transaction_id, parse_compact_nodes, parse_compact_peers,
     serialize_compact_nodes, serialize_compact_peers,
+    parse_compact_nodes6, parse_compact_peers6, serialize_compact_nodes6, serialize_compact_peers6,
 };
 pub use storage::{
     PieceStorage, PieceStatus, FileStorage, ResumeData, ResumeManager,
-    Piece, Block, DownloadManager, PieceDownload, DownloadStats as StorageDownloadStats
+    Piece, Block, DownloadManager, PieceDownload, DownloadStats as StorageDownloadStats,
+    FilePriority,
 };
-pub use cli::{CliArgs, Config, ProgressDisplay, DownloadStats};
+pub use cli::{CliArgs, Command, CreateArgs, Config, ProgressDisplay, DownloadStats};
@@ -4,20 +4,31 @@
 
 use anyhow::{Context, Result};
 use rust_torrent_downloader::{
-    CliArgs, Config, ProgressDisplay, DownloadStats,
-    TorrentParser, TorrentInfo,
+    CliArgs, Command, CreateArgs, Config, ProgressDisplay, DownloadStats,
+    TorrentParser, TorrentInfo, TorrentCreator, TorrentDetails, TorrentEditor,
     PeerManager,
     DHT,
+    DHT_LOOKUP_INTERVAL,
+    Crawler,
+    UdpDemux,
+    RateLimits,
+    bind_tcp,
     TorrentError,
 };
 use rust_torrent_downloader::torrent::TorrentFile;
-use rust_torrent_downloader::storage::FileDownloadManager;
-use std::path::Path;
+use rust_torrent_downloader::storage::{FileDownloadManager, select_files};
+use rust_torrent_downloader::cli::{EditArgs, InfoArgs};
+use rust_torrent_downloader::cli::config::DEFAULT_DHT_STATE_FILE;
+use rust_torrent_downloader::torrent::details::format_unix_time;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, trace, warn};
 
+/// Seconds between attempts to connect to newly discovered peers
+const PEER_MANAGEMENT_INTERVAL_SECS: u64 = 10;
+
 /// Set up panic handler for unexpected errors
 fn setup_panic_handler() {
     std::panic::set_hook(Box::new(|panic_info| {
@@ -55,8 +66,22 @@ async fn main() -> Result<()> {
     // Initialize logging
     init_logging(&args);
 
+    match &args.command {
+        Some(Command::Create(create)) => return run_create(create).await,
+        Some(Command::Info(info)) => return run_info(info),
+        Some(Command::Edit(edit)) => return run_edit(edit),
+        None => {}
+    }
+
+    // Crawl the DHT instead of downloading
+    if let Some(crawl_file) = &args.crawl {
+        return run_crawler(&args, crawl_file).await;
+    }
+
     // Load torrent file
-    let torrent_info = load_torrent_file(&args.torrent_file)
+    let torrent_file = args.torrent_file.as_deref()
+        .context("No torrent file given")?;
+    let torrent_info = load_torrent_file(torrent_file)
         .context("Failed to load torrent file")?;
 
     // Create configuration
@@ -70,11 +95,55 @@ async fn main() -> Result<()> {
     display_torrent_info(&torrent_info, &config)?;
 
     // Initialize components
-    let peer_manager = Arc::new(PeerManager::new(
+    let mut peer_manager = PeerManager::new(
         config.max_connections,
         Arc::new(torrent_info.clone()),
         rust_torrent_downloader::Handshake::generate_peer_id(),
-    ));
+    );
+    peer_manager.set_encryption_policy(config.encryption);
+    peer_manager.set_global_rate_limits(RateLimits::new(config.download_limit, config.upload_limit));
+    peer_manager.torrent_rate_limits().set(config.torrent_download_limit, config.torrent_upload_limit);
+    peer_manager.set_peer_rate_limits(config.peer_download_limit, config.peer_upload_limit).await;
+    peer_manager.set_count_overhead(config.limit_overhead);
+
+    // One UDP socket per address family serves both the DHT and uTP
+    let mut udp = None;
+    let mut udp6 = None;
+    if config.is_dht_enabled() || config.is_utp_enabled() {
+        let bind_addr: std::net::SocketAddr = config.get_listen_addr().parse()
+            .context("Invalid bind address for UDP socket")?;
+        udp = Some(Arc::new(UdpDemux::bind(bind_addr).await?));
+        if config.is_ipv6_enabled() {
+            let bind_addr6: std::net::SocketAddr = config.get_listen_addr6().parse()
+                .context("Invalid IPv6 bind address for UDP socket")?;
+            match UdpDemux::bind(bind_addr6).await {
+                Ok(demux) => udp6 = Some(Arc::new(demux)),
+                Err(e) => warn!("IPv6 UDP socket unavailable, continuing with IPv4 only: {}", e),
+            }
+        }
+        for demux in udp.iter().chain(udp6.iter()) {
+            demux.set_utp_enabled(config.is_utp_enabled());
+            if config.is_utp_enabled() {
+                peer_manager.set_utp(demux.clone());
+            }
+        }
+    }
+    let peer_manager = Arc::new(peer_manager);
+
+    // Without a listener, e.g. when another instance has the port, only outbound connections are made
+    if let Err(e) = spawn_peer_listener(&config.get_listen_addr(), peer_manager.clone()).await {
+        warn!("Not listening for incoming peers: {}", e);
+    }
+    if config.is_ipv6_enabled() {
+        if let Err(e) = spawn_peer_listener(&config.get_listen_addr6(), peer_manager.clone()).await {
+            warn!("Not listening for IPv6 peers: {}", e);
+        }
+    }
+    if config.is_utp_enabled() {
+        for demux in udp.iter().chain(udp6.iter()) {
+            spawn_utp_listener(demux.clone(), peer_manager.clone());
+        }
+    }
 
     let file_storage = Arc::new(RwLock::new(
         rust_torrent_downloader::FileStorage::new(
@@ -91,18 +160,48 @@ async fn main() -> Result<()> {
         file_storage.clone(),
         peer_manager.clone(),
     ));
+    if config.has_file_selection() {
+        let files: Vec<TorrentFile> = torrent_info.files_iter().collect();
+        let priorities = select_files(&files, &config.only_files, &config.exclude_files)
+            .map_err(|e| {
+                error!("Invalid file selection: {}", e);
+                anyhow::Error::from(e)
+            })?;
+        let selected = priorities.iter().zip(&files).filter(|(p, f)| !p.is_skipped() && !f.is_padding()).count();
+        info!("Selected {} of {} files", selected, files.iter().filter(|f| !f.is_padding()).count());
+        download_manager.set_file_priorities(priorities).await?;
+    }
+    if let Some(v2) = &torrent_info.v2 {
+        download_manager.set_v2_info(v2.clone()).await;
+    }
+    #[cfg(feature = "download")]
+    for seed in rust_torrent_downloader::peer::WebSeed::from_torrent(&Arc::new(torrent_info.clone())) {
+        download_manager.add_web_seed(seed).await;
+    }
 
     let mut dht = None;
+    let mut dht6 = None;
     if config.is_dht_enabled() {
-        info!("Initializing DHT...");
-        let bind_addr: std::net::SocketAddr = format!("0.0.0.0:{}", config.port).parse()
-            .context("Invalid bind address for DHT")?;
-        dht = Some(DHT::new(bind_addr, peer_manager.clone()).await
-            .map_err(|e| {
-                error!("Failed to initialize DHT: {}", e);
-                anyhow::Error::from(TorrentError::dht_error_full("Failed to initialize DHT", "unknown", e.to_string()))
-            })?);
-        info!("DHT initialized successfully");
+        if let Some(demux) = &udp {
+            dht = Some(init_dht(demux, peer_manager.clone(), &config.dht_state_file).await?);
+        }
+        if let Some(demux) = &udp6 {
+            dht6 = Some(init_dht(demux, peer_manager.clone(), &config.dht6_state_file()).await?);
+        }
+        if let (Some(dht), Some(dht6)) = (dht.as_mut(), dht6.as_mut()) {
+            dht.pair(dht6);
+        }
+    }
+    let dhts: Vec<(Arc<DHT>, PathBuf)> = dht.map(|dht| (Arc::new(dht), config.dht_state_file.clone()))
+        .into_iter()
+        .chain(dht6.map(|dht| (Arc::new(dht), config.dht6_state_file())))
+        .collect();
+    for (dht, _) in &dhts {
+        dht.set_read_only(config.dht_read_only);
+        dht.set_query_limits(config.dht_query_limit, config.dht_query_limit_per_ip);
+    }
+    if config.dht_read_only {
+        info!("DHT is read-only: not answering queries from other nodes");
     }
 
     // Create progress display
@@ -111,15 +210,32 @@ async fn main() -> Result<()> {
     // Start download
     progress.print_status("Starting download...")?;
 
-    let download_result = run_download(
-        &torrent_info,
-        &config,
-        &peer_manager,
-        &download_manager,
-        &mut progress,
-        dht.as_ref(),
-    ).await;
+    // Ctrl-C ends the download, but the DHT state is still saved below
+    let download_result = tokio::select! {
+        result = run_download(
+            &torrent_info,
+            &config,
+            &peer_manager,
+            &download_manager,
+            &mut progress,
+            &dhts,
+        ) => Some(result),
+        _ = tokio::signal::ctrl_c() => {
+            info!("Download interrupted");
+            None
+        }
+    };
+
+    for (dht, state_file) in &dhts {
+        if let Err(e) = dht.save_state(state_file).await {
+            warn!("Failed to save DHT state: {}", e);
+        }
+    }
 
+    let Some(download_result) = download_result else {
+        progress.print_status("Download interrupted")?;
+        return Ok(());
+    };
     match download_result {
         Ok(_) => {
             // Download completed
@@ -158,7 +274,12 @@ fn init_logging(args: &CliArgs) {
     let level = args.log_level();
     debug!("Initializing logging with level: {:?}", level);
     
+    // Keep stdout clean for machine-readable output
+    let to_stderr = matches!(&args.command, Some(Command::Info(info)) if info.json);
     let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || -> Box<dyn std::io::Write> {
+            if to_stderr { Box::new(std::io::stderr()) } else { Box::new(std::io::stdout()) }
+        })
         .with_max_level(level)
         .with_target(false)
         .with_thread_ids(false)
@@ -201,6 +322,15 @@ fn load_torrent_file(path: &Path) -> Result<TorrentInfo> {
     Ok(info)
 }
 
+/// Format a rate limit for display
+fn format_rate(limit: u64) -> String {
+    if limit == 0 {
+        "unlimited".to_string()
+    } else {
+        DownloadStats::format_speed(limit as f64)
+    }
+}
+
 /// Display torrent information
 fn display_torrent_info(torrent_info: &TorrentInfo, config: &Config) -> Result<()> {
     println!("Torrent Information:");
@@ -214,12 +344,31 @@ fn display_torrent_info(torrent_info: &TorrentInfo, config: &Config) -> Result<(
         DownloadStats::format_bytes(torrent_info.piece_length)
     );
     println!("  Info hash: {}", torrent_info.info_hash_hex());
+    if !torrent_info.web_seeds.is_empty() || !torrent_info.http_seeds.is_empty() {
+        println!("  Web seeds: {}", torrent_info.web_seeds.len() + torrent_info.http_seeds.len());
+    }
+    if let Some(info_hash_v2) = torrent_info.info_hash_v2() {
+        println!("  Info hash (v2): {}{}", hex::encode(info_hash_v2), if torrent_info.is_hybrid() { " (hybrid)" } else { "" });
+    }
     println!();
     println!("Configuration:");
     println!("  Output directory: {}", config.output_dir.display());
     println!("  Listen port: {}", config.port);
     println!("  Max connections: {}", config.max_connections);
+    println!("  Encryption: {}", config.encryption);
+    if config.has_file_selection() {
+        println!("  Files: only {:?}, excluding {:?}", config.only_files, config.exclude_files);
+    }
+    if config.is_rate_limited() {
+        println!("  Rate limits (down/up): global {}/{}, torrent {}/{}, peer {}/{}{}",
+            format_rate(config.download_limit), format_rate(config.upload_limit),
+            format_rate(config.torrent_download_limit), format_rate(config.torrent_upload_limit),
+            format_rate(config.peer_download_limit), format_rate(config.peer_upload_limit),
+            if config.limit_overhead { " (including overhead)" } else { "" }
+        );
+    }
     println!("  DHT: {}", if config.is_dht_enabled() { "enabled" } else { "disabled" });
+    println!("  uTP: {}", if config.is_utp_enabled() { "enabled" } else { "disabled" });
     println!("  Tracker: {}", if config.is_tracker_enabled() { "enabled" } else { "disabled" });
     println!("  Seeding: {}", if config.is_seeding_enabled() { "enabled" } else { "disabled" });
     if config.is_seeding_enabled() {
@@ -235,6 +384,341 @@ fn display_torrent_info(torrent_info: &TorrentInfo, config: &Config) -> Result<(
     Ok(())
 }
 
+/// Create a DHT instance on a shared UDP socket, restoring its saved state
+async fn init_dht(demux: &UdpDemux, peer_manager: Arc<PeerManager>, state_file: &Path) -> Result<DHT> {
+    info!("Initializing DHT on {}...", demux.local_addr());
+    let mut instance = DHT::with_demux(demux, peer_manager)
+        .map_err(|e| {
+            error!("Failed to initialize DHT: {}", e);
+            anyhow::Error::from(TorrentError::dht_error_full("Failed to initialize DHT", demux.local_addr().to_string(), e.to_string()))
+        })?;
+    if let Err(e) = instance.load_state(state_file).await {
+        warn!("Ignoring DHT state: {}", e);
+    }
+    info!("DHT initialized successfully");
+    Ok(instance)
+}
+
+/// Listen for incoming peer connections
+async fn spawn_peer_listener(listen_addr: &str, peer_manager: Arc<PeerManager>) -> Result<()> {
+    let bind_addr: std::net::SocketAddr = listen_addr.parse()
+        .context("Invalid bind address for peer listener")?;
+    let listener = bind_tcp(bind_addr)
+        .map_err(|e| {
+            error!("Failed to bind peer listener on {}: {}", listen_addr, e);
+            anyhow::Error::from(TorrentError::network_error_full("Failed to bind peer listener", listen_addr.to_string(), e.to_string()))
+        })?;
+    info!("Listening for incoming peers on {}", listen_addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    debug!("Incoming connection from {}", addr);
+                    let peer_manager = peer_manager.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = peer_manager.accept_connection(socket).await {
+                            debug!("Failed to accept peer {}: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("Failed to accept incoming connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Accept incoming uTP connections from the shared UDP socket
+fn spawn_utp_listener(demux: Arc<UdpDemux>, peer_manager: Arc<PeerManager>) {
+    info!("Listening for incoming uTP peers on {}", demux.local_addr());
+    tokio::spawn(async move {
+        loop {
+            match demux.accept().await {
+                Ok(stream) => {
+                    let addr = stream.peer_addr();
+                    debug!("Incoming uTP connection from {}", addr);
+                    let peer_manager = peer_manager.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = peer_manager.accept_connection(stream).await {
+                            debug!("Failed to accept uTP peer {}: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("uTP listener stopped: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Start the DHT, then keep looking up and announcing the torrent in the background
+///
+/// Hybrid torrents are tracked in both their v1 and v2 swarms.
+async fn start_dht(dht: Arc<DHT>, info_hashes: Vec<[u8; 20]>, port: u16) -> Result<()> {
+    dht.start().await
+        .map_err(|e| {
+            error!("Failed to start DHT: {}", e);
+            anyhow::Error::from(TorrentError::dht_error_full("Failed to start DHT", "unknown", e.to_string()))
+        })?;
+
+    let event_loop = dht.clone();
+    tokio::spawn(async move {
+        if let Err(e) = event_loop.run_loop().await {
+            error!("DHT event loop failed: {}", e);
+        }
+    });
+
+    tokio::spawn(async move {
+        info!("Bootstrapping DHT...");
+        dht.bootstrap().await;
+        let tracking = info_hashes.iter().map(|info_hash| dht.track_torrent(*info_hash, port, DHT_LOOKUP_INTERVAL));
+        futures::future::join_all(tracking).await;
+    });
+
+    Ok(())
+}
+
+/// Create a .torrent file for the `create` command
+async fn run_create(args: &CreateArgs) -> Result<()> {
+    let mut creator = TorrentCreator::new(&args.path)
+        .with_trackers(args.tracker_tiers())
+        .with_web_seeds(args.web_seeds.clone())
+        .with_created_by(args.created_by.clone())
+        .with_private(args.private);
+    if args.no_date {
+        creator = creator.with_creation_date(None);
+    } else if let Some(date) = args.creation_date {
+        creator = creator.with_creation_date(Some(date));
+    }
+    if let Some(kib) = args.piece_length {
+        let piece_length = kib.checked_mul(1024)
+            .ok_or_else(|| TorrentError::config_error_with_field("Piece length is too large", "piece_length"))?;
+        creator = creator.with_piece_length(piece_length);
+    }
+    if let Some(comment) = &args.comment {
+        creator = creator.with_comment(comment);
+    }
+    if let Some(source) = &args.source {
+        creator = creator.with_source(source);
+    }
+    if let Some(threads) = args.threads {
+        creator = creator.with_threads(threads);
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        let name = std::fs::canonicalize(&args.path).ok()
+            .and_then(|path| path.file_name().map(|name| name.to_string_lossy().to_string()))
+            .unwrap_or_else(|| "output".to_string());
+        PathBuf::from(format!("{}.torrent", name))
+    });
+
+    let written = output.clone();
+    let torrent_info = tokio::task::spawn_blocking(move || creator.write(&written)).await
+        .context("Torrent creation was interrupted")?
+        .context("Failed to create torrent")?;
+
+    println!("Created {}", output.display());
+    println!("  Name: {}", torrent_info.name);
+    println!("  Size: {} bytes in {} pieces", torrent_info.total_size(), torrent_info.piece_count());
+    println!("  Info hash: {}", torrent_info.info_hash_hex());
+    Ok(())
+}
+
+/// Edit .torrent files, keeping their info hash unless info keys change
+fn run_edit(args: &EditArgs) -> Result<()> {
+    if args.output.is_some() && args.torrent_files.len() > 1 {
+        return Err(TorrentError::config_error_with_field("--output needs a single torrent file", "output").into());
+    }
+
+    for torrent_file in &args.torrent_files {
+        let mut editor = TorrentEditor::from_file(torrent_file)
+            .with_context(|| format!("Failed to load {}", torrent_file.display()))?;
+        let before = TorrentParser::parse_file(torrent_file)?;
+
+        if args.clear_trackers {
+            editor = editor.with_trackers(Vec::new());
+        } else if !args.trackers.is_empty() {
+            editor = editor.with_trackers(args.tracker_tiers());
+        }
+        for (from, to) in args.tracker_replacements() {
+            editor = editor.with_tracker_replaced(from, to);
+        }
+        if args.clear_comment {
+            editor = editor.with_comment(None);
+        } else if let Some(comment) = &args.comment {
+            editor = editor.with_comment(Some(comment));
+        }
+        if args.clear_web_seeds {
+            editor = editor.with_web_seeds(Vec::new());
+        } else if !args.web_seeds.is_empty() {
+            editor = editor.with_web_seeds(args.web_seeds.clone());
+        }
+        if let Some(private) = args.private {
+            editor = editor.with_private(private);
+        }
+        if args.clear_source {
+            editor = editor.with_source(None);
+        } else if let Some(source) = &args.source {
+            editor = editor.with_source(Some(source));
+        }
+
+        let output = args.output.as_deref().unwrap_or(torrent_file);
+        let after = editor.write(output)
+            .with_context(|| format!("Failed to write {}", output.display()))?;
+        println!("Edited {}", output.display());
+        if after.info_hash == before.info_hash {
+            println!("  Info hash: {} (unchanged)", after.info_hash_hex());
+        } else {
+            println!("  Warning: info hash changed from {} to {}; peers of the old torrent won't find this one",
+                before.info_hash_hex(), after.info_hash_hex());
+        }
+    }
+    Ok(())
+}
+
+/// Print everything a .torrent file contains
+fn run_info(args: &InfoArgs) -> Result<()> {
+    let details = TorrentDetails::from_file(&args.torrent_file)
+        .context("Failed to load torrent file")?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&details).context("Failed to serialize torrent information")?);
+        return Ok(());
+    }
+
+    println!("Name: {}", details.name);
+    println!("Version: {}", details.version);
+    if let Some(info_hash) = &details.info_hash_v1 {
+        println!("Info hash (v1): {}", info_hash);
+    }
+    if let Some(info_hash) = &details.info_hash_v2 {
+        println!("Info hash (v2): {}", info_hash);
+    }
+    println!("Size: {} ({})", details.total_size, DownloadStats::format_bytes(details.total_size));
+    println!("Pieces: {} x {}", details.piece_count, DownloadStats::format_bytes(details.piece_length));
+    println!("Private: {}", if details.private { "yes" } else { "no" });
+    if let Some(source) = &details.source {
+        println!("Source: {}", source);
+    }
+    if let Some(date) = details.creation_date {
+        println!("Created: {}", format_unix_time(date));
+    }
+    if let Some(created_by) = &details.created_by {
+        println!("Created by: {}", created_by);
+    }
+    if let Some(comment) = &details.comment {
+        println!("Comment: {}", comment);
+    }
+
+    if !details.trackers.is_empty() {
+        println!();
+        println!("Trackers:");
+        for (index, tier) in details.trackers.iter().enumerate() {
+            println!("  Tier {}:", index + 1);
+            for url in tier {
+                println!("    {}", url);
+            }
+        }
+    }
+    if !details.web_seeds.is_empty() || !details.http_seeds.is_empty() {
+        println!();
+        println!("Web seeds:");
+        for url in &details.web_seeds {
+            println!("  {}", url);
+        }
+        for url in &details.http_seeds {
+            println!("  {} (HTTP seed)", url);
+        }
+    }
+
+    // File tree, with directories printed when first entered
+    println!();
+    println!("Files ({}):", details.real_files().count());
+    let multi_file = details.files.len() > 1 || details.files.first().is_some_and(|file| file.path != details.name);
+    let root_depth = if multi_file {
+        println!("  {}/", details.name);
+        1
+    } else {
+        0
+    };
+    let mut current_dirs: Vec<&str> = Vec::new();
+    for file in details.real_files() {
+        let components: Vec<&str> = file.path.split('/').collect();
+        let (name, dirs) = components.split_last().unwrap_or((&"", &[]));
+        let common = current_dirs.iter().zip(dirs.iter()).take_while(|(a, b)| a == b).count();
+        for (depth, dir) in dirs.iter().enumerate().skip(common) {
+            println!("  {}{}/", "  ".repeat(root_depth + depth), dir);
+        }
+        current_dirs = dirs.to_vec();
+
+        let pieces = match file.pieces {
+            Some((first, last)) if first == last => format!("piece {}", first),
+            Some((first, last)) => format!("pieces {}-{}", first, last),
+            None => "no pieces".to_string(),
+        };
+        let attr = if file.attr.is_empty() { String::new() } else { format!(" [{}]", file.attr) };
+        let target = file.symlink_path.as_ref().map(|target| format!(" -> {}", target)).unwrap_or_default();
+        println!("  {}{}{}{} ({}, {})",
+            "  ".repeat(root_depth + dirs.len()), name, target, attr, DownloadStats::format_bytes(file.length), pieces);
+    }
+
+    println!();
+    println!("Magnet: {}", details.magnet);
+    Ok(())
+}
+
+/// Crawl the DHT with sample_infohashes, appending discovered info hashes to a file
+async fn run_crawler(args: &CliArgs, crawl_file: &Path) -> Result<()> {
+    let bind_addr: std::net::SocketAddr = format!("0.0.0.0:{}", args.port).parse()
+        .context("Invalid bind address for UDP socket")?;
+    let demux = UdpDemux::bind(bind_addr).await?;
+    let state_file = args.dht_state.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_DHT_STATE_FILE));
+    let dht = Arc::new(init_dht(&demux, Arc::new(PeerManager::default()), &state_file).await?);
+    dht.set_read_only(args.dht_read_only);
+    dht.set_query_limits(args.dht_query_limit, args.dht_query_limit_per_ip);
+    dht.start().await
+        .map_err(|e| {
+            error!("Failed to start DHT: {}", e);
+            anyhow::Error::from(TorrentError::dht_error_full("Failed to start DHT", "unknown", e.to_string()))
+        })?;
+    let event_loop = dht.clone();
+    tokio::spawn(async move {
+        if let Err(e) = event_loop.run_loop().await {
+            error!("DHT event loop failed: {}", e);
+        }
+    });
+    dht.bootstrap().await;
+
+    let out = std::fs::OpenOptions::new().create(true).append(true).open(crawl_file)
+        .map_err(|e| {
+            error!("Failed to open crawl output {}: {}", crawl_file.display(), e);
+            anyhow::Error::from(TorrentError::storage_error_full("Failed to open crawl output", crawl_file.display().to_string(), e.to_string()))
+        })?;
+    let duration = (args.crawl_time > 0).then(|| Duration::from_secs(args.crawl_time * 60));
+
+    let mut crawler = Crawler::new(dht.clone());
+    let result = tokio::select! {
+        result = crawler.run(std::io::BufWriter::new(out), duration) => result.map(|_| ()),
+        _ = tokio::signal::ctrl_c() => {
+            info!("Crawl interrupted");
+            Ok(())
+        }
+    };
+    info!("Crawl found {} info hashes, written to {}", crawler.discovered_count(), crawl_file.display());
+
+    if let Err(e) = dht.save_state(&state_file).await {
+        warn!("Failed to save DHT state: {}", e);
+    }
+    dht.stop().await;
+    result
+}
+
 /// Run download process
 async fn run_download(
     torrent_info: &TorrentInfo,
@@ -242,7 +726,7 @@ async fn run_download(
     peer_manager: &Arc<PeerManager>,
     download_manager: &Arc<FileDownloadManager>,
     progress: &mut ProgressDisplay,
-    dht: Option<&DHT>,
+    dhts: &[(Arc<DHT>, PathBuf)],
 ) -> Result<()> {
     info!("Starting download for: {}", torrent_info.name);
     debug!("Total size: {} bytes ({} pieces)", torrent_info.total_size(), torrent_info.piece_count());
@@ -265,12 +749,14 @@ async fn run_download(
     }
 
     // Bootstrap DHT if enabled
-    if let Some(dht) = dht {
-        info!("Bootstrapping DHT...");
-        // TODO: Implement DHT bootstrap
-        warn!("DHT bootstrap not yet implemented");
+    for (dht, _) in dhts {
+        start_dht(dht.clone(), torrent_info.swarm_hashes(), config.port).await?;
     }
 
+    // Connect to the peers we discover
+    let manager = peer_manager.clone();
+    tokio::spawn(async move { manager.run_management_loop(PEER_MANAGEMENT_INTERVAL_SECS).await });
+
     // Main download loop
     let mut last_stats = download_manager.get_stats().await;
     let mut last_time = std::time::Instant::now();
@@ -283,6 +769,11 @@ async fn run_download(
         // Check if download is complete
         if download_manager.is_complete().await {
             info!("Download complete!");
+            download_manager.complete().await
+                .map_err(|e| {
+                    error!("Failed to finish download: {}", e);
+                    anyhow::Error::from(TorrentError::storage_error_full("Failed to finish download", torrent_info.name.clone(), e.to_string()))
+                })?;
             break;
         }
 
@@ -299,9 +790,17 @@ async fn run_download(
             let download_speed = downloaded_delta as f64 / elapsed.as_secs_f64();
             let upload_speed = uploaded_delta as f64 / elapsed.as_secs_f64();
 
-            // Get peer count
+            // Get peer count, counting web seeds that aren't backing off
             let peer_count = peer_manager.connected_addresses().await.len();
             debug!("Connected peers: {}", peer_count);
+            #[cfg(feature = "download")]
+            let peer_count = {
+                let web_seeds = download_manager.web_seed_stats().await;
+                for seed in &web_seeds {
+                    trace!("Web seed {} ({}): {} pieces, {} failures", seed.url, seed.kind, seed.pieces_downloaded, seed.failures);
+                }
+                peer_count + web_seeds.iter().filter(|seed| !seed.backing_off).count()
+            };
 
             // Get progress
             let progress_value = download_manager.get_progress().await;
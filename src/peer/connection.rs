@@ -2,11 +2,16 @@
 //!
 //! Manages individual peer connections.
 
-use crate::protocol::{Handshake, Message, BitTorrentWire, WireProtocol};
+use crate::protocol::{Handshake, HashRequest, Message, BitTorrentWire, PeerCodec, WireProtocol};
+use crate::protocol::mse::{self, EncryptionPolicy};
 use crate::peer::{Peer, PeerState};
+use crate::torrent::InfoV2;
 use crate::error::TorrentError;
+use crate::transport::{BoxedTransport, RateLimits, Throttled, Transport, TransportKind, UdpDemux};
+use futures::{SinkExt, StreamExt};
 use tokio::net::TcpStream;
 use tokio::time::{timeout, Duration};
+use tokio_util::codec::Framed;
 use anyhow::Result;
 use std::net::SocketAddr;
 use tracing::{debug, error, info, trace, warn};
@@ -15,8 +20,10 @@ use tracing::{debug, error, info, trace, warn};
 pub struct PeerConnection {
     /// Peer information
     pub peer: Peer,
-    /// TCP connection stream
-    stream: TcpStream,
+    /// Framed transport stream, including any encryption and rate limiting layers
+    stream: Framed<Throttled<BoxedTransport>, PeerCodec>,
+    /// Rate limits for this peer alone
+    rate_limits: RateLimits,
     /// Whether handshake has been completed
     pub handshake_completed: bool,
     /// Wire protocol handler
@@ -26,26 +33,38 @@ pub struct PeerConnection {
 impl PeerConnection {
     /// Create a new peer connection from an existing socket
     pub fn from_socket(socket: TcpStream) -> Result<Self> {
-        let peer_addr = socket.peer_addr()
+        Self::from_transport(Box::new(socket))
+    }
+
+    /// Create a new peer connection over any transport
+    pub fn from_transport(transport: BoxedTransport) -> Result<Self> {
+        let peer_addr = transport.peer_addr()
             .map_err(|e| {
-                error!("Failed to get peer address from socket: {}", e);
+                error!("Failed to get peer address from transport: {}", e);
                 TorrentError::peer_error_full("Failed to get peer address", "unknown".to_string(), e.to_string())
             })?;
-        info!("Creating peer connection from socket: {}", peer_addr);
+        info!("Creating peer connection from {} transport: {}", transport.kind(), peer_addr);
         Ok(Self {
             peer: Peer::new(peer_addr),
-            stream: socket,
+            stream: Self::framed(transport),
+            rate_limits: RateLimits::unlimited(),
             handshake_completed: false,
             wire: BitTorrentWire,
         })
     }
 
+    /// Frame a transport, with a rate limiting layer that starts out unlimited
+    fn framed(transport: BoxedTransport) -> Framed<Throttled<BoxedTransport>, PeerCodec> {
+        Framed::new(Throttled::new(transport), PeerCodec::new())
+    }
+
     /// Create a new peer connection with a peer
     pub fn with_peer(socket: TcpStream, peer: Peer) -> Result<Self> {
         info!("Creating peer connection with peer: {}", peer.addr);
         Ok(Self {
             peer,
-            stream: socket,
+            stream: Self::framed(Box::new(socket)),
+            rate_limits: RateLimits::unlimited(),
             handshake_completed: false,
             wire: BitTorrentWire,
         })
@@ -53,22 +72,94 @@ impl PeerConnection {
 
     /// Connect to a peer at the given address and perform handshake
     pub async fn connect(addr: SocketAddr, info_hash: [u8; 20], our_peer_id: [u8; 20]) -> Result<Self> {
-        info!("Connecting to peer: {}", addr);
-        
-        // Set connection timeout
-        let socket = timeout(Duration::from_secs(10), TcpStream::connect(addr))
-            .await
-            .map_err(|e| {
-                warn!("Connection timeout to {}", addr);
-                TorrentError::network_error_full("Connection timeout", addr.to_string(), e.to_string())
-            })?
+        Self::connect_with_policy(addr, info_hash, our_peer_id, EncryptionPolicy::Disabled).await
+    }
+
+    /// Connect to a peer using the given encryption policy and perform handshake
+    ///
+    /// With `EncryptionPolicy::Prefer` a failed encrypted handshake is retried
+    /// over a fresh plaintext connection.
+    pub async fn connect_with_policy(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        our_peer_id: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<Self> {
+        Self::establish(addr, None, info_hash, our_peer_id, policy).await
+    }
+
+    /// Connect to a peer over uTP and perform handshake
+    pub async fn connect_utp(
+        demux: &UdpDemux,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        our_peer_id: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<Self> {
+        Self::establish(addr, Some(demux), info_hash, our_peer_id, policy).await
+    }
+
+    /// Perform the outgoing handshakes over an already open transport
+    ///
+    /// Unlike [`PeerConnection::connect_with_policy`] there is no plaintext
+    /// retry, since the transport cannot be reopened.
+    pub async fn connect_over(
+        transport: BoxedTransport,
+        info_hash: [u8; 20],
+        our_peer_id: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<Self> {
+        let addr = transport.peer_addr()
             .map_err(|e| {
-                error!("Failed to connect to {}: {}", addr, e);
-                TorrentError::network_error_full("Failed to connect", addr.to_string(), e.to_string())
+                error!("Failed to get peer address from transport: {}", e);
+                TorrentError::peer_error_full("Failed to get peer address", "unknown".to_string(), e.to_string())
             })?;
+        info!("Connecting to peer: {} over existing {} transport (encryption: {})", addr, transport.kind(), policy);
 
-        debug!("Connected to peer: {}", addr);
-        let mut connection = Self::from_socket(socket)?;
+        let stream = Self::negotiate_outgoing(transport, addr, info_hash, policy).await?;
+        let mut connection = Self {
+            peer: Peer::new(addr),
+            stream: Self::framed(stream),
+            rate_limits: RateLimits::unlimited(),
+            handshake_completed: false,
+            wire: BitTorrentWire,
+        };
+        connection.peer.set_state(PeerState::Connecting);
+        connection.perform_handshake(info_hash, our_peer_id).await?;
+
+        info!("Successfully handshaked with peer: {} over {}", addr, connection.transport_kind());
+        Ok(connection)
+    }
+
+    /// Open the transport, negotiate encryption and perform the handshake
+    async fn establish(
+        addr: SocketAddr,
+        utp: Option<&UdpDemux>,
+        info_hash: [u8; 20],
+        our_peer_id: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<Self> {
+        info!("Connecting to peer: {} (transport: {}, encryption: {})",
+            addr, if utp.is_some() { "utp" } else { "tcp" }, policy);
+
+        let transport = Self::open_transport(addr, utp).await?;
+        let stream = match Self::negotiate_outgoing(transport, addr, info_hash, policy).await {
+            Ok(stream) => stream,
+            Err(e) if policy == EncryptionPolicy::Prefer => {
+                debug!("Encrypted handshake with {} failed ({}), retrying in plaintext", addr, e);
+                Self::open_transport(addr, utp).await?
+            }
+            Err(e) => return Err(e),
+        };
+
+        debug!("Connected to peer: {} (encrypted: {})", addr, stream.is_encrypted());
+        let mut connection = Self {
+            peer: Peer::new(addr),
+            stream: Self::framed(stream),
+            rate_limits: RateLimits::unlimited(),
+            handshake_completed: false,
+            wire: BitTorrentWire,
+        };
         connection.peer.set_state(PeerState::Connecting);
 
         // Perform handshake
@@ -78,6 +169,118 @@ impl PeerConnection {
         Ok(connection)
     }
 
+    /// Run the initiator side of MSE, layering encryption over the transport
+    async fn negotiate_outgoing(
+        transport: BoxedTransport,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<BoxedTransport> {
+        if policy == EncryptionPolicy::Disabled {
+            return Ok(transport);
+        }
+        match timeout(Duration::from_secs(10), mse::initiate(transport, info_hash, policy)).await {
+            Ok(Ok(stream)) => Ok(Box::new(stream)),
+            Ok(Err(e)) => {
+                warn!("Encrypted handshake with {} failed: {}", addr, e);
+                Err(TorrentError::peer_error_full("Encryption handshake failed", addr.to_string(), e.to_string()).into())
+            }
+            Err(e) => {
+                warn!("Encrypted handshake with {} timed out", addr);
+                Err(TorrentError::peer_error_full("Encryption handshake timeout", addr.to_string(), e.to_string()).into())
+            }
+        }
+    }
+
+    /// Accept an incoming connection and perform the responder side of the handshakes
+    ///
+    /// `info_hashes` lists the torrents we serve; the peer's handshake must match one of them.
+    pub async fn accept(
+        transport: BoxedTransport,
+        info_hashes: &[[u8; 20]],
+        our_peer_id: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<Self> {
+        let addr = transport.peer_addr()
+            .map_err(|e| {
+                error!("Failed to get peer address from transport: {}", e);
+                TorrentError::peer_error_full("Failed to get peer address", "unknown".to_string(), e.to_string())
+            })?;
+        info!("Accepting {} connection from peer: {} (encryption: {})", transport.kind(), addr, policy);
+
+        let (stream, _) = timeout(Duration::from_secs(10), mse::respond(transport, info_hashes, policy))
+            .await
+            .map_err(|e| {
+                warn!("Encryption handshake timeout from {}", addr);
+                TorrentError::peer_error_full("Encryption handshake timeout", addr.to_string(), e.to_string())
+            })?
+            .map_err(|e| {
+                warn!("Encryption handshake with {} failed: {}", addr, e);
+                TorrentError::peer_error_full("Encryption handshake failed", addr.to_string(), e.to_string())
+            })?;
+
+        let mut connection = Self {
+            peer: Peer::new(addr),
+            stream: Self::framed(Box::new(stream)),
+            rate_limits: RateLimits::unlimited(),
+            handshake_completed: false,
+            wire: BitTorrentWire,
+        };
+        connection.peer.set_state(PeerState::Connecting);
+
+        // Read the peer's handshake first, then answer with ours
+        let peer_handshake = connection.wire.read_handshake(connection.stream.get_mut().get_mut()).await
+            .map_err(|e| {
+                error!("Failed to read handshake from {}: {}", addr, e);
+                TorrentError::peer_error_full("Failed to read handshake", addr.to_string(), e.to_string())
+            })?;
+        if !info_hashes.iter().any(|hash| peer_handshake.validate(hash)) {
+            error!("Handshake from {} is for an unknown torrent", addr);
+            return Err(TorrentError::peer_error_full(
+                "Handshake validation failed: unknown info hash",
+                addr.to_string(),
+                "unknown info hash".to_string()
+            ).into());
+        }
+
+        let our_handshake = Handshake::new(peer_handshake.info_hash, our_peer_id);
+        connection.wire.write_handshake(connection.stream.get_mut().get_mut(), &our_handshake).await
+            .map_err(|e| {
+                error!("Failed to send handshake to {}: {}", addr, e);
+                TorrentError::peer_error_full("Failed to send handshake", addr.to_string(), e.to_string())
+            })?;
+
+        connection.peer.set_peer_id(peer_handshake.peer_id);
+        connection.peer.set_state(PeerState::Connected);
+        connection.handshake_completed = true;
+
+        info!("Accepted peer: {} (encrypted: {})", addr, connection.is_encrypted());
+        Ok(connection)
+    }
+
+    /// Open a TCP or uTP connection with the standard connect timeout
+    async fn open_transport(addr: SocketAddr, utp: Option<&UdpDemux>) -> Result<BoxedTransport> {
+        let stream: BoxedTransport = match utp {
+            Some(demux) => Box::new(timeout(Duration::from_secs(10), demux.connect(addr))
+                .await
+                .map_err(|e| {
+                    warn!("uTP connection timeout to {}", addr);
+                    TorrentError::network_error_full("Connection timeout", addr.to_string(), e.to_string())
+                })??),
+            None => Box::new(timeout(Duration::from_secs(10), TcpStream::connect(addr))
+                .await
+                .map_err(|e| {
+                    warn!("Connection timeout to {}", addr);
+                    TorrentError::network_error_full("Connection timeout", addr.to_string(), e.to_string())
+                })?
+                .map_err(|e| {
+                    error!("Failed to connect to {}: {}", addr, e);
+                    TorrentError::network_error_full("Failed to connect", addr.to_string(), e.to_string())
+                })?),
+        };
+        Ok(stream)
+    }
+
     /// Perform the BitTorrent handshake
     async fn perform_handshake(&mut self, info_hash: [u8; 20], our_peer_id: [u8; 20]) -> Result<()> {
         info!("Performing handshake with peer: {}", self.peer.addr);
@@ -87,7 +290,7 @@ impl PeerConnection {
         
         // Send our handshake
         debug!("Sending handshake to peer: {}", self.peer.addr);
-        self.wire.write_handshake(&mut self.stream, &our_handshake).await
+        self.wire.write_handshake(self.stream.get_mut().get_mut(), &our_handshake).await
             .map_err(|e| {
                 error!("Failed to send handshake to {}: {}", self.peer.addr, e);
                 TorrentError::peer_error_full("Failed to send handshake", self.peer.addr.to_string(), e.to_string())
@@ -95,7 +298,7 @@ impl PeerConnection {
 
         // Read peer's handshake
         debug!("Reading handshake from peer: {}", self.peer.addr);
-        let peer_handshake = self.wire.read_handshake(&mut self.stream).await
+        let peer_handshake = self.wire.read_handshake(self.stream.get_mut().get_mut()).await
             .map_err(|e| {
                 error!("Failed to read handshake from {}: {}", self.peer.addr, e);
                 TorrentError::peer_error_full("Failed to read handshake", self.peer.addr.to_string(), e.to_string())
@@ -120,55 +323,116 @@ impl PeerConnection {
         Ok(())
     }
 
-    /// Send a message to the peer
-    pub async fn send_message(&mut self, message: &Message) -> Result<()> {
+    /// Ensure the handshake is done before exchanging messages
+    fn check_handshake(&self, action: &str) -> Result<()> {
         if !self.handshake_completed {
-            error!("Attempted to send message before handshake completion to peer: {}", self.peer.addr);
+            error!("Attempted to {} before handshake completion with peer: {}", action, self.peer.addr);
             return Err(TorrentError::peer_error_full(
-                "Cannot send message: handshake not completed",
+                format!("Cannot {}: handshake not completed", action),
                 self.peer.addr.to_string(),
                 "handshake not completed".to_string()
             ).into());
         }
+        Ok(())
+    }
+
+    /// Send a message to the peer and flush it
+    pub async fn send_message(&mut self, message: &Message) -> Result<()> {
+        self.queue_message(message).await?;
+        self.flush().await
+    }
+
+    /// Queue a message without flushing
+    ///
+    /// Queued messages are written together on the next `flush` (or once the
+    /// write buffer fills up), so small messages share one write.
+    pub async fn queue_message(&mut self, message: &Message) -> Result<()> {
+        self.check_handshake("send message")?;
 
         debug!("Sending {:?} message to peer: {}", message.message_id(), self.peer.addr);
-        self.wire.write_message(&mut self.stream, message).await
+        self.stream.feed(message).await
             .map_err(|e| {
                 error!("Failed to send message to {}: {}", self.peer.addr, e);
                 TorrentError::peer_error_full("Failed to send message", self.peer.addr.to_string(), e.to_string())
             })?;
+        if !self.stream.get_ref().counts_overhead() {
+            self.stream.get_ref().refund_upload(protocol_overhead(message));
+        }
+        Ok(())
+    }
+
+    /// Flush queued messages to the peer
+    pub async fn flush(&mut self) -> Result<()> {
+        SinkExt::<&Message>::flush(&mut self.stream).await
+            .map_err(|e| {
+                error!("Failed to flush messages to {}: {}", self.peer.addr, e);
+                TorrentError::peer_error_full("Failed to send message", self.peer.addr.to_string(), e.to_string())
+            })?;
         Ok(())
     }
 
     /// Receive a message from the peer
     pub async fn receive_message(&mut self) -> Result<Message> {
-        if !self.handshake_completed {
-            error!("Attempted to receive message before handshake completion from peer: {}", self.peer.addr);
-            return Err(TorrentError::peer_error_full(
-                "Cannot receive message: handshake not completed",
-                self.peer.addr.to_string(),
-                "handshake not completed".to_string()
-            ).into());
-        }
+        self.check_handshake("receive message")?;
 
         // Set read timeout
-        let message = timeout(Duration::from_secs(30), self.wire.read_message(&mut self.stream))
+        let message = timeout(Duration::from_secs(30), self.stream.next())
             .await
             .map_err(|e| {
                 warn!("Receive message timeout from peer: {}", self.peer.addr);
                 TorrentError::peer_error_full("Receive message timeout", self.peer.addr.to_string(), e.to_string())
             })?
+            .ok_or_else(|| {
+                debug!("Peer {} closed the connection", self.peer.addr);
+                TorrentError::peer_error_full("Failed to read message", self.peer.addr.to_string(), "connection closed".to_string())
+            })?
             .map_err(|e| {
                 error!("Failed to read message from {}: {}", self.peer.addr, e);
                 TorrentError::peer_error_full("Failed to read message", self.peer.addr.to_string(), e.to_string())
             })?;
 
+        if !self.stream.get_ref().counts_overhead() {
+            self.stream.get_ref().refund_download(protocol_overhead(&message));
+        }
+
         debug!("Received {:?} message from peer: {}", message.message_id(), self.peer.addr);
         Ok(message)
     }
 
+    /// Get the largest message accepted from the peer
+    pub fn max_message_size(&self) -> usize {
+        self.stream.codec().max_message_size()
+    }
+
+    /// Set the largest message accepted from the peer
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        debug!("Max message size for peer {} set to {} bytes", self.peer.addr, max_message_size);
+        self.stream.codec_mut().set_max_message_size(max_message_size);
+    }
+
+    /// Get the rate limits for this peer alone, adjustable at runtime
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+
+    /// Apply shared rate limits (global first) on top of this peer's own limits
+    ///
+    /// When `count_overhead` is false only piece payload counts against the limits.
+    pub fn set_rate_limits(&mut self, shared: &[RateLimits], count_overhead: bool) {
+        let mut levels = shared.to_vec();
+        levels.push(self.rate_limits.clone());
+        let throttle = self.stream.get_mut();
+        throttle.set_limits(&levels);
+        throttle.set_count_overhead(count_overhead);
+    }
+
     /// Request a piece block from the peer
     pub async fn request_piece(&mut self, piece_index: u32, begin: u32, length: u32) -> Result<()> {
+        self.request_blocks(&[(piece_index, begin, length)]).await
+    }
+
+    /// Request several blocks, given as `(piece, begin, length)`, in one write
+    pub async fn request_blocks(&mut self, blocks: &[(u32, u32, u32)]) -> Result<()> {
         if !self.peer.can_request() {
             warn!("Cannot request piece from peer {}: peer is not ready", self.peer.addr);
             return Err(TorrentError::peer_error_full(
@@ -178,26 +442,37 @@ impl PeerConnection {
             ).into());
         }
 
-        debug!("Requesting piece {} block {} ({} bytes) from peer: {}", piece_index, begin, length, self.peer.addr);
-        let request = Message::Request {
-            index: piece_index,
-            begin,
-            length,
-        };
+        for &(index, begin, length) in blocks {
+            debug!("Requesting piece {} block {} ({} bytes) from peer: {}", index, begin, length, self.peer.addr);
+            self.queue_message(&Message::Request { index, begin, length }).await?;
+        }
+        self.flush().await
+    }
+
+    /// Ask the peer for merkle hashes of a v2 file
+    pub async fn request_hashes(&mut self, request: HashRequest) -> Result<()> {
+        self.check_handshake("request hashes")?;
+
+        debug!("Requesting {} hashes of layer {} from peer: {}", request.length, request.base_layer, self.peer.addr);
+        self.send_message(&Message::HashRequest(request)).await
+    }
 
-        self.send_message(&request).await
+    /// Answer a hash request from the piece layers we have, rejecting it otherwise
+    pub async fn answer_hash_request(&mut self, v2: &InfoV2, request: HashRequest) -> Result<()> {
+        let hashes = v2.hashes(&request.pieces_root, request.base_layer, request.index, request.length, request.proof_layers);
+        let message = match hashes {
+            Some(hashes) => Message::Hashes { request, hashes },
+            None => {
+                debug!("Rejecting hash request from peer {}: hashes not available", self.peer.addr);
+                Message::HashReject(request)
+            }
+        };
+        self.send_message(&message).await
     }
 
     /// Send our bitfield to the peer
     pub async fn send_bitfield(&mut self, bitfield: Vec<u8>) -> Result<()> {
-        if !self.handshake_completed {
-            error!("Attempted to send bitfield before handshake completion to peer: {}", self.peer.addr);
-            return Err(TorrentError::peer_error_full(
-                "Cannot send bitfield: handshake not completed",
-                self.peer.addr.to_string(),
-                "handshake not completed".to_string()
-            ).into());
-        }
+        self.check_handshake("send bitfield")?;
 
         debug!("Sending bitfield ({} bytes) to peer: {}", bitfield.len(), self.peer.addr);
         let message = Message::Bitfield { bitfield };
@@ -291,19 +566,210 @@ impl PeerConnection {
         self.peer.peer_interested
     }
 
+    /// Check if the connection payload is RC4-encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.stream.get_ref().get_ref().is_encrypted()
+    }
+
+    /// Check if the connection runs over uTP
+    pub fn is_utp(&self) -> bool {
+        self.transport_kind() == TransportKind::Utp
+    }
+
+    /// Get the kind of the underlying transport
+    pub fn transport_kind(&self) -> TransportKind {
+        self.stream.get_ref().get_ref().kind()
+    }
+
     /// Check if the connection is active
     pub fn is_active(&self) -> bool {
         self.handshake_completed && self.peer.state.is_connected()
     }
 }
 
+/// Bytes of a message that are not piece payload
+fn protocol_overhead(message: &Message) -> usize {
+    4 + message.length() as usize - message.block_len().unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::transport::MemoryTransport;
 
     #[test]
     fn test_peer_connection_from_socket() {
         // This test would require a real socket, so we just verify the struct compiles
         // In a real test, we'd create a mock socket
     }
+
+    async fn loopback_session(outgoing: EncryptionPolicy, incoming: EncryptionPolicy) -> (Result<PeerConnection>, Result<PeerConnection>) {
+        let info_hash = [5u8; 20];
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = async {
+            // Prefer may reconnect in plaintext, so serve until the client settles
+            let mut last = None;
+            for _ in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let result = PeerConnection::accept(Box::new(socket), &[info_hash], [2u8; 20], incoming).await;
+                let done = result.is_ok();
+                last = Some(result);
+                if done || outgoing != EncryptionPolicy::Prefer {
+                    break;
+                }
+            }
+            last.unwrap()
+        };
+        let client = PeerConnection::connect_with_policy(addr, info_hash, [1u8; 20], outgoing);
+        let (client, server) = tokio::join!(client, server);
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_session_over_loopback() {
+        let (client, server) = loopback_session(EncryptionPolicy::Require, EncryptionPolicy::Prefer).await;
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+
+        assert!(client.is_encrypted());
+        assert!(server.is_encrypted());
+        assert_eq!(client.peer_id(), Some([2u8; 20]));
+        assert_eq!(server.peer_id(), Some([1u8; 20]));
+
+        client.send_message(&Message::Have { piece_index: 42 }).await.unwrap();
+        match server.receive_message().await.unwrap() {
+            Message::Have { piece_index } => assert_eq!(piece_index, 42),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_session_over_loopback() {
+        let (client, server) = loopback_session(EncryptionPolicy::Disabled, EncryptionPolicy::Prefer).await;
+        let client = client.unwrap();
+        let server = server.unwrap();
+        assert!(!client.is_encrypted());
+        assert!(!server.is_encrypted());
+    }
+
+    #[tokio::test]
+    async fn test_prefer_falls_back_to_plaintext() {
+        let (client, server) = loopback_session(EncryptionPolicy::Prefer, EncryptionPolicy::Disabled).await;
+        assert!(!client.unwrap().is_encrypted());
+        assert!(!server.unwrap().is_encrypted());
+    }
+
+    async fn memory_session(policy: EncryptionPolicy) -> (PeerConnection, PeerConnection) {
+        let info_hash = [8u8; 20];
+        let known = [info_hash];
+        let (a, b) = MemoryTransport::pair("10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6881".parse().unwrap());
+        let (client, server) = tokio::join!(
+            PeerConnection::connect_over(Box::new(a), info_hash, [1u8; 20], policy),
+            PeerConnection::accept(Box::new(b), &known, [2u8; 20], policy),
+        );
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_full_session_in_memory() {
+        for policy in [EncryptionPolicy::Disabled, EncryptionPolicy::Require] {
+            let (mut client, mut server) = memory_session(policy).await;
+            assert_eq!(client.transport_kind(), TransportKind::Memory);
+            assert_eq!(client.is_encrypted(), policy == EncryptionPolicy::Require);
+            assert_eq!(client.peer_addr(), "10.0.0.2:6881".parse().unwrap());
+            assert_eq!(server.peer_addr(), "10.0.0.1:6881".parse().unwrap());
+
+            server.send_bitfield(vec![0b1000_0000]).await.unwrap();
+            match client.receive_message().await.unwrap() {
+                Message::Bitfield { bitfield } => client.peer.update_bitfield(bitfield),
+                other => panic!("unexpected message: {:?}", other),
+            }
+            assert!(client.peer.has_piece(0));
+
+            client.send_interested().await.unwrap();
+            assert!(matches!(server.receive_message().await.unwrap(), Message::Interested));
+            server.send_unchoke().await.unwrap();
+            assert!(matches!(client.receive_message().await.unwrap(), Message::Unchoke));
+            client.peer.peer_choking = false;
+
+            client.request_piece(0, 0, 4).await.unwrap();
+            let (index, begin, length) = match server.receive_message().await.unwrap() {
+                Message::Request { index, begin, length } => (index, begin, length),
+                other => panic!("unexpected message: {:?}", other),
+            };
+            server.send_message(&Message::Piece { index, begin, block: vec![0xab; length as usize].into() }).await.unwrap();
+            match client.receive_message().await.unwrap() {
+                Message::Piece { index, begin, block } => {
+                    assert_eq!((index, begin), (0, 0));
+                    assert_eq!(block, vec![0xab; 4]);
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_requests_and_message_size_limit() {
+        let (mut client, mut server) = memory_session(EncryptionPolicy::Disabled).await;
+        client.peer.peer_choking = false;
+        client.peer.am_interested = true;
+
+        client.queue_message(&Message::Interested).await.unwrap();
+        client.request_blocks(&[(0, 0, 16384), (0, 16384, 16384), (1, 0, 16384)]).await.unwrap();
+        assert!(matches!(server.receive_message().await.unwrap(), Message::Interested));
+        for expected in [(0, 0), (0, 16384), (1, 0)] {
+            match server.receive_message().await.unwrap() {
+                Message::Request { index, begin, .. } => assert_eq!((index, begin), expected),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        client.set_max_message_size(1024);
+        assert_eq!(client.max_message_size(), 1024);
+        server.send_bitfield(vec![0xff; 2048]).await.unwrap();
+        assert!(client.receive_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_handshake_rejects_wrong_torrent() {
+        let known = [[2u8; 20]];
+        let (a, b) = MemoryTransport::pair("10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6881".parse().unwrap());
+        let (client, server) = tokio::join!(
+            PeerConnection::connect_over(Box::new(a), [1u8; 20], [1u8; 20], EncryptionPolicy::Disabled),
+            PeerConnection::accept(Box::new(b), &known, [2u8; 20], EncryptionPolicy::Disabled),
+        );
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_session_over_utp() {
+        let info_hash = [6u8; 20];
+        let server = UdpDemux::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let client = UdpDemux::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let accept = async {
+            let stream = server.accept().await.unwrap();
+            PeerConnection::accept(Box::new(stream), &[info_hash], [2u8; 20], EncryptionPolicy::Prefer).await
+        };
+        let connect = PeerConnection::connect_utp(&client, server.local_addr(), info_hash, [1u8; 20], EncryptionPolicy::Require);
+        let (outgoing, incoming) = tokio::join!(connect, accept);
+        let mut outgoing = outgoing.unwrap();
+        let mut incoming = incoming.unwrap();
+
+        assert!(outgoing.is_utp() && incoming.is_utp());
+        assert!(outgoing.is_encrypted() && incoming.is_encrypted());
+
+        incoming.send_message(&Message::Unchoke).await.unwrap();
+        assert!(matches!(outgoing.receive_message().await.unwrap(), Message::Unchoke));
+    }
+
+    #[tokio::test]
+    async fn test_require_rejects_plaintext_peer() {
+        let (client, server) = loopback_session(EncryptionPolicy::Disabled, EncryptionPolicy::Require).await;
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
 }
@@ -2,9 +2,10 @@
 //!
 //! Manages multiple peer connections.
 
-use crate::peer::{Peer, PeerConnection, PeerState};
-use crate::protocol::Handshake;
+use crate::peer::{Peer, PeerConnection, PeerSource, PeerState};
+use crate::protocol::{EncryptionPolicy, Handshake};
 use crate::torrent::TorrentInfo;
+use crate::transport::{RateLimits, Transport, UdpDemux};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -25,6 +26,18 @@ pub struct PeerManager {
     torrent_info: Arc<TorrentInfo>,
     /// Our peer ID
     our_peer_id: [u8; 20],
+    /// Encryption policy for outgoing and incoming connections
+    encryption_policy: EncryptionPolicy,
+    /// Shared UDP sockets for uTP connections, one per address family
+    utp: Vec<Arc<UdpDemux>>,
+    /// Rate limits shared with every other torrent
+    global_limits: RateLimits,
+    /// Rate limits for this torrent
+    torrent_limits: RateLimits,
+    /// Download and upload limit applied to each peer, in bytes per second
+    peer_limits: RwLock<(u64, u64)>,
+    /// Whether protocol overhead counts against the rate limits
+    count_overhead: bool,
 }
 
 impl PeerManager {
@@ -36,9 +49,70 @@ impl PeerManager {
             max_connections,
             torrent_info,
             our_peer_id,
+            encryption_policy: EncryptionPolicy::default(),
+            utp: Vec::new(),
+            global_limits: RateLimits::unlimited(),
+            torrent_limits: RateLimits::unlimited(),
+            peer_limits: RwLock::new((0, 0)),
+            count_overhead: false,
         }
     }
 
+    /// Set the encryption policy used for new connections
+    pub fn set_encryption_policy(&mut self, policy: EncryptionPolicy) {
+        info!("Peer encryption policy: {}", policy);
+        self.encryption_policy = policy;
+    }
+
+    /// Enable uTP for outgoing connections of the socket's address family; TCP is used as a fallback
+    pub fn set_utp(&mut self, demux: Arc<UdpDemux>) {
+        info!("uTP enabled for outgoing connections from {}", demux.local_addr());
+        self.utp.retain(|existing| existing.local_addr().is_ipv6() != demux.local_addr().is_ipv6());
+        self.utp.push(demux);
+    }
+
+    /// Get the encryption policy used for new connections
+    pub fn encryption_policy(&self) -> EncryptionPolicy {
+        self.encryption_policy
+    }
+
+    /// Share global rate limits with this torrent's connections
+    pub fn set_global_rate_limits(&mut self, limits: RateLimits) {
+        self.global_limits = limits;
+    }
+
+    /// Get the global rate limits
+    pub fn global_rate_limits(&self) -> &RateLimits {
+        &self.global_limits
+    }
+
+    /// Get this torrent's rate limits, adjustable at runtime
+    pub fn torrent_rate_limits(&self) -> &RateLimits {
+        &self.torrent_limits
+    }
+
+    /// Set whether protocol overhead counts against the rate limits
+    pub fn set_count_overhead(&mut self, count_overhead: bool) {
+        self.count_overhead = count_overhead;
+    }
+
+    /// Set the per-peer limits in bytes per second (0 = unlimited), including live connections
+    pub async fn set_peer_rate_limits(&self, download: u64, upload: u64) {
+        info!("Per-peer rate limits: download {} B/s, upload {} B/s", download, upload);
+        *self.peer_limits.write().await = (download, upload);
+        let connections = self.active_connections.read().await;
+        for connection in connections.values() {
+            connection.rate_limits().set(download, upload);
+        }
+    }
+
+    /// Put a new connection under the global, torrent and per-peer limits
+    async fn apply_rate_limits(&self, connection: &mut PeerConnection) {
+        let (download, upload) = *self.peer_limits.read().await;
+        connection.rate_limits().set(download, upload);
+        connection.set_rate_limits(&[self.global_limits.clone(), self.torrent_limits.clone()], self.count_overhead);
+    }
+
     /// Add a peer to the manager
     pub async fn add_peer(&self, addr: SocketAddr) -> Result<()> {
         let mut peers = self.peers.write().await;
@@ -90,6 +164,22 @@ impl PeerManager {
         Ok(())
     }
 
+    /// Add peers discovered from a source, returning how many were new
+    pub async fn add_peers_from(&self, addrs: Vec<SocketAddr>, source: PeerSource) -> usize {
+        let mut peers = self.peers.write().await;
+        let mut added_count = 0;
+
+        for addr in addrs {
+            if !peers.iter().any(|p| p.addr == addr) {
+                peers.push(Peer::with_source(addr, source));
+                added_count += 1;
+            }
+        }
+
+        info!("Added {} peers from {:?} (total: {})", added_count, source, peers.len());
+        added_count
+    }
+
     /// Remove a peer from the manager
     pub async fn remove_peer(&self, addr: SocketAddr) {
         debug!("Removing peer: {}", addr);
@@ -139,8 +229,9 @@ impl PeerManager {
         
         for addr in peers_to_connect {
             info!("Connecting to peer: {}", addr);
-            match PeerConnection::connect(addr, info_hash, our_peer_id).await {
-                Ok(connection) => {
+            match self.open_connection(addr, info_hash, our_peer_id).await {
+                Ok(mut connection) => {
+                    self.apply_rate_limits(&mut connection).await;
                     let mut connections = self.active_connections.write().await;
                     connections.insert(addr, connection);
                     connected_count += 1;
@@ -161,6 +252,48 @@ impl PeerManager {
         Ok(connected_count)
     }
 
+    /// Connect to a peer, trying uTP first when it is enabled
+    async fn open_connection(&self, addr: SocketAddr, info_hash: [u8; 20], our_peer_id: [u8; 20]) -> Result<PeerConnection> {
+        if let Some(demux) = self.utp.iter().find(|demux| demux.local_addr().is_ipv6() == addr.is_ipv6()) {
+            match PeerConnection::connect_utp(demux, addr, info_hash, our_peer_id, self.encryption_policy).await {
+                Ok(connection) => return Ok(connection),
+                Err(e) => debug!("uTP connection to {} failed ({}), falling back to TCP", addr, e),
+            }
+        }
+        PeerConnection::connect_with_policy(addr, info_hash, our_peer_id, self.encryption_policy).await
+    }
+
+    /// Accept an incoming TCP or uTP connection
+    pub async fn accept_connection(&self, transport: impl Transport + 'static) -> Result<()> {
+        if !self.can_add_connection().await {
+            debug!("Rejecting incoming connection: no connection slots available");
+            return Ok(());
+        }
+
+        // Hybrid torrents accept peers from both swarms
+        let mut connection = PeerConnection::accept(
+            Box::new(transport),
+            &self.torrent_info.swarm_hashes(),
+            self.our_peer_id,
+            self.encryption_policy,
+        ).await?;
+        self.apply_rate_limits(&mut connection).await;
+        let addr = connection.peer_addr();
+
+        {
+            let mut peers = self.peers.write().await;
+            match peers.iter_mut().find(|p| p.addr == addr) {
+                Some(peer) => peer.set_state(PeerState::Connected),
+                None => peers.push(connection.peer.clone()),
+            }
+        }
+
+        let mut connections = self.active_connections.write().await;
+        connections.insert(addr, connection);
+        info!("Accepted incoming peer: {} (total connections: {})", addr, connections.len());
+        Ok(())
+    }
+
     /// Manage active connections (send keep-alive, etc.)
     pub async fn manage_connections(&self) -> Result<()> {
         let mut connections = self.active_connections.write().await;
@@ -271,6 +404,11 @@ impl PeerManager {
         self.peers.read().await.len()
     }
 
+    /// Get the number of known peers discovered from a source
+    pub async fn peer_count_from(&self, source: PeerSource) -> usize {
+        self.peers.read().await.iter().filter(|p| p.source == source).count()
+    }
+
     /// Check if we can add more connections
     pub async fn can_add_connection(&self) -> bool {
         self.active_connections.read().await.len() < self.max_connections
@@ -334,6 +472,9 @@ impl Default for PeerManager {
                 name: String::new(),
                 length: None,
                 files: None,
+                v2: None,
+                web_seeds: Vec::new(),
+                http_seeds: Vec::new(),
             }),
             Handshake::generate_peer_id(),
         )
@@ -355,6 +496,9 @@ mod tests {
             name: String::new(),
             length: None,
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         });
         
         let manager = PeerManager::new(10, torrent_info, Handshake::generate_peer_id());
@@ -375,6 +519,9 @@ mod tests {
             name: String::new(),
             length: None,
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         });
         
         let manager = PeerManager::new(10, torrent_info, Handshake::generate_peer_id());
@@ -397,6 +544,9 @@ mod tests {
             name: String::new(),
             length: None,
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         });
         
         let manager = PeerManager::new(10, torrent_info, Handshake::generate_peer_id());
@@ -420,6 +570,9 @@ mod tests {
             name: String::new(),
             length: None,
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         });
         
         let manager = PeerManager::new(2, torrent_info, Handshake::generate_peer_id());
@@ -438,6 +591,9 @@ mod tests {
             name: String::new(),
             length: None,
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         });
         
         let manager = PeerManager::new(10, torrent_info, Handshake::generate_peer_id());
@@ -457,4 +613,38 @@ mod tests {
             assert!(peer_addrs.contains(&addr));
         }
     }
+
+    #[tokio::test]
+    async fn test_rate_limits_apply_to_accepted_connections() {
+        let mut manager = PeerManager::default();
+        let global = RateLimits::new(1_000_000, 500_000);
+        manager.set_global_rate_limits(global.clone());
+        manager.torrent_rate_limits().set(200_000, 0);
+        manager.set_peer_rate_limits(50_000, 25_000).await;
+
+        let info_hash = manager.torrent_info.info_hash;
+        let (a, b) = crate::transport::MemoryTransport::pair("10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6881".parse().unwrap());
+        let (client, accepted) = tokio::join!(
+            PeerConnection::connect_over(Box::new(a), info_hash, [1u8; 20], EncryptionPolicy::Disabled),
+            manager.accept_connection(b),
+        );
+        client.unwrap();
+        accepted.unwrap();
+
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        {
+            let connections = manager.active_connections.read().await;
+            let connection = connections.get(&addr).unwrap();
+            assert_eq!(connection.rate_limits().download.rate(), 50_000);
+            assert_eq!(connection.rate_limits().upload.rate(), 25_000);
+        }
+        assert_eq!(manager.global_rate_limits().download.rate(), 1_000_000);
+        assert_eq!(manager.torrent_rate_limits().download.rate(), 200_000);
+
+        // Adjusting at runtime reaches live connections
+        manager.set_peer_rate_limits(0, 10_000).await;
+        let connections = manager.active_connections.read().await;
+        assert!(connections.get(&addr).unwrap().rate_limits().download.is_unlimited());
+        assert_eq!(connections.get(&addr).unwrap().rate_limits().upload.rate(), 10_000);
+    }
 }
@@ -6,7 +6,14 @@ pub mod connection;
 pub mod manager;
 pub mod state;
 
+#[cfg(feature = "download")]
+pub mod web_seed;
+
 // Re-export main types
 pub use connection::PeerConnection;
 pub use manager::PeerManager;
 pub use state::{Peer, PeerState, PeerInfo, PeerSource, PeerStats};
+
+// Re-export web seed types
+#[cfg(feature = "download")]
+pub use web_seed::{WebSeed, WebSeedKind, WebSeedStats};
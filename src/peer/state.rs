@@ -112,6 +112,8 @@ pub struct Peer {
     pub pieces_downloaded: u32,
     /// Pieces uploaded to this peer
     pub pieces_uploaded: u32,
+    /// Where the peer was discovered from
+    pub source: PeerSource,
 }
 
 impl Peer {
@@ -128,9 +130,17 @@ impl Peer {
             bitfield: None,
             pieces_downloaded: 0,
             pieces_uploaded: 0,
+            source: PeerSource::Manual,
         }
     }
 
+    /// Create a new peer discovered from the given source
+    pub fn with_source(addr: SocketAddr, source: PeerSource) -> Self {
+        let mut peer = Self::new(addr);
+        peer.source = source;
+        peer
+    }
+
     /// Create a new peer with peer ID
     pub fn with_peer_id(addr: SocketAddr, peer_id: [u8; 20]) -> Self {
         let mut peer = Self::new(addr);
@@ -175,6 +185,7 @@ impl Peer {
             pieces_downloaded: self.pieces_downloaded,
             pieces_uploaded: self.pieces_uploaded,
             has_bitfield: self.bitfield.is_some(),
+            source: self.source,
         }
     }
 
@@ -231,6 +242,8 @@ pub struct PeerStats {
     pub pieces_uploaded: u32,
     /// Whether peer has sent bitfield
     pub has_bitfield: bool,
+    /// Where the peer was discovered from
+    pub source: PeerSource,
 }
 
 impl PeerStats {
@@ -258,6 +271,16 @@ mod tests {
         assert!(!peer.peer_interested);
         assert_eq!(peer.pieces_downloaded, 0);
         assert_eq!(peer.pieces_uploaded, 0);
+        assert_eq!(peer.source, PeerSource::Manual);
+    }
+
+    #[test]
+    fn test_peer_with_source() {
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let peer = Peer::with_source(addr, PeerSource::DHT);
+
+        assert_eq!(peer.source, PeerSource::DHT);
+        assert_eq!(peer.stats().source, PeerSource::DHT);
     }
 
     #[test]
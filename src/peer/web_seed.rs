@@ -0,0 +1,528 @@
+//! Web seeds
+//!
+//! Downloads pieces over HTTP from GetRight-style web seeds (`url-list`,
+//! BEP 19), which serve the torrent's files and take Range requests, and
+//! Hoffman-style HTTP seeds (`httpseeds`, BEP 17), which serve whole pieces.
+
+#![cfg(feature = "download")]
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use reqwest::{header, StatusCode};
+use tokio::sync::RwLock;
+use tracing::{debug, error, trace, warn};
+use crate::error::TorrentError;
+use crate::torrent::TorrentInfo;
+
+/// Timeout of one HTTP request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait after the first failure; doubled for each further failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Longest wait between attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Result of a request, with how long the seed asked us to wait on failure
+type FetchResult<T> = std::result::Result<T, (anyhow::Error, Option<Duration>)>;
+
+/// Kind of web seed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// Serves the files; pieces map to Range requests (BEP 19)
+    GetRight,
+    /// Serves pieces by index (BEP 17)
+    Hoffman,
+}
+
+impl fmt::Display for WebSeedKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSeedKind::GetRight => write!(f, "url-list"),
+            WebSeedKind::Hoffman => write!(f, "httpseed"),
+        }
+    }
+}
+
+/// Web seed statistics
+#[derive(Debug, Clone)]
+pub struct WebSeedStats {
+    /// Seed URL
+    pub url: String,
+    /// Kind of seed
+    pub kind: WebSeedKind,
+    /// Bytes downloaded from this seed
+    pub bytes_downloaded: u64,
+    /// Pieces downloaded from this seed
+    pub pieces_downloaded: u32,
+    /// Failures since the last good piece
+    pub failures: u32,
+    /// Whether a piece is being downloaded
+    pub busy: bool,
+    /// Whether the seed is waiting after failures
+    pub backing_off: bool,
+}
+
+/// Mutable state of a web seed
+#[derive(Debug, Default)]
+struct WebSeedState {
+    busy: bool,
+    failures: u32,
+    retry_at: Option<Instant>,
+    bytes_downloaded: u64,
+    pieces_downloaded: u32,
+}
+
+/// A web seed, used like a peer that always has every piece
+#[derive(Debug)]
+pub struct WebSeed {
+    /// Seed URL
+    url: String,
+    /// Kind of seed
+    kind: WebSeedKind,
+    /// Torrent being downloaded
+    torrent_info: Arc<TorrentInfo>,
+    /// HTTP client
+    client: reqwest::Client,
+    /// Download state
+    state: RwLock<WebSeedState>,
+}
+
+impl WebSeed {
+    /// Create a web seed
+    pub fn new(url: impl Into<String>, kind: WebSeedKind, torrent_info: Arc<TorrentInfo>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            url: url.into(),
+            kind,
+            torrent_info,
+            client,
+            state: RwLock::new(WebSeedState::default()),
+        }
+    }
+
+    /// Create the web seeds listed in a torrent
+    pub fn from_torrent(torrent_info: &Arc<TorrentInfo>) -> Vec<Self> {
+        let web_seeds = torrent_info.web_seeds.iter()
+            .map(|url| Self::new(url.clone(), WebSeedKind::GetRight, torrent_info.clone()));
+        let http_seeds = torrent_info.http_seeds.iter()
+            .map(|url| Self::new(url.clone(), WebSeedKind::Hoffman, torrent_info.clone()));
+        web_seeds.chain(http_seeds).collect()
+    }
+
+    /// Get the seed URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Get the kind of seed
+    pub fn kind(&self) -> WebSeedKind {
+        self.kind
+    }
+
+    /// Claim the seed for a piece, if it is idle and not backing off
+    pub async fn try_claim(&self) -> bool {
+        let mut state = self.state.write().await;
+        if state.busy || state.retry_at.is_some_and(|at| Instant::now() < at) {
+            return false;
+        }
+        state.busy = true;
+        true
+    }
+
+    /// Release the seed after a piece
+    pub async fn release(&self) {
+        self.state.write().await.busy = false;
+    }
+
+    /// Record a good piece, which ends any backoff
+    pub async fn record_success(&self, bytes: usize) {
+        let mut state = self.state.write().await;
+        state.failures = 0;
+        state.retry_at = None;
+        state.bytes_downloaded += bytes as u64;
+        state.pieces_downloaded += 1;
+    }
+
+    /// Record a failed request or bad piece and back off
+    ///
+    /// Waits `retry_after` if the seed asked for it, otherwise doubles the
+    /// wait with each failure.
+    pub async fn record_failure(&self, retry_after: Option<Duration>) {
+        let mut state = self.state.write().await;
+        state.failures = state.failures.saturating_add(1);
+        let backoff = retry_after.unwrap_or_else(|| {
+            INITIAL_BACKOFF.saturating_mul(1 << (state.failures - 1).min(16)).min(MAX_BACKOFF)
+        });
+        state.retry_at = Some(Instant::now() + backoff);
+        warn!("Web seed {} failed {} times, retrying in {:?}", self.url, state.failures, backoff);
+    }
+
+    /// Get statistics
+    pub async fn stats(&self) -> WebSeedStats {
+        let state = self.state.read().await;
+        WebSeedStats {
+            url: self.url.clone(),
+            kind: self.kind,
+            bytes_downloaded: state.bytes_downloaded,
+            pieces_downloaded: state.pieces_downloaded,
+            failures: state.failures,
+            busy: state.busy,
+            backing_off: state.retry_at.is_some_and(|at| Instant::now() < at),
+        }
+    }
+
+    /// Download a piece
+    ///
+    /// The data is not verified here. Failed requests back the seed off.
+    pub async fn fetch_piece(&self, piece_index: u32) -> Result<Vec<u8>> {
+        let (start, end) = self.torrent_info.piece_range(piece_index as usize)
+            .ok_or_else(|| {
+                error!("Invalid piece index: {}", piece_index);
+                TorrentError::validation_error_with_field("Invalid piece index", "piece_index".to_string())
+            })?;
+        debug!("Fetching piece {} from web seed {}", piece_index, self.url);
+
+        let result = match self.kind {
+            WebSeedKind::GetRight => self.fetch_range(start, end).await,
+            WebSeedKind::Hoffman => self.fetch_hoffman_piece(piece_index, end - start).await,
+        };
+        match result {
+            Ok(data) => {
+                trace!("Fetched {} bytes of piece {} from {}", data.len(), piece_index, self.url);
+                Ok(data)
+            }
+            Err((e, retry_after)) => {
+                self.record_failure(retry_after).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Fetch a byte range of the torrent, one Range request per file
+    async fn fetch_range(&self, start: u64, end: u64) -> FetchResult<Vec<u8>> {
+        let mut data = Vec::with_capacity((end - start) as usize);
+        let mut file_start = 0u64;
+        let files: Vec<_> = self.torrent_info.files_iter().collect();
+        for file in files {
+            let file_end = file_start + file.length;
+            let (from, to) = (start.max(file_start), end.min(file_end));
+            if from < to {
+                if file.is_padding() {
+                    // Padding files aren't served (BEP 47)
+                    data.resize(data.len() + (to - from) as usize, 0);
+                } else {
+                    let url = self.file_url(&file.path).map_err(|e| (e, None))?;
+                    let chunk = self.fetch_file_range(&url, from - file_start, to - file_start).await?;
+                    data.extend_from_slice(&chunk);
+                }
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+        Ok(data)
+    }
+
+    /// Fetch `[from, to)` of one file
+    async fn fetch_file_range(&self, url: &str, from: u64, to: u64) -> FetchResult<Vec<u8>> {
+        trace!("GET {} bytes {}-{}", url, from, to - 1);
+        let response = self.client.get(url)
+            .header(header::RANGE, format!("bytes={}-{}", from, to - 1))
+            .send()
+            .await
+            .map_err(|e| (self.http_error("Web seed request failed", e.to_string()), None))?;
+        let status = response.status();
+        let retry_after = retry_after_header(&response);
+        if !status.is_success() {
+            return Err((self.http_error("Web seed returned an error", status.to_string()), retry_after));
+        }
+
+        // A server that ignores Range would send the whole file for every
+        // piece; only a range covering the whole file may be answered with 200
+        let length = (to - from) as usize;
+        let whole_file = from == 0 && response.content_length() == Some(length as u64);
+        if status != StatusCode::PARTIAL_CONTENT && !whole_file {
+            return Err((self.http_error("Web seed doesn't support range requests", status.to_string()), None));
+        }
+
+        let body = self.read_body(response, length).await?;
+        if body.len() != length {
+            return Err((self.http_error("Web seed sent the wrong amount of data", format!("{} bytes for a {} byte range", body.len(), length)), None));
+        }
+        Ok(body)
+    }
+
+    /// Read a response body, giving up once it is longer than expected
+    async fn read_body(&self, mut response: reqwest::Response, length: usize) -> FetchResult<Vec<u8>> {
+        let mut body = Vec::with_capacity(length);
+        while let Some(chunk) = response.chunk().await
+            .map_err(|e| (self.http_error("Failed to read web seed response", e.to_string()), None))?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() > length {
+                return Err((self.http_error("Web seed sent too much data", format!("more than {} bytes", length)), None));
+            }
+        }
+        Ok(body)
+    }
+
+    /// Fetch a whole piece from a Hoffman-style seed
+    async fn fetch_hoffman_piece(&self, piece_index: u32, length: u64) -> FetchResult<Vec<u8>> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}info_hash={}&piece={}", self.url, separator, url_encode_bytes(&self.torrent_info.info_hash), piece_index);
+        trace!("GET {}", url);
+        let response = self.client.get(&url)
+            .send()
+            .await
+            .map_err(|e| (self.http_error("HTTP seed request failed", e.to_string()), None))?;
+        let status = response.status();
+        let retry_after = retry_after_header(&response);
+        let body = self.read_body(response, length as usize).await?;
+
+        // A busy seed answers 503 with the seconds to wait as the body
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let wait = std::str::from_utf8(&body).ok()
+                .and_then(|body| body.trim().parse().ok())
+                .map(Duration::from_secs)
+                .or(retry_after);
+            return Err((self.http_error("HTTP seed is busy", status.to_string()), wait));
+        }
+        if !status.is_success() {
+            return Err((self.http_error("HTTP seed returned an error", status.to_string()), retry_after));
+        }
+        if body.len() as u64 != length {
+            return Err((self.http_error("HTTP seed sent the wrong amount of data", format!("{} bytes for a {} byte piece", body.len(), length)), None));
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Get the URL of a file on a GetRight-style seed
+    fn file_url(&self, path: &[String]) -> Result<String> {
+        let mut url = url::Url::parse(&self.url)
+            .map_err(|e| self.http_error("Invalid web seed URL", e.to_string()))?;
+        // A URL ending in a slash is a directory holding the torrent
+        if self.torrent_info.is_multi_file() || self.url.ends_with('/') {
+            let mut segments = url.path_segments_mut()
+                .map_err(|_| self.http_error("Invalid web seed URL", "cannot be a base".to_string()))?;
+            segments.pop_if_empty().push(&self.torrent_info.name);
+            if self.torrent_info.is_multi_file() {
+                segments.extend(path);
+            }
+        }
+        Ok(url.to_string())
+    }
+
+    fn http_error(&self, message: &str, detail: String) -> anyhow::Error {
+        debug!("{} ({}): {}", message, self.url, detail);
+        TorrentError::peer_error_full(message, self.url.clone(), detail).into()
+    }
+}
+
+/// Get the Retry-After header in seconds
+fn retry_after_header(response: &reqwest::Response) -> Option<Duration> {
+    response.headers().get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Percent-encode raw bytes for a query string
+fn url_encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::peer::PeerManager;
+    use crate::storage::{FileDownloadManager, FileStorage};
+    use crate::torrent::{FileAttributes, TorrentFile};
+
+    /// Serve static files; 206 entries honour Range, 200 entries ignore it and
+    /// 503 entries are busy. A query is looked up with its path
+    async fn serve(files: HashMap<String, (u16, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else { break };
+                let files = files.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    let target = request.split_whitespace().nth(1).unwrap_or_default().to_string();
+                    let range = request.lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                        .and_then(|range| {
+                            let (from, to) = range.trim().split_once('-')?;
+                            Some((from.parse::<usize>().ok()?, to.parse::<usize>().ok()?))
+                        });
+                    let (status, body) = match (files.get(&target), range) {
+                        (Some((206, body)), Some((from, to))) => ("206 Partial Content", body[from..=to].to_vec()),
+                        (Some((206 | 200, body)), _) => ("200 OK", body.clone()),
+                        (Some((503, body)), _) => ("503 Service Unavailable", body.clone()),
+                        _ => ("404 Not Found", Vec::new()),
+                    };
+                    let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Torrent of a.bin (20 bytes), 12 bytes of padding and "b file.bin" (30 bytes)
+    fn torrent(web_seeds: Vec<String>, http_seeds: Vec<String>) -> (Arc<TorrentInfo>, Vec<u8>, Vec<u8>) {
+        let a: Vec<u8> = (0..20).collect();
+        let b: Vec<u8> = (100..130).collect();
+        let mut content = a.clone();
+        content.resize(32, 0);
+        content.extend_from_slice(&b);
+        let files = vec![
+            TorrentFile::new(vec!["a.bin".to_string()], 20),
+            TorrentFile {
+                attr: FileAttributes { padding: true, ..Default::default() },
+                ..TorrentFile::new(vec![".pad".to_string(), "12".to_string()], 12)
+            },
+            TorrentFile::new(vec!["b file.bin".to_string()], 30),
+        ];
+        let info = TorrentInfo {
+            announce: String::new(),
+            announce_list: vec![],
+            info_hash: [0xab; 20],
+            piece_length: 32,
+            pieces: content.chunks(32).map(TorrentInfo::generate_info_hash).collect(),
+            name: "root".to_string(),
+            length: None,
+            files: Some(files),
+            v2: None,
+            web_seeds,
+            http_seeds,
+        };
+        (Arc::new(info), a, b)
+    }
+
+    #[tokio::test]
+    async fn test_get_right_seed_maps_pieces_to_ranges() {
+        let (_, a, b) = torrent(vec![], vec![]);
+        let whole: Vec<u8> = (0..40).collect();
+        let base = serve(HashMap::from([
+            ("/no-range/root".to_string(), (200, whole.clone())),
+            ("/seed/root/a.bin".to_string(), (206, a.clone())),
+            ("/seed/root/b%20file.bin".to_string(), (206, b.clone())),
+            ("/no-range/root/a.bin".to_string(), (200, a.clone())),
+            ("/no-range/root/b%20file.bin".to_string(), (200, b.clone())),
+        ])).await;
+        let (info, _, _) = torrent(vec![format!("{}/seed/", base)], vec![]);
+        let seed = &WebSeed::from_torrent(&info)[0];
+        assert_eq!(seed.kind(), WebSeedKind::GetRight);
+
+        let mut expected = a.clone();
+        expected.resize(32, 0);
+        assert_eq!(seed.fetch_piece(0).await.unwrap(), expected);
+        assert_eq!(seed.fetch_piece(1).await.unwrap(), b);
+        assert!(seed.fetch_piece(2).await.is_err());
+
+        // A server that ignores Range is only used for whole files
+        let (info, _, _) = torrent(vec![format!("{}/no-range/", base)], vec![]);
+        let seed = &WebSeed::from_torrent(&info)[0];
+        assert_eq!(seed.fetch_piece(1).await.unwrap(), b);
+        let single = TorrentInfo {
+            length: Some(40),
+            files: None,
+            pieces: whole.chunks(32).map(TorrentInfo::generate_info_hash).collect(),
+            ..(*info).clone()
+        };
+        let seed = &WebSeed::from_torrent(&Arc::new(single))[0];
+        assert!(seed.fetch_piece(1).await.is_err());
+        assert!(seed.stats().await.backing_off);
+
+        // A missing file backs the seed off
+        let (info, _, _) = torrent(vec![format!("{}/missing/", base)], vec![]);
+        let seed = &WebSeed::from_torrent(&info)[0];
+        assert!(seed.fetch_piece(0).await.is_err());
+        let stats = seed.stats().await;
+        assert_eq!(stats.failures, 1);
+        assert!(stats.backing_off);
+        assert!(!seed.try_claim().await);
+    }
+
+    #[tokio::test]
+    async fn test_hoffman_seed_and_retry_after() {
+        let (_, a, _) = torrent(vec![], vec![]);
+        let mut piece = a.clone();
+        piece.resize(32, 0);
+        let info_hash = "%AB".repeat(20);
+        let base = serve(HashMap::from([
+            (format!("/seed?info_hash={}&piece=0", info_hash), (200, piece.clone())),
+            (format!("/busy?info_hash={}&piece=0", info_hash), (503, b"7".to_vec())),
+        ])).await;
+
+        let (info, _, _) = torrent(vec![], vec![format!("{}/seed", base), format!("{}/busy", base)]);
+        let seeds = WebSeed::from_torrent(&info);
+        assert_eq!(seeds[0].kind(), WebSeedKind::Hoffman);
+        assert_eq!(seeds[0].fetch_piece(0).await.unwrap(), piece);
+
+        assert!(seeds[1].fetch_piece(0).await.is_err());
+        let retry_at = seeds[1].state.read().await.retry_at.unwrap();
+        let wait = retry_at - Instant::now();
+        assert!(wait > Duration::from_secs(6) && wait <= Duration::from_secs(7));
+    }
+
+    #[tokio::test]
+    async fn test_download_from_web_seed() {
+        let (_, a, b) = torrent(vec![], vec![]);
+        let base = serve(HashMap::from([
+            ("/root/a.bin".to_string(), (206, a.clone())),
+            ("/root/b%20file.bin".to_string(), (206, b.clone())),
+        ])).await;
+        let (info, _, _) = torrent(vec![format!("{}/", base)], vec![]);
+
+        let base_path = std::env::temp_dir().join(format!("test_web_seed_download_{}", std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&base_path).await;
+        let storage = FileStorage::new(base_path.clone(), info.clone()).await.unwrap();
+        let manager = FileDownloadManager::new(Arc::new(RwLock::new(storage)), Arc::new(PeerManager::default()));
+        for seed in WebSeed::from_torrent(&info) {
+            manager.add_web_seed(seed).await;
+        }
+
+        manager.start_download(info.files_iter().collect()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            // Pieces count as verified once they are written
+            while manager.get_stats().await.pieces_verified < 2 {
+                let _ = manager.request_next_pieces().await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }).await.unwrap();
+
+        assert_eq!(tokio::fs::read(base_path.join("a.bin")).await.unwrap(), a);
+        assert_eq!(tokio::fs::read(base_path.join("b file.bin")).await.unwrap(), b);
+        assert!(manager.is_complete().await);
+        assert_eq!(manager.web_seed_stats().await[0].pieces_downloaded, 2);
+
+        let _ = tokio::fs::remove_dir_all(&base_path).await;
+    }
+}
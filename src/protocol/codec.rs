@@ -0,0 +1,132 @@
+//! Peer wire codec
+//!
+//! `tokio_util` Decoder/Encoder for length-prefixed peer messages, used with
+//! `Framed` once the handshake is done.
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::trace;
+
+use super::wire::{read_frame, DEFAULT_MAX_MESSAGE_SIZE};
+use super::Message;
+
+/// Codec for peer wire messages with a maximum message size
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCodec {
+    max_message_size: usize,
+}
+
+impl PeerCodec {
+    /// Create a codec with the default message size limit
+    pub fn new() -> Self {
+        Self::with_max_message_size(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    /// Create a codec rejecting messages longer than `max_message_size` bytes
+    pub fn with_max_message_size(max_message_size: usize) -> Self {
+        Self { max_message_size }
+    }
+
+    /// Get the message size limit
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Set the message size limit
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+}
+
+impl Default for PeerCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = Message;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Self::Error> {
+        match read_frame(src, self.max_message_size)? {
+            Some(frame) => {
+                trace!("Decoded frame of {} bytes", frame.len());
+                Message::from_frame(frame).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<&Message> for PeerCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        message.encode(dst);
+        Ok(())
+    }
+}
+
+impl Encoder<Message> for PeerCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        message.encode(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, Bytes};
+
+    #[test]
+    fn test_decode_partial_and_multiple_messages() {
+        let mut codec = PeerCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Message::Interested, &mut buf).unwrap();
+        codec.encode(&Message::Have { piece_index: 7 }, &mut buf).unwrap();
+        codec.encode(Message::KeepAlive, &mut buf).unwrap();
+
+        let mut partial = buf.split_to(3);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        let mut buf = partial;
+
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(Message::Interested)));
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(Message::Have { piece_index: 7 })));
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(Message::KeepAlive)));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_piece_without_copy() {
+        let mut codec = PeerCodec::new();
+        let mut buf = BytesMut::new();
+        let block = Bytes::from(vec![0xab; 16384]);
+        codec.encode(Message::Piece { index: 1, begin: 16384, block: block.clone() }, &mut buf).unwrap();
+        let start = buf.as_ptr() as usize;
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Message::Piece { index, begin, block: received }) => {
+                assert_eq!((index, begin), (1, 16384));
+                assert_eq!(received, block);
+                // The block points into the receive buffer: 4 byte prefix, id, index, begin
+                assert_eq!(received.as_ptr() as usize, start + 13);
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_message() {
+        let mut codec = PeerCodec::with_max_message_size(1024);
+        let mut buf = BytesMut::new();
+        buf.put_u32(1025);
+        buf.put_u8(7);
+        assert!(codec.decode(&mut buf).is_err());
+        assert_eq!(codec.max_message_size(), 1024);
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! Defines all message types used in the BitTorrent protocol.
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use anyhow::Result;
 use tracing::{debug, error, trace};
 
@@ -22,6 +22,9 @@ pub enum MessageId {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
 }
 
 impl TryFrom<u8> for MessageId {
@@ -40,6 +43,9 @@ impl TryFrom<u8> for MessageId {
             7 => Ok(MessageId::Piece),
             8 => Ok(MessageId::Cancel),
             9 => Ok(MessageId::Port),
+            21 => Ok(MessageId::HashRequest),
+            22 => Ok(MessageId::Hashes),
+            23 => Ok(MessageId::HashReject),
             _ => {
                 error!("Invalid message ID: {}", value);
                 Err(TorrentError::protocol_error_with_source(
@@ -51,6 +57,53 @@ impl TryFrom<u8> for MessageId {
     }
 }
 
+/// Range of merkle hashes asked for in v2 hash messages (BEP 52)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    /// Root of the file's merkle tree
+    pub pieces_root: [u8; 32],
+    /// Layer of the requested hashes, 0 being the leaves
+    pub base_layer: u32,
+    /// Offset of the first hash in the layer
+    pub index: u32,
+    /// Number of hashes
+    pub length: u32,
+    /// Number of uncle hashes wanted to prove the range
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    /// Encoded size in bytes
+    const LEN: usize = 48;
+
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_slice(&self.pieces_root);
+        buf.put_u32(self.base_layer);
+        buf.put_u32(self.index);
+        buf.put_u32(self.length);
+        buf.put_u32(self.proof_layers);
+    }
+
+    fn decode(buf: &mut Bytes, name: &str) -> Result<Self> {
+        if buf.remaining() < Self::LEN {
+            error!("{} message too short: expected {} bytes, got {}", name, Self::LEN, buf.remaining());
+            return Err(TorrentError::protocol_error_with_source(
+                format!("{} message too short", name),
+                format!("expected {} bytes, got {}", Self::LEN, buf.remaining())
+            ).into());
+        }
+        let mut pieces_root = [0u8; 32];
+        buf.copy_to_slice(&mut pieces_root);
+        Ok(Self {
+            pieces_root,
+            base_layer: buf.get_u32(),
+            index: buf.get_u32(),
+            length: buf.get_u32(),
+            proof_layers: buf.get_u32(),
+        })
+    }
+}
+
 /// BitTorrent protocol message
 #[derive(Debug, Clone)]
 pub enum Message {
@@ -62,9 +115,12 @@ pub enum Message {
     Have { piece_index: u32 },
     Bitfield { bitfield: Vec<u8> },
     Request { index: u32, begin: u32, length: u32 },
-    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Piece { index: u32, begin: u32, block: Bytes },
     Cancel { index: u32, begin: u32, length: u32 },
     Port { listen_port: u16 },
+    HashRequest(HashRequest),
+    Hashes { request: HashRequest, hashes: Vec<[u8; 32]> },
+    HashReject(HashRequest),
 }
 
 impl Message {
@@ -81,6 +137,9 @@ impl Message {
             Message::Piece { .. } => Some(MessageId::Piece),
             Message::Cancel { .. } => Some(MessageId::Cancel),
             Message::Port { .. } => Some(MessageId::Port),
+            Message::HashRequest(_) => Some(MessageId::HashRequest),
+            Message::Hashes { .. } => Some(MessageId::Hashes),
+            Message::HashReject(_) => Some(MessageId::HashReject),
             Message::KeepAlive => None,
         }
     }
@@ -99,6 +158,8 @@ impl Message {
             Message::Piece { block, .. } => 9 + block.len() as u32,
             Message::Cancel { .. } => 13,
             Message::Port { .. } => 3,
+            Message::HashRequest(_) | Message::HashReject(_) => 1 + HashRequest::LEN as u32,
+            Message::Hashes { hashes, .. } => 1 + HashRequest::LEN as u32 + 32 * hashes.len() as u32,
         }
     }
 
@@ -112,8 +173,15 @@ impl Message {
 
     /// Serialize the message to bytes (including length prefix)
     pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(4 + self.length() as usize);
+        self.encode(&mut buf);
+        buf.to_vec()
+    }
+
+    /// Append the message (including length prefix) to a buffer
+    pub fn encode(&self, buf: &mut BytesMut) {
         trace!("Serializing message: {:?}", self.message_id());
-        let mut buf = BytesMut::new();
+        buf.reserve(4 + self.length() as usize);
 
         // Write length prefix
         buf.put_u32(self.length());
@@ -164,24 +232,39 @@ impl Message {
                 buf.put_u8(MessageId::Port as u8);
                 buf.put_u16(*listen_port);
             }
+            Message::HashRequest(request) => {
+                buf.put_u8(MessageId::HashRequest as u8);
+                request.encode(buf);
+            }
+            Message::Hashes { request, hashes } => {
+                buf.put_u8(MessageId::Hashes as u8);
+                request.encode(buf);
+                for hash in hashes {
+                    buf.put_slice(hash);
+                }
+            }
+            Message::HashReject(request) => {
+                buf.put_u8(MessageId::HashReject as u8);
+                request.encode(buf);
+            }
         }
 
-        trace!("Message serialized: {} bytes", buf.len());
-        buf.to_vec()
+        trace!("Message serialized: {} bytes", self.length() + 4);
     }
 
     /// Deserialize a message from bytes (including length prefix)
     pub fn deserialize(data: &[u8]) -> Result<Self> {
         trace!("Deserializing message from {} bytes", data.len());
-        let mut buf = BytesMut::from(data);
-
-        if buf.is_empty() {
-            error!("Empty message data");
-            return Err(TorrentError::protocol_error("Empty message data").into());
+        if data.len() < 4 {
+            error!("Message too short: missing length prefix");
+            return Err(TorrentError::protocol_error_with_source(
+                "Message too short",
+                "missing length prefix"
+            ).into());
         }
 
         // Read length prefix
-        let length = buf.get_u32() as usize;
+        let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
         debug!("Message length prefix: {}", length);
 
         // KeepAlive message has length 0 and no message ID
@@ -191,7 +274,7 @@ impl Message {
         }
 
         // Check if we have enough data for the message ID
-        if buf.remaining() < 1 {
+        if data.len() < 5 {
             error!("Message too short: missing message ID");
             return Err(TorrentError::protocol_error_with_source(
                 "Message too short",
@@ -199,6 +282,18 @@ impl Message {
             ).into());
         }
 
+        Self::from_frame(Bytes::copy_from_slice(&data[4..]))
+    }
+
+    /// Parse a message from its payload (without the length prefix)
+    ///
+    /// Piece blocks are sliced out of the frame without copying.
+    pub fn from_frame(mut buf: Bytes) -> Result<Self> {
+        if buf.is_empty() {
+            debug!("Received KeepAlive message");
+            return Ok(Message::KeepAlive);
+        }
+
         let id = buf.get_u8();
         let message_id = MessageId::try_from(id)?;
         debug!("Message ID: {:?}", message_id);
@@ -261,7 +356,7 @@ impl Message {
                 }
                 let index = buf.get_u32();
                 let begin = buf.get_u32();
-                let block = buf.to_vec();
+                let block = buf;
                 debug!("Received Piece message: index={}, begin={}, block_len={}", index, begin, block.len());
                 Ok(Message::Piece { index, begin, block })
             }
@@ -291,6 +386,29 @@ impl Message {
                 debug!("Received Port message: listen_port={}", listen_port);
                 Ok(Message::Port { listen_port })
             }
+            MessageId::HashRequest => {
+                let request = HashRequest::decode(&mut buf, "Hash request")?;
+                debug!("Received HashRequest message: base_layer={}, index={}, length={}", request.base_layer, request.index, request.length);
+                Ok(Message::HashRequest(request))
+            }
+            MessageId::Hashes => {
+                let request = HashRequest::decode(&mut buf, "Hashes")?;
+                if !buf.remaining().is_multiple_of(32) {
+                    error!("Hashes message has {} trailing bytes", buf.remaining() % 32);
+                    return Err(TorrentError::protocol_error_with_source(
+                        "Invalid Hashes message",
+                        format!("{} bytes of hashes is not a multiple of 32", buf.remaining())
+                    ).into());
+                }
+                let hashes = buf.chunks_exact(32).map(|hash| hash.try_into().unwrap()).collect::<Vec<_>>();
+                debug!("Received Hashes message: base_layer={}, index={}, {} hashes", request.base_layer, request.index, hashes.len());
+                Ok(Message::Hashes { request, hashes })
+            }
+            MessageId::HashReject => {
+                let request = HashRequest::decode(&mut buf, "Hash reject")?;
+                debug!("Received HashReject message: base_layer={}, index={}, length={}", request.base_layer, request.index, request.length);
+                Ok(Message::HashReject(request))
+            }
         }
     }
 }
@@ -344,15 +462,15 @@ mod tests {
 
     #[test]
     fn test_message_serialize_deserialize_piece() {
-        let block = vec![1, 2, 3, 4, 5];
-        let message = Message::Piece { index: 10, begin: 0, block: block.clone() };
+        let expected = Bytes::from_static(&[1, 2, 3, 4, 5]);
+        let message = Message::Piece { index: 10, begin: 0, block: expected.clone() };
         let serialized = message.serialize();
         let deserialized = Message::deserialize(&serialized).unwrap();
         match deserialized {
             Message::Piece { index, begin, block } => {
                 assert_eq!(index, 10);
                 assert_eq!(begin, 0);
-                assert_eq!(block, block);
+                assert_eq!(block, expected);
             }
             _ => panic!("Wrong message type"),
         }
@@ -375,7 +493,7 @@ mod tests {
         assert_eq!(Message::Choke.length(), 1);
         assert_eq!(Message::Have { piece_index: 0 }.length(), 5);
         assert_eq!(Message::Request { index: 0, begin: 0, length: 0 }.length(), 13);
-        assert_eq!(Message::Piece { index: 0, begin: 0, block: vec![1, 2, 3] }.length(), 12);
+        assert_eq!(Message::Piece { index: 0, begin: 0, block: Bytes::from_static(&[1, 2, 3]) }.length(), 12);
         assert_eq!(Message::Port { listen_port: 0 }.length(), 3);
     }
 
@@ -388,7 +506,7 @@ mod tests {
         assert_eq!(Message::Have { piece_index: 0 }.message_id(), Some(MessageId::Have));
         assert_eq!(Message::Bitfield { bitfield: vec![] }.message_id(), Some(MessageId::Bitfield));
         assert_eq!(Message::Request { index: 0, begin: 0, length: 0 }.message_id(), Some(MessageId::Request));
-        assert_eq!(Message::Piece { index: 0, begin: 0, block: vec![] }.message_id(), Some(MessageId::Piece));
+        assert_eq!(Message::Piece { index: 0, begin: 0, block: Bytes::new() }.message_id(), Some(MessageId::Piece));
         assert_eq!(Message::Cancel { index: 0, begin: 0, length: 0 }.message_id(), Some(MessageId::Cancel));
         assert_eq!(Message::Port { listen_port: 0 }.message_id(), Some(MessageId::Port));
         assert_eq!(Message::KeepAlive.message_id(), None);
@@ -400,5 +518,25 @@ mod tests {
         assert_eq!(MessageId::try_from(1).unwrap(), MessageId::Unchoke);
         assert_eq!(MessageId::try_from(9).unwrap(), MessageId::Port);
         assert!(MessageId::try_from(10).is_err());
+        assert_eq!(MessageId::try_from(22).unwrap(), MessageId::Hashes);
+    }
+
+    #[test]
+    fn test_message_serialize_deserialize_hashes() {
+        let request = HashRequest { pieces_root: [9u8; 32], base_layer: 0, index: 4, length: 2, proof_layers: 1 };
+        let message = Message::Hashes { request, hashes: vec![[1u8; 32], [2u8; 32], [3u8; 32]] };
+        let serialized = message.serialize();
+        assert_eq!(serialized.len(), 4 + 49 + 96);
+        match Message::deserialize(&serialized).unwrap() {
+            Message::Hashes { request: received, hashes } => {
+                assert_eq!(received, request);
+                assert_eq!(hashes, vec![[1u8; 32], [2u8; 32], [3u8; 32]]);
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        let reject = Message::HashReject(request).serialize();
+        assert!(matches!(Message::deserialize(&reject).unwrap(), Message::HashReject(received) if received == request));
+        assert!(Message::deserialize(&reject[..20]).is_err());
     }
 }
@@ -2,11 +2,15 @@
 //!
 //! Implements the BitTorrent peer-to-peer protocol.
 
+pub mod codec;
 pub mod handshake;
 pub mod message;
+pub mod mse;
 pub mod wire;
 
 // Re-export main types
+pub use codec::PeerCodec;
 pub use handshake::{Handshake, PROTOCOL_STRING, PROTOCOL_LENGTH};
-pub use message::{Message, MessageId};
-pub use wire::{BitTorrentWire, WireProtocol, read_message, write_message};
+pub use message::{HashRequest, Message, MessageId};
+pub use mse::{EncryptionPolicy, MseStream};
+pub use wire::{BitTorrentWire, WireProtocol, read_frame, read_message, write_message, DEFAULT_MAX_MESSAGE_SIZE};
@@ -0,0 +1,733 @@
+//! Message Stream Encryption (MSE/PE)
+//!
+//! Implements the obfuscated handshake that precedes the BitTorrent handshake
+//! on encrypted connections: a Diffie-Hellman key exchange, RC4 stream
+//! obfuscation and crypto_provide/crypto_select negotiation.
+
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{debug, error, info, trace, warn};
+
+use super::handshake::{PROTOCOL_LENGTH, PROTOCOL_STRING};
+use crate::error::TorrentError;
+
+/// 768-bit safe prime used for the key exchange
+const DH_PRIME_HEX: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// Generator used for the key exchange
+const DH_GENERATOR: u32 = 2;
+
+/// Length of a public key and of the shared secret in bytes
+const KEY_LENGTH: usize = 96;
+
+/// Length of the private key in bytes (160 bits)
+const PRIVATE_KEY_LENGTH: usize = 20;
+
+/// Verification constant, sent encrypted to locate the end of the padding
+const VC: [u8; 8] = [0u8; 8];
+
+/// Maximum length of any padding field
+const MAX_PAD_LENGTH: usize = 512;
+
+/// Number of keystream bytes discarded after RC4 key setup
+const RC4_DISCARD: usize = 1024;
+
+/// crypto_provide/crypto_select bit for plaintext payloads
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+
+/// crypto_provide/crypto_select bit for RC4 payloads
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Encryption policy for peer connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Never use MSE; only plaintext connections
+    Disabled,
+    /// Use RC4 when possible, fall back to plaintext
+    #[default]
+    Prefer,
+    /// Only accept RC4-encrypted connections
+    Require,
+}
+
+impl EncryptionPolicy {
+    /// Get the crypto_provide bitfield we offer under this policy
+    pub fn crypto_provide(&self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Require => CRYPTO_RC4,
+        }
+    }
+
+    /// Pick a crypto method from the remote crypto_provide bitfield
+    pub fn crypto_select(&self, provide: u32) -> Option<u32> {
+        let allowed = self.crypto_provide() & provide;
+        if allowed & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if allowed & CRYPTO_PLAINTEXT != 0 {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionPolicy::Disabled => write!(f, "disabled"),
+            EncryptionPolicy::Prefer => write!(f, "prefer"),
+            EncryptionPolicy::Require => write!(f, "require"),
+        }
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = TorrentError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "disabled" | "disable" | "off" => Ok(EncryptionPolicy::Disabled),
+            "prefer" | "enabled" | "on" => Ok(EncryptionPolicy::Prefer),
+            "require" | "required" | "forced" => Ok(EncryptionPolicy::Require),
+            _ => Err(TorrentError::config_error_with_field(
+                format!("Unknown encryption policy '{}' (expected disabled, prefer or require)", s),
+                "encryption",
+            )),
+        }
+    }
+}
+
+/// RC4 stream cipher
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// Create a new RC4 cipher from a key
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypt or decrypt data in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+
+    /// Discard keystream bytes
+    fn discard(&mut self, count: usize) {
+        let mut scratch = vec![0u8; count];
+        self.apply(&mut scratch);
+    }
+}
+
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rc4").finish_non_exhaustive()
+    }
+}
+
+/// Diffie-Hellman key pair for the MSE key exchange
+struct DhKeyPair {
+    private_key: BigUint,
+    public_key: [u8; KEY_LENGTH],
+}
+
+impl DhKeyPair {
+    /// Generate a random key pair
+    fn generate() -> Self {
+        let private_bytes: [u8; PRIVATE_KEY_LENGTH] = rand::random();
+        let private_key = BigUint::from_bytes_be(&private_bytes);
+        let public = BigUint::from(DH_GENERATOR).modpow(&private_key, &dh_prime());
+        Self {
+            private_key,
+            public_key: to_key_bytes(&public),
+        }
+    }
+
+    /// Compute the shared secret S from the remote public key
+    fn shared_secret(&self, remote_public: &[u8]) -> [u8; KEY_LENGTH] {
+        let remote = BigUint::from_bytes_be(remote_public);
+        to_key_bytes(&remote.modpow(&self.private_key, &dh_prime()))
+    }
+}
+
+fn dh_prime() -> BigUint {
+    BigUint::parse_bytes(DH_PRIME_HEX, 16).expect("DH prime constant is valid hex")
+}
+
+/// Left-pad a number to the 96-byte wire representation
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut out = [0u8; KEY_LENGTH];
+    out[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+/// SHA1 over the concatenation of all parts
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// HASH('req2', SKEY) xor HASH('req3', S), used to identify the torrent
+fn obfuscated_skey(secret: &[u8], skey: &[u8; 20]) -> [u8; 20] {
+    let req2 = hash(&[b"req2", skey]);
+    let req3 = hash(&[b"req3", secret]);
+    let mut out = [0u8; 20];
+    for i in 0..20 {
+        out[i] = req2[i] ^ req3[i];
+    }
+    out
+}
+
+/// Build the RC4 cipher for one direction ("keyA" or "keyB")
+fn stream_cipher(label: &[u8], secret: &[u8], skey: &[u8; 20]) -> Rc4 {
+    let mut cipher = Rc4::new(&hash(&[label, secret, skey]));
+    cipher.discard(RC4_DISCARD);
+    cipher
+}
+
+fn random_padding() -> Vec<u8> {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let length = rng.gen_range(0..=MAX_PAD_LENGTH);
+    (0..length).map(|_| rng.gen()).collect()
+}
+
+fn mse_error(message: &str, source: impl Into<String>) -> anyhow::Error {
+    TorrentError::protocol_error_with_source(message, source).into()
+}
+
+/// Buffered reader used while the handshake is in progress
+///
+/// Sync points are found by scanning, so the reader may pull in more bytes
+/// than the handshake needs; those are handed over to the resulting stream.
+struct HandshakeReader {
+    buf: BytesMut,
+}
+
+impl HandshakeReader {
+    fn new() -> Self {
+        Self { buf: BytesMut::with_capacity(KEY_LENGTH + MAX_PAD_LENGTH + 64) }
+    }
+
+    /// Read more bytes from the stream into the buffer
+    async fn fill<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<()> {
+        let read = stream.read_buf(&mut self.buf).await?;
+        if read == 0 {
+            return Err(mse_error("Connection closed during encryption handshake", "unexpected EOF"));
+        }
+        trace!("MSE handshake buffered {} bytes", read);
+        Ok(())
+    }
+
+    /// Read exactly `length` bytes
+    async fn read_exact<S: AsyncRead + Unpin>(&mut self, stream: &mut S, length: usize) -> Result<BytesMut> {
+        while self.buf.len() < length {
+            self.fill(stream).await?;
+        }
+        Ok(self.buf.split_to(length))
+    }
+
+    /// Skip bytes until `pattern` has been consumed, allowing at most `max_skip` bytes before it
+    async fn sync_on<S: AsyncRead + Unpin>(&mut self, stream: &mut S, pattern: &[u8], max_skip: usize) -> Result<()> {
+        loop {
+            if let Some(pos) = self.buf.windows(pattern.len()).position(|w| w == pattern) {
+                if pos > max_skip {
+                    break;
+                }
+                self.buf.advance(pos + pattern.len());
+                return Ok(());
+            }
+            if self.buf.len() >= max_skip + pattern.len() {
+                break;
+            }
+            self.fill(stream).await?;
+        }
+        Err(mse_error("Encryption handshake failed", "sync pattern not found"))
+    }
+}
+
+/// A stream that transparently applies the negotiated MSE cipher
+///
+/// When plaintext was negotiated (or MSE was not used at all) data passes
+/// through unchanged.
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Already decrypted bytes to return before anything else
+    read_plain: BytesMut,
+    /// Raw bytes read during the handshake, not yet decrypted
+    read_raw: BytesMut,
+    /// Encrypted bytes accepted from the caller but not yet written
+    write_pending: BytesMut,
+}
+
+impl<S> MseStream<S> {
+    /// Wrap a stream without encryption
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            read_cipher: None,
+            write_cipher: None,
+            read_plain: BytesMut::new(),
+            read_raw: BytesMut::new(),
+            write_pending: BytesMut::new(),
+        }
+    }
+
+    /// Check if the payload stream is RC4-encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.read_cipher.is_some() && self.write_cipher.is_some()
+    }
+
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    /// Write out buffered ciphertext
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_pending.is_empty() {
+            let written = match Pin::new(&mut self.inner).poll_write(cx, &self.write_pending) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read_plain.is_empty() {
+            let n = this.read_plain.len().min(buf.remaining());
+            buf.put_slice(&this.read_plain[..n]);
+            this.read_plain.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        if !this.read_raw.is_empty() {
+            let n = this.read_raw.len().min(buf.remaining());
+            let mut chunk = this.read_raw.split_to(n);
+            if let Some(cipher) = this.read_cipher.as_mut() {
+                cipher.apply(&mut chunk);
+            }
+            buf.put_slice(&chunk);
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if let Some(cipher) = this.read_cipher.as_mut() {
+                    cipher.apply(&mut buf.filled_mut()[before..]);
+                }
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if !this.write_pending.is_empty() {
+            return Poll::Pending;
+        }
+
+        match this.write_cipher.as_mut() {
+            None => Pin::new(&mut this.inner).poll_write(cx, buf),
+            Some(cipher) => {
+                let start = this.write_pending.len();
+                this.write_pending.put_slice(buf);
+                cipher.apply(&mut this.write_pending[start..]);
+                // The data is accepted once encrypted; a pending drain finishes on flush
+                if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+impl<S> fmt::Debug for MseStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MseStream")
+            .field("encrypted", &self.is_encrypted())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Perform the outgoing (initiator) side of the MSE handshake
+///
+/// `info_hash` is used as SKEY. Returns a stream ready for the BitTorrent
+/// handshake, encrypted if RC4 was selected by the remote peer.
+pub async fn initiate<S>(mut stream: S, info_hash: [u8; 20], policy: EncryptionPolicy) -> Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if policy == EncryptionPolicy::Disabled {
+        debug!("Encryption disabled, skipping MSE handshake");
+        return Ok(MseStream::plaintext(stream));
+    }
+
+    info!("Starting MSE handshake (initiator, policy: {})", policy);
+    let keys = DhKeyPair::generate();
+
+    // 1. A->B: Ya, PadA
+    let mut out = BytesMut::with_capacity(KEY_LENGTH + MAX_PAD_LENGTH);
+    out.put_slice(&keys.public_key);
+    out.put_slice(&random_padding());
+    stream.write_all(&out).await?;
+    stream.flush().await?;
+    trace!("Sent public key and {} bytes of padding", out.len() - KEY_LENGTH);
+
+    // 2. B->A: Yb, PadB
+    let mut reader = HandshakeReader::new();
+    let remote_public = reader.read_exact(&mut stream, KEY_LENGTH).await?;
+    let secret = keys.shared_secret(&remote_public);
+
+    let mut encryptor = stream_cipher(b"keyA", &secret, &info_hash);
+    let mut decryptor = stream_cipher(b"keyB", &secret, &info_hash);
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    //    ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA))
+    let mut out = BytesMut::with_capacity(64);
+    out.put_slice(&hash(&[b"req1", &secret]));
+    out.put_slice(&obfuscated_skey(&secret, &info_hash));
+    let mut encrypted = BytesMut::with_capacity(16);
+    encrypted.put_slice(&VC);
+    encrypted.put_u32(policy.crypto_provide());
+    encrypted.put_u16(0); // len(PadC)
+    encrypted.put_u16(0); // len(IA), the BitTorrent handshake follows separately
+    encryptor.apply(&mut encrypted);
+    out.put_slice(&encrypted);
+    stream.write_all(&out).await?;
+    stream.flush().await?;
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    let mut expected_vc = VC;
+    decryptor.clone().apply(&mut expected_vc);
+    reader.sync_on(&mut stream, &expected_vc, MAX_PAD_LENGTH).await?;
+    decryptor.apply(&mut [0u8; 8]);
+
+    let mut header = reader.read_exact(&mut stream, 6).await?;
+    decryptor.apply(&mut header);
+    let crypto_select = header.get_u32();
+    let pad_length = header.get_u16() as usize;
+    if pad_length > MAX_PAD_LENGTH {
+        error!("MSE padD too long: {}", pad_length);
+        return Err(mse_error("Encryption handshake failed", format!("padD length {} too long", pad_length)));
+    }
+    let mut pad = reader.read_exact(&mut stream, pad_length).await?;
+    decryptor.apply(&mut pad);
+
+    if crypto_select & policy.crypto_provide() == 0 || crypto_select.count_ones() != 1 {
+        warn!("Peer selected unsupported crypto method: 0x{:x}", crypto_select);
+        return Err(mse_error("Encryption handshake failed", format!("invalid crypto_select 0x{:x}", crypto_select)));
+    }
+
+    let encrypted = crypto_select == CRYPTO_RC4;
+    info!("MSE handshake complete (initiator, {})", if encrypted { "rc4" } else { "plaintext" });
+    Ok(MseStream {
+        inner: stream,
+        read_cipher: encrypted.then_some(decryptor),
+        write_cipher: encrypted.then_some(encryptor),
+        read_plain: BytesMut::new(),
+        read_raw: reader.buf,
+        write_pending: BytesMut::new(),
+    })
+}
+
+/// Perform the incoming (responder) side of the MSE handshake
+///
+/// Detects plaintext BitTorrent handshakes and accepts them unless the policy
+/// requires encryption; encrypted handshakes are refused when it is disabled.
+/// For encrypted connections the torrent is identified among `info_hashes`,
+/// and its info hash is returned alongside the stream.
+pub async fn respond<S>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, Option<[u8; 20]>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("Waiting for MSE handshake (responder, policy: {})", policy);
+    let mut reader = HandshakeReader::new();
+
+    // A plaintext handshake starts with the protocol string, a public key is random
+    let prefix = reader.read_exact(&mut stream, 1 + PROTOCOL_LENGTH as usize).await?;
+    if prefix[0] == PROTOCOL_LENGTH && &prefix[1..] == PROTOCOL_STRING.as_bytes() {
+        if policy == EncryptionPolicy::Require {
+            warn!("Rejecting plaintext connection: encryption is required");
+            return Err(mse_error("Plaintext connection rejected", "encryption required"));
+        }
+        debug!("Incoming connection uses a plaintext handshake");
+        let mut read_plain = prefix;
+        read_plain.unsplit(reader.buf);
+        let mut plain = MseStream::plaintext(stream);
+        plain.read_raw = read_plain;
+        return Ok((plain, None));
+    }
+
+    if policy == EncryptionPolicy::Disabled {
+        debug!("Rejecting encrypted connection: encryption is disabled");
+        return Err(mse_error("Encrypted connection rejected", "encryption disabled"));
+    }
+
+    // 1. A->B: Ya, PadA
+    let rest = reader.read_exact(&mut stream, KEY_LENGTH - prefix.len()).await?;
+    let mut remote_public = prefix;
+    remote_public.unsplit(rest);
+
+    // 2. B->A: Yb, PadB
+    let keys = DhKeyPair::generate();
+    let secret = keys.shared_secret(&remote_public);
+    let mut out = BytesMut::with_capacity(KEY_LENGTH + MAX_PAD_LENGTH);
+    out.put_slice(&keys.public_key);
+    out.put_slice(&random_padding());
+    stream.write_all(&out).await?;
+    stream.flush().await?;
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S), ENCRYPT(...)
+    reader.sync_on(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD_LENGTH).await?;
+    let skey_hash = reader.read_exact(&mut stream, 20).await?;
+    let info_hash = info_hashes.iter()
+        .find(|candidate| obfuscated_skey(&secret, candidate)[..] == skey_hash[..])
+        .copied()
+        .ok_or_else(|| {
+            warn!("MSE handshake for unknown torrent");
+            mse_error("Encryption handshake failed", "unknown info hash")
+        })?;
+    debug!("MSE handshake for torrent: {}", hex::encode(info_hash));
+
+    let mut decryptor = stream_cipher(b"keyA", &secret, &info_hash);
+    let mut encryptor = stream_cipher(b"keyB", &secret, &info_hash);
+
+    let mut header = reader.read_exact(&mut stream, 14).await?;
+    decryptor.apply(&mut header);
+    if header[..8] != VC {
+        error!("MSE verification constant mismatch");
+        return Err(mse_error("Encryption handshake failed", "verification constant mismatch"));
+    }
+    header.advance(8);
+    let crypto_provide = header.get_u32();
+    let pad_length = header.get_u16() as usize;
+    if pad_length > MAX_PAD_LENGTH {
+        return Err(mse_error("Encryption handshake failed", format!("padC length {} too long", pad_length)));
+    }
+    let mut pad = reader.read_exact(&mut stream, pad_length).await?;
+    decryptor.apply(&mut pad);
+
+    let mut ia_length = reader.read_exact(&mut stream, 2).await?;
+    decryptor.apply(&mut ia_length);
+    let ia_length = ia_length.get_u16() as usize;
+    let mut initial_payload = reader.read_exact(&mut stream, ia_length).await?;
+    decryptor.apply(&mut initial_payload);
+
+    let crypto_select = policy.crypto_select(crypto_provide).ok_or_else(|| {
+        warn!("No common crypto method (peer provides 0x{:x}, policy {})", crypto_provide, policy);
+        mse_error("Encryption handshake failed", format!("no common crypto method in 0x{:x}", crypto_provide))
+    })?;
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    let mut out = BytesMut::with_capacity(14);
+    out.put_slice(&VC);
+    out.put_u32(crypto_select);
+    out.put_u16(0);
+    encryptor.apply(&mut out);
+    stream.write_all(&out).await?;
+    stream.flush().await?;
+
+    let encrypted = crypto_select == CRYPTO_RC4;
+    info!("MSE handshake complete (responder, {})", if encrypted { "rc4" } else { "plaintext" });
+    Ok((
+        MseStream {
+            inner: stream,
+            read_cipher: encrypted.then_some(decryptor),
+            write_cipher: encrypted.then_some(encryptor),
+            read_plain: initial_payload,
+            read_raw: reader.buf,
+            write_pending: BytesMut::new(),
+        },
+        Some(info_hash),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[test]
+    fn test_rc4_known_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+    }
+
+    #[test]
+    fn test_dh_shared_secret_matches() {
+        let a = DhKeyPair::generate();
+        let b = DhKeyPair::generate();
+        assert_eq!(a.shared_secret(&b.public_key), b.shared_secret(&a.public_key));
+    }
+
+    #[test]
+    fn test_crypto_select() {
+        assert_eq!(EncryptionPolicy::Prefer.crypto_select(CRYPTO_RC4 | CRYPTO_PLAINTEXT), Some(CRYPTO_RC4));
+        assert_eq!(EncryptionPolicy::Prefer.crypto_select(CRYPTO_PLAINTEXT), Some(CRYPTO_PLAINTEXT));
+        assert_eq!(EncryptionPolicy::Require.crypto_select(CRYPTO_PLAINTEXT), None);
+        assert_eq!(EncryptionPolicy::Disabled.crypto_select(CRYPTO_RC4), None);
+    }
+
+    #[test]
+    fn test_policy_from_str() {
+        assert_eq!("prefer".parse::<EncryptionPolicy>().unwrap(), EncryptionPolicy::Prefer);
+        assert_eq!("REQUIRE".parse::<EncryptionPolicy>().unwrap(), EncryptionPolicy::Require);
+        assert_eq!("disabled".parse::<EncryptionPolicy>().unwrap(), EncryptionPolicy::Disabled);
+        assert!("sometimes".parse::<EncryptionPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rc4_roundtrip() {
+        let info_hash = [7u8; 20];
+        let known = [[1u8; 20], info_hash];
+        let (client, server) = tcp_pair().await;
+
+        let (outgoing, incoming) = tokio::join!(
+            initiate(client, info_hash, EncryptionPolicy::Prefer),
+            respond(server, &known, EncryptionPolicy::Prefer),
+        );
+        let mut outgoing = outgoing.unwrap();
+        let (mut incoming, skey) = incoming.unwrap();
+
+        assert_eq!(skey, Some(info_hash));
+        assert!(outgoing.is_encrypted());
+        assert!(incoming.is_encrypted());
+
+        outgoing.write_all(b"hello from A").await.unwrap();
+        outgoing.flush().await.unwrap();
+        let mut buf = [0u8; 12];
+        incoming.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from A");
+
+        incoming.write_all(b"hello from B").await.unwrap();
+        incoming.flush().await.unwrap();
+        outgoing.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from B");
+    }
+
+    #[tokio::test]
+    async fn test_responder_accepts_plaintext_handshake() {
+        let (mut client, server) = tcp_pair().await;
+        let handshake = crate::protocol::Handshake::new([3u8; 20], [4u8; 20]).serialize();
+        client.write_all(&handshake).await.unwrap();
+
+        let (mut incoming, skey) = respond(server, &[[3u8; 20]], EncryptionPolicy::Prefer).await.unwrap();
+        assert!(skey.is_none());
+        assert!(!incoming.is_encrypted());
+
+        let mut buf = vec![0u8; handshake.len()];
+        incoming.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, handshake);
+    }
+
+    #[tokio::test]
+    async fn test_require_rejects_plaintext_handshake() {
+        let (mut client, server) = tcp_pair().await;
+        let handshake = crate::protocol::Handshake::new([3u8; 20], [4u8; 20]).serialize();
+        client.write_all(&handshake).await.unwrap();
+
+        assert!(respond(server, &[[3u8; 20]], EncryptionPolicy::Require).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_info_hash_rejected() {
+        let (client, server) = tcp_pair().await;
+        let (outgoing, incoming) = tokio::join!(
+            initiate(client, [9u8; 20], EncryptionPolicy::Require),
+            respond(server, &[[1u8; 20]], EncryptionPolicy::Prefer),
+        );
+        assert!(incoming.is_err());
+        assert!(outgoing.is_err());
+    }
+}
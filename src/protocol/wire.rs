@@ -2,11 +2,19 @@
 //!
 //! Helper functions and traits for working with the BitTorrent wire protocol.
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use anyhow::Result;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::error;
 
 use super::{Handshake, Message};
+use crate::error::TorrentError;
+
+/// Default limit for a single message payload
+///
+/// Large enough for a 16 KiB block or the bitfield of a torrent with
+/// millions of pieces.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
 
 /// WireProtocol trait for protocol utilities
 pub trait WireProtocol {
@@ -39,16 +47,13 @@ impl WireProtocol for BitTorrentWire {
             return Ok(Message::KeepAlive);
         }
 
+        check_message_size(length, DEFAULT_MAX_MESSAGE_SIZE)?;
+
         // Read the message payload
-        let mut payload = vec![0u8; length];
+        let mut payload = BytesMut::zeroed(length);
         reader.read_exact(&mut payload).await?;
 
-        // Parse the message
-        let mut full_message = BytesMut::with_capacity(4 + length);
-        full_message.put_slice(&length_buf);
-        full_message.put_slice(&payload);
-
-        Message::deserialize(&full_message)
+        Message::from_frame(payload.freeze())
     }
 
     /// Write a message to the stream
@@ -102,22 +107,42 @@ impl WireProtocol for BitTorrentWire {
     }
 }
 
-/// Read a length-prefixed message from the buffer
-pub fn read_message(buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+/// Reject a length prefix above the limit before allocating for it
+fn check_message_size(length: usize, max_size: usize) -> Result<()> {
+    if length > max_size {
+        error!("Message of {} bytes exceeds the limit of {} bytes", length, max_size);
+        return Err(TorrentError::protocol_error_with_source(
+            "Message too large",
+            format!("{} bytes, limit {} bytes", length, max_size)
+        ).into());
+    }
+    Ok(())
+}
+
+/// Split a length-prefixed frame off the buffer without copying
+///
+/// Returns `None` until the whole frame has arrived, and an error if the
+/// length prefix exceeds `max_size`.
+pub fn read_frame(buf: &mut BytesMut, max_size: usize) -> Result<Option<Bytes>> {
     if buf.len() < 4 {
         return Ok(None);
     }
 
     let length = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    check_message_size(length, max_size)?;
 
     if buf.len() < 4 + length {
+        buf.reserve(4 + length - buf.len());
         return Ok(None);
     }
 
-    let message = buf[4..4 + length].to_vec();
-    buf.advance(4 + length);
+    buf.advance(4);
+    Ok(Some(buf.split_to(length).freeze()))
+}
 
-    Ok(Some(message))
+/// Read a length-prefixed message from the buffer
+pub fn read_message(buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+    Ok(read_frame(buf, DEFAULT_MAX_MESSAGE_SIZE)?.map(|frame| frame.to_vec()))
 }
 
 /// Write a length-prefixed message to the buffer
@@ -167,6 +192,26 @@ mod tests {
         assert_eq!(result, message);
     }
 
+    #[test]
+    fn test_read_frame_rejects_oversized_length() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(u32::MAX);
+        assert!(read_frame(&mut buf, DEFAULT_MAX_MESSAGE_SIZE).is_err());
+        // Nothing was reserved for the bogus length
+        assert!(buf.capacity() < 1024 * 1024);
+
+        let mut buf = BytesMut::new();
+        write_message(&mut buf, &[0u8; 17]);
+        assert!(read_frame(&mut buf, 16).is_err());
+        assert!(read_frame(&mut buf, 17).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_wire_read_message_rejects_oversized_length() {
+        let mut data: &[u8] = &[0xff, 0xff, 0xff, 0xff, 7];
+        assert!(BitTorrentWire.read_message(&mut data).await.is_err());
+    }
+
     #[test]
     fn test_wire_protocol_trait_bounds() {
         // This test just verifies the trait is properly defined
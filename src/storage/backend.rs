@@ -8,6 +8,7 @@ use bytes::Bytes;
 use std::path::PathBuf;
 
 use crate::storage::piece::PieceStorage;
+use crate::storage::priority::FilePriority;
 use crate::torrent::info::TorrentFile;
 use anyhow::Result;
 
@@ -25,6 +26,15 @@ pub trait StorageBackend: Send + Sync {
     /// For FileStorage: Creates sparse files on disk
     /// For DriveStorage: Creates resumable upload sessions
     async fn initialize(&mut self, files: &[TorrentFile]) -> Result<()>;
+
+    /// Apply per-file priorities
+    ///
+    /// Called before `initialize` and again whenever priorities change.
+    /// Skipped files should not be allocated. The default ignores them.
+    async fn set_file_priorities(&mut self, priorities: &[FilePriority]) -> Result<()> {
+        let _ = priorities;
+        Ok(())
+    }
     
     /// Complete all storage operations
     ///
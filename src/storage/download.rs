@@ -3,15 +3,19 @@
 //! Manages the download process for torrents with piece verification.
 
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use anyhow::Result;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, trace, warn};
-use crate::peer::PeerManager;
-use crate::protocol::Message;
+use crate::peer::{PeerConnection, PeerManager};
+#[cfg(feature = "download")]
+use crate::peer::{WebSeed, WebSeedStats};
+use crate::protocol::{HashRequest, Message};
 use crate::storage::backend::StorageBackend;
-use crate::torrent::info::TorrentFile;
+use crate::storage::priority::{piece_priorities, FilePriority};
+use crate::torrent::info::{InfoV2, TorrentFile};
 use crate::error::TorrentError;
 use bytes::Bytes;
 
@@ -124,9 +128,22 @@ pub struct DownloadManager<S: StorageBackend> {
     max_concurrent_downloads: usize,
     /// Block size for requests
     block_size: u32,
+    /// Byte ranges of padding files, which are zero-filled instead of requested
+    padding: Arc<RwLock<Vec<Range<u64>>>>,
+    /// Files of the torrent, once the download has started
+    files: Arc<RwLock<Vec<TorrentFile>>>,
+    /// Priority of each file (normal if missing)
+    file_priorities: Arc<RwLock<Vec<FilePriority>>>,
+    /// Priority of each piece, from the file priorities
+    piece_priorities: Arc<RwLock<Vec<FilePriority>>>,
+    /// v2 metadata, gaining piece layers as peers send them
+    v2: Arc<RwLock<Option<InfoV2>>>,
+    /// HTTP seeds, used for pieces whenever one is idle
+    #[cfg(feature = "download")]
+    web_seeds: Arc<RwLock<Vec<Arc<WebSeed>>>>,
 }
 
-impl<S: StorageBackend> DownloadManager<S> {
+impl<S: StorageBackend + 'static> DownloadManager<S> {
     /// Create a new download manager with a storage backend
     pub fn new(
         storage: Arc<RwLock<S>>,
@@ -141,6 +158,13 @@ impl<S: StorageBackend> DownloadManager<S> {
             stats: Arc::new(RwLock::new(DownloadStats::default())),
             max_concurrent_downloads: 5,
             block_size: 16 * 1024, // 16KB blocks
+            padding: Arc::new(RwLock::new(Vec::new())),
+            files: Arc::new(RwLock::new(Vec::new())),
+            file_priorities: Arc::new(RwLock::new(Vec::new())),
+            piece_priorities: Arc::new(RwLock::new(Vec::new())),
+            v2: Arc::new(RwLock::new(None)),
+            #[cfg(feature = "download")]
+            web_seeds: Arc::new(RwLock::new(Vec::new())),
         }
     }
 
@@ -159,8 +183,14 @@ impl<S: StorageBackend> DownloadManager<S> {
         info!("Starting download with {:?} storage",
               self.storage.read().await.storage_type());
         
-        // Initialize storage backend with actual torrent files
+        // Initialize storage backend with actual torrent files, skipping unwanted ones
         let mut storage = self.storage.write().await;
+        storage.set_file_priorities(&self.file_priorities.read().await).await
+            .map_err(|e| {
+                error!("Failed to apply file priorities: {}", e);
+                TorrentError::storage_error_full("Failed to apply file priorities",
+                    "unknown".to_string(), e.to_string())
+            })?;
         storage.initialize(&files).await
             .map_err(|e| {
                 error!("Failed to initialize storage: {}", e);
@@ -169,6 +199,20 @@ impl<S: StorageBackend> DownloadManager<S> {
             })?;
         drop(storage);
 
+        let mut offset = 0;
+        let mut padding = self.padding.write().await;
+        padding.clear();
+        for file in &files {
+            if file.is_padding() {
+                padding.push(offset..offset + file.length);
+            }
+            offset += file.length;
+        }
+        debug!("Torrent has {} padding files", padding.len());
+        drop(padding);
+        *self.files.write().await = files;
+        self.update_piece_priorities().await;
+
         // Request initial pieces
         self.request_next_pieces().await?;
 
@@ -176,6 +220,79 @@ impl<S: StorageBackend> DownloadManager<S> {
         Ok(())
     }
 
+    /// Set the priority of every file
+    ///
+    /// Can be called before or during the download. Files without a
+    /// priority are downloaded normally, and downloads of pieces that are
+    /// now skipped are cancelled.
+    pub async fn set_file_priorities(&self, priorities: Vec<FilePriority>) -> Result<()> {
+        info!("Setting priorities of {} files", priorities.len());
+        let started = !self.files.read().await.is_empty();
+        if started {
+            let mut storage = self.storage.write().await;
+            storage.set_file_priorities(&priorities).await
+                .map_err(|e| {
+                    error!("Failed to apply file priorities: {}", e);
+                    TorrentError::storage_error_full("Failed to apply file priorities",
+                        "unknown".to_string(), e.to_string())
+                })?;
+        }
+        *self.file_priorities.write().await = priorities;
+        if !started {
+            return Ok(());
+        }
+
+        self.update_piece_priorities().await;
+        let piece_priorities = self.piece_priorities.read().await;
+        let skipped: Vec<u32> = self.active_downloads.read().await.keys()
+            .copied()
+            .filter(|&index| piece_priorities.get(index as usize).is_some_and(FilePriority::is_skipped))
+            .collect();
+        drop(piece_priorities);
+        for piece_index in skipped {
+            self.cancel_piece_download(piece_index).await?;
+        }
+        Ok(())
+    }
+
+    /// Set the priority of one file
+    pub async fn set_file_priority(&self, file_index: usize, priority: FilePriority) -> Result<()> {
+        let file_count = self.files.read().await.len();
+        if file_index >= file_count {
+            error!("Invalid file index: {} (torrent has {} files)", file_index, file_count);
+            return Err(TorrentError::validation_error_with_field("Invalid file index", "file_index".to_string()).into());
+        }
+        let mut priorities = self.file_priorities.read().await.clone();
+        priorities.resize(file_count, FilePriority::Normal);
+        priorities[file_index] = priority;
+        self.set_file_priorities(priorities).await
+    }
+
+    /// Get the priority of every file
+    pub async fn file_priorities(&self) -> Vec<FilePriority> {
+        let mut priorities = self.file_priorities.read().await.clone();
+        priorities.resize(self.files.read().await.len().max(priorities.len()), FilePriority::Normal);
+        priorities
+    }
+
+    /// Recompute piece priorities from the file priorities
+    async fn update_piece_priorities(&self) {
+        let storage = self.storage.read().await;
+        let piece_length = storage.pieces().piece_length() as u64;
+        let piece_count = storage.pieces().piece_count();
+        drop(storage);
+
+        let priorities = piece_priorities(&self.files.read().await, &self.file_priorities.read().await, piece_length, piece_count);
+        let skipped = priorities.iter().filter(|p| p.is_skipped()).count();
+        debug!("{} of {} pieces skipped", skipped, piece_count);
+        *self.piece_priorities.write().await = priorities;
+    }
+
+    /// Get the priority of a piece
+    fn piece_priority(priorities: &[FilePriority], piece_index: u32) -> FilePriority {
+        priorities.get(piece_index as usize).copied().unwrap_or_default()
+    }
+
     /// Request the next pieces to download
     pub async fn request_next_pieces(&self) -> Result<()> {
         let active_downloads = self.active_downloads.read().await;
@@ -191,11 +308,17 @@ impl<S: StorageBackend> DownloadManager<S> {
         debug!("Requesting next pieces ({} slots available)", slots_available);
         let pieces_to_download = self.select_pieces(slots_available).await?;
 
+        // Without a peer or idle web seed the rest have to wait for the next round
+        let mut started = 0;
         for piece_index in &pieces_to_download {
-            self.start_piece_download(*piece_index).await?;
+            if let Err(e) = self.start_piece_download(*piece_index).await {
+                debug!("No source for piece {} yet: {}", piece_index, e);
+                break;
+            }
+            started += 1;
         }
 
-        debug!("Requested {} new pieces", pieces_to_download.len());
+        debug!("Requested {} new pieces", started);
         Ok(())
     }
 
@@ -204,12 +327,14 @@ impl<S: StorageBackend> DownloadManager<S> {
         let storage = self.storage.read().await;
         let pieces = storage.pieces();
         let active_downloads = self.active_downloads.read().await;
+        let priorities = self.piece_priorities.read().await;
 
-        // Find pieces that are not downloaded and not being downloaded
+        // Find wanted pieces that are not downloaded and not being downloaded
         let mut available_pieces: Vec<u32> = pieces.pieces()
             .iter()
             .filter(|p| !p.is_verified())
             .filter(|p| !active_downloads.contains_key(&p.index))
+            .filter(|p| !Self::piece_priority(&priorities, p.index).is_skipped())
             .map(|p| p.index)
             .collect();
 
@@ -220,11 +345,14 @@ impl<S: StorageBackend> DownloadManager<S> {
         // In a full implementation, we would query peers for their bitfields
         use rand::seq::SliceRandom;
         available_pieces.shuffle(&mut rand::thread_rng());
+        // Higher priority pieces first, keeping the random order within each priority
+        available_pieces.sort_by_key(|&index| std::cmp::Reverse(Self::piece_priority(&priorities, index)));
 
         let selected: Vec<_> = available_pieces.into_iter().take(count).collect();
         debug!("Selected {} pieces for download", selected.len());
         drop(storage);
         drop(active_downloads);
+        drop(priorities);
 
         Ok(selected)
     }
@@ -247,14 +375,223 @@ impl<S: StorageBackend> DownloadManager<S> {
         let mut active_downloads = self.active_downloads.write().await;
         active_downloads.insert(piece_index, PieceDownload::new(piece_index, block_count));
         drop(active_downloads);
+        self.fill_padding_blocks(piece_index).await;
 
-        // Request blocks from peers
-        self.request_piece_blocks(piece_index).await?;
+        // An idle web seed takes the whole piece
+        #[cfg(feature = "download")]
+        if self.assign_web_seed(piece_index).await {
+            return Ok(());
+        }
+
+        // Request blocks from peers, giving the piece back if none can take it
+        if let Err(e) = self.request_piece_blocks(piece_index).await {
+            self.active_downloads.write().await.remove(&piece_index);
+            return Err(e);
+        }
 
         debug!("Piece {} download started", piece_index);
         Ok(())
     }
 
+    /// Add a web seed
+    #[cfg(feature = "download")]
+    pub async fn add_web_seed(&self, seed: WebSeed) {
+        info!("Adding {} web seed {}", seed.kind(), seed.url());
+        self.web_seeds.write().await.push(Arc::new(seed));
+    }
+
+    /// Get statistics of every web seed
+    #[cfg(feature = "download")]
+    pub async fn web_seed_stats(&self) -> Vec<WebSeedStats> {
+        let seeds = self.web_seeds.read().await.clone();
+        let mut stats = Vec::with_capacity(seeds.len());
+        for seed in seeds {
+            stats.push(seed.stats().await);
+        }
+        stats
+    }
+
+    /// Hand a piece to an idle web seed, returning false if none is free
+    #[cfg(feature = "download")]
+    async fn assign_web_seed(&self, piece_index: u32) -> bool {
+        let seeds = self.web_seeds.read().await.clone();
+        for seed in seeds {
+            if seed.try_claim().await {
+                debug!("Downloading piece {} from web seed {}", piece_index, seed.url());
+                tokio::spawn(self.clone().download_from_web_seed(seed, piece_index));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Download a piece from a web seed and feed it through the normal block path
+    ///
+    /// The seed stays claimed until the piece is checked, so a bad piece
+    /// isn't retried from the same seed straight away. Boxed because
+    /// finishing a piece starts the next ones, which can come back here.
+    #[cfg(feature = "download")]
+    fn download_from_web_seed(self, seed: Arc<WebSeed>, piece_index: u32) -> futures::future::BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let data = match seed.fetch_piece(piece_index).await {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Web seed {} failed piece {}: {}", seed.url(), piece_index, e);
+                    seed.release().await;
+                    // Give the piece back so peers or another seed can take it
+                    let _ = self.cancel_piece_download(piece_index).await;
+                    return;
+                }
+            };
+
+            let length = data.len();
+            let mut result = Ok(());
+            for (index, block) in data.chunks(self.block_size as usize).enumerate() {
+                let offset = index as u32 * self.block_size;
+                let already_have = self.storage.read().await.pieces().get_piece(piece_index as usize)
+                    .is_some_and(|piece| piece.is_verified() || piece.blocks.get(index).is_some_and(Option::is_some));
+                if already_have {
+                    continue;
+                }
+                result = self.handle_piece_message(piece_index, offset, block.to_vec()).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            let verified = self.storage.read().await.pieces().get_piece(piece_index as usize)
+                .is_some_and(|piece| piece.is_verified());
+            if verified {
+                seed.record_success(length).await;
+            } else {
+                warn!("Piece {} from web seed {} failed verification", piece_index, seed.url());
+                seed.record_failure(None).await;
+            }
+            seed.release().await;
+            if let Err(e) = result {
+                warn!("Failed to store piece {} from web seed {}: {}", piece_index, seed.url(), e);
+            }
+        })
+    }
+
+    /// Set the v2 metadata of a v2 or hybrid torrent
+    pub async fn set_v2_info(&self, v2: InfoV2) {
+        let missing = v2.missing_piece_layers().count();
+        if missing > 0 {
+            info!("{} files have no piece layer yet, asking peers for them", missing);
+        }
+        *self.v2.write().await = Some(v2);
+    }
+
+    /// Handle a message from a peer
+    ///
+    /// Blocks go through [`handle_piece_message`](Self::handle_piece_message).
+    /// Hash requests are answered from our piece layers, and hashes fill in
+    /// the piece layers we are missing (BEP 52).
+    pub async fn handle_peer_message(&self, connection: &mut PeerConnection, message: Message) -> Result<()> {
+        match message {
+            Message::Piece { index, begin, block } => self.handle_piece_message(index, begin, block.to_vec()).await,
+            Message::HashRequest(request) => {
+                let v2 = self.v2.read().await;
+                match v2.as_ref() {
+                    Some(v2) => connection.answer_hash_request(v2, request).await,
+                    None => connection.send_message(&Message::HashReject(request)).await,
+                }
+            }
+            Message::Hashes { request, hashes } => self.handle_hashes(&request, &hashes).await,
+            Message::HashReject(request) => {
+                debug!("Peer {} rejected hash request for {}", connection.peer_addr(), hex::encode(request.pieces_root));
+                Ok(())
+            }
+            other => {
+                trace!("Not handling {:?} from peer {}", other.message_id(), connection.peer_addr());
+                Ok(())
+            }
+        }
+    }
+
+    /// Ask a peer for every piece layer we are missing, returning how many were requested
+    pub async fn request_missing_hashes(&self, connection: &mut PeerConnection) -> Result<usize> {
+        let piece_length = self.storage.read().await.pieces().piece_length() as u64;
+        let requests: Vec<HashRequest> = match self.v2.read().await.as_ref() {
+            Some(v2) => v2.missing_piece_layers()
+                .filter_map(|file| Some(HashRequest {
+                    pieces_root: file.pieces_root?,
+                    base_layer: v2.piece_layer_height(),
+                    index: 0,
+                    // The whole layer, padded to a power of two, proves itself
+                    length: file.length.div_ceil(piece_length).next_power_of_two() as u32,
+                    proof_layers: 0,
+                }))
+                .collect(),
+            None => Vec::new(),
+        };
+        for request in &requests {
+            connection.request_hashes(*request).await?;
+        }
+        Ok(requests.len())
+    }
+
+    /// Add hashes from a peer, giving pieces their v2 hash once their layer is complete
+    async fn handle_hashes(&self, request: &HashRequest, hashes: &[[u8; 32]]) -> Result<()> {
+        let mut v2 = self.v2.write().await;
+        let Some(v2) = v2.as_mut() else {
+            debug!("Ignoring hashes for a torrent without v2 metadata");
+            return Ok(());
+        };
+        v2.add_hashes(request.pieces_root, request.base_layer, request.index, request.length, hashes)
+            .map_err(|e| {
+                warn!("Invalid hashes for {}: {}", hex::encode(request.pieces_root), e);
+                TorrentError::validation_error_with_field(format!("Invalid hashes: {}", e), "hashes".to_string())
+            })?;
+
+        let mut storage = self.storage.write().await;
+        let pieces = storage.pieces_mut();
+        let mut added = 0;
+        for index in 0..pieces.piece_count() {
+            if let Some(piece) = pieces.get_piece_mut(index).filter(|piece| piece.hash_v2.is_none()) {
+                piece.hash_v2 = v2.piece_hash(index);
+                added += piece.hash_v2.is_some() as usize;
+            }
+        }
+        debug!("Received hashes for {}, {} pieces can now be checked", hex::encode(request.pieces_root), added);
+        Ok(())
+    }
+
+    /// Fill blocks that lie entirely in padding files with zeros, returning how many were filled
+    async fn fill_padding_blocks(&self, piece_index: u32) -> usize {
+        let padding = self.padding.read().await;
+        if padding.is_empty() {
+            return 0;
+        }
+
+        let mut storage = self.storage.write().await;
+        let piece_start = piece_index as u64 * storage.pieces().piece_length() as u64;
+        let Some(piece) = storage.pieces_mut().get_piece_mut(piece_index as usize) else {
+            return 0;
+        };
+        let mut active_downloads = self.active_downloads.write().await;
+        let mut filled = 0;
+        for (offset, length) in piece.get_missing_blocks() {
+            let start = piece_start + offset as u64;
+            let end = start + length as u64;
+            if !padding.iter().any(|pad| pad.start <= start && end <= pad.end) {
+                continue;
+            }
+            if piece.add_block(offset, vec![0u8; length as usize]).is_ok() {
+                if let Some(download) = active_downloads.get_mut(&piece_index) {
+                    download.mark_block_downloaded((offset / self.block_size) as usize);
+                }
+                filled += 1;
+            }
+        }
+
+        if filled > 0 {
+            debug!("Filled {} padding blocks of piece {} with zeros", filled, piece_index);
+        }
+        filled
+    }
+
     /// Request blocks for a piece from peers
     async fn request_piece_blocks(&self, piece_index: u32) -> Result<()> {
         debug!("Requesting blocks for piece {}", piece_index);
@@ -375,10 +712,10 @@ impl<S: StorageBackend> DownloadManager<S> {
         if piece.is_complete() {
             info!("Piece {} download complete, verifying...", piece_index);
             
+            // Verify piece, which assembles its blocks into the piece data
+            let is_valid = piece.verify();
             // Get piece data before dropping storage
             let piece_data = Bytes::from(piece.data().to_vec());
-            // Verify piece
-            let is_valid = piece.verify();
             drop(storage);
 
             if is_valid {
@@ -467,10 +804,13 @@ impl<S: StorageBackend> DownloadManager<S> {
         Ok(())
     }
     
-    /// Check if download is complete
+    /// Check if every wanted piece is downloaded
     pub async fn is_complete(&self) -> bool {
         let storage = self.storage.read().await;
-        storage.is_complete()
+        let priorities = self.piece_priorities.read().await;
+        storage.pieces().pieces().iter()
+            .filter(|p| !Self::piece_priority(&priorities, p.index).is_skipped())
+            .all(|p| p.is_verified())
     }
 
     /// Get download statistics
@@ -479,10 +819,18 @@ impl<S: StorageBackend> DownloadManager<S> {
         stats.clone()
     }
 
-    /// Get download progress (0.0 to 1.0)
+    /// Get download progress of the wanted pieces (0.0 to 1.0)
     pub async fn get_progress(&self) -> f64 {
         let storage = self.storage.read().await;
-        storage.get_progress()
+        let priorities = self.piece_priorities.read().await;
+        let (wanted, verified) = storage.pieces().pieces().iter()
+            .filter(|p| !Self::piece_priority(&priorities, p.index).is_skipped())
+            .fold((0usize, 0usize), |(wanted, verified), p| (wanted + 1, verified + p.is_verified() as usize));
+        if wanted == 0 {
+            storage.get_progress()
+        } else {
+            verified as f64 / wanted as f64
+        }
     }
 
     /// Get the number of active downloads
@@ -559,6 +907,13 @@ impl<S: StorageBackend> Clone for DownloadManager<S> {
             stats: Arc::clone(&self.stats),
             max_concurrent_downloads: self.max_concurrent_downloads,
             block_size: self.block_size,
+            padding: Arc::clone(&self.padding),
+            files: Arc::clone(&self.files),
+            file_priorities: Arc::clone(&self.file_priorities),
+            piece_priorities: Arc::clone(&self.piece_priorities),
+            v2: Arc::clone(&self.v2),
+            #[cfg(feature = "download")]
+            web_seeds: Arc::clone(&self.web_seeds),
         }
     }
 }
@@ -570,3 +925,161 @@ pub type FileDownloadManager = DownloadManager<crate::storage::file::FileStorage
 /// Download manager with Google Drive storage
 #[cfg(feature = "gdrive")]
 pub type DriveDownloadManager = DownloadManager<crate::storage::drive::DriveStorage>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::EncryptionPolicy;
+    use crate::storage::FileStorage;
+    use crate::torrent::info::{TorrentFileV2, TorrentInfo};
+    use crate::torrent::merkle::{MerkleTree, BLOCK_SIZE};
+    use crate::torrent::FileAttributes;
+    use crate::transport::MemoryTransport;
+
+    /// Torrent of four 16-byte files, one piece each
+    fn torrent() -> (TorrentInfo, Vec<Vec<u8>>) {
+        let contents: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 16]).collect();
+        let info = TorrentInfo {
+            announce: String::new(),
+            announce_list: vec![],
+            info_hash: [0xcd; 20],
+            piece_length: 16,
+            pieces: contents.iter().map(|c| TorrentInfo::generate_info_hash(c)).collect(),
+            name: "root".to_string(),
+            length: None,
+            files: Some((0..4).map(|i| TorrentFile::new(vec![format!("{}.bin", i)], 16)).collect()),
+            v2: None,
+            web_seeds: vec![],
+            http_seeds: vec![],
+        };
+        (info, contents)
+    }
+
+    async fn manager(name: &str) -> (FileDownloadManager, TorrentInfo, Vec<Vec<u8>>, std::path::PathBuf) {
+        let (info, contents) = torrent();
+        let base_path = std::env::temp_dir().join(format!("test_download_{}_{}", name, std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&base_path).await;
+        let storage = FileStorage::new(base_path.clone(), Arc::new(info.clone())).await.unwrap();
+        let manager = FileDownloadManager::new(Arc::new(RwLock::new(storage)), Arc::new(PeerManager::default()));
+        (manager, info, contents, base_path)
+    }
+
+    /// Mark a piece verified as if it had been downloaded
+    async fn verify_piece(manager: &FileDownloadManager, index: u32, data: &[u8]) {
+        let mut storage = manager.storage.write().await;
+        let piece = storage.pieces_mut().get_piece_mut(index as usize).unwrap();
+        piece.add_block(0, data.to_vec()).unwrap();
+        assert!(piece.verify());
+    }
+
+    #[tokio::test]
+    async fn test_select_pieces_by_priority() {
+        let (manager, info, _, base_path) = manager("priority").await;
+        manager.set_file_priorities(vec![FilePriority::Low, FilePriority::Skip, FilePriority::High, FilePriority::Normal])
+            .await.unwrap();
+        manager.start_download(info.files_iter().collect()).await.unwrap();
+
+        // High before Normal before Low, and the skipped piece never
+        for _ in 0..10 {
+            assert_eq!(manager.select_pieces(4).await.unwrap(), vec![2, 3, 0]);
+        }
+        assert_eq!(manager.select_pieces(1).await.unwrap(), vec![2]);
+        assert!(!manager.get_active_downloads().await.iter().any(|d| d.piece_index == 1));
+
+        let _ = tokio::fs::remove_dir_all(&base_path).await;
+    }
+
+    #[tokio::test]
+    async fn test_set_file_priority_during_download() {
+        let (manager, info, contents, base_path) = manager("set_priority").await;
+        manager.start_download(info.files_iter().collect()).await.unwrap();
+        verify_piece(&manager, 0, &contents[0]).await;
+        assert_eq!(manager.get_progress().await, 0.25);
+        assert!(!manager.is_complete().await);
+
+        // Skipping the missing files leaves only the verified piece wanted
+        for index in 1..4 {
+            manager.set_file_priority(index, FilePriority::Skip).await.unwrap();
+        }
+        assert_eq!(manager.get_progress().await, 1.0);
+        assert!(manager.is_complete().await);
+        assert!(manager.select_pieces(4).await.unwrap().is_empty());
+
+        manager.set_file_priority(3, FilePriority::High).await.unwrap();
+        assert_eq!(manager.get_progress().await, 0.5);
+        assert_eq!(manager.select_pieces(4).await.unwrap(), vec![3]);
+        assert_eq!(manager.file_priorities().await[3], FilePriority::High);
+        assert!(manager.set_file_priority(4, FilePriority::Low).await.is_err());
+
+        let _ = tokio::fs::remove_dir_all(&base_path).await;
+    }
+
+    #[tokio::test]
+    async fn test_piece_layer_from_peer() {
+        let piece_length = 2 * BLOCK_SIZE as u64;
+        let data: Vec<u8> = (0..7 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        let tree = MerkleTree::from_data(&data);
+        let file = TorrentFileV2 { path: vec!["f".to_string()], length: data.len() as u64, pieces_root: Some(tree.root()), attr: FileAttributes::default() };
+        let info = |layers| TorrentInfo {
+            announce: String::new(),
+            announce_list: vec![],
+            info_hash: [0xef; 20],
+            piece_length,
+            pieces: data.chunks(piece_length as usize).map(TorrentInfo::generate_info_hash).collect(),
+            name: "f".to_string(),
+            length: Some(data.len() as u64),
+            files: None,
+            v2: Some(InfoV2::new([1u8; 32], vec![file.clone()], layers, piece_length)),
+            web_seeds: vec![],
+            http_seeds: vec![],
+        };
+        let seeder_info = info(HashMap::from([(tree.root(), tree.piece_layer(piece_length))]));
+        let leecher_info = info(HashMap::new());
+
+        let mut managers = Vec::new();
+        for (name, info) in [("seeder", seeder_info), ("leecher", leecher_info)] {
+            let base_path = std::env::temp_dir().join(format!("test_download_hashes_{}_{}", name, std::process::id()));
+            let storage = FileStorage::new(base_path.clone(), Arc::new(info.clone())).await.unwrap();
+            let manager = FileDownloadManager::new(Arc::new(RwLock::new(storage)), Arc::new(PeerManager::default()));
+            manager.set_v2_info(info.v2.unwrap()).await;
+            managers.push((manager, base_path));
+        }
+        let (seeder, leecher) = (&managers[0].0, &managers[1].0);
+        assert!(leecher.storage.read().await.pieces().get_piece(3).unwrap().hash_v2.is_none());
+
+        let (a, b) = MemoryTransport::pair("10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6881".parse().unwrap());
+        let (client, server) = tokio::join!(
+            PeerConnection::connect_over(Box::new(a), [0xef; 20], [1u8; 20], EncryptionPolicy::Disabled),
+            PeerConnection::accept(Box::new(b), &[[0xef; 20]], [2u8; 20], EncryptionPolicy::Disabled),
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        // The seeder answers the request for the missing layer
+        assert_eq!(leecher.request_missing_hashes(&mut client).await.unwrap(), 1);
+        let request = server.receive_message().await.unwrap();
+        assert!(matches!(request, Message::HashRequest(_)));
+        seeder.handle_peer_message(&mut server, request).await.unwrap();
+        let hashes = client.receive_message().await.unwrap();
+        let Message::Hashes { request, .. } = &hashes else { panic!("unexpected message: {:?}", hashes) };
+        let request = *request;
+        leecher.handle_peer_message(&mut client, hashes).await.unwrap();
+
+        let hash_v2 = leecher.storage.read().await.pieces().get_piece(3).unwrap().hash_v2.unwrap();
+        assert!(hash_v2.verify(&data[6 * BLOCK_SIZE..]));
+        assert_eq!(leecher.request_missing_hashes(&mut client).await.unwrap(), 0);
+
+        // Wrong hashes are an error, unknown files are rejected
+        let forged = Message::Hashes { request, hashes: vec![[0u8; 32]; 4] };
+        assert!(leecher.handle_peer_message(&mut client, forged).await.is_err());
+        client.request_hashes(HashRequest { pieces_root: [9u8; 32], ..request }).await.unwrap();
+        let unknown = server.receive_message().await.unwrap();
+        seeder.handle_peer_message(&mut server, unknown).await.unwrap();
+        let reject = client.receive_message().await.unwrap();
+        assert!(matches!(reject, Message::HashReject(rejected) if rejected.pieces_root == [9u8; 32]));
+        leecher.handle_peer_message(&mut client, reject).await.unwrap();
+
+        for (_, base_path) in managers {
+            let _ = tokio::fs::remove_dir_all(&base_path).await;
+        }
+    }
+}
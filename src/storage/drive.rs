@@ -13,6 +13,7 @@ use tracing::{debug, info, warn};
 use crate::torrent::info::TorrentFile;
 use crate::storage::piece::PieceStorage;
 use crate::storage::backend::{StorageBackend, StorageType, StorageMetadata};
+use crate::storage::sanitize::sanitize_files;
 
 /// Google Drive API client for uploading files
 pub struct DriveClient {
@@ -272,7 +273,9 @@ impl DriveStorage {
 
         self.upload_sessions = Vec::new();
 
-        for (index, file) in files.iter().enumerate() {
+        // Padding files are never uploaded
+        let files = sanitize_files(files);
+        for (index, file) in files.iter().enumerate().filter(|(_, file)| !file.is_padding()) {
             let filename = file.path.join("/");
             let total_size = file.length;
 
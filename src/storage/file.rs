@@ -2,6 +2,7 @@
 //!
 //! Handles file I/O operations for torrent data.
 
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use anyhow::Result;
@@ -14,6 +15,9 @@ use bytes::Bytes;
 use crate::torrent::TorrentInfo;
 use crate::storage::piece::{Piece, PieceStorage, PieceStatus};
 use crate::storage::backend::{StorageBackend, StorageType, StorageMetadata};
+use crate::storage::partfile::PartFile;
+use crate::storage::priority::FilePriority;
+use crate::storage::sanitize::sanitize_files;
 use crate::torrent::info::TorrentFile;
 use crate::error::TorrentError;
 
@@ -24,6 +28,12 @@ pub struct FileStorage {
     base_path: PathBuf,
     /// Torrent information
     torrent_info: Arc<TorrentInfo>,
+    /// Files with sanitized paths
+    files: Vec<TorrentFile>,
+    /// Priority of each file (normal if missing)
+    priorities: Vec<FilePriority>,
+    /// Data of skipped files that share pieces with wanted files
+    partfile: PartFile,
     /// All pieces
     pieces: PieceStorage,
     /// Which pieces are downloaded (bitfield)
@@ -44,11 +54,9 @@ impl FileStorage {
         info!("Creating file storage for torrent: {}", torrent_info.name);
         info!("Base path: {}", base_path.display());
         
-        let pieces = PieceStorage::new(
-            torrent_info.pieces.clone(),
-            torrent_info.piece_length as u32,
-            torrent_info.total_size(),
-        );
+        let pieces = PieceStorage::for_torrent(&torrent_info);
+        let files = sanitize_files(&torrent_info.files_iter().collect::<Vec<_>>());
+        let partfile = PartFile::new(base_path.join(PartFile::file_name(&torrent_info.info_hash)), torrent_info.piece_length);
 
         let downloaded_pieces = vec![false; pieces.piece_count()];
         debug!("Initialized {} pieces", pieces.piece_count());
@@ -56,6 +64,9 @@ impl FileStorage {
         Ok(Self {
             base_path,
             torrent_info,
+            files,
+            priorities: Vec::new(),
+            partfile,
             pieces,
             downloaded_pieces,
         })
@@ -75,71 +86,193 @@ impl FileStorage {
                 })?;
         }
 
-        // Handle multi-file torrent
-        if let Some(files) = &self.torrent_info.files {
-            info!("Creating {} files for multi-file torrent", files.len());
-            for file in files {
-                let file_path = self.base_path.join(file.path.join("/"));
-                debug!("Creating file: {}", file_path.display());
-                if let Some(parent) = file_path.parent() {
-                    if !parent.exists() {
-                        debug!("Creating directory: {}", parent.display());
-                        fs::create_dir_all(parent).await
-                            .map_err(|e| {
-                                error!("Failed to create directory '{}': {}", parent.display(), e);
-                                TorrentError::storage_error_full("Failed to create directory", parent.display().to_string(), e.to_string())
-                            })?;
-                    }
-                }
-                // Create sparse file
-                let mut f = fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&file_path)
-                    .await
-                    .map_err(|e| {
-                        error!("Failed to create file '{}': {}", file_path.display(), e);
-                        TorrentError::storage_error_full("Failed to create file", file_path.display().to_string(), e.to_string())
-                    })?;
-                f.set_len(file.length).await
-                    .map_err(|e| {
-                        error!("Failed to set file length for '{}': {}", file_path.display(), e);
-                        TorrentError::storage_error_full("Failed to set file length", file_path.display().to_string(), e.to_string())
-                    })?;
-                f.flush().await
+        info!("Creating {} files", self.files.len());
+        for (index, file) in self.files.iter().enumerate() {
+            // Data skipped files share with wanted ones goes to the partfile
+            if self.is_skipped(index) {
+                trace!("Not allocating skipped file {}", file.path.join("/"));
+                continue;
+            }
+            self.allocate_file(file).await?;
+        }
+
+        info!("File structure created successfully");
+        Ok(())
+    }
+
+    /// Create a sparse file at its full length, keeping any data already in it
+    async fn allocate_file(&self, file: &TorrentFile) -> Result<()> {
+        let file_path = self.base_path.join(file.path.join("/"));
+        // Padding files are never created; symlinks are made on completion
+        if file.is_padding() || file.attr.symlink {
+            trace!("Not creating {}", file_path.display());
+            return Ok(());
+        }
+        debug!("Creating file: {} ({} bytes)", file_path.display(), file.length);
+        if let Some(parent) = file_path.parent() {
+            if !parent.exists() {
+                debug!("Creating directory: {}", parent.display());
+                fs::create_dir_all(parent).await
                     .map_err(|e| {
-                        error!("Failed to flush file '{}': {}", file_path.display(), e);
-                        TorrentError::storage_error_full("Failed to flush file", file_path.display().to_string(), e.to_string())
+                        error!("Failed to create directory '{}': {}", parent.display(), e);
+                        TorrentError::storage_error_full("Failed to create directory", parent.display().to_string(), e.to_string())
                     })?;
             }
-        } else {
-            // Single file torrent
-            info!("Creating single file torrent");
-            let file_path = self.base_path.join(&self.torrent_info.name);
-            let length = self.torrent_info.length.unwrap_or(0);
-            debug!("Creating file: {} ({} bytes)", file_path.display(), length);
-            let mut f = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(&file_path)
-                .await
-                .map_err(|e| {
-                    error!("Failed to create file '{}': {}", file_path.display(), e);
-                    TorrentError::storage_error_full("Failed to create file", file_path.display().to_string(), e.to_string())
-                })?;
-            f.set_len(length).await
+        }
+        // Create sparse file
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&file_path)
+            .await
+            .map_err(|e| {
+                error!("Failed to create file '{}': {}", file_path.display(), e);
+                TorrentError::storage_error_full("Failed to create file", file_path.display().to_string(), e.to_string())
+            })?;
+        f.set_len(file.length).await
+            .map_err(|e| {
+                error!("Failed to set file length for '{}': {}", file_path.display(), e);
+                TorrentError::storage_error_full("Failed to set file length", file_path.display().to_string(), e.to_string())
+            })?;
+        f.flush().await
+            .map_err(|e| {
+                error!("Failed to flush file '{}': {}", file_path.display(), e);
+                TorrentError::storage_error_full("Failed to flush file", file_path.display().to_string(), e.to_string())
+            })?;
+        Ok(())
+    }
+
+    /// Set file priorities, allocating files that are no longer skipped
+    ///
+    /// Data a newly selected file shares with other pieces is moved out of
+    /// the partfile, which is deleted once no file is skipped.
+    pub async fn set_file_priorities(&mut self, priorities: &[FilePriority]) -> Result<()> {
+        let previous = std::mem::replace(&mut self.priorities, priorities.to_vec());
+        for (index, (file, file_start)) in self.file_spans().into_iter().enumerate() {
+            let was_skipped = previous.get(index).is_some_and(FilePriority::is_skipped);
+            if !was_skipped || self.is_skipped(index) || file.is_padding() || file.attr.symlink {
+                continue;
+            }
+            let file_path = self.base_path.join(file.path.join("/"));
+            let from_partfile = !file_path.exists();
+            info!("File {} selected, allocating it", file.path.join("/"));
+            self.allocate_file(file).await?;
+            if from_partfile {
+                self.move_from_partfile(file, file_start).await?;
+            }
+        }
+
+        let skipped = (0..self.files.len()).filter(|&i| self.is_skipped(i)).count();
+        debug!("{} of {} files skipped", skipped, self.files.len());
+        if skipped == 0 {
+            self.partfile.remove().await?;
+        }
+        Ok(())
+    }
+
+    /// Check if a file is skipped
+    fn is_skipped(&self, index: usize) -> bool {
+        self.priorities.get(index).is_some_and(FilePriority::is_skipped)
+    }
+
+    /// Check if a file's data lives in the partfile
+    ///
+    /// A skipped file that is already on disk keeps using it.
+    fn in_partfile(&self, index: usize, file_path: &Path) -> bool {
+        self.is_skipped(index) && !file_path.exists()
+    }
+
+    /// Get the piece and the offset in it of a torrent offset
+    fn piece_position(&self, offset: u64) -> (u32, u64) {
+        let piece_length = self.torrent_info.piece_length.max(1);
+        ((offset / piece_length) as u32, offset % piece_length)
+    }
+
+    /// Move a newly selected file's data out of the partfile
+    ///
+    /// Only the first and last piece of a file can be shared with another
+    /// file, so nothing else can be in the partfile.
+    async fn move_from_partfile(&self, file: &TorrentFile, file_start: u64) -> Result<()> {
+        if !self.partfile.exists() || file.length == 0 {
+            return Ok(());
+        }
+        let piece_length = self.torrent_info.piece_length.max(1);
+        let file_end = file_start + file.length;
+        let first_end = file_end.min((file_start / piece_length + 1) * piece_length);
+        let last_start = file_start.max((file_end - 1) / piece_length * piece_length);
+        let mut ranges: Vec<Range<u64>> = Vec::with_capacity(2);
+        ranges.push(file_start..first_end);
+        if last_start >= first_end {
+            ranges.push(last_start..file_end);
+        }
+
+        let file_path = self.base_path.join(file.path.join("/"));
+        let mut file_handle = fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .await
+            .map_err(|e| {
+                error!("Failed to open file '{}': {}", file_path.display(), e);
+                TorrentError::storage_error_full("Failed to open file for writing", file_path.display().to_string(), e.to_string())
+            })?;
+        for range in ranges {
+            let (piece_index, piece_offset) = self.piece_position(range.start);
+            let data = self.partfile.read(piece_index, piece_offset, (range.end - range.start) as usize).await?;
+            trace!("Moving {} bytes of piece {} from the partfile to {}", data.len(), piece_index, file_path.display());
+            file_handle.seek(std::io::SeekFrom::Start(range.start - file_start)).await
                 .map_err(|e| {
-                    error!("Failed to set file length for '{}': {}", file_path.display(), e);
-                    TorrentError::storage_error_full("Failed to set file length", file_path.display().to_string(), e.to_string())
+                    error!("Failed to seek in file '{}': {}", file_path.display(), e);
+                    TorrentError::storage_error_full("Failed to seek in file", file_path.display().to_string(), e.to_string())
                 })?;
-            f.flush().await
+            file_handle.write_all(&data).await
                 .map_err(|e| {
-                    error!("Failed to flush file '{}': {}", file_path.display(), e);
-                    TorrentError::storage_error_full("Failed to flush file", file_path.display().to_string(), e.to_string())
+                    error!("Failed to write to file '{}': {}", file_path.display(), e);
+                    TorrentError::storage_error_full("Failed to write to file", file_path.display().to_string(), e.to_string())
                 })?;
         }
+        file_handle.flush().await
+            .map_err(|e| {
+                error!("Failed to flush file '{}': {}", file_path.display(), e);
+                TorrentError::storage_error_full("Failed to flush file", file_path.display().to_string(), e.to_string())
+            })?;
+        debug!("Moved partfile data of {} into place", file_path.display());
+        Ok(())
+    }
 
-        info!("File structure created successfully");
+    /// Set executable bits and create symlinks once the download is complete
+    pub async fn apply_file_attributes(&self) -> Result<()> {
+        for (index, file) in self.files.iter().enumerate() {
+            if file.is_padding() || self.is_skipped(index) {
+                continue;
+            }
+            let file_path = self.base_path.join(file.path.join("/"));
+            if file.attr.executable {
+                debug!("Marking {} as executable", file_path.display());
+                set_executable(&file_path).await
+                    .map_err(|e| {
+                        error!("Failed to make '{}' executable: {}", file_path.display(), e);
+                        TorrentError::storage_error_full("Failed to set file permissions", file_path.display().to_string(), e.to_string())
+                    })?;
+            }
+            if file.attr.symlink {
+                let Some(target) = &file.symlink_path else {
+                    warn!("Symlink {} has no target", file_path.display());
+                    continue;
+                };
+                let mut relative = PathBuf::new();
+                for _ in 1..file.path.len() {
+                    relative.push("..");
+                }
+                relative.push(target.join("/"));
+                debug!("Creating symlink {} -> {}", file_path.display(), relative.display());
+                create_symlink(&relative, &file_path).await
+                    .map_err(|e| {
+                        error!("Failed to create symlink '{}': {}", file_path.display(), e);
+                        TorrentError::storage_error_full("Failed to create symlink", file_path.display().to_string(), e.to_string())
+                    })?;
+            }
+        }
         Ok(())
     }
 
@@ -158,11 +291,8 @@ impl FileStorage {
         let mut remaining_data = data;
         let mut current_offset = offset;
   
-        // Collect files into a Vec to avoid Send issues with iterator
-        let files: Vec<_> = self.torrent_info.as_ref().files_iter().collect();
-        for file in files {
+        for (index, (file, file_start)) in self.file_spans().into_iter().enumerate() {
             let file_path = self.base_path.join(file.path.join("/"));
-            let file_start = self.get_file_offset(&file);
             let file_end = file_start + file.length;
  
             // Skip files that come before this offset
@@ -178,7 +308,19 @@ impl FileStorage {
                 file.length - write_offset,
             ) as usize;
  
-            if write_length > 0 {
+            // Padding is never written to disk
+            if file.is_padding() {
+                trace!("Skipping {} bytes of padding", write_length);
+                remaining_data = &remaining_data[write_length..];
+                current_offset += write_length as u64;
+            } else if self.in_partfile(index, &file_path) {
+                // Skipped files only get the bytes of pieces they share with wanted files
+                let (piece_index, piece_offset) = self.piece_position(current_offset);
+                trace!("Writing {} bytes of skipped file {} to the partfile", write_length, file_path.display());
+                self.partfile.write(piece_index, piece_offset, &remaining_data[..write_length]).await?;
+                remaining_data = &remaining_data[write_length..];
+                current_offset += write_length as u64;
+            } else if write_length > 0 {
                 trace!("Writing {} bytes to file {} at offset {}", write_length, file_path.display(), write_offset);
                 let mut file_handle = fs::OpenOptions::new()
                     .write(true)
@@ -238,11 +380,8 @@ impl FileStorage {
         let mut remaining_length = length as u64;
         let mut current_offset = offset;
   
-        // Collect files into a Vec to avoid Send issues with iterator
-        let files: Vec<_> = self.torrent_info.as_ref().files_iter().collect();
-        for file in files {
+        for (index, (file, file_start)) in self.file_spans().into_iter().enumerate() {
             let file_path = self.base_path.join(file.path.join("/"));
-            let file_start = self.get_file_offset(&file);
             let file_end = file_start + file.length;
  
             // Skip files that come before this offset
@@ -258,7 +397,18 @@ impl FileStorage {
                 file.length - read_offset,
             ) as usize;
  
-            if read_length > 0 {
+            // Padding reads as zeros
+            if file.is_padding() {
+                buffer.resize(buffer.len() + read_length, 0);
+                remaining_length -= read_length as u64;
+                current_offset += read_length as u64;
+            } else if self.in_partfile(index, &file_path) {
+                let (piece_index, piece_offset) = self.piece_position(current_offset);
+                trace!("Reading {} bytes of skipped file {} from the partfile", read_length, file_path.display());
+                buffer.extend(self.partfile.read(piece_index, piece_offset, read_length).await?);
+                remaining_length -= read_length as u64;
+                current_offset += read_length as u64;
+            } else if read_length > 0 {
                 trace!("Reading {} bytes from file {} at offset {}", read_length, file_path.display(), read_offset);
                 let mut file_handle = fs::File::open(&file_path).await
                     .map_err(|e| {
@@ -296,18 +446,12 @@ impl FileStorage {
     pub async fn verify_piece(&self, piece_index: u32) -> Result<bool> {
         debug!("Verifying piece {}", piece_index);
         let data = self.read_piece_internal(piece_index).await?;
-        let expected_hash = self.torrent_info.as_ref().piece_hash(piece_index as usize)
+        let is_valid = self.torrent_info.verify_piece(piece_index as usize, &data)
             .ok_or_else(|| {
                 error!("Invalid piece index: {}", piece_index);
                 TorrentError::validation_error_with_field("Invalid piece index", "piece_index".to_string())
             })?;
  
-        use sha1::{Digest, Sha1};
-        let mut hasher = Sha1::new();
-        hasher.update(&data);
-        let hash = hasher.finalize();
- 
-        let is_valid = hash.as_slice() == expected_hash;
         if is_valid {
             debug!("Piece {} verification: PASSED", piece_index);
         } else {
@@ -418,19 +562,54 @@ impl FileStorage {
         self.downloaded_pieces.iter().filter(|&&d| d).count()
     }
 
-    /// Get file offset for a file
-    fn get_file_offset(&self, file: &crate::torrent::TorrentFile) -> u64 {
+    /// Get every file with its offset in the torrent
+    ///
+    /// Padding files often share a path, so offsets are summed in order
+    /// rather than looked up by path.
+    fn file_spans(&self) -> Vec<(&TorrentFile, u64)> {
         let mut offset = 0u64;
-        for f in self.torrent_info.as_ref().files_iter() {
-            if f.path == file.path {
-                break;
-            }
-            offset += f.length;
-        }
-        offset
+        self.files.iter()
+            .map(|file| {
+                let start = offset;
+                offset += file.length;
+                (file, start)
+            })
+            .collect()
     }
 }
 
+#[cfg(unix)]
+async fn set_executable(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path).await?.permissions();
+    // Executable for everyone who may read it
+    permissions.set_mode(permissions.mode() | ((permissions.mode() & 0o444) >> 2));
+    fs::set_permissions(path, permissions).await
+}
+
+#[cfg(not(unix))]
+async fn set_executable(path: &Path) -> std::io::Result<()> {
+    trace!("Executable bit not supported here, ignoring for {}", path.display());
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    if fs::symlink_metadata(link).await.is_ok() {
+        fs::remove_file(link).await?;
+    }
+    fs::symlink(target, link).await
+}
+
+#[cfg(not(unix))]
+async fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    warn!("Symlinks not supported here, skipping {} -> {}", link.display(), target.display());
+    Ok(())
+}
+
 /// Resume data for a torrent
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResumeData {
@@ -504,6 +683,10 @@ impl StorageBackend for FileStorage {
         // We ignore the files parameter since we already have torrent_info
         self.create_files().await
     }
+
+    async fn set_file_priorities(&mut self, priorities: &[FilePriority]) -> Result<()> {
+        FileStorage::set_file_priorities(self, priorities).await
+    }
     
     async fn write_piece(&mut self, piece_index: u32, data: Bytes) -> Result<()> {
         // Convert Bytes to &[u8] for compatibility with existing write_piece
@@ -518,8 +701,8 @@ impl StorageBackend for FileStorage {
     }
     
     async fn complete(&self) -> Result<()> {
-        // No-op for file storage - files are already written
-        Ok(())
+        // Files are already written; only their attributes are left
+        self.apply_file_attributes().await
     }
     
     fn is_complete(&self) -> bool {
@@ -560,3 +743,148 @@ impl StorageBackend for FileStorage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::FileAttributes;
+
+    #[tokio::test]
+    async fn test_padding_files_and_attributes() {
+        let base_path = std::env::temp_dir().join(format!("test_file_storage_padding_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base_path).await;
+
+        let pad = FileAttributes { padding: true, ..Default::default() };
+        let executable = FileAttributes { executable: true, ..Default::default() };
+        let symlink = FileAttributes { symlink: true, ..Default::default() };
+        let files = vec![
+            TorrentFile { attr: executable, ..TorrentFile::new(vec!["run.sh".to_string()], 20_000) },
+            TorrentFile { attr: pad, ..TorrentFile::new(vec![".pad".to_string(), "12768".to_string()], 12_768) },
+            TorrentFile::new(vec!["data".to_string(), "b.bin".to_string()], 100),
+            TorrentFile {
+                attr: symlink,
+                symlink_path: Some(vec!["run.sh".to_string()]),
+                ..TorrentFile::new(vec!["data".to_string(), "link".to_string()], 0)
+            },
+        ];
+        let mut content = vec![1u8; 20_000];
+        content.resize(32_768, 0);
+        content.extend_from_slice(&[2u8; 100]);
+        let pieces = content.chunks(32_768).map(TorrentInfo::generate_info_hash).collect();
+        let torrent_info = Arc::new(TorrentInfo {
+            announce: String::new(),
+            announce_list: vec![],
+            info_hash: [0u8; 20],
+            piece_length: 32_768,
+            pieces,
+            name: "padded".to_string(),
+            length: None,
+            files: Some(files),
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+        });
+
+        let storage = FileStorage::new(base_path.clone(), torrent_info.clone()).await.unwrap();
+        storage.create_files().await.unwrap();
+        assert!(!base_path.join(".pad").exists());
+
+        storage.write_piece_internal(0, &content[..32_768]).await.unwrap();
+        storage.write_piece_internal(1, &content[32_768..]).await.unwrap();
+        assert!(!base_path.join(".pad").exists());
+        assert_eq!(fs::metadata(base_path.join("run.sh")).await.unwrap().len(), 20_000);
+        // The padding reads back as zeros
+        assert_eq!(storage.read_data(0, 32_768).await.unwrap(), content[..32_768].to_vec());
+
+        storage.complete().await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(base_path.join("run.sh")).await.unwrap().permissions().mode();
+            assert_ne!(mode & 0o100, 0);
+            let target = fs::read_link(base_path.join("data/link")).await.unwrap();
+            assert_eq!(target, PathBuf::from("../run.sh"));
+        }
+
+        let _ = fs::remove_dir_all(&base_path).await;
+    }
+
+    #[tokio::test]
+    async fn test_crafted_paths_stay_inside_base_path() {
+        let root = std::env::temp_dir().join(format!("test_file_storage_traversal_{}", std::process::id()));
+        let base_path = root.join("downloads");
+        let _ = fs::remove_dir_all(&root).await;
+
+        // Two files that try to escape, and two that land on the same path
+        let data = b"d4:infod5:filesld6:lengthi4e4:pathl2:..6:escapeee\
+d6:lengthi4e4:pathl0:3:tmp6:escapeee\
+d6:lengthi4e4:pathl3:dup1:.3:dupee\
+d6:lengthi4e4:pathl3:DUP3:dupeee\
+4:name1:x12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent_info = Arc::new(crate::torrent::TorrentParser::parse_bytes(data).unwrap());
+
+        let storage = FileStorage::new(base_path.clone(), torrent_info).await.unwrap();
+        storage.create_files().await.unwrap();
+        storage.write_piece_internal(0, b"abcdefghijklmnop").await.unwrap();
+
+        assert!(!root.join("escape").exists());
+        assert_eq!(fs::read(base_path.join("escape")).await.unwrap(), b"abcd");
+        assert_eq!(fs::read(base_path.join("tmp/escape")).await.unwrap(), b"efgh");
+        assert_eq!(fs::read(base_path.join("dup/dup")).await.unwrap(), b"ijkl");
+        assert_eq!(fs::read(base_path.join("dup/dup (1)")).await.unwrap(), b"mnop");
+
+        let _ = fs::remove_dir_all(&root).await;
+    }
+
+    #[tokio::test]
+    async fn test_skipped_files_are_not_allocated() {
+        let base_path = std::env::temp_dir().join(format!("test_file_storage_skipped_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base_path).await;
+
+        // b.bin shares piece 1 with the skipped c.bin; d.bin is only in piece 2
+        let files = vec![
+            TorrentFile::new(vec!["a.bin".to_string()], 16),
+            TorrentFile::new(vec!["b.bin".to_string()], 8),
+            TorrentFile::new(vec!["c.bin".to_string()], 8),
+            TorrentFile::new(vec!["d.bin".to_string()], 16),
+        ];
+        let torrent_info = Arc::new(TorrentInfo {
+            announce: String::new(),
+            announce_list: vec![],
+            info_hash: [0u8; 20],
+            piece_length: 16,
+            pieces: vec![[0u8; 20]; 3],
+            name: "skipped".to_string(),
+            length: None,
+            files: Some(files),
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+        });
+
+        let mut storage = FileStorage::new(base_path.clone(), torrent_info).await.unwrap();
+        let priorities = [FilePriority::Normal, FilePriority::High, FilePriority::Skip, FilePriority::Skip];
+        storage.set_file_priorities(&priorities).await.unwrap();
+        storage.create_files().await.unwrap();
+        assert!(base_path.join("a.bin").exists());
+        assert!(!base_path.join("c.bin").exists());
+        assert!(!base_path.join("d.bin").exists());
+        // Skipped data that was never written reads as zeros
+        assert_eq!(storage.read_data(16, 16).await.unwrap(), vec![0u8; 16]);
+
+        // A piece shared with a wanted file still has to be stored, in the partfile
+        storage.write_piece_internal(1, b"bbbbbbbbcccccccc").await.unwrap();
+        assert!(!base_path.join("c.bin").exists());
+        assert!(storage.partfile.exists());
+        assert_eq!(storage.read_data(16, 16).await.unwrap(), b"bbbbbbbbcccccccc".to_vec());
+
+        // Selecting a file later moves its data into place
+        storage.set_file_priorities(&[FilePriority::Normal; 4]).await.unwrap();
+        assert_eq!(fs::read(base_path.join("c.bin")).await.unwrap(), b"cccccccc");
+        assert_eq!(fs::metadata(base_path.join("d.bin")).await.unwrap().len(), 16);
+        assert!(!storage.partfile.exists());
+        assert_eq!(storage.read_data(16, 16).await.unwrap(), b"bbbbbbbbcccccccc".to_vec());
+
+        let _ = fs::remove_dir_all(&base_path).await;
+    }
+}
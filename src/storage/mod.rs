@@ -7,6 +7,9 @@ pub mod piece;
 pub mod file;
 pub mod resume;
 pub mod download;
+pub mod sanitize;
+pub mod priority;
+pub mod partfile;
 
 #[cfg(feature = "gdrive")]
 pub mod drive;
@@ -26,6 +29,15 @@ pub use resume::{ResumeData, PieceState, ResumeManager};
 // Re-export download types
 pub use download::{DownloadManager, PieceDownload, DownloadStats, FileDownloadManager};
 
+// Re-export file priority types
+pub use priority::{FilePriority, piece_priorities, select_files};
+
+// Re-export partfile types
+pub use partfile::PartFile;
+
+// Re-export path sanitization
+pub use sanitize::{sanitize_component, sanitize_path, sanitize_files};
+
 // Re-export drive types
 #[cfg(feature = "gdrive")]
 pub use drive::{DriveClient, DriveStorage, DriveFile};
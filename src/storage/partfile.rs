@@ -0,0 +1,134 @@
+//! Partfile store
+//!
+//! A piece shared by a wanted and a skipped file still has to be downloaded
+//! and verified, but the skipped file's bytes shouldn't create that file.
+//! They go into one sparse partfile per torrent instead, where each piece
+//! sits at `piece_index * piece_length`, so only the boundary pieces take
+//! up space on disk.
+
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, error, trace};
+use crate::error::TorrentError;
+
+/// Sparse file holding the data of skipped files, indexed by piece
+#[derive(Debug, Clone)]
+pub struct PartFile {
+    /// Location of the partfile
+    path: PathBuf,
+    /// Piece length of the torrent
+    piece_length: u64,
+}
+
+impl PartFile {
+    /// Create a partfile store; nothing touches the disk until data is written
+    pub fn new(path: PathBuf, piece_length: u64) -> Self {
+        Self { path, piece_length }
+    }
+
+    /// Get the partfile name for a torrent
+    pub fn file_name(info_hash: &[u8; 20]) -> String {
+        format!(".{}.parts", hex::encode(info_hash))
+    }
+
+    /// Get the location of the partfile
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check if the partfile has been created
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Write data at an offset in a piece
+    pub async fn write(&self, piece_index: u32, offset: u64, data: &[u8]) -> Result<()> {
+        let position = self.position(piece_index, offset);
+        trace!("Writing {} bytes of piece {} to partfile at {}", data.len(), piece_index, position);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .await
+            .map_err(|e| self.io_error("Failed to open partfile", e))?;
+        file.seek(std::io::SeekFrom::Start(position)).await
+            .map_err(|e| self.io_error("Failed to seek in partfile", e))?;
+        file.write_all(data).await
+            .map_err(|e| self.io_error("Failed to write to partfile", e))?;
+        file.flush().await
+            .map_err(|e| self.io_error("Failed to flush partfile", e))?;
+        Ok(())
+    }
+
+    /// Read data at an offset in a piece; anything never written reads as zeros
+    pub async fn read(&self, piece_index: u32, offset: u64, length: usize) -> Result<Vec<u8>> {
+        let position = self.position(piece_index, offset);
+        trace!("Reading {} bytes of piece {} from partfile at {}", length, piece_index, position);
+        let mut buffer = vec![0u8; length];
+        let mut file = match fs::File::open(&self.path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(buffer),
+            Err(e) => return Err(self.io_error("Failed to open partfile", e)),
+        };
+        let file_length = file.metadata().await
+            .map_err(|e| self.io_error("Failed to read partfile metadata", e))?
+            .len();
+        let available = file_length.saturating_sub(position).min(length as u64) as usize;
+        if available > 0 {
+            file.seek(std::io::SeekFrom::Start(position)).await
+                .map_err(|e| self.io_error("Failed to seek in partfile", e))?;
+            file.read_exact(&mut buffer[..available]).await
+                .map_err(|e| self.io_error("Failed to read from partfile", e))?;
+        }
+        Ok(buffer)
+    }
+
+    /// Delete the partfile once no file is skipped
+    pub async fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.path).await {
+            Ok(()) => {
+                debug!("Removed partfile {}", self.path.display());
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(self.io_error("Failed to remove partfile", e)),
+        }
+    }
+
+    fn position(&self, piece_index: u32, offset: u64) -> u64 {
+        piece_index as u64 * self.piece_length + offset
+    }
+
+    fn io_error(&self, message: &str, e: std::io::Error) -> anyhow::Error {
+        error!("{} '{}': {}", message, self.path.display(), e);
+        TorrentError::storage_error_full(message, self.path.display().to_string(), e.to_string()).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_partfile_read_write() {
+        let path = std::env::temp_dir().join(format!("test_partfile_{}.parts", std::process::id()));
+        let _ = fs::remove_file(&path).await;
+        let partfile = PartFile::new(path.clone(), 1024);
+
+        // Reads before anything is written are zeros
+        assert_eq!(partfile.read(3, 0, 4).await.unwrap(), vec![0u8; 4]);
+        assert!(!partfile.exists());
+
+        partfile.write(3, 10, b"abcd").await.unwrap();
+        assert_eq!(fs::metadata(&path).await.unwrap().len(), 3 * 1024 + 14);
+        assert_eq!(partfile.read(3, 8, 8).await.unwrap(), b"\0\0abcd\0\0".to_vec());
+        assert_eq!(partfile.read(1, 0, 2).await.unwrap(), vec![0u8; 2]);
+
+        partfile.remove().await.unwrap();
+        assert!(!partfile.exists());
+        partfile.remove().await.unwrap();
+    }
+}
@@ -6,6 +6,9 @@ use sha1::{Digest, Sha1};
 use anyhow::Result;
 use serde::{Serialize, Deserialize};
 
+use crate::torrent::merkle::PieceHashV2;
+use crate::torrent::TorrentInfo;
+
 /// Status of a piece
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PieceStatus {
@@ -64,6 +67,9 @@ pub struct Piece {
     pub data: Vec<u8>,
     /// Expected SHA1 hash
     pub hash: [u8; 20],
+    /// Expected merkle hash of v2 torrents, checked instead of the SHA1 hash
+    #[serde(default)]
+    pub hash_v2: Option<PieceHashV2>,
     /// Whether piece is verified
     pub verified: bool,
     /// Blocks within piece (None for missing blocks)
@@ -81,11 +87,18 @@ impl Piece {
             index,
             data: Vec::with_capacity(piece_length),
             hash: expected_hash,
+            hash_v2: None,
             verified: false,
             blocks: vec![None; num_blocks],
         }
     }
 
+    /// Set the merkle hash of a v2 piece
+    pub fn with_v2_hash(mut self, hash_v2: PieceHashV2) -> Self {
+        self.hash_v2 = Some(hash_v2);
+        self
+    }
+
     /// Add a block to the piece
     pub fn add_block(&mut self, offset: u32, data: Vec<u8>) -> Result<()> {
         let block_index = (offset as usize) / (16 * 1024);
@@ -113,6 +126,11 @@ impl Piece {
             }
         }
 
+        if let Some(hash_v2) = &self.hash_v2 {
+            self.verified = hash_v2.verify(&self.data);
+            return self.verified;
+        }
+
         // Calculate SHA1 hash
         let mut hasher = Sha1::new();
         hasher.update(&self.data);
@@ -198,6 +216,33 @@ impl PieceStorage {
         }
     }
 
+    /// Create piece storage for a torrent, with merkle hashes for v2 pieces
+    ///
+    /// Pieces of v2-only torrents end with their file, so they hold no padding.
+    pub fn for_torrent(torrent_info: &TorrentInfo) -> Self {
+        let v2_only = torrent_info.pieces.is_empty();
+        let pieces = (0..torrent_info.piece_count())
+            .map(|index| {
+                let (start, end) = torrent_info.piece_range(index).unwrap_or((0, 0));
+                let hash_v2 = torrent_info.v2.as_ref().and_then(|v2| v2.piece_hash(index));
+                let length = match hash_v2 {
+                    Some(hash_v2) if v2_only => hash_v2.length,
+                    _ => (end - start) as usize,
+                };
+                let piece = Piece::new(index as u32, length, torrent_info.piece_hash(index).unwrap_or_default());
+                match hash_v2 {
+                    Some(hash_v2) => piece.with_v2_hash(hash_v2),
+                    None => piece,
+                }
+            })
+            .collect();
+
+        Self {
+            pieces,
+            piece_length: torrent_info.piece_length as u32,
+        }
+    }
+
     /// Get a piece by index
     pub fn get_piece(&self, index: usize) -> Option<&Piece> {
         self.pieces.get(index)
@@ -0,0 +1,165 @@
+//! File priorities
+//!
+//! Each file has a priority. Skipped files are not downloaded and the
+//! priority of a piece is the highest priority of the files it touches.
+
+use std::fmt;
+use std::str::FromStr;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use crate::error::TorrentError;
+use crate::torrent::info::TorrentFile;
+
+/// Download priority of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub enum FilePriority {
+    /// Don't download the file
+    Skip,
+    /// Download after everything else
+    Low,
+    /// Download normally
+    #[default]
+    Normal,
+    /// Download before everything else
+    High,
+}
+
+impl FilePriority {
+    /// Check if the file is not downloaded
+    pub fn is_skipped(&self) -> bool {
+        *self == FilePriority::Skip
+    }
+}
+
+impl fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilePriority::Skip => write!(f, "skip"),
+            FilePriority::Low => write!(f, "low"),
+            FilePriority::Normal => write!(f, "normal"),
+            FilePriority::High => write!(f, "high"),
+        }
+    }
+}
+
+impl FromStr for FilePriority {
+    type Err = TorrentError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" | "off" | "0" => Ok(FilePriority::Skip),
+            "low" | "1" => Ok(FilePriority::Low),
+            "normal" | "2" => Ok(FilePriority::Normal),
+            "high" | "3" => Ok(FilePriority::High),
+            _ => Err(TorrentError::config_error_with_field(
+                format!("Unknown file priority '{}' (expected skip, low, normal or high)", s),
+                "priority",
+            )),
+        }
+    }
+}
+
+/// Get the priority of every piece from the priorities of the files
+///
+/// Padding and empty files don't count, so a piece is only skipped if every
+/// real file it touches is skipped. Files without a priority are normal.
+pub fn piece_priorities(files: &[TorrentFile], priorities: &[FilePriority], piece_length: u64, piece_count: usize) -> Vec<FilePriority> {
+    let mut pieces = vec![FilePriority::Skip; piece_count];
+    if piece_length == 0 {
+        return pieces;
+    }
+    let mut offset = 0u64;
+    for (index, file) in files.iter().enumerate() {
+        let start = offset;
+        offset += file.length;
+        if file.is_padding() || file.length == 0 {
+            continue;
+        }
+        let priority = priorities.get(index).copied().unwrap_or_default();
+        let first = (start / piece_length) as usize;
+        let last = ((offset - 1) / piece_length) as usize;
+        for piece in pieces.iter_mut().take(last + 1).skip(first) {
+            *piece = (*piece).max(priority);
+        }
+    }
+    pieces
+}
+
+/// Select files by glob patterns on their paths
+///
+/// With `only` patterns, files that match none of them are skipped. Files
+/// that match an `exclude` pattern are always skipped. `*` doesn't match
+/// `/`, use `**` to match across directories. Selecting no files at all is
+/// an error, since there would be nothing to download.
+pub fn select_files(files: &[TorrentFile], only: &[String], exclude: &[String]) -> std::result::Result<Vec<FilePriority>, TorrentError> {
+    let compile = |patterns: &[String], field: &str| -> std::result::Result<Vec<Pattern>, TorrentError> {
+        patterns.iter()
+            .map(|pattern| Pattern::new(pattern).map_err(|e| {
+                TorrentError::config_error_with_field(format!("Invalid file pattern '{}': {}", pattern, e), field)
+            }))
+            .collect()
+    };
+    let only = compile(only, "only")?;
+    let exclude = compile(exclude, "exclude")?;
+    let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+
+    let priorities: Vec<FilePriority> = files.iter()
+        .map(|file| {
+            let path = file.path.join("/");
+            let included = only.is_empty() || only.iter().any(|p| p.matches_with(&path, options));
+            let excluded = exclude.iter().any(|p| p.matches_with(&path, options));
+            if included && !excluded { FilePriority::Normal } else { FilePriority::Skip }
+        })
+        .collect();
+    if priorities.iter().zip(files).all(|(priority, file)| priority.is_skipped() || file.is_padding()) {
+        let field = if only.is_empty() { "exclude" } else { "only" };
+        return Err(TorrentError::config_error_with_field("No files match the file selection", field));
+    }
+    Ok(priorities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::FileAttributes;
+
+    fn files() -> Vec<TorrentFile> {
+        vec![
+            TorrentFile::new(vec!["video".to_string(), "movie.mkv".to_string()], 30),
+            TorrentFile {
+                attr: FileAttributes { padding: true, ..Default::default() },
+                ..TorrentFile::new(vec![".pad".to_string(), "2".to_string()], 2)
+            },
+            TorrentFile::new(vec!["video".to_string(), "extras".to_string(), "sample.mkv".to_string()], 10),
+            TorrentFile::new(vec!["readme.txt".to_string()], 5),
+        ]
+    }
+
+    #[test]
+    fn test_piece_priorities() {
+        let priorities = [FilePriority::High, FilePriority::Normal, FilePriority::Skip, FilePriority::Low];
+        let pieces = piece_priorities(&files(), &priorities, 16, 3);
+        // The padding doesn't keep piece 1 from taking the movie's priority
+        assert_eq!(pieces, vec![FilePriority::High, FilePriority::High, FilePriority::Low]);
+        assert_eq!(piece_priorities(&files(), &[], 16, 3), vec![FilePriority::Normal; 3]);
+    }
+
+    #[test]
+    fn test_select_files() {
+        let selected = select_files(&files(), &["video/*.mkv".to_string()], &[]).unwrap();
+        assert_eq!(selected[0], FilePriority::Normal);
+        assert_eq!(selected[2], FilePriority::Skip);
+        assert_eq!(selected[3], FilePriority::Skip);
+
+        let selected = select_files(&files(), &["video/**".to_string()], &["**/sample*".to_string()]).unwrap();
+        assert_eq!(selected[0], FilePriority::Normal);
+        assert_eq!(selected[2], FilePriority::Skip);
+
+        assert!(select_files(&files(), &["[".to_string()], &[]).is_err());
+        // Selecting nothing, or only padding, is an error
+        assert!(select_files(&files(), &["*.iso".to_string()], &[]).is_err());
+        assert!(select_files(&files(), &[], &["**".to_string()]).is_err());
+        assert!(select_files(&files(), &[".pad/*".to_string()], &[]).is_err());
+        assert_eq!("HIGH".parse::<FilePriority>().unwrap(), FilePriority::High);
+    }
+}
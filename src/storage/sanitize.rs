@@ -0,0 +1,220 @@
+//! Path sanitization
+//!
+//! File paths come straight from the torrent, so a malicious torrent could
+//! use `..`, absolute paths or drive letters to write outside the download
+//! directory. Every storage backend maps paths through here first.
+
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+use crate::torrent::info::TorrentFile;
+
+/// Longest file name most filesystems accept, in bytes
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+/// Longest extension kept when a name is truncated, in bytes
+const MAX_EXTENSION_LEN: usize = 16;
+
+/// Device names Windows reserves in every directory
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Make one path component safe to use as a file name
+///
+/// Separators, NUL and control characters are replaced, drive letters and
+/// reserved device names are neutralized and long names are truncated.
+/// `""`, `.` and `..` become `_`.
+pub fn sanitize_component(component: &str) -> String {
+    let mut name: String = component.chars()
+        .map(|c| if is_forbidden_char(c) { '_' } else { c })
+        .collect();
+
+    // "C:" would make the path absolute on Windows
+    let bytes = name.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        name.replace_range(1..2, "_");
+    }
+
+    // Windows drops trailing dots and spaces, which could turn a name into ".."
+    if cfg!(windows) {
+        name.truncate(name.trim_end_matches(['.', ' ']).len());
+    }
+
+    if name.is_empty() || name == "." || name == ".." {
+        return "_".to_string();
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end())) {
+        name.insert(0, '_');
+    }
+
+    truncate_component(&name, "")
+}
+
+/// Make a torrent file path safe to join onto a download directory
+///
+/// Empty, `.` and `..` components are dropped. The result is never empty.
+pub fn sanitize_path(path: &[String]) -> Vec<String> {
+    let sanitized: Vec<String> = path.iter()
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .map(|component| sanitize_component(component))
+        .collect();
+    if sanitized.is_empty() {
+        vec!["_".to_string()]
+    } else {
+        sanitized
+    }
+}
+
+/// Sanitize the paths of every file in a torrent
+///
+/// Files keep their order. Paths that clash with an earlier file or
+/// directory, ignoring case, get a ` (n)` suffix. Padding files never reach
+/// the disk, so they are left out of the clash checks. Symlink targets that
+/// would leave the torrent are dropped.
+pub fn sanitize_files(files: &[TorrentFile]) -> Vec<TorrentFile> {
+    let mut used_files: HashSet<Vec<String>> = HashSet::new();
+    // Directories map to the spelling they were first created with
+    let mut used_dirs: HashMap<Vec<String>, String> = HashMap::new();
+
+    files.iter()
+        .map(|file| {
+            let mut path = sanitize_path(&file.path);
+            if !file.is_padding() {
+                // A directory can't share its name with a file
+                for depth in 1..path.len() {
+                    if used_files.contains(&key(&path[..depth])) {
+                        let original = path[depth - 1].clone();
+                        path[depth - 1] = (1..)
+                            .map(|n| truncate_component(&original, &format!(" ({})", n)))
+                            .find(|name| {
+                                let mut candidate = key(&path[..depth - 1]);
+                                candidate.push(name.to_lowercase());
+                                !used_files.contains(&candidate)
+                            })
+                            .unwrap_or(original);
+                    }
+                    if let Some(spelling) = used_dirs.get(&key(&path[..depth])) {
+                        path[depth - 1] = spelling.clone();
+                    }
+                }
+                if used_files.contains(&key(&path)) || used_dirs.contains_key(&key(&path)) {
+                    let last = path.len() - 1;
+                    let original = path[last].clone();
+                    path[last] = (1..)
+                        .map(|n| truncate_component(&original, &format!(" ({})", n)))
+                        .find(|name| {
+                            let mut candidate = key(&path[..last]);
+                            candidate.push(name.to_lowercase());
+                            !used_files.contains(&candidate) && !used_dirs.contains_key(&candidate)
+                        })
+                        .unwrap_or(original);
+                }
+                for depth in 1..path.len() {
+                    used_dirs.entry(key(&path[..depth])).or_insert_with(|| path[depth - 1].clone());
+                }
+                used_files.insert(key(&path));
+            }
+
+            if path != file.path {
+                warn!("Sanitized file path '{}' to '{}'", file.path.join("/"), path.join("/"));
+            }
+
+            // Symlink targets are relative to the torrent root and must stay inside it
+            let symlink_path = file.symlink_path.as_ref().and_then(|target| {
+                if target.iter().any(|c| c.is_empty() || c == "." || c == ".." || c.contains(['/', '\\'])) {
+                    warn!("Dropping unsafe symlink target '{}' of {}", target.join("/"), path.join("/"));
+                    None
+                } else {
+                    Some(sanitize_path(target))
+                }
+            });
+
+            TorrentFile { path, symlink_path, ..file.clone() }
+        })
+        .collect()
+}
+
+fn is_forbidden_char(c: char) -> bool {
+    matches!(c, '/' | '\\' | '\0')
+        || c.is_control()
+        || (cfg!(windows) && matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+}
+
+/// Case-insensitive key for clash checks
+fn key(path: &[String]) -> Vec<String> {
+    path.iter().map(|component| component.to_lowercase()).collect()
+}
+
+/// Add a suffix before the extension and cut the stem to fit the length limit
+fn truncate_component(name: &str, suffix: &str) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= MAX_EXTENSION_LEN => name.split_at(dot),
+        _ => (name, ""),
+    };
+    let mut end = stem.len().min(MAX_COMPONENT_LEN.saturating_sub(suffix.len() + extension.len()));
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}{}", &stem[..end], suffix, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(components: &[&str]) -> Vec<String> {
+        components.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sanitize_component() {
+        assert_eq!(sanitize_component("movie.mkv"), "movie.mkv");
+        assert_eq!(sanitize_component("/etc"), "_etc");
+        assert_eq!(sanitize_component("a\\b"), "a_b");
+        assert_eq!(sanitize_component("C:"), "C_");
+        assert_eq!(sanitize_component("bad\0name"), "bad_name");
+        assert_eq!(sanitize_component(".."), "_");
+        assert_eq!(sanitize_component("con.txt"), "_con.txt");
+        assert_eq!(sanitize_component("console.txt"), "console.txt");
+        #[cfg(not(windows))]
+        assert_eq!(sanitize_component("Episode 1: Pilot"), "Episode 1: Pilot");
+
+        let long = format!("{}.mkv", "é".repeat(200));
+        let truncated = sanitize_component(&long);
+        assert!(truncated.len() <= MAX_COMPONENT_LEN);
+        assert!(truncated.ends_with("é.mkv"));
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(sanitize_path(&path(&["..", "..", "etc", "passwd"])), path(&["etc", "passwd"]));
+        assert_eq!(sanitize_path(&path(&["", "etc", "passwd"])), path(&["etc", "passwd"]));
+        assert_eq!(sanitize_path(&path(&["a", ".", "b"])), path(&["a", "b"]));
+        assert_eq!(sanitize_path(&path(&[".."])), path(&["_"]));
+    }
+
+    #[test]
+    fn test_sanitize_files_deduplicates() {
+        let files = vec![
+            TorrentFile::new(path(&["a.txt"]), 1),
+            TorrentFile::new(path(&["A.TXT"]), 1),
+            TorrentFile::new(path(&["x", "..", "a.txt"]), 1),
+            TorrentFile::new(path(&["dir"]), 1),
+            TorrentFile::new(path(&["dir", "b.txt"]), 1),
+            TorrentFile::new(path(&["DIR", "c.txt"]), 1),
+            TorrentFile {
+                symlink_path: Some(path(&["..", "secret"])),
+                ..TorrentFile::new(path(&["link"]), 0)
+            },
+        ];
+        let sanitized: Vec<String> = sanitize_files(&files).iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(sanitized, vec![
+            "a.txt", "A (1).TXT", "x/a.txt", "dir", "dir (1)/b.txt", "dir (1)/c.txt", "link",
+        ]);
+        assert_eq!(sanitize_files(&files)[6].symlink_path, None);
+    }
+}
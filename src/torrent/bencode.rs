@@ -0,0 +1,246 @@
+//! Bencode values for .torrent files
+//!
+//! Dictionary keys are kept as raw bytes in sorted order, so encoding a
+//! decoded value gives its canonical form.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Bencoded dictionary, sorted by raw key
+pub type BencodeDict = BTreeMap<Vec<u8>, BencodeValue>;
+
+/// Bencode value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BencodeValue>),
+    Dict(BencodeDict),
+}
+
+impl BencodeValue {
+    /// Decode a value, ignoring anything after it
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut idx = 0;
+        Self::decode_at(data, &mut idx)
+    }
+
+    /// Decode the value starting at `idx`, leaving `idx` just past it
+    pub fn decode_at(data: &[u8], idx: &mut usize) -> Result<Self> {
+        if *idx >= data.len() {
+            return Err(anyhow::anyhow!("Unexpected end of data"));
+        }
+
+        let byte = data[*idx];
+
+        match byte {
+            b'i' => {
+                // Integer
+                *idx += 1;
+                let end = data[*idx..].iter().position(|&b| b == b'e')
+                    .ok_or_else(|| anyhow::anyhow!("Unterminated integer"))? + *idx;
+                let num_str = std::str::from_utf8(&data[*idx..end])?;
+                let value: i64 = num_str.parse()?;
+                *idx = end + 1;
+                Ok(BencodeValue::Int(value))
+            }
+            b'l' => {
+                // List
+                *idx += 1;
+                let mut list = Vec::new();
+                while *idx < data.len() && data[*idx] != b'e' {
+                    list.push(Self::decode_at(data, idx)?);
+                }
+                *idx += 1; // skip 'e'
+                Ok(BencodeValue::List(list))
+            }
+            b'd' => {
+                // Dictionary
+                *idx += 1;
+                let mut dict = BencodeDict::new();
+                while *idx < data.len() && data[*idx] != b'e' {
+                    let key = match Self::decode_at(data, idx)? {
+                        BencodeValue::Bytes(b) => b,
+                        _ => return Err(anyhow::anyhow!("Dictionary key must be bytes")),
+                    };
+                    let value = Self::decode_at(data, idx)?;
+                    dict.insert(key, value);
+                }
+                *idx += 1; // skip 'e'
+                Ok(BencodeValue::Dict(dict))
+            }
+            b'0'..=b'9' => {
+                // Byte string
+                let colon = data[*idx..].iter().position(|&b| b == b':')
+                    .ok_or_else(|| anyhow::anyhow!("Unterminated string length"))? + *idx;
+                let len_str = std::str::from_utf8(&data[*idx..colon])?;
+                let length: usize = len_str.parse()?;
+                let start = colon + 1;
+                if length > data.len() - start {
+                    return Err(anyhow::anyhow!("String of {} bytes runs past the end of data", length));
+                }
+                *idx = start + length;
+                Ok(BencodeValue::Bytes(data[start..*idx].to_vec()))
+            }
+            _ => Err(anyhow::anyhow!("Unknown bencode type: {}", byte)),
+        }
+    }
+
+    /// Encode the value
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            BencodeValue::Int(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+            BencodeValue::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            BencodeValue::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            BencodeValue::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    out.extend_from_slice(format!("{}:", key.len()).as_bytes());
+                    out.extend_from_slice(key);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// Create a byte string value from text
+    pub fn string(s: &str) -> Self {
+        BencodeValue::Bytes(s.as_bytes().to_vec())
+    }
+
+    /// Get an integer value
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Get a byte string value
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Get a byte string as text, replacing invalid UTF-8
+    pub fn as_string(&self) -> Option<String> {
+        self.as_bytes().map(|bytes| String::from_utf8_lossy(bytes).to_string())
+    }
+
+    /// Get a list value
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// Get a dictionary value
+    pub fn as_dict(&self) -> Option<&BencodeDict> {
+        match self {
+            BencodeValue::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Look up a key if this is a dictionary
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        self.as_dict().and_then(|dict| dict.get(key.as_bytes()))
+    }
+}
+
+/// Get the tracker tiers of a torrent's root dictionary
+///
+/// Without an announce-list (BEP 12), announce is a tier of its own.
+pub fn tracker_tiers(root: &BencodeDict) -> Vec<Vec<String>> {
+    let tiers: Vec<Vec<String>> = root.get(b"announce-list".as_slice())
+        .and_then(|v| v.as_list())
+        .unwrap_or_default()
+        .iter()
+        .map(|tier| tier.as_list().unwrap_or_default().iter().filter_map(|url| url.as_string()).collect())
+        .filter(|tier: &Vec<String>| !tier.is_empty())
+        .collect();
+    if !tiers.is_empty() {
+        return tiers;
+    }
+    root.get(b"announce".as_slice())
+        .and_then(|v| v.as_string())
+        .filter(|url| !url.is_empty())
+        .map(|url| vec![vec![url]])
+        .unwrap_or_default()
+}
+
+/// Find where the value of `key` lies in an encoded top-level dictionary
+///
+/// Used to hash the `info` dictionary exactly as it appears in the file.
+pub fn dict_value_span(data: &[u8], key: &[u8]) -> Result<Option<Range<usize>>> {
+    if data.first() != Some(&b'd') {
+        return Err(anyhow::anyhow!("Root must be a dictionary"));
+    }
+    let mut idx = 1;
+    while idx < data.len() && data[idx] != b'e' {
+        let found = match BencodeValue::decode_at(data, &mut idx)? {
+            BencodeValue::Bytes(k) => k == key,
+            _ => return Err(anyhow::anyhow!("Dictionary key must be bytes")),
+        };
+        let start = idx;
+        BencodeValue::decode_at(data, &mut idx)?;
+        if found {
+            return Ok(Some(start..idx));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"d3:bar4:spam3:fooi42e4:listl1:ai-3eee";
+        let value = BencodeValue::decode(data).unwrap();
+        assert_eq!(value.get("foo").and_then(BencodeValue::as_int), Some(42));
+        assert_eq!(value.encode(), data.to_vec());
+    }
+
+    #[test]
+    fn test_encode_sorts_keys() {
+        let mut dict = BencodeDict::new();
+        dict.insert(b"zz".to_vec(), BencodeValue::Int(1));
+        dict.insert(b"aa".to_vec(), BencodeValue::string("x"));
+        assert_eq!(BencodeValue::Dict(dict).encode(), b"d2:aa1:x2:zzi1ee".to_vec());
+    }
+
+    #[test]
+    fn test_truncated_string() {
+        assert!(BencodeValue::decode(b"10:short").is_err());
+    }
+
+    #[test]
+    fn test_dict_value_span() {
+        let data = b"d8:announce3:url4:infod4:name1:xee";
+        let span = dict_value_span(data, b"info").unwrap().unwrap();
+        assert_eq!(&data[span], b"d4:name1:xe");
+        assert_eq!(dict_value_span(data, b"missing").unwrap(), None);
+    }
+}
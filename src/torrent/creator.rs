@@ -0,0 +1,465 @@
+//! Torrent creation
+//!
+//! Builds a .torrent file from a file or directory. Files are added in
+//! sorted order, so the same input always gives the same info hash, and
+//! pieces are hashed on several threads.
+
+use anyhow::Result;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+use crate::error::TorrentError;
+use crate::torrent::bencode::{BencodeDict, BencodeValue};
+use crate::torrent::info::TorrentInfo;
+use crate::torrent::parser::TorrentParser;
+
+/// Smallest piece length allowed
+pub const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+
+/// Largest piece length allowed, and the most chosen automatically
+pub const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// Number of pieces an automatic piece length stays under, where it can
+const TARGET_PIECE_COUNT: u64 = 2000;
+
+/// Choose a piece length for a torrent of `total_size` bytes
+pub fn auto_piece_length(total_size: u64) -> u64 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total_size.div_ceil(piece_length) > TARGET_PIECE_COUNT {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/// A file to add, with its path inside the torrent
+#[derive(Debug, Clone)]
+struct SourceFile {
+    path: PathBuf,
+    components: Vec<String>,
+    length: u64,
+}
+
+/// Builder for .torrent files
+#[derive(Debug, Clone)]
+pub struct TorrentCreator {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    trackers: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    source: Option<String>,
+    threads: usize,
+}
+
+impl TorrentCreator {
+    /// Create a torrent of a file or directory, dated now
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        Self {
+            path: path.into(),
+            piece_length: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: Some(now),
+            private: false,
+            source: None,
+            threads: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        }
+    }
+
+    /// Use a fixed piece length instead of choosing one from the total size
+    pub fn with_piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Set the trackers, one list of URLs per tier (BEP 12)
+    pub fn with_trackers(mut self, tiers: Vec<Vec<String>>) -> Self {
+        self.trackers = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        self
+    }
+
+    /// Set the web seed URLs (BEP 19)
+    pub fn with_web_seeds(mut self, urls: Vec<String>) -> Self {
+        self.web_seeds = urls;
+        self
+    }
+
+    /// Set the comment
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Set the program named as the creator
+    pub fn with_created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    /// Set the creation date as a Unix timestamp, or leave it out
+    pub fn with_creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    /// Mark the torrent private (BEP 27)
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Set the source tag, which gives the torrent a distinct info hash
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Hash pieces on this many threads
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Hash the files and build the bencoded torrent
+    pub fn create(&self) -> Result<Vec<u8>> {
+        let (name, files, single) = self.collect_files()?;
+        let total_size: u64 = files.iter().map(|f| f.length).sum();
+        let piece_length = match self.piece_length {
+            Some(piece_length) if !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length)
+                || !piece_length.is_power_of_two() => {
+                error!("Invalid piece length: {}", piece_length);
+                return Err(TorrentError::validation_error_with_field(
+                    format!(
+                        "Piece length must be a power of two from {} to {} bytes",
+                        MIN_PIECE_LENGTH, MAX_PIECE_LENGTH
+                    ),
+                    "piece_length",
+                ).into());
+            }
+            Some(piece_length) => piece_length,
+            None => auto_piece_length(total_size),
+        };
+        info!(
+            "Creating torrent '{}': {} files, {} bytes, {} KiB pieces",
+            name, files.len(), total_size, piece_length / 1024
+        );
+
+        let pieces = hash_pieces(&files, total_size, piece_length, self.threads)?;
+        info!("Hashed {} pieces", pieces.len());
+
+        let mut info = BencodeDict::new();
+        info.insert(b"name".to_vec(), BencodeValue::string(&name));
+        info.insert(b"piece length".to_vec(), BencodeValue::Int(piece_length as i64));
+        info.insert(b"pieces".to_vec(), BencodeValue::Bytes(pieces.concat()));
+        if single {
+            info.insert(b"length".to_vec(), BencodeValue::Int(total_size as i64));
+        } else {
+            let file_list = files.iter().map(|file| {
+                let mut entry = BencodeDict::new();
+                entry.insert(b"length".to_vec(), BencodeValue::Int(file.length as i64));
+                entry.insert(b"path".to_vec(), BencodeValue::List(
+                    file.components.iter().map(|c| BencodeValue::string(c)).collect(),
+                ));
+                BencodeValue::Dict(entry)
+            }).collect();
+            info.insert(b"files".to_vec(), BencodeValue::List(file_list));
+        }
+        if self.private {
+            info.insert(b"private".to_vec(), BencodeValue::Int(1));
+        }
+        if let Some(source) = &self.source {
+            info.insert(b"source".to_vec(), BencodeValue::string(source));
+        }
+
+        let mut root = BencodeDict::new();
+        if let Some(announce) = self.trackers.first().and_then(|tier| tier.first()) {
+            root.insert(b"announce".to_vec(), BencodeValue::string(announce));
+        }
+        if self.trackers.iter().map(Vec::len).sum::<usize>() > 1 {
+            let tiers = self.trackers.iter()
+                .map(|tier| BencodeValue::List(tier.iter().map(|url| BencodeValue::string(url)).collect()))
+                .collect();
+            root.insert(b"announce-list".to_vec(), BencodeValue::List(tiers));
+        }
+        if let Some(comment) = &self.comment {
+            root.insert(b"comment".to_vec(), BencodeValue::string(comment));
+        }
+        if let Some(created_by) = &self.created_by {
+            root.insert(b"created by".to_vec(), BencodeValue::string(created_by));
+        }
+        if let Some(creation_date) = self.creation_date {
+            root.insert(b"creation date".to_vec(), BencodeValue::Int(creation_date));
+        }
+        if !self.web_seeds.is_empty() {
+            let urls = self.web_seeds.iter().map(|url| BencodeValue::string(url)).collect();
+            root.insert(b"url-list".to_vec(), BencodeValue::List(urls));
+        }
+        root.insert(b"info".to_vec(), BencodeValue::Dict(info));
+
+        Ok(BencodeValue::Dict(root).encode())
+    }
+
+    /// Create the torrent and write it to a file
+    pub fn write(&self, output: &Path) -> Result<TorrentInfo> {
+        let data = self.create()?;
+        std::fs::write(output, &data)
+            .map_err(|e| {
+                error!("Failed to write torrent file '{}': {}", output.display(), e);
+                TorrentError::storage_error_full("Failed to write torrent file", output.display().to_string(), e.to_string())
+            })?;
+        info!("Wrote {}", output.display());
+        TorrentParser::parse_bytes(&data)
+    }
+
+    /// Find the torrent name and files, and whether it is a single-file torrent
+    fn collect_files(&self) -> Result<(String, Vec<SourceFile>, bool)> {
+        let metadata = std::fs::metadata(&self.path)
+            .map_err(|e| {
+                error!("Cannot read '{}': {}", self.path.display(), e);
+                TorrentError::storage_error_full("Cannot read torrent source", self.path.display().to_string(), e.to_string())
+            })?;
+        let name = std::fs::canonicalize(&self.path)
+            .ok()
+            .and_then(|path| path.file_name().map(|name| name.to_string_lossy().to_string()))
+            .ok_or_else(|| TorrentError::storage_error_with_path("Torrent source has no name", self.path.display().to_string()))?;
+
+        if metadata.is_file() {
+            let file = SourceFile { path: self.path.clone(), components: vec![name.clone()], length: metadata.len() };
+            return Ok((name, vec![file], true));
+        }
+
+        let mut files = Vec::new();
+        walk_dir(&self.path, &mut Vec::new(), &mut files)?;
+        if files.is_empty() {
+            error!("No files found in '{}'", self.path.display());
+            return Err(TorrentError::storage_error_with_path("No files to add to the torrent", self.path.display().to_string()).into());
+        }
+        Ok((name, files, false))
+    }
+}
+
+/// Add the files under a directory, in sorted order
+fn walk_dir(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<SourceFile>) -> Result<()> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| TorrentError::storage_error_full("Failed to read directory", dir.display().to_string(), e.to_string()))?;
+    let mut entries: Vec<_> = entries.collect::<std::io::Result<_>>()
+        .map_err(|e| TorrentError::storage_error_full("Failed to read directory", dir.display().to_string(), e.to_string()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file_type = entry.file_type()?;
+        // Follow links to files, but not to directories, which could loop
+        let metadata = std::fs::metadata(&path)?;
+        if metadata.is_dir() {
+            if file_type.is_symlink() {
+                warn!("Skipping link to directory '{}'", path.display());
+                continue;
+            }
+            prefix.push(name);
+            walk_dir(&path, prefix, files)?;
+            prefix.pop();
+        } else if metadata.is_file() {
+            debug!("Adding '{}' ({} bytes)", path.display(), metadata.len());
+            let mut components = prefix.clone();
+            components.push(name);
+            files.push(SourceFile { path, components, length: metadata.len() });
+        }
+    }
+    Ok(())
+}
+
+/// Hash every piece, handing pieces out to `threads` threads
+fn hash_pieces(files: &[SourceFile], total_size: u64, piece_length: u64, threads: usize) -> Result<Vec<[u8; 20]>> {
+    let piece_count = total_size.div_ceil(piece_length) as usize;
+    let mut pieces = vec![[0u8; 20]; piece_count];
+    let next_piece = AtomicUsize::new(0);
+
+    std::thread::scope(|scope| -> Result<()> {
+        let workers: Vec<_> = (0..threads.clamp(1, piece_count.max(1)))
+            .map(|_| scope.spawn(|| -> Result<Vec<(usize, [u8; 20])>> {
+                let mut reader = FileSpanReader::new(files);
+                // Never more than the whole torrent, however long the pieces
+                let mut buffer = vec![0u8; piece_length.min(total_size) as usize];
+                let mut hashed = Vec::new();
+                loop {
+                    let index = next_piece.fetch_add(1, Ordering::Relaxed);
+                    if index >= piece_count {
+                        return Ok(hashed);
+                    }
+                    let start = index as u64 * piece_length;
+                    let length = piece_length.min(total_size - start) as usize;
+                    reader.read_at(start, &mut buffer[..length])?;
+                    hashed.push((index, Sha1::digest(&buffer[..length]).into()));
+                }
+            }))
+            .collect();
+
+        for worker in workers {
+            let hashed = worker.join().map_err(|_| anyhow::anyhow!("Hashing thread panicked"))??;
+            for (index, hash) in hashed {
+                pieces[index] = hash;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(pieces)
+}
+
+/// Reads byte ranges of the files laid end to end
+struct FileSpanReader<'a> {
+    files: &'a [SourceFile],
+    /// Offset of each file's first byte
+    offsets: Vec<u64>,
+    open: Option<(usize, File)>,
+}
+
+impl<'a> FileSpanReader<'a> {
+    fn new(files: &'a [SourceFile]) -> Self {
+        let offsets = files.iter()
+            .scan(0u64, |offset, file| {
+                let start = *offset;
+                *offset += file.length;
+                Some(start)
+            })
+            .collect();
+        Self { files, offsets, open: None }
+    }
+
+    fn read_at(&mut self, mut offset: u64, mut buffer: &mut [u8]) -> Result<()> {
+        while !buffer.is_empty() {
+            // The last file starting at or before the offset; never an empty one
+            let index = self.offsets.partition_point(|start| *start <= offset) - 1;
+            let file_offset = offset - self.offsets[index];
+            let available = (self.files[index].length - file_offset).min(buffer.len() as u64) as usize;
+
+            let file = self.file(index)?;
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.read_exact(&mut buffer[..available])
+                .map_err(|e| TorrentError::storage_error_full(
+                    "File changed while hashing",
+                    self.files[index].path.display().to_string(),
+                    e.to_string(),
+                ))?;
+            buffer = &mut buffer[available..];
+            offset += available as u64;
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, index: usize) -> Result<&mut File> {
+        if self.open.as_ref().map(|(open, _)| *open) != Some(index) {
+            let path = &self.files[index].path;
+            let file = File::open(path)
+                .map_err(|e| TorrentError::storage_error_full("Failed to open file", path.display().to_string(), e.to_string()))?;
+            self.open = Some((index, file));
+        }
+        Ok(&mut self.open.as_mut().unwrap().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("test_creator_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("b.bin"), vec![2u8; 20_000]).unwrap();
+        std::fs::write(dir.join("a.txt"), vec![1u8; 30_000]).unwrap();
+        std::fs::write(dir.join("empty"), b"").unwrap();
+        std::fs::write(dir.join("sub").join("c.dat"), vec![3u8; 5_000]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(1024 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(auto_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_directory_round_trip() {
+        let dir = source_dir("dir");
+        let data = TorrentCreator::new(&dir)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_trackers(vec![
+                vec!["http://a.example/announce".to_string(), "http://b.example/announce".to_string()],
+                vec!["udp://c.example:6969".to_string()],
+            ])
+            .with_comment("nightly build")
+            .with_threads(3)
+            .create()
+            .unwrap();
+        let info = TorrentParser::parse_bytes(&data).unwrap();
+
+        let paths: Vec<String> = info.files_iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, vec!["a.txt", "b.bin", "empty", "sub/c.dat"]);
+        assert_eq!(info.total_size(), 55_000);
+        assert_eq!(info.announce, "http://a.example/announce");
+        assert_eq!(info.announce_list.len(), 3);
+
+        // Pieces span file boundaries
+        let mut content = vec![1u8; 30_000];
+        content.extend(vec![2u8; 20_000]);
+        content.extend(vec![3u8; 5_000]);
+        let expected: Vec<[u8; 20]> = content.chunks(MIN_PIECE_LENGTH as usize)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        assert_eq!(info.pieces, expected);
+
+        // The info hash covers exactly the info dictionary
+        let root = BencodeValue::decode(&data).unwrap();
+        let info_bytes = root.get("info").unwrap().encode();
+        assert_eq!(info.info_hash, TorrentInfo::generate_info_hash(&info_bytes));
+        assert_eq!(root.get("comment").and_then(BencodeValue::as_string).as_deref(), Some("nightly build"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_file_and_info_hash_inputs() {
+        let dir = source_dir("single");
+        let file = dir.join("a.txt");
+        let create = || TorrentCreator::new(&file).with_creation_date(None);
+
+        let info = TorrentParser::parse_bytes(&create().create().unwrap()).unwrap();
+        assert!(!info.is_multi_file());
+        assert_eq!(info.name, "a.txt");
+        assert_eq!(info.length, Some(30_000));
+        assert!(info.announce.is_empty());
+
+        // Only info keys change the hash
+        let plain = info.info_hash;
+        let commented = TorrentParser::parse_bytes(&create().with_comment("x").create().unwrap()).unwrap();
+        assert_eq!(commented.info_hash, plain);
+        let private = TorrentParser::parse_bytes(&create().with_private(true).create().unwrap()).unwrap();
+        assert_ne!(private.info_hash, plain);
+        let sourced = TorrentParser::parse_bytes(&create().with_source("site").create().unwrap()).unwrap();
+        assert_ne!(sourced.info_hash, plain);
+        assert_ne!(sourced.info_hash, private.info_hash);
+
+        assert!(create().with_piece_length(20_000).create().is_err());
+        assert!(create().with_piece_length(MAX_PIECE_LENGTH).create().is_ok());
+        assert!(create().with_piece_length(MAX_PIECE_LENGTH * 2).create().is_err());
+        assert!(create().with_piece_length(1 << 40).create().is_err());
+        assert!(TorrentCreator::new(dir.join("missing")).create().is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
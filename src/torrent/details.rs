@@ -0,0 +1,214 @@
+//! Torrent inspection
+//!
+//! Collects everything a .torrent file says, including the keys that
+//! downloading doesn't need, for the `info` command and for scripts.
+
+use std::path::Path;
+use anyhow::Result;
+use serde::Serialize;
+use tracing::{debug, error};
+
+use crate::error::TorrentError;
+use crate::torrent::bencode::{tracker_tiers, BencodeValue};
+use crate::torrent::info::TorrentInfo;
+use crate::torrent::magnet::MagnetInfo;
+use crate::torrent::parser::TorrentParser;
+
+/// Full description of a torrent
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentDetails {
+    /// Torrent name
+    pub name: String,
+    /// Metadata version: v1, v2 or hybrid
+    pub version: String,
+    /// SHA1 info hash (None for v2-only torrents)
+    pub info_hash_v1: Option<String>,
+    /// SHA-256 info hash (None for v1-only torrents)
+    pub info_hash_v2: Option<String>,
+    /// Total size of all files in bytes, padding included
+    pub total_size: u64,
+    /// Size of each piece in bytes
+    pub piece_length: u64,
+    /// Number of pieces
+    pub piece_count: usize,
+    /// Whether clients should only use the torrent's trackers (BEP 27)
+    pub private: bool,
+    /// Source tag
+    pub source: Option<String>,
+    /// Comment
+    pub comment: Option<String>,
+    /// Program that created the torrent
+    pub created_by: Option<String>,
+    /// Creation date as a Unix timestamp
+    pub creation_date: Option<i64>,
+    /// Tracker tiers in announce order
+    pub trackers: Vec<Vec<String>>,
+    /// GetRight-style web seeds (url-list)
+    pub web_seeds: Vec<String>,
+    /// Hoffman-style HTTP seeds (httpseeds)
+    pub http_seeds: Vec<String>,
+    /// Files in torrent order
+    pub files: Vec<FileDetails>,
+    /// Magnet link of the torrent
+    pub magnet: String,
+}
+
+/// Description of one file in a torrent
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDetails {
+    /// Path inside the torrent, `/`-separated
+    pub path: String,
+    /// Size in bytes
+    pub length: u64,
+    /// Offset of the file in the torrent data
+    pub offset: u64,
+    /// First and last piece holding the file (None for empty files)
+    pub pieces: Option<(usize, usize)>,
+    /// File attributes (BEP 47), e.g. `x` for executable
+    pub attr: String,
+    /// Whether the file is padding
+    pub padding: bool,
+    /// Link target of symlinks
+    pub symlink_path: Option<String>,
+}
+
+impl TorrentDetails {
+    /// Describe a torrent from the bytes of a .torrent file
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let torrent_info = TorrentParser::parse_bytes(data)?;
+        let root = BencodeValue::decode(data)?;
+        let info = root.get("info");
+        let string = |value: Option<&BencodeValue>| value.and_then(|v| v.as_string()).filter(|s| !s.is_empty());
+
+        let version = match (torrent_info.is_v2(), torrent_info.is_hybrid()) {
+            (true, true) => "hybrid",
+            (true, false) => "v2",
+            _ => "v1",
+        };
+        let v2_only = torrent_info.is_v2() && !torrent_info.is_hybrid();
+        let details = Self {
+            name: torrent_info.name.clone(),
+            version: version.to_string(),
+            info_hash_v1: (!v2_only).then(|| torrent_info.info_hash_hex()),
+            info_hash_v2: torrent_info.info_hash_v2().map(hex::encode),
+            total_size: torrent_info.total_size(),
+            piece_length: torrent_info.piece_length,
+            piece_count: torrent_info.piece_count(),
+            private: info.and_then(|info| info.get("private")).and_then(|v| v.as_int()) == Some(1),
+            source: string(info.and_then(|info| info.get("source"))),
+            comment: string(root.get("comment")),
+            created_by: string(root.get("created by")),
+            creation_date: root.get("creation date").and_then(|v| v.as_int()),
+            trackers: root.as_dict().map(tracker_tiers).unwrap_or_default(),
+            web_seeds: torrent_info.web_seeds.clone(),
+            http_seeds: torrent_info.http_seeds.clone(),
+            files: Self::file_details(&torrent_info),
+            magnet: MagnetInfo::from_torrent(&torrent_info).to_uri(),
+        };
+        debug!("Described torrent {} with {} files", details.name, details.files.len());
+        Ok(details)
+    }
+
+    /// Describe a .torrent file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|e| {
+            error!("Failed to read torrent file '{}': {}", path.display(), e);
+            TorrentError::storage_error_full("Failed to read torrent file", path.display().to_string(), e.to_string())
+        })?;
+        Self::from_bytes(&data)
+    }
+
+    /// Get the files that aren't padding
+    pub fn real_files(&self) -> impl Iterator<Item = &FileDetails> {
+        self.files.iter().filter(|file| !file.padding)
+    }
+
+    fn file_details(torrent_info: &TorrentInfo) -> Vec<FileDetails> {
+        let piece_length = torrent_info.piece_length.max(1);
+        let mut offset = 0;
+        torrent_info.files_iter()
+            .map(|file| {
+                let details = FileDetails {
+                    path: file.path.join("/"),
+                    length: file.length,
+                    offset,
+                    pieces: (file.length > 0).then(|| {
+                        ((offset / piece_length) as usize, ((offset + file.length - 1) / piece_length) as usize)
+                    }),
+                    attr: file.attr.to_attr_string(),
+                    padding: file.is_padding(),
+                    symlink_path: file.symlink_path.as_ref().map(|target| target.join("/")),
+                };
+                offset += file.length;
+                details
+            })
+            .collect()
+    }
+}
+
+/// Format a Unix timestamp as a UTC date and time
+pub fn format_unix_time(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let seconds = timestamp.rem_euclid(86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torrent_details() {
+        let data = b"d8:announce11:http://a/an13:announce-listll11:http://a/an11:http://b/anel9:udp://c:1ee\
+7:comment5:hello10:created by4:test13:creation datei1700000000e\
+4:infod5:filesld6:lengthi20e4:pathl5:a.binee\
+d4:attr1:p6:lengthi12e4:pathl4:.pad2:12ee\
+d4:attr1:x6:lengthi40e4:pathl3:bin3:runee\
+d6:lengthi0e4:pathl5:emptyeee\
+4:name4:root12:piece lengthi32e6:pieces60:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbcccccccccccccccccccc\
+7:privatei1e6:source3:ABCe\
+8:url-list9:http://s/e";
+        let details = TorrentDetails::from_bytes(data).unwrap();
+        assert_eq!(details.version, "v1");
+        assert!(details.private);
+        assert_eq!(details.source.as_deref(), Some("ABC"));
+        assert_eq!(details.comment.as_deref(), Some("hello"));
+        assert_eq!(details.created_by.as_deref(), Some("test"));
+        assert_eq!(details.trackers, vec![
+            vec!["http://a/an".to_string(), "http://b/an".to_string()],
+            vec!["udp://c:1".to_string()],
+        ]);
+        assert_eq!(details.files.len(), 4);
+        assert_eq!(details.real_files().count(), 3);
+        assert_eq!(details.files[2].path, "bin/run");
+        assert_eq!(details.files[2].pieces, Some((1, 2)));
+        assert_eq!(details.files[2].attr, "x");
+        assert_eq!(details.files[3].pieces, None);
+        assert!(details.magnet.starts_with("magnet:?xt=urn:btih:"));
+
+        let json = serde_json::to_value(&details).unwrap();
+        assert_eq!(json["creation_date"], 1_700_000_000);
+        assert_eq!(json["files"][0]["pieces"], serde_json::json!([0, 0]));
+    }
+
+    #[test]
+    fn test_format_unix_time() {
+        assert_eq!(format_unix_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_unix_time(1_700_000_000), "2023-11-14 22:13:20 UTC");
+        assert_eq!(format_unix_time(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_unix_time(-1), "1969-12-31 23:59:59 UTC");
+    }
+}
@@ -0,0 +1,268 @@
+//! Torrent editing
+//!
+//! Changes the keys outside the info dictionary of an existing .torrent
+//! file, such as trackers and the comment, while keeping the info hash.
+//! The info dictionary is written back byte for byte unless one of its own
+//! keys (private, source) is changed, which gives the torrent a new hash.
+
+use std::io::Write;
+use std::path::Path;
+use anyhow::Result;
+use tracing::{debug, error, info, warn};
+
+use crate::error::TorrentError;
+use crate::torrent::bencode::{dict_value_span, tracker_tiers, BencodeDict, BencodeValue};
+use crate::torrent::info::TorrentInfo;
+use crate::torrent::parser::TorrentParser;
+
+/// Editor for existing .torrent files
+#[derive(Debug, Clone)]
+pub struct TorrentEditor {
+    /// Root dictionary, info included
+    root: BencodeDict,
+    /// Info dictionary as it was read
+    original_info: BencodeValue,
+    /// Info dictionary exactly as encoded in the file
+    info_bytes: Vec<u8>,
+}
+
+impl TorrentEditor {
+    /// Edit a torrent from the bytes of a .torrent file
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        // Only edit torrents that parse, so the result is usable
+        TorrentParser::parse_bytes(data)?;
+        let root = match BencodeValue::decode(data)? {
+            BencodeValue::Dict(root) => root,
+            _ => return Err(anyhow::anyhow!("Root must be a dictionary")),
+        };
+        let span = dict_value_span(data, b"info")?
+            .ok_or_else(|| anyhow::anyhow!("Missing info dictionary"))?;
+        let original_info = root.get(b"info".as_slice()).cloned()
+            .ok_or_else(|| anyhow::anyhow!("Missing info dictionary"))?;
+        Ok(Self { root, original_info, info_bytes: data[span].to_vec() })
+    }
+
+    /// Edit a .torrent file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|e| {
+            error!("Failed to read torrent file '{}': {}", path.display(), e);
+            TorrentError::storage_error_full("Failed to read torrent file", path.display().to_string(), e.to_string())
+        })?;
+        Self::from_bytes(&data)
+    }
+
+    /// Get the tracker tiers; announce is a tier of its own without announce-list
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        tracker_tiers(&self.root)
+    }
+
+    /// Replace all trackers, one tier per inner list
+    pub fn with_trackers(mut self, tiers: Vec<Vec<String>>) -> Self {
+        let tiers: Vec<Vec<String>> = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        debug!("Setting {} tracker tiers", tiers.len());
+        match tiers.first().and_then(|tier| tier.first()) {
+            Some(announce) => self.root.insert(b"announce".to_vec(), BencodeValue::string(announce)),
+            None => self.root.remove(b"announce".as_slice()),
+        };
+        if tiers.iter().map(Vec::len).sum::<usize>() > 1 {
+            let tiers = tiers.iter()
+                .map(|tier| BencodeValue::List(tier.iter().map(|url| BencodeValue::string(url)).collect()))
+                .collect();
+            self.root.insert(b"announce-list".to_vec(), BencodeValue::List(tiers));
+        } else {
+            self.root.remove(b"announce-list".as_slice());
+        }
+        self
+    }
+
+    /// Replace text in every tracker URL, e.g. an old passkey with a new one
+    pub fn with_tracker_replaced(mut self, from: &str, to: &str) -> Self {
+        let mut replaced = 0;
+        let mut replace = |value: &mut BencodeValue| {
+            if let Some(url) = value.as_string().filter(|url| url.contains(from)) {
+                *value = BencodeValue::string(&url.replace(from, to));
+                replaced += 1;
+            }
+        };
+        if let Some(announce) = self.root.get_mut(b"announce".as_slice()) {
+            replace(announce);
+        }
+        if let Some(BencodeValue::List(tiers)) = self.root.get_mut(b"announce-list".as_slice()) {
+            for tier in tiers {
+                if let BencodeValue::List(urls) = tier {
+                    urls.iter_mut().for_each(&mut replace);
+                }
+            }
+        }
+        debug!("Replaced '{}' with '{}' in {} tracker URLs", from, to, replaced);
+        self
+    }
+
+    /// Set the comment, or remove it
+    pub fn with_comment(mut self, comment: Option<&str>) -> Self {
+        set_or_remove(&mut self.root, b"comment", comment.map(BencodeValue::string));
+        self
+    }
+
+    /// Replace the web seeds (url-list), removing them if empty
+    pub fn with_web_seeds(mut self, web_seeds: Vec<String>) -> Self {
+        let urls = (!web_seeds.is_empty())
+            .then(|| BencodeValue::List(web_seeds.iter().map(|url| BencodeValue::string(url)).collect()));
+        set_or_remove(&mut self.root, b"url-list", urls);
+        self
+    }
+
+    /// Mark the torrent private or public (BEP 27); changes the info hash
+    pub fn with_private(mut self, private: bool) -> Self {
+        set_or_remove(self.info_mut(), b"private", private.then_some(BencodeValue::Int(1)));
+        self
+    }
+
+    /// Set the source tag, or remove it; changes the info hash
+    pub fn with_source(mut self, source: Option<&str>) -> Self {
+        set_or_remove(self.info_mut(), b"source", source.map(BencodeValue::string));
+        self
+    }
+
+    /// Check if the edits change the info dictionary and so the info hash
+    pub fn changes_info_hash(&self) -> bool {
+        self.root.get(b"info".as_slice()) != Some(&self.original_info)
+    }
+
+    /// Encode the edited torrent
+    ///
+    /// The root dictionary is written in canonical key order. The info
+    /// dictionary keeps its original bytes unless it was changed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let info_changed = self.changes_info_hash();
+        if info_changed {
+            warn!("The info dictionary changed, so the torrent gets a new info hash");
+        }
+        let mut out = vec![b'd'];
+        for (key, value) in &self.root {
+            out.extend_from_slice(format!("{}:", key.len()).as_bytes());
+            out.extend_from_slice(key);
+            if key == b"info" && !info_changed {
+                out.extend_from_slice(&self.info_bytes);
+            } else {
+                out.extend_from_slice(&value.encode());
+            }
+        }
+        out.push(b'e');
+        out
+    }
+
+    /// Write the edited torrent to a file
+    ///
+    /// The data goes to a temporary file next to the output first, which is
+    /// then renamed over it, so an existing torrent is never left half written.
+    pub fn write(&self, output: &Path) -> Result<TorrentInfo> {
+        let data = self.to_bytes();
+        let file_name = output.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let temp = output.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+        let written = std::fs::File::create(&temp)
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.sync_all()
+            })
+            .and_then(|()| std::fs::rename(&temp, output));
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&temp);
+            error!("Failed to write torrent file '{}': {}", output.display(), e);
+            return Err(TorrentError::storage_error_full("Failed to write torrent file", output.display().to_string(), e.to_string()).into());
+        }
+        info!("Wrote {}", output.display());
+        TorrentParser::parse_bytes(&data)
+    }
+
+    fn info_mut(&mut self) -> &mut BencodeDict {
+        match self.root.get_mut(b"info".as_slice()) {
+            Some(BencodeValue::Dict(info)) => info,
+            // from_bytes only accepts torrents whose info is a dictionary
+            _ => unreachable!("info dictionary checked when parsing"),
+        }
+    }
+}
+
+/// Insert a key, or remove it when there is no value
+fn set_or_remove(dict: &mut BencodeDict, key: &[u8], value: Option<BencodeValue>) {
+    match value {
+        Some(value) => dict.insert(key.to_vec(), value),
+        None => dict.remove(key),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Root keys out of order and an info dictionary with unsorted keys
+    const TORRENT: &[u8] = b"d4:infod4:name1:x12:piece lengthi16e6:lengthi5e6:pieces20:bbbbbbbbbbbbbbbbbbbbe\
+8:announce24:http://t/abc123/announce7:comment3:olde";
+
+    #[test]
+    fn test_edit_keeps_info_hash() {
+        let original = TorrentParser::parse_bytes(TORRENT).unwrap();
+        let editor = TorrentEditor::from_bytes(TORRENT).unwrap()
+            .with_tracker_replaced("abc123", "xyz789")
+            .with_comment(None)
+            .with_web_seeds(vec!["http://seed/".to_string()]);
+        assert!(!editor.changes_info_hash());
+
+        let data = editor.to_bytes();
+        assert!(data.starts_with(b"d8:announce24:http://t/xyz789/announce4:infod4:name1:x"));
+        let edited = TorrentParser::parse_bytes(&data).unwrap();
+        assert_eq!(edited.info_hash, original.info_hash);
+        assert_eq!(edited.announce, "http://t/xyz789/announce");
+        assert_eq!(edited.web_seeds, vec!["http://seed/".to_string()]);
+
+        let tiers = vec![vec!["http://a/".to_string(), "http://b/".to_string()], vec!["udp://c:1".to_string()]];
+        let editor = TorrentEditor::from_bytes(&data).unwrap().with_trackers(tiers.clone());
+        assert_eq!(editor.tracker_tiers(), tiers);
+        let editor = editor.with_trackers(Vec::new());
+        assert!(editor.tracker_tiers().is_empty());
+        assert_eq!(TorrentParser::parse_bytes(&editor.to_bytes()).unwrap().info_hash, original.info_hash);
+    }
+
+    #[test]
+    fn test_edit_info_keys_changes_info_hash() {
+        let original = TorrentParser::parse_bytes(TORRENT).unwrap();
+        let editor = TorrentEditor::from_bytes(TORRENT).unwrap().with_private(false).with_source(None);
+        assert!(!editor.changes_info_hash());
+
+        let editor = editor.with_private(true).with_source(Some("SITE"));
+        assert!(editor.changes_info_hash());
+        let data = editor.to_bytes();
+        let edited = TorrentParser::parse_bytes(&data).unwrap();
+        assert_ne!(edited.info_hash, original.info_hash);
+
+        // The changed info dictionary is written canonically
+        let span = dict_value_span(&data, b"info").unwrap().unwrap();
+        assert!(data[span].starts_with(b"d6:lengthi5e4:name1:x12:piece lengthi16e6:pieces20:"));
+        assert_eq!(BencodeValue::decode(&data).unwrap().get("info").unwrap().get("private"), Some(&BencodeValue::Int(1)));
+    }
+
+    #[test]
+    fn test_write_replaces_file() {
+        let dir = std::env::temp_dir().join(format!("test_torrent_editor_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.torrent");
+        std::fs::write(&path, TORRENT).unwrap();
+
+        let edited = TorrentEditor::from_file(&path).unwrap()
+            .with_comment(Some("new"))
+            .write(&path)
+            .unwrap();
+        assert_eq!(edited.info_hash, TorrentParser::parse_bytes(TORRENT).unwrap().info_hash);
+        assert!(std::fs::read(&path).unwrap().ends_with(b"7:comment3:new4:infod4:name1:x12:piece lengthi16e6:lengthi5e6:pieces20:bbbbbbbbbbbbbbbbbbbbee"));
+        // Only the torrent is left, no temporary file
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A failed write leaves nothing behind
+        assert!(TorrentEditor::from_file(&path).unwrap().write(&dir.join("missing").join("y.torrent")).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -4,14 +4,210 @@
 
 use sha1::{Digest, Sha1};
 use anyhow::Result;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::torrent::merkle::{self, Hash256, MerkleTree, PieceHashV2, BLOCK_SIZE};
+
+/// File attributes from the `attr` key (BEP 47)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// Padding file that aligns the next file to a piece boundary ('p')
+    pub padding: bool,
+    /// Executable file ('x')
+    pub executable: bool,
+    /// Hidden file ('h')
+    pub hidden: bool,
+    /// Symbolic link ('l')
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    /// Parse an `attr` string, ignoring unknown flags
+    pub fn parse(attr: &[u8]) -> Self {
+        Self {
+            padding: attr.contains(&b'p'),
+            executable: attr.contains(&b'x'),
+            hidden: attr.contains(&b'h'),
+            symlink: attr.contains(&b'l'),
+        }
+    }
+
+    /// Encode as an `attr` string
+    pub fn to_attr_string(&self) -> String {
+        [(self.executable, 'x'), (self.hidden, 'h'), (self.padding, 'p'), (self.symlink, 'l')]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| *flag)
+            .collect()
+    }
+
+    /// Check if no attribute is set
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
 
 /// Represents a file in a multi-file torrent
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TorrentFile {
     /// File path components (e.g., ["folder", "subfolder", "file.txt"])
     pub path: Vec<String>,
     /// File size in bytes
     pub length: u64,
+    /// File attributes (BEP 47)
+    pub attr: FileAttributes,
+    /// SHA1 hash of the whole file, if the torrent has one
+    pub sha1: Option<[u8; 20]>,
+    /// Target of a symlink, relative to the torrent root
+    pub symlink_path: Option<Vec<String>>,
+}
+
+impl TorrentFile {
+    /// Create a regular file entry
+    pub fn new(path: Vec<String>, length: u64) -> Self {
+        Self { path, length, ..Default::default() }
+    }
+
+    /// Check if this is a padding file, which is never written to disk
+    pub fn is_padding(&self) -> bool {
+        self.attr.padding
+    }
+}
+
+/// A file in the v2 file tree
+#[derive(Debug, Clone)]
+pub struct TorrentFileV2 {
+    /// File path components
+    pub path: Vec<String>,
+    /// File size in bytes
+    pub length: u64,
+    /// Merkle root of the file (None for empty files)
+    pub pieces_root: Option<Hash256>,
+    /// File attributes (BEP 47)
+    pub attr: FileAttributes,
+}
+
+/// v2 metadata of a v2 or hybrid torrent (BEP 52)
+#[derive(Debug, Clone)]
+pub struct InfoV2 {
+    /// SHA-256 hash of info dictionary
+    pub info_hash: Hash256,
+    /// Files in file tree order
+    pub files: Vec<TorrentFileV2>,
+    /// Piece hashes of files longer than one piece, keyed by pieces root
+    pub piece_layers: HashMap<Hash256, Vec<Hash256>>,
+    /// Expected hash of every piece, None while its file's piece layer is unknown
+    piece_hashes: Vec<Option<PieceHashV2>>,
+    piece_length: u64,
+}
+
+impl InfoV2 {
+    /// Create v2 metadata, dropping piece layers that don't match their file
+    pub fn new(info_hash: Hash256, files: Vec<TorrentFileV2>, piece_layers: HashMap<Hash256, Vec<Hash256>>, piece_length: u64) -> Self {
+        let mut info = Self {
+            info_hash,
+            files,
+            piece_layers: HashMap::new(),
+            piece_hashes: Vec::new(),
+            piece_length,
+        };
+        for (pieces_root, layer) in piece_layers {
+            if let Err(e) = info.add_piece_layer(pieces_root, layer) {
+                warn!("Ignoring piece layer {}: {}", hex::encode(pieces_root), e);
+            }
+        }
+        info.update_piece_hashes();
+        info
+    }
+
+    /// Add a file's piece layer, e.g. one received from a peer
+    pub fn add_piece_layer(&mut self, pieces_root: Hash256, layer: Vec<Hash256>) -> Result<()> {
+        let file = self.files.iter()
+            .find(|file| file.pieces_root == Some(pieces_root))
+            .ok_or_else(|| anyhow::anyhow!("No file has this pieces root"))?;
+        if !merkle::verify_piece_layer(&layer, &pieces_root, self.piece_length, file.length) {
+            return Err(anyhow::anyhow!("Piece layer does not match the pieces root"));
+        }
+        self.piece_layers.insert(pieces_root, layer);
+        self.update_piece_hashes();
+        Ok(())
+    }
+
+    /// Add hashes received from a peer, keeping them if they are a whole piece layer
+    ///
+    /// `hashes` must prove the file's pieces root.
+    pub fn add_hashes(&mut self, pieces_root: Hash256, base_layer: u32, index: u32, length: u32, hashes: &[Hash256]) -> Result<()> {
+        let file = self.files.iter()
+            .find(|file| file.pieces_root == Some(pieces_root))
+            .ok_or_else(|| anyhow::anyhow!("No file has this pieces root"))?;
+        let height = merkle::leaf_width(file.length).trailing_zeros();
+        if !merkle::verify_hashes(&pieces_root, height, base_layer, index, length, hashes) {
+            return Err(anyhow::anyhow!("Hashes don't match the pieces root"));
+        }
+
+        let pieces = file.length.div_ceil(self.piece_length) as usize;
+        if base_layer == self.piece_layer_height() && index == 0 && length as usize >= pieces
+            && !self.piece_layers.contains_key(&pieces_root)
+        {
+            self.add_piece_layer(pieces_root, hashes[..pieces].to_vec())?;
+        }
+        Ok(())
+    }
+
+    /// Get hashes for a peer's hash request from the piece layers
+    ///
+    /// Only layers at or above the piece layer can be served.
+    pub fn hashes(&self, pieces_root: &Hash256, base_layer: u32, index: u32, length: u32, proof_layers: u32) -> Option<Vec<Hash256>> {
+        let height = self.piece_layer_height();
+        let layer = self.piece_layers.get(pieces_root)?;
+        let tree = MerkleTree::from_layer(layer.clone(), merkle::pad_hash(height));
+        tree.hashes(base_layer.checked_sub(height)?, index, length, proof_layers)
+    }
+
+    /// Get the layer of the piece hashes in every file's tree
+    pub fn piece_layer_height(&self) -> u32 {
+        (self.piece_length / BLOCK_SIZE as u64).max(1).trailing_zeros()
+    }
+
+    /// Get the expected hash of a piece
+    pub fn piece_hash(&self, index: usize) -> Option<PieceHashV2> {
+        self.piece_hashes.get(index).copied().flatten()
+    }
+
+    /// Get the number of pieces, with every file starting on a new piece
+    pub fn piece_count(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    /// Get files whose piece layer is still unknown
+    pub fn missing_piece_layers(&self) -> impl Iterator<Item = &TorrentFileV2> + '_ {
+        self.files.iter().filter(|file| {
+            file.length > self.piece_length
+                && file.pieces_root.is_some_and(|root| !self.piece_layers.contains_key(&root))
+        })
+    }
+
+    fn update_piece_hashes(&mut self) {
+        let piece_length = self.piece_length;
+        let leaves_per_piece = (piece_length as usize / BLOCK_SIZE).max(1);
+        let mut hashes = Vec::new();
+        for file in &self.files {
+            let Some(pieces_root) = file.pieces_root else { continue };
+            let pieces = file.length.div_ceil(piece_length) as usize;
+            let length_of = |piece: usize| (file.length - piece as u64 * piece_length).min(piece_length) as usize;
+            if pieces == 1 {
+                hashes.push(Some(PieceHashV2 { root: pieces_root, leaves: merkle::leaf_width(file.length), length: file.length as usize }));
+            } else if let Some(layer) = self.piece_layers.get(&pieces_root) {
+                hashes.extend(layer.iter().enumerate().map(|(piece, root)| {
+                    Some(PieceHashV2 { root: *root, leaves: leaves_per_piece, length: length_of(piece) })
+                }));
+            } else {
+                hashes.extend(std::iter::repeat_n(None, pieces));
+            }
+        }
+        self.piece_hashes = hashes;
+    }
 }
 
 /// High-level torrent information
@@ -21,7 +217,7 @@ pub struct TorrentInfo {
     pub announce: String,
     /// List of all tracker announce URLs
     pub announce_list: Vec<String>,
-    /// SHA1 hash of info dictionary
+    /// SHA1 hash of info dictionary (truncated SHA-256 for v2-only torrents)
     pub info_hash: [u8; 20],
     /// Size of each piece in bytes
     pub piece_length: u64,
@@ -33,6 +229,12 @@ pub struct TorrentInfo {
     pub length: Option<u64>,
     /// Files in multi-file torrents (None for single-file torrents)
     pub files: Option<Vec<TorrentFile>>,
+    /// v2 metadata (None for v1-only torrents)
+    pub v2: Option<InfoV2>,
+    /// GetRight-style web seed URLs (url-list, BEP 19)
+    pub web_seeds: Vec<String>,
+    /// Hoffman-style HTTP seed URLs (httpseeds, BEP 17)
+    pub http_seeds: Vec<String>,
 }
 
 impl TorrentInfo {
@@ -49,7 +251,57 @@ impl TorrentInfo {
 
     /// Get number of pieces in torrent
     pub fn piece_count(&self) -> usize {
-        self.pieces.len()
+        match &self.v2 {
+            Some(v2) if self.pieces.is_empty() => v2.piece_count(),
+            _ => self.pieces.len(),
+        }
+    }
+
+    /// Check if the torrent has v2 metadata
+    pub fn is_v2(&self) -> bool {
+        self.v2.is_some()
+    }
+
+    /// Check if the torrent has both v1 and v2 metadata
+    pub fn is_hybrid(&self) -> bool {
+        self.v2.is_some() && !self.pieces.is_empty()
+    }
+
+    /// Get the SHA-256 info hash of a v2 or hybrid torrent
+    pub fn info_hash_v2(&self) -> Option<Hash256> {
+        self.v2.as_ref().map(|v2| v2.info_hash)
+    }
+
+    /// Get the 20-byte hashes of every swarm the torrent is in
+    ///
+    /// Hybrid torrents are in the v1 swarm and in the swarm of their
+    /// truncated v2 hash.
+    pub fn swarm_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash];
+        if let Some(v2_hash) = self.info_hash_v2().map(truncate_info_hash) {
+            if !hashes.contains(&v2_hash) {
+                hashes.push(v2_hash);
+            }
+        }
+        hashes
+    }
+
+    /// Check piece data, using the merkle hash when the torrent has one
+    ///
+    /// Returns None if the piece index is out of range.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> Option<bool> {
+        if let Some(v2) = &self.v2 {
+            match v2.piece_hash(index) {
+                Some(hash) => return Some(hash.verify(data)),
+                // Without a piece layer, pieces of v2-only torrents can't be checked yet
+                None if self.pieces.is_empty() => return (index < v2.piece_count()).then_some(false),
+                None => {}
+            }
+        }
+        let expected = self.piece_hash(index)?;
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        Some(hasher.finalize().as_slice() == expected)
     }
 
     /// Get an iterator over all files in torrent
@@ -61,10 +313,7 @@ impl TorrentInfo {
             Box::new(files.iter().cloned()) as Box<dyn Iterator<Item = _> + '_>
         } else if self.length.is_some() {
             // Single file torrent - create a virtual TorrentFile
-            Box::new(std::iter::once(TorrentFile {
-                path: vec![name],
-                length,
-            })) as Box<dyn Iterator<Item = _> + '_>
+            Box::new(std::iter::once(TorrentFile::new(vec![name], length))) as Box<dyn Iterator<Item = _> + '_>
         } else {
             Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _> + '_>
         }
@@ -114,7 +363,7 @@ impl TorrentInfo {
 
     /// Get byte range for a specific piece
     pub fn piece_range(&self, index: usize) -> Option<(u64, u64)> {
-        if index >= self.pieces.len() {
+        if index >= self.piece_count() {
             return None;
         }
 
@@ -126,6 +375,13 @@ impl TorrentInfo {
     }
 }
 
+/// Truncate a SHA-256 info hash to the 20 bytes used in handshakes, trackers and the DHT
+pub fn truncate_info_hash(info_hash: Hash256) -> [u8; 20] {
+    let mut truncated = [0u8; 20];
+    truncated.copy_from_slice(&info_hash[..20]);
+    truncated
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -135,9 +391,21 @@ mod tests {
         let file = TorrentFile {
             path: vec!["folder".to_string(), "file.txt".to_string()],
             length: 1024,
+            ..Default::default()
         };
         assert_eq!(file.path.len(), 2);
         assert_eq!(file.length, 1024);
+        assert!(!file.is_padding());
+    }
+
+    #[test]
+    fn test_file_attributes() {
+        let attr = FileAttributes::parse(b"xhq");
+        assert!(attr.executable && attr.hidden);
+        assert!(!attr.padding && !attr.symlink);
+        assert_eq!(attr.to_attr_string(), "xh");
+        assert!(FileAttributes::parse(b"p").padding);
+        assert!(FileAttributes::parse(b"").is_empty());
     }
 
     #[test]
@@ -151,6 +419,9 @@ mod tests {
             name: "test.torrent".to_string(),
             length: Some(2048),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         assert_eq!(info.total_size(), 2048);
@@ -170,9 +441,12 @@ mod tests {
             name: "test.torrent".to_string(),
             length: None,
             files: Some(vec![
-                TorrentFile { path: vec!["file1.txt".to_string()], length: 500 },
-                TorrentFile { path: vec!["file2.txt".to_string()], length: 524 },
+                TorrentFile::new(vec!["file1.txt".to_string()], 500),
+                TorrentFile::new(vec!["file2.txt".to_string()], 524),
             ]),
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         assert_eq!(info.total_size(), 1024);
@@ -190,6 +464,9 @@ mod tests {
             name: "single.txt".to_string(),
             length: Some(2048),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         let files: Vec<_> = info.files_iter().collect();
@@ -209,9 +486,12 @@ mod tests {
             name: "multi".to_string(),
             length: None,
             files: Some(vec![
-                TorrentFile { path: vec!["file1.txt".to_string()], length: 100 },
-                TorrentFile { path: vec!["file2.txt".to_string()], length: 200 },
+                TorrentFile::new(vec!["file1.txt".to_string()], 100),
+                TorrentFile::new(vec!["file2.txt".to_string()], 200),
             ]),
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         let files: Vec<_> = info.files_iter().collect();
@@ -251,6 +531,9 @@ mod tests {
             name: "test".to_string(),
             length: Some(2048),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         assert_eq!(info.piece_hash(0), Some([2u8; 20]));
@@ -269,10 +552,38 @@ mod tests {
             name: "test".to_string(),
             length: Some(1500),
             files: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         assert_eq!(info.piece_range(0), Some((0, 1024)));
         assert_eq!(info.piece_range(1), Some((1024, 1500))); // Last piece is shorter
         assert_eq!(info.piece_range(2), None);
     }
+
+    #[test]
+    fn test_piece_layer_from_peer() {
+        let piece_length = 2 * BLOCK_SIZE as u64;
+        let data: Vec<u8> = (0..7 * BLOCK_SIZE).map(|i| (i % 253) as u8).collect();
+        let tree = MerkleTree::from_data(&data);
+        let file = TorrentFileV2 { path: vec!["f".to_string()], length: data.len() as u64, pieces_root: Some(tree.root()), attr: FileAttributes::default() };
+        let layers = HashMap::from([(tree.root(), tree.piece_layer(piece_length))]);
+
+        let seeder = InfoV2::new([1u8; 32], vec![file.clone()], layers, piece_length);
+        let mut leecher = InfoV2::new([1u8; 32], vec![file], HashMap::new(), piece_length);
+        assert_eq!(leecher.missing_piece_layers().count(), 1);
+        assert_eq!(leecher.piece_hash(0), None);
+
+        // Ask for the whole piece layer (4 nodes, one of them padding)
+        let height = seeder.piece_layer_height();
+        let hashes = seeder.hashes(&tree.root(), height, 0, 4, 0).unwrap();
+        assert!(seeder.hashes(&tree.root(), 0, 0, 4, 0).is_none());
+        assert!(leecher.add_hashes(tree.root(), height, 0, 4, &[[0u8; 32]; 4]).is_err());
+        leecher.add_hashes(tree.root(), height, 0, 4, &hashes).unwrap();
+
+        assert_eq!(leecher.missing_piece_layers().count(), 0);
+        assert_eq!(leecher.piece_count(), 4);
+        assert!(leecher.piece_hash(3).unwrap().verify(&data[6 * BLOCK_SIZE..]));
+    }
 }
@@ -2,15 +2,27 @@
 //!
 //! Handles parsing of magnet:// URIs to extract torrent metadata.
 
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use anyhow::{anyhow, Result};
 use tracing::{debug, info, warn};
 use url::Url;
+use url::form_urlencoded::byte_serialize;
+
+use crate::peer::{PeerManager, PeerSource};
+use crate::storage::priority::FilePriority;
+use crate::torrent::info::{truncate_info_hash, TorrentInfo};
+
+/// RFC 4648 base32 alphabet used by base32 info hashes
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
 
 /// Parsed magnet link information
 #[derive(Debug, Clone)]
 pub struct MagnetInfo {
-    /// SHA1 info hash from the magnet link
+    /// SHA1 info hash from the magnet link (truncated v2 hash for v2-only links)
     pub info_hash: [u8; 20],
+    /// SHA-256 info hash of v2 and hybrid torrents (urn:btmh)
+    pub info_hash_v2: Option<[u8; 32]>,
     /// Display name (dn parameter)
     pub display_name: Option<String>,
     /// Tracker URLs (tr parameters)
@@ -21,6 +33,106 @@ pub struct MagnetInfo {
     pub exact_sources: Vec<String>,
     /// Total file size in bytes (xl parameter)
     pub total_size: Option<u64>,
+    /// File indices to download, empty for all files (so parameter, BEP 53)
+    pub select_only: Vec<RangeInclusive<usize>>,
+    /// Peer addresses as `host:port` (x.pe parameters)
+    pub peers: Vec<String>,
+}
+
+impl MagnetInfo {
+    /// Build a magnet link for a torrent
+    ///
+    /// Carries the info hashes, name, size, trackers and web seeds.
+    pub fn from_torrent(torrent_info: &TorrentInfo) -> Self {
+        let mut trackers = Vec::new();
+        for tracker in std::iter::once(&torrent_info.announce).chain(&torrent_info.announce_list) {
+            if !tracker.is_empty() && !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+        Self {
+            info_hash: torrent_info.info_hash,
+            info_hash_v2: torrent_info.info_hash_v2(),
+            display_name: Some(torrent_info.name.clone()),
+            trackers,
+            web_seeds: torrent_info.web_seeds.clone(),
+            exact_sources: Vec::new(),
+            total_size: Some(torrent_info.total_size()),
+            select_only: Vec::new(),
+            peers: Vec::new(),
+        }
+    }
+
+    /// Check if the link only has a v2 info hash
+    fn is_v2_only(&self) -> bool {
+        self.info_hash_v2.map(truncate_info_hash) == Some(self.info_hash)
+    }
+
+    /// Serialize to a magnet URI
+    pub fn to_uri(&self) -> String {
+        let encode = |value: &str| byte_serialize(value.as_bytes()).collect::<String>();
+        let mut params = Vec::new();
+        if !self.is_v2_only() {
+            params.push(format!("xt=urn:btih:{}", hex::encode(self.info_hash)));
+        }
+        if let Some(hash) = self.info_hash_v2 {
+            params.push(format!("xt=urn:btmh:1220{}", hex::encode(hash)));
+        }
+        if let Some(name) = &self.display_name {
+            params.push(format!("dn={}", encode(name)));
+        }
+        if let Some(size) = self.total_size {
+            params.push(format!("xl={}", size));
+        }
+        params.extend(self.trackers.iter().map(|tracker| format!("tr={}", encode(tracker))));
+        params.extend(self.web_seeds.iter().map(|seed| format!("ws={}", encode(seed))));
+        params.extend(self.exact_sources.iter().map(|source| format!("xs={}", encode(source))));
+        params.extend(self.peers.iter().map(|peer| format!("x.pe={}", encode(peer))));
+        if !self.select_only.is_empty() {
+            let ranges: Vec<String> = self.select_only.iter()
+                .map(|range| if range.start() == range.end() {
+                    range.start().to_string()
+                } else {
+                    format!("{}-{}", range.start(), range.end())
+                })
+                .collect();
+            params.push(format!("so={}", ranges.join(",")));
+        }
+        format!("magnet:?{}", params.join("&"))
+    }
+
+    /// Resolve the x.pe peers and add them to a peer manager as manual peers
+    ///
+    /// Returns how many new peers were added. Peers that don't resolve are skipped.
+    pub async fn add_peers_to(&self, peer_manager: &PeerManager) -> usize {
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for peer in &self.peers {
+            match tokio::net::lookup_host(peer.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved),
+                Err(e) => warn!("Failed to resolve magnet peer {}: {}", peer, e),
+            }
+        }
+        debug!("Resolved {} of {} magnet peers", addrs.len(), self.peers.len());
+        peer_manager.add_peers_from(addrs, PeerSource::Manual).await
+    }
+
+    /// Get file priorities from the `so` parameter
+    ///
+    /// Returns None if the link doesn't select files.
+    pub fn file_priorities(&self, file_count: usize) -> Option<Vec<FilePriority>> {
+        if self.select_only.is_empty() {
+            return None;
+        }
+        Some((0..file_count)
+            .map(|index| {
+                if self.select_only.iter().any(|range| range.contains(&index)) {
+                    FilePriority::Normal
+                } else {
+                    FilePriority::Skip
+                }
+            })
+            .collect())
+    }
 }
 
 /// Parser for magnet links
@@ -59,21 +171,47 @@ impl MagnetParser {
         debug!("Found {} query parameters", params.len());
 
         let mut info_hash = None;
+        let mut info_hash_v2 = None;
         let mut display_name = None;
         let mut trackers = Vec::new();
         let mut web_seeds = Vec::new();
         let mut exact_sources = Vec::new();
         let mut total_size = None;
+        let mut select_only = Vec::new();
+        let mut peers = Vec::new();
 
         for (key, value) in params {
             debug!("Processing parameter: {} = {}", key, value);
 
-            match key.as_str() {
-                // Exact topic (xt) - contains the info hash
+            // Repeated parameters may be numbered, e.g. xt.1 and xt.2
+            let key = match key.split_once('.') {
+                Some((name, n)) if n.parse::<u32>().is_ok() => name,
+                _ => key.as_str(),
+            };
+
+            match key {
+                // Exact topic (xt) - contains the info hash; the first of each kind wins
                 "xt" => {
                     if let Some(hash) = Self::extract_info_hash(&value)? {
-                        info_hash = Some(hash);
-                        debug!("Extracted info hash: {}", hex::encode(hash));
+                        match info_hash {
+                            Some(existing) if existing != hash => {
+                                warn!("Ignoring additional info hash {}", hex::encode(hash));
+                            }
+                            _ => {
+                                info_hash = Some(hash);
+                                debug!("Extracted info hash: {}", hex::encode(hash));
+                            }
+                        }
+                    } else if let Some(hash) = Self::extract_info_hash_v2(&value)? {
+                        match info_hash_v2 {
+                            Some(existing) if existing != hash => {
+                                warn!("Ignoring additional v2 info hash {}", hex::encode(hash));
+                            }
+                            _ => {
+                                info_hash_v2 = Some(hash);
+                                debug!("Extracted v2 info hash: {}", hex::encode(hash));
+                            }
+                        }
                     }
                 }
                 // Display name (dn)
@@ -105,16 +243,36 @@ impl MagnetParser {
                         warn!("Invalid xl parameter value: {}", value);
                     }
                 }
+                // Select only (so) - file indices and ranges
+                "so" => {
+                    match Self::parse_select_only(&value) {
+                        Some(ranges) => {
+                            debug!("Selected files: {}", value);
+                            select_only.extend(ranges);
+                        }
+                        None => warn!("Invalid so parameter value: {}", value),
+                    }
+                }
+                // Peer address (x.pe) - host:port, IPv6 hosts in brackets
+                "x.pe" => {
+                    let port = value.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok());
+                    if port.is_some() {
+                        peers.push(value.clone());
+                        debug!("Added peer: {}", value);
+                    } else {
+                        warn!("Invalid x.pe parameter value: {}", value);
+                    }
+                }
                 _ => {
                     debug!("Ignoring unknown parameter: {}", key);
                 }
             }
         }
 
-        // Info hash is required
-        let info_hash = info_hash.ok_or_else(|| {
+        // Info hash is required; v2-only links join the swarm of the truncated v2 hash
+        let info_hash = info_hash.or_else(|| info_hash_v2.map(truncate_info_hash)).ok_or_else(|| {
             warn!("Magnet link missing required info hash (xt parameter)");
-            anyhow!("Magnet link must contain an info hash (xt=urn:btih:<hash>)")
+            anyhow!("Magnet link must contain an info hash (xt=urn:btih:<hash> or xt=urn:btmh:1220<hash>)")
         })?;
 
         info!(
@@ -126,14 +284,28 @@ impl MagnetParser {
 
         Ok(MagnetInfo {
             info_hash,
+            info_hash_v2,
             display_name,
             trackers,
             web_seeds,
             exact_sources,
             total_size,
+            select_only,
+            peers,
         })
     }
 
+    /// Parse an so parameter value like `0,2,4-6`
+    fn parse_select_only(so_value: &str) -> Option<Vec<RangeInclusive<usize>>> {
+        so_value.split(',')
+            .map(|item| {
+                let (start, end) = item.split_once('-').unwrap_or((item, item));
+                let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+                (start <= end).then_some(start..=end)
+            })
+            .collect()
+    }
+
     /// Extract info hash from an xt parameter value
     ///
     /// The xt parameter has the format: urn:btih:<hash>
@@ -170,11 +342,12 @@ impl MagnetParser {
         // Try base32 format (32 characters)
         if hash_str.len() == 32 {
             debug!("Attempting to parse as 32-character base32 string");
-            // Note: base32 decoding would require the base32 crate
-            // For now, we'll return an error suggesting hex format
-            return Err(anyhow!(
-                "Base32-encoded info hashes are not yet supported. Please use a hex-encoded magnet link."
-            ));
+            return Self::decode_base32(hash_str)
+                .map(Some)
+                .ok_or_else(|| {
+                    warn!("Invalid base32 info hash: {}", hash_str);
+                    anyhow!("Invalid base32 info hash: {}", hash_str)
+                });
         }
 
         warn!(
@@ -182,11 +355,51 @@ impl MagnetParser {
             hash_str.len()
         );
         Err(anyhow!(
-            "Info hash has invalid length: {} (expected 40 for hex)",
+            "Info hash has invalid length: {} (expected 40 for hex or 32 for base32)",
             hash_str.len()
         ))
     }
 
+    /// Decode a 32-character base32 string into a 20-byte hash
+    fn decode_base32(hash_str: &str) -> Option<[u8; 20]> {
+        let mut hash = [0u8; 20];
+        let mut bits = 0u64;
+        let mut bit_count = 0;
+        let mut index = 0;
+        for c in hash_str.bytes() {
+            let value = BASE32_ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())?;
+            bits = (bits << 5) | value as u64;
+            bit_count += 5;
+            if bit_count >= 8 {
+                bit_count -= 8;
+                *hash.get_mut(index)? = (bits >> bit_count) as u8;
+                index += 1;
+            }
+        }
+        (index == hash.len()).then_some(hash)
+    }
+
+    /// Extract a v2 info hash from an xt parameter value
+    ///
+    /// The xt parameter has the format: urn:btmh:<multihash>, where the
+    /// multihash is hex encoded and must be SHA-256 (prefix 1220).
+    fn extract_info_hash_v2(xt_value: &str) -> Result<Option<[u8; 32]>> {
+        let Some(multihash) = xt_value.strip_prefix("urn:btmh:") else {
+            return Ok(None);
+        };
+        let Some(hash_str) = multihash.strip_prefix("1220") else {
+            warn!("Unsupported multihash in magnet link: {}", multihash);
+            return Err(anyhow!("Only SHA-256 multihashes (1220...) are supported in urn:btmh"));
+        };
+
+        let bytes = hex::decode(hash_str).map_err(|e| anyhow!("Invalid v2 info hash: {}", e))?;
+        let hash: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            warn!("v2 info hash has {} bytes, expected 32", bytes.len());
+            anyhow!("v2 info hash has invalid length: {} bytes (expected 32)", bytes.len())
+        })?;
+        Ok(Some(hash))
+    }
+
     /// Check if a string looks like a magnet link
     pub fn is_magnet_link(input: &str) -> bool {
         input.trim().starts_with("magnet:?") || input.trim().starts_with("magnet://")
@@ -213,9 +426,8 @@ mod tests {
         // Check display name
         assert_eq!(info.display_name, Some("Big Buck Bunny".to_string()));
 
-        // Check we have multiple trackers
-        assert!(!info.trackers.is_empty());
-        assert!(info.trackers.len() >= 9);
+        // Check we have all eight trackers
+        assert_eq!(info.trackers.len(), 8);
 
         // Check we have web seeds
         assert!(!info.web_seeds.is_empty());
@@ -314,9 +526,103 @@ mod tests {
         assert!(MagnetParser::extract_info_hash(xt).is_err());
     }
 
+    #[test]
+    fn test_parse_v2_and_hybrid_magnets() {
+        let v2_hash = "caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let v2 = MagnetParser::parse(&format!("magnet:?xt=urn:btmh:1220{}", v2_hash)).unwrap();
+        assert_eq!(v2.info_hash_v2.map(hex::encode), Some(v2_hash.to_string()));
+        assert_eq!(hex::encode(v2.info_hash), &v2_hash[..40]);
+
+        let hybrid = MagnetParser::parse(&format!(
+            "magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&xt=urn:btmh:1220{}", v2_hash
+        )).unwrap();
+        assert_eq!(hex::encode(hybrid.info_hash), "dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c");
+        assert!(hybrid.info_hash_v2.is_some());
+
+        assert!(MagnetParser::parse(&format!("magnet:?xt=urn:btmh:1114{}", v2_hash)).is_err());
+        assert!(MagnetParser::parse("magnet:?xt=urn:btmh:1220abcd").is_err());
+    }
+
+    #[test]
+    fn test_parse_select_only() {
+        let magnet = "magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&so=0,2,4-5";
+        let info = MagnetParser::parse(magnet).unwrap();
+        assert_eq!(info.select_only, vec![0..=0, 2..=2, 4..=5]);
+        let priorities = info.file_priorities(7).unwrap();
+        let selected: Vec<usize> = (0..7).filter(|&i| !priorities[i].is_skipped()).collect();
+        assert_eq!(selected, vec![0, 2, 4, 5]);
+
+        // A bad so parameter selects nothing rather than failing the link
+        let info = MagnetParser::parse("magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&so=3-1").unwrap();
+        assert!(info.file_priorities(7).is_none());
+    }
+
     #[test]
     fn test_extract_info_hash_non_bittorrent() {
         let xt = "urn:sha1:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c";
         assert!(MagnetParser::extract_info_hash(xt).unwrap().is_none());
     }
+
+    #[test]
+    fn test_parse_base32_and_numbered_xt() {
+        let info = MagnetParser::parse("magnet:?xt=urn:btih:3WBFL3G4PSSV7MF37AJSHWDQMLNR63I4").unwrap();
+        assert_eq!(hex::encode(info.info_hash), "dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c");
+        let lower = MagnetParser::parse("magnet:?xt=urn:btih:3wbfl3g4pssv7mf37ajshwdqmlnr63i4").unwrap();
+        assert_eq!(lower.info_hash, info.info_hash);
+        assert!(MagnetParser::parse("magnet:?xt=urn:btih:1WBFL3G4PSSV7MF37AJSHWDQMLNR63I4").is_err());
+
+        // Numbered topics are read like repeated ones and the first hash wins
+        let info = MagnetParser::parse(
+            "magnet:?xt.1=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&xt.2=urn:btih:0000000000000000000000000000000000000000&tr.1=http://t1"
+        ).unwrap();
+        assert_eq!(hex::encode(info.info_hash), "dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c");
+        assert_eq!(info.trackers, vec!["http://t1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_parse_direct_peers() {
+        let magnet = "magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&x.pe=127.0.0.1:6881&x.pe=[::1]:6882&x.pe=noport";
+        let info = MagnetParser::parse(magnet).unwrap();
+        assert_eq!(info.peers, vec!["127.0.0.1:6881".to_string(), "[::1]:6882".to_string()]);
+
+        let peer_manager = PeerManager::default();
+        assert_eq!(info.add_peers_to(&peer_manager).await, 2);
+        assert_eq!(peer_manager.peer_count_from(PeerSource::Manual).await, 2);
+    }
+
+    #[test]
+    fn test_magnet_uri_round_trip() {
+        let magnet = "magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&dn=Big+Buck+Bunny&xl=42\
+&tr=udp%3A%2F%2Fexplodie.org%3A6969&ws=https%3A%2F%2Fwebtorrent.io%2Ftorrents%2F&x.pe=10.0.0.1%3A6881&so=0%2C2-4";
+        let info = MagnetParser::parse(magnet).unwrap();
+        assert_eq!(info.to_uri(), magnet.replace("so=0%2C2-4", "so=0,2-4"));
+
+        let v2_hash = "caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let v2_only = format!("magnet:?xt=urn:btmh:1220{}", v2_hash);
+        assert_eq!(MagnetParser::parse(&v2_only).unwrap().to_uri(), v2_only);
+    }
+
+    #[test]
+    fn test_magnet_from_torrent() {
+        let torrent_info = TorrentInfo {
+            announce: "http://tracker1.com/announce".to_string(),
+            announce_list: vec!["http://tracker1.com/announce".to_string(), "http://tracker2.com/announce".to_string()],
+            info_hash: [0xab; 20],
+            piece_length: 16384,
+            pieces: vec![[0; 20]],
+            name: "My File.bin".to_string(),
+            length: Some(1000),
+            files: None,
+            v2: None,
+            web_seeds: vec!["http://seed.com/".to_string()],
+            http_seeds: Vec::new(),
+        };
+        let uri = MagnetInfo::from_torrent(&torrent_info).to_uri();
+        let info = MagnetParser::parse(&uri).unwrap();
+        assert_eq!(info.info_hash, [0xab; 20]);
+        assert_eq!(info.display_name.as_deref(), Some("My File.bin"));
+        assert_eq!(info.total_size, Some(1000));
+        assert_eq!(info.trackers.len(), 2);
+        assert_eq!(info.web_seeds, vec!["http://seed.com/".to_string()]);
+    }
 }
@@ -0,0 +1,228 @@
+//! SHA-256 merkle trees for v2 torrents (BEP 52)
+//!
+//! Every file is hashed on its own. Its 16 KiB blocks are the leaves, the
+//! tree is padded with zero hashes to a power of two, and the root is the
+//! file's `pieces root`. The layer where each node covers one piece is the
+//! file's piece layer.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Size of a merkle leaf block
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// A SHA-256 hash
+pub type Hash256 = [u8; 32];
+
+/// Hash one leaf block
+pub fn hash_block(data: &[u8]) -> Hash256 {
+    Sha256::digest(data).into()
+}
+
+/// Hash two sibling nodes into their parent
+pub fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a subtree of `2^height` zero leaves
+pub fn pad_hash(height: u32) -> Hash256 {
+    (0..height).fold([0u8; 32], |pad, _| hash_pair(&pad, &pad))
+}
+
+/// Leaf hashes of some data
+pub fn block_hashes(data: &[u8]) -> Vec<Hash256> {
+    data.chunks(BLOCK_SIZE).map(hash_block).collect()
+}
+
+/// Root of the tree over `hashes`, filled up with `pad` to `width` nodes
+///
+/// `width` must be a power of two no smaller than `hashes.len()`.
+pub fn root(hashes: &[Hash256], width: usize, pad: Hash256) -> Hash256 {
+    let mut layer = hashes.to_vec();
+    let mut pad = pad;
+    let mut width = width.max(1);
+    while width > 1 {
+        layer = next_layer(&layer, &pad);
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+fn next_layer(layer: &[Hash256], pad: &Hash256) -> Vec<Hash256> {
+    layer.chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(pad)))
+        .collect()
+}
+
+/// Number of leaves in the padded tree of a file
+pub fn leaf_width(file_length: u64) -> usize {
+    (file_length.div_ceil(BLOCK_SIZE as u64) as usize).next_power_of_two()
+}
+
+/// Check a piece layer against the file's pieces root
+pub fn verify_piece_layer(layer: &[Hash256], pieces_root: &Hash256, piece_length: u64, file_length: u64) -> bool {
+    let leaves_per_piece = (piece_length as usize / BLOCK_SIZE).max(1);
+    let pieces = file_length.div_ceil(piece_length) as usize;
+    if layer.len() != pieces {
+        return false;
+    }
+    let width = (leaf_width(file_length) / leaves_per_piece).max(1);
+    root(layer, width, pad_hash(leaves_per_piece.trailing_zeros())) == *pieces_root
+}
+
+/// Check hashes from a hash request reply
+///
+/// `hashes` holds `length` nodes of `base_layer` starting at `index`,
+/// followed by the uncle hashes up to the root of a tree `height` layers high.
+pub fn verify_hashes(pieces_root: &Hash256, height: u32, base_layer: u32, index: u32, length: u32, hashes: &[Hash256]) -> bool {
+    let length = length as usize;
+    if length == 0 || !length.is_power_of_two() || !(index as usize).is_multiple_of(length) || hashes.len() < length {
+        return false;
+    }
+    let mut node = root(&hashes[..length], length, [0u8; 32]);
+    let mut position = index as usize / length;
+    let mut layer = base_layer + length.trailing_zeros();
+    for uncle in &hashes[length..] {
+        node = if position.is_multiple_of(2) { hash_pair(&node, uncle) } else { hash_pair(uncle, &node) };
+        position /= 2;
+        layer += 1;
+    }
+    layer == height && position == 0 && node == *pieces_root
+}
+
+/// Expected hash of one piece of a v2 torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceHashV2 {
+    /// Root of the piece's subtree
+    pub root: Hash256,
+    /// Number of leaves in the subtree, including zero padding
+    pub leaves: usize,
+    /// Bytes of file data in the piece
+    pub length: usize,
+}
+
+impl PieceHashV2 {
+    /// Check piece data, ignoring anything past the end of the file
+    pub fn verify(&self, data: &[u8]) -> bool {
+        data.len() >= self.length && root(&block_hashes(&data[..self.length]), self.leaves, [0u8; 32]) == self.root
+    }
+}
+
+/// Full merkle tree of one file
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    /// Layers from the padded leaves up to the root
+    layers: Vec<Vec<Hash256>>,
+    /// Number of real leaves
+    leaf_count: usize,
+}
+
+impl MerkleTree {
+    /// Build the tree over some leaf hashes
+    pub fn new(leaves: Vec<Hash256>) -> Self {
+        Self::from_layer(leaves, [0u8; 32])
+    }
+
+    /// Build the upper part of a tree from one of its layers, padded with `pad`
+    pub fn from_layer(nodes: Vec<Hash256>, pad: Hash256) -> Self {
+        let leaf_count = nodes.len();
+        let mut layer = nodes;
+        layer.resize(leaf_count.next_power_of_two(), pad);
+        let mut layers = vec![layer];
+        while layers.last().map_or(0, Vec::len) > 1 {
+            let next = next_layer(layers.last().unwrap(), &pad);
+            layers.push(next);
+        }
+        Self { layers, leaf_count }
+    }
+
+    /// Build the tree over a file's contents
+    pub fn from_data(data: &[u8]) -> Self {
+        Self::new(block_hashes(data))
+    }
+
+    /// Get the root hash
+    pub fn root(&self) -> Hash256 {
+        self.layers.last().and_then(|layer| layer.first()).copied().unwrap_or([0u8; 32])
+    }
+
+    /// Get the number of layers above the leaves
+    pub fn height(&self) -> u32 {
+        self.layers.len() as u32 - 1
+    }
+
+    /// Get the piece layer for a piece length, without padding
+    pub fn piece_layer(&self, piece_length: u64) -> Vec<Hash256> {
+        let leaves_per_piece = (piece_length as usize / BLOCK_SIZE).max(1);
+        let height = leaves_per_piece.trailing_zeros() as usize;
+        match self.layers.get(height) {
+            Some(layer) => layer[..self.leaf_count.div_ceil(leaves_per_piece)].to_vec(),
+            None => vec![self.root()],
+        }
+    }
+
+    /// Get hashes for a hash request, followed by up to `proof_layers` uncle hashes
+    ///
+    /// Returns None if the range is not in the tree.
+    pub fn hashes(&self, base_layer: u32, index: u32, length: u32, proof_layers: u32) -> Option<Vec<Hash256>> {
+        let layer = self.layers.get(base_layer as usize)?;
+        let (index, length) = (index as usize, length as usize);
+        if length == 0 || !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > layer.len() {
+            return None;
+        }
+        let mut hashes = layer[index..index + length].to_vec();
+        let subtree_layer = base_layer as usize + length.trailing_zeros() as usize;
+        let mut position = index / length;
+        let top = (subtree_layer + proof_layers as usize).min(self.layers.len() - 1);
+        for layer in &self.layers[subtree_layer..top] {
+            hashes.push(layer[position ^ 1]);
+            position /= 2;
+        }
+        Some(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_pads_with_zero_hashes() {
+        let leaves = vec![hash_block(b"a"), hash_block(b"b"), hash_block(b"c")];
+        let expected = hash_pair(&hash_pair(&leaves[0], &leaves[1]), &hash_pair(&leaves[2], &[0u8; 32]));
+        assert_eq!(root(&leaves, 4, [0u8; 32]), expected);
+        assert_eq!(MerkleTree::new(leaves).root(), expected);
+        assert_eq!(root(&[], 4, [0u8; 32]), pad_hash(2));
+    }
+
+    #[test]
+    fn test_piece_layer_matches_root() {
+        let piece_length = 2 * BLOCK_SIZE as u64;
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let tree = MerkleTree::from_data(&data);
+        let layer = tree.piece_layer(piece_length);
+        assert_eq!(layer.len(), 3);
+        assert!(verify_piece_layer(&layer, &tree.root(), piece_length, data.len() as u64));
+
+        // The last piece is short but still padded to a full piece of leaves
+        let last = PieceHashV2 { root: layer[2], leaves: 2, length: BLOCK_SIZE + 100 };
+        assert!(last.verify(&data[4 * BLOCK_SIZE..]));
+        assert!(!last.verify(&data[3 * BLOCK_SIZE..4 * BLOCK_SIZE + 100]));
+    }
+
+    #[test]
+    fn test_hash_request_proof() {
+        let data: Vec<u8> = (0..8 * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8).collect();
+        let tree = MerkleTree::from_data(&data);
+        let hashes = tree.hashes(0, 4, 2, 10).unwrap();
+        // Two leaves, then uncles at layers 1 and 2
+        assert_eq!(hashes.len(), 4);
+        assert!(verify_hashes(&tree.root(), tree.height(), 0, 4, 2, &hashes));
+        assert!(!verify_hashes(&tree.root(), tree.height(), 0, 2, 2, &hashes));
+        assert!(tree.hashes(0, 3, 2, 0).is_none());
+    }
+}
@@ -5,7 +5,15 @@
 pub mod parser;
 pub mod info;
 pub mod magnet;
+pub mod bencode;
+pub mod creator;
+pub mod merkle;
+pub mod details;
+pub mod editor;
 
 pub use parser::TorrentParser;
-pub use info::{TorrentInfo, TorrentFile};
+pub use info::{FileAttributes, InfoV2, TorrentInfo, TorrentFile, TorrentFileV2};
 pub use magnet::{MagnetParser, MagnetInfo};
+pub use creator::TorrentCreator;
+pub use details::{FileDetails, TorrentDetails};
+pub use editor::TorrentEditor;
@@ -5,9 +5,14 @@
 use serde_bencode::{ser};
 use serde::{Serialize};
 use anyhow::Result;
+use sha2::{Digest, Sha256};
 use tracing::{debug, error, info, trace, warn};
 
-use crate::torrent::info::{TorrentInfo, TorrentFile};
+use std::collections::HashMap;
+
+use crate::torrent::bencode::{dict_value_span, BencodeDict, BencodeValue};
+use crate::torrent::info::{truncate_info_hash, FileAttributes, InfoV2, TorrentInfo, TorrentFile, TorrentFileV2};
+use crate::torrent::merkle::{Hash256, BLOCK_SIZE};
 use crate::error::TorrentError;
 
 /// Parser for .torrent files
@@ -53,61 +58,7 @@ impl TorrentParser {
     }
 
     fn parse_value(data: &[u8], idx: &mut usize) -> Result<BencodeValue> {
-        if *idx >= data.len() {
-            return Err(anyhow::anyhow!("Unexpected end of data"));
-        }
-
-        let byte = data[*idx];
-
-        match byte {
-            b'i' => {
-                // Integer
-                *idx += 1;
-                let end = data[*idx..].iter().position(|&b| b == b'e')
-                    .ok_or_else(|| anyhow::anyhow!("Unterminated integer"))? + *idx;
-                let num_str = std::str::from_utf8(&data[*idx..end])?;
-                let value: i64 = num_str.parse()?;
-                *idx = end + 1;
-                Ok(BencodeValue::Int(value))
-            }
-            b'l' => {
-                // List
-                *idx += 1;
-                let mut list = Vec::new();
-                while *idx < data.len() && data[*idx] != b'e' {
-                    list.push(Self::parse_value(data, idx)?);
-                }
-                *idx += 1; // skip 'e'
-                Ok(BencodeValue::List(list))
-            }
-            b'd' => {
-                // Dictionary
-                *idx += 1;
-                let mut dict = std::collections::BTreeMap::new();
-                while *idx < data.len() && data[*idx] != b'e' {
-                    let key = match Self::parse_value(data, idx)? {
-                        BencodeValue::Bytes(b) => b,
-                        _ => return Err(anyhow::anyhow!("Dictionary key must be bytes")),
-                    };
-                    let value = Self::parse_value(data, idx)?;
-                    dict.insert(key, value);
-                }
-                *idx += 1; // skip 'e'
-                Ok(BencodeValue::Dict(dict))
-            }
-            b'0'..=b'9' => {
-                // Byte string
-                let colon = data[*idx..].iter().position(|&b| b == b':')
-                    .ok_or_else(|| anyhow::anyhow!("Unterminated string length"))? + *idx;
-                let len_str = std::str::from_utf8(&data[*idx..colon])?;
-                let length: usize = len_str.parse()?;
-                *idx = colon + 1;
-                let start = *idx;
-                *idx += length;
-                Ok(BencodeValue::Bytes(data[start..*idx].to_vec()))
-            }
-            _ => Err(anyhow::anyhow!("Unknown bencode type: {}", byte)),
-        }
+        BencodeValue::decode_at(data, idx)
     }
 
     fn convert_to_torrent_info(parsed: BencodeValue, original_data: &[u8]) -> Result<TorrentInfo> {
@@ -124,13 +75,19 @@ impl TorrentParser {
             dict.iter().find(|(k, _)| k.as_slice() == key).and_then(|(_, v)| v.as_bytes())
         }
 
-        // Get announce URL
-        let announce_bytes = get_bytes(&root_dict, b"announce")
-            .ok_or_else(|| anyhow::anyhow!("Missing announce field"))?;
-        let announce = String::from_utf8_lossy(announce_bytes).to_string();
+        // Get announce URL; trackerless torrents rely on the DHT
+        let announce = get_bytes(&root_dict, b"announce")
+            .map(|bytes| String::from_utf8_lossy(bytes).to_string())
+            .unwrap_or_default();
+        if announce.is_empty() {
+            debug!("Torrent has no announce URL");
+        }
 
         // Get announce list
-        let mut announce_list = vec![announce.clone()];
+        let mut announce_list = Vec::new();
+        if !announce.is_empty() {
+            announce_list.push(announce.clone());
+        }
         if let Some(BencodeValue::List(tiers)) = root_dict.get(&b"announce-list".to_vec()) {
             for tier in tiers {
                 if let BencodeValue::List(urls) = tier {
@@ -146,6 +103,20 @@ impl TorrentParser {
             }
         }
 
+        // Web seeds; url-list may be a single URL or a list
+        let url_strings = |key: &[u8]| -> Vec<String> {
+            match root_dict.get(key) {
+                Some(BencodeValue::List(urls)) => urls.iter().filter_map(|url| url.as_string()).filter(|url| !url.is_empty()).collect(),
+                Some(url) => url.as_string().filter(|url| !url.is_empty()).into_iter().collect(),
+                None => Vec::new(),
+            }
+        };
+        let web_seeds = url_strings(b"url-list");
+        let http_seeds = url_strings(b"httpseeds");
+        if !web_seeds.is_empty() || !http_seeds.is_empty() {
+            debug!("Torrent has {} web seeds and {} HTTP seeds", web_seeds.len(), http_seeds.len());
+        }
+
         // Get info dict
         let info_dict = root_dict.get(&b"info".to_vec())
             .and_then(|v| v.as_dict())
@@ -161,10 +132,31 @@ impl TorrentParser {
             .and_then(|v| v.as_int())
             .ok_or_else(|| anyhow::anyhow!("Missing piece length"))? as u64;
 
+        // v2 torrents (BEP 52) carry a file tree; hybrids also keep the v1 keys
+        let meta_version = info_dict.get(b"meta version".as_slice())
+            .and_then(|v| v.as_int())
+            .unwrap_or(1);
+        if !(1..=2).contains(&meta_version) {
+            error!("Unsupported meta version: {}", meta_version);
+            return Err(anyhow::anyhow!("Unsupported meta version {}", meta_version));
+        }
+
+        let info_span = dict_value_span(original_data, b"info")?
+            .ok_or_else(|| anyhow::anyhow!("Missing info dictionary"))?;
+        let info_bytes = &original_data[info_span];
+
         // Get pieces
-        let pieces_bytes = get_bytes(info_dict, b"pieces")
-            .ok_or_else(|| anyhow::anyhow!("Missing pieces field"))?;
-        let pieces = TorrentInfo::parse_piece_hashes(pieces_bytes)?;
+        let pieces = match get_bytes(info_dict, b"pieces") {
+            Some(pieces_bytes) => TorrentInfo::parse_piece_hashes(pieces_bytes)?,
+            None if meta_version == 2 => Vec::new(),
+            None => return Err(anyhow::anyhow!("Missing pieces field")),
+        };
+
+        let v2 = if meta_version == 2 {
+            Some(Self::parse_v2(&root_dict, info_dict, info_bytes, piece_length)?)
+        } else {
+            None
+        };
 
         // Check if it's single or multi-file
         let (length, files) = if info_dict.contains_key(&b"length".to_vec()) {
@@ -193,22 +185,49 @@ impl TorrentParser {
                         }
                     }
 
+                    let attr = file_dict.get(b"attr".as_slice())
+                        .and_then(|v| v.as_bytes())
+                        .map(FileAttributes::parse)
+                        .unwrap_or_default();
+                    let sha1 = file_dict.get(b"sha1".as_slice())
+                        .and_then(|v| v.as_bytes())
+                        .and_then(|hash| hash.try_into().ok());
+                    let symlink_path = file_dict.get(b"symlink path".as_slice())
+                        .and_then(|v| v.as_list())
+                        .map(|components| components.iter().filter_map(|c| c.as_string()).collect());
+                    if !attr.is_empty() {
+                        trace!("File {} has attributes '{}'", path.join("/"), attr.to_attr_string());
+                    }
+
                     torrent_files.push(TorrentFile {
                         path,
                         length: file_len,
+                        attr,
+                        sha1,
+                        symlink_path,
                     });
                 }
             }
             (None, Some(torrent_files))
+        } else if let Some(v2) = &v2 {
+            Self::v2_layout(&name, v2, piece_length)
         } else {
             return Err(anyhow::anyhow!("Neither length nor files found in info dict"));
         };
 
-        // Calculate info hash
-        let info_start = original_data.iter().position(|&b| b == b'd')
-            .ok_or_else(|| anyhow::anyhow!("Could not find info dict start"))?;
-        let info_bytes = Self::extract_info_dict(original_data, info_start)?;
-        let info_hash = TorrentInfo::generate_info_hash(&info_bytes);
+        // Calculate info hash over the info dictionary exactly as encoded
+        let info_hash = match &v2 {
+            Some(v2) if pieces.is_empty() => truncate_info_hash(v2.info_hash),
+            _ => TorrentInfo::generate_info_hash(info_bytes),
+        };
+
+        if let Some(v2) = &v2 {
+            if !pieces.is_empty() && v2.piece_count() != pieces.len() {
+                error!("Hybrid torrent has {} v1 pieces but {} v2 pieces", pieces.len(), v2.piece_count());
+                return Err(anyhow::anyhow!("v1 and v2 pieces of hybrid torrent don't match"));
+            }
+            debug!("v2 info hash: {}{}", hex::encode(v2.info_hash), if pieces.is_empty() { "" } else { " (hybrid)" });
+        }
 
         info!("Successfully converted torrent info: {}", name);
         Ok(TorrentInfo {
@@ -220,78 +239,96 @@ impl TorrentParser {
             name,
             length,
             files,
+            v2,
+            web_seeds,
+            http_seeds,
         })
     }
 
-    /// Extract the info dictionary bytes for info hash calculation
-    fn extract_info_dict(data: &[u8], start: usize) -> Result<Vec<u8>> {
-        let mut idx = start + 1; // Skip initial 'd'
-        let mut depth = 1;
-
-        while idx < data.len() && depth > 0 {
-            match data[idx] {
-                b'd' => depth += 1,
-                b'l' => depth += 1,
-                b'e' => depth -= 1,
-                b'i' => {
-                    // Skip integer
-                    idx = data[idx..].iter().position(|&b| b == b'e').map_or(idx, |p| idx + p + 1);
-                    continue;
-                }
-                b'0'..=b'9' => {
-                    // Skip string
-                    if let Some(colon) = data[idx..].iter().position(|&b| b == b':') {
-                        if let Ok(len) = std::str::from_utf8(&data[idx..idx + colon]).unwrap_or("0").parse::<usize>() {
-                            idx = idx + colon + 1 + len;
-                            continue;
-                        }
-                    }
-                }
-                _ => {}
-            }
-            idx += 1;
+    /// Parse the file tree and piece layers of a v2 torrent
+    fn parse_v2(root_dict: &BencodeDict, info_dict: &BencodeDict, info_bytes: &[u8], piece_length: u64) -> Result<InfoV2> {
+        if piece_length < BLOCK_SIZE as u64 || !piece_length.is_power_of_two() {
+            return Err(anyhow::anyhow!("Piece length of v2 torrent must be a power of two of at least 16 KiB"));
         }
 
-        Ok(data[start..idx].to_vec())
-    }
-}
-
-/// Bencode value
-#[derive(Debug, Clone)]
-enum BencodeValue {
-    Int(i64),
-    Bytes(Vec<u8>),
-    List(Vec<BencodeValue>),
-    Dict(std::collections::BTreeMap<Vec<u8>, BencodeValue>),
-}
+        let file_tree = info_dict.get(b"file tree".as_slice())
+            .and_then(|v| v.as_dict())
+            .ok_or_else(|| anyhow::anyhow!("Missing file tree"))?;
+        let mut files = Vec::new();
+        Self::parse_file_tree(file_tree, &mut Vec::new(), &mut files)?;
+
+        let mut piece_layers = HashMap::new();
+        if let Some(layers) = root_dict.get(b"piece layers".as_slice()).and_then(|v| v.as_dict()) {
+            for (pieces_root, layer) in layers {
+                let pieces_root: Hash256 = pieces_root.as_slice().try_into()
+                    .map_err(|_| anyhow::anyhow!("Piece layer key must be 32 bytes"))?;
+                let layer = layer.as_bytes()
+                    .filter(|bytes| bytes.len() % 32 == 0)
+                    .ok_or_else(|| anyhow::anyhow!("Piece layer must be a multiple of 32 bytes"))?;
+                let hashes = layer.chunks_exact(32)
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect();
+                piece_layers.insert(pieces_root, hashes);
+            }
+        }
 
-impl BencodeValue {
-    fn as_int(&self) -> Option<i64> {
-        match self {
-            BencodeValue::Int(i) => Some(*i),
-            _ => None,
+        let mut hasher = Sha256::new();
+        hasher.update(info_bytes);
+        let v2 = InfoV2::new(hasher.finalize().into(), files, piece_layers, piece_length);
+        for file in v2.missing_piece_layers() {
+            warn!("Missing piece layer for {}", file.path.join("/"));
         }
+        Ok(v2)
     }
 
-    fn as_bytes(&self) -> Option<&[u8]> {
-        match self {
-            BencodeValue::Bytes(b) => Some(b),
-            _ => None,
+    /// Collect the files of a file tree, in tree order
+    fn parse_file_tree(tree: &BencodeDict, path: &mut Vec<String>, files: &mut Vec<TorrentFileV2>) -> Result<()> {
+        for (key, node) in tree {
+            let node = node.as_dict()
+                .ok_or_else(|| anyhow::anyhow!("File tree entries must be dictionaries"))?;
+            if !key.is_empty() {
+                path.push(String::from_utf8_lossy(key).to_string());
+                Self::parse_file_tree(node, path, files)?;
+                path.pop();
+                continue;
+            }
+
+            let length = node.get(b"length".as_slice())
+                .and_then(|v| v.as_int())
+                .filter(|length| *length >= 0)
+                .ok_or_else(|| anyhow::anyhow!("Missing file length in file tree"))? as u64;
+            let pieces_root = match node.get(b"pieces root".as_slice()).and_then(|v| v.as_bytes()) {
+                Some(root) => Some(root.try_into().map_err(|_| anyhow::anyhow!("Pieces root must be 32 bytes"))?),
+                None if length == 0 => None,
+                None => return Err(anyhow::anyhow!("Missing pieces root for {}", path.join("/"))),
+            };
+            let attr = node.get(b"attr".as_slice())
+                .and_then(|v| v.as_bytes())
+                .map(FileAttributes::parse)
+                .unwrap_or_default();
+            files.push(TorrentFileV2 { path: path.clone(), length, pieces_root, attr });
         }
+        Ok(())
     }
 
-    fn as_list(&self) -> Option<&[BencodeValue]> {
-        match self {
-            BencodeValue::List(l) => Some(l),
-            _ => None,
+    /// Lay out the files of a v2-only torrent, padding each file to a piece boundary
+    fn v2_layout(name: &str, v2: &InfoV2, piece_length: u64) -> (Option<u64>, Option<Vec<TorrentFile>>) {
+        if let [file] = v2.files.as_slice() {
+            if file.path.len() == 1 && file.path[0] == name {
+                return (Some(file.length), None);
+            }
         }
-    }
 
-    fn as_dict(&self) -> Option<&std::collections::BTreeMap<Vec<u8>, BencodeValue>> {
-        match self {
-            BencodeValue::Dict(d) => Some(d),
-            _ => None,
+        let mut files = Vec::new();
+        for (i, file) in v2.files.iter().enumerate() {
+            files.push(TorrentFile { attr: file.attr, ..TorrentFile::new(file.path.clone(), file.length) });
+            let pad = (piece_length - file.length % piece_length) % piece_length;
+            if pad > 0 && i + 1 < v2.files.len() {
+                let attr = FileAttributes { padding: true, ..Default::default() };
+                files.push(TorrentFile { attr, ..TorrentFile::new(vec![".pad".to_string(), pad.to_string()], pad) });
+            }
         }
+        (None, Some(files))
     }
 }
 
@@ -330,4 +367,136 @@ mod tests {
         let value = TorrentParser::parse_value(data, &mut idx).unwrap();
         assert!(value.as_dict().is_some());
     }
+
+    /// Build a v2 torrent of a 40 KiB and a 10 KiB file with 32 KiB pieces, optionally hybrid
+    fn v2_torrent(hybrid: bool, layer_override: Option<Vec<u8>>) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        use crate::torrent::bencode::BencodeDict;
+        use crate::torrent::merkle::MerkleTree;
+
+        let piece_length = 32 * 1024u64;
+        let a: Vec<u8> = (0..40 * 1024).map(|i| (i % 251) as u8).collect();
+        let b = vec![7u8; 10 * 1024];
+        let (tree_a, tree_b) = (MerkleTree::from_data(&a), MerkleTree::from_data(&b));
+
+        let file_node = |length: usize, root: [u8; 32]| {
+            let mut leaf = BencodeDict::new();
+            leaf.insert(b"length".to_vec(), BencodeValue::Int(length as i64));
+            leaf.insert(b"pieces root".to_vec(), BencodeValue::Bytes(root.to_vec()));
+            let mut node = BencodeDict::new();
+            node.insert(Vec::new(), BencodeValue::Dict(leaf));
+            BencodeValue::Dict(node)
+        };
+        let mut file_tree = BencodeDict::new();
+        file_tree.insert(b"a.bin".to_vec(), file_node(a.len(), tree_a.root()));
+        file_tree.insert(b"b.bin".to_vec(), file_node(b.len(), tree_b.root()));
+
+        let mut info = BencodeDict::new();
+        info.insert(b"name".to_vec(), BencodeValue::string("v2"));
+        info.insert(b"piece length".to_vec(), BencodeValue::Int(piece_length as i64));
+        info.insert(b"meta version".to_vec(), BencodeValue::Int(2));
+        info.insert(b"file tree".to_vec(), BencodeValue::Dict(file_tree));
+
+        // Hybrids pad every file but the last to a piece boundary in the v1 layout
+        let mut padded = a.clone();
+        padded.resize(piece_length as usize * 2, 0);
+        padded.extend_from_slice(&b);
+        if hybrid {
+            let file = |path: &[&str], length: usize| {
+                let mut dict = BencodeDict::new();
+                dict.insert(b"length".to_vec(), BencodeValue::Int(length as i64));
+                dict.insert(b"path".to_vec(), BencodeValue::List(path.iter().map(|p| BencodeValue::string(p)).collect()));
+                BencodeValue::Dict(dict)
+            };
+            info.insert(b"files".to_vec(), BencodeValue::List(vec![
+                file(&["a.bin"], a.len()),
+                file(&[".pad", "24576"], 24 * 1024),
+                file(&["b.bin"], b.len()),
+            ]));
+            let pieces: Vec<u8> = padded.chunks(piece_length as usize)
+                .flat_map(TorrentInfo::generate_info_hash)
+                .collect();
+            info.insert(b"pieces".to_vec(), BencodeValue::Bytes(pieces));
+        }
+
+        let layer = layer_override.unwrap_or_else(|| tree_a.piece_layer(piece_length).concat());
+        let mut layers = BencodeDict::new();
+        layers.insert(tree_a.root().to_vec(), BencodeValue::Bytes(layer));
+        let mut root = BencodeDict::new();
+        root.insert(b"info".to_vec(), BencodeValue::Dict(info));
+        root.insert(b"piece layers".to_vec(), BencodeValue::Dict(layers));
+        (BencodeValue::Dict(root).encode(), padded, b)
+    }
+
+    #[test]
+    fn test_parse_v2_torrent() {
+        let (data, padded, b) = v2_torrent(false, None);
+        let info = TorrentParser::parse_bytes(&data).unwrap();
+        let span = dict_value_span(&data, b"info").unwrap().unwrap();
+        let info_hash_v2: [u8; 32] = Sha256::digest(&data[span]).into();
+
+        assert!(info.is_v2() && !info.is_hybrid());
+        assert_eq!(info.info_hash_v2(), Some(info_hash_v2));
+        assert_eq!(info.info_hash, truncate_info_hash(info_hash_v2));
+        assert_eq!(info.swarm_hashes(), vec![info.info_hash]);
+        assert_eq!(info.piece_count(), 3);
+
+        // Files are laid out with a pad so that b.bin starts on a new piece
+        let files = info.files.as_ref().unwrap();
+        let paths: Vec<String> = files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, vec!["a.bin", ".pad/24576", "b.bin"]);
+
+        assert_eq!(info.verify_piece(0, &padded[..32 * 1024]), Some(true));
+        assert_eq!(info.verify_piece(1, &padded[32 * 1024..40 * 1024]), Some(true));
+        assert_eq!(info.verify_piece(2, &b), Some(true));
+        assert_eq!(info.verify_piece(2, &padded[..10 * 1024]), Some(false));
+        assert_eq!(info.verify_piece(3, &b), None);
+    }
+
+    #[test]
+    fn test_parse_hybrid_torrent() {
+        let (data, padded, _) = v2_torrent(true, None);
+        let info = TorrentParser::parse_bytes(&data).unwrap();
+        let span = dict_value_span(&data, b"info").unwrap().unwrap();
+
+        assert!(info.is_hybrid());
+        assert_eq!(info.info_hash, TorrentInfo::generate_info_hash(&data[span]));
+        assert_eq!(info.swarm_hashes().len(), 2);
+        assert_eq!(info.piece_count(), 3);
+        // v1 pieces include the padding, which the merkle hash ignores
+        assert_eq!(info.verify_piece(1, &padded[32 * 1024..64 * 1024]), Some(true));
+    }
+
+    #[test]
+    fn test_parse_v2_torrent_with_bad_piece_layer() {
+        let (data, padded, _) = v2_torrent(false, Some(vec![0u8; 64]));
+        let info = TorrentParser::parse_bytes(&data).unwrap();
+        let v2 = info.v2.as_ref().unwrap();
+        assert_eq!(v2.missing_piece_layers().count(), 1);
+        assert_eq!(info.verify_piece(0, &padded[..32 * 1024]), Some(false));
+    }
+
+    #[test]
+    fn test_parse_file_attributes() {
+        let data = b"d4:infod5:filesld6:lengthi5e4:pathl5:a.binee\
+d4:attr1:p6:lengthi11e4:pathl4:.pad2:11ee\
+d4:attr2:xl6:lengthi0e4:pathl4:linke4:sha120:aaaaaaaaaaaaaaaaaaaa12:symlink pathl5:a.bineee\
+4:name1:x12:piece lengthi16e6:pieces20:bbbbbbbbbbbbbbbbbbbbee";
+        let info = TorrentParser::parse_bytes(data).unwrap();
+        let files = info.files.unwrap();
+        assert!(!files[0].is_padding());
+        assert!(files[1].is_padding());
+        assert!(files[2].attr.executable && files[2].attr.symlink);
+        assert_eq!(files[2].sha1, Some([b'a'; 20]));
+        assert_eq!(files[2].symlink_path, Some(vec!["a.bin".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_web_seeds() {
+        let data = b"d9:httpseedsl19:http://h.example/s1e\
+4:infod6:lengthi5e4:name1:x12:piece lengthi16e6:pieces20:bbbbbbbbbbbbbbbbbbbbe\
+8:url-list17:http://example/x/e";
+        let info = TorrentParser::parse_bytes(data).unwrap();
+        assert_eq!(info.web_seeds, vec!["http://example/x/".to_string()]);
+        assert_eq!(info.http_seeds, vec!["http://h.example/s1".to_string()]);
+    }
 }
@@ -0,0 +1,274 @@
+//! UDP demultiplexer
+//!
+//! Shares one UDP socket between the DHT and uTP. Bencoded datagrams are
+//! handed to the DHT, uTP packets are routed to their connection.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace, warn};
+
+use super::socket::bind_udp;
+use super::utp::{flush_transmit, Connection, ConnectionMap, Packet, PacketType, UtpStream};
+use crate::error::TorrentError;
+
+/// Datagram received for the DHT
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+/// Queue length for DHT datagrams and pending incoming uTP connections
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Largest datagram we expect to receive
+const MAX_DATAGRAM: usize = 65536;
+
+/// A UDP socket shared between the DHT and uTP
+pub struct UdpDemux {
+    socket: Arc<UdpSocket>,
+    connections: ConnectionMap,
+    dht_receiver: Mutex<Option<mpsc::Receiver<Datagram>>>,
+    accept_receiver: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    local_addr: SocketAddr,
+    utp_enabled: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl UdpDemux {
+    /// Bind the shared UDP socket and start routing datagrams
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = bind_udp(addr)
+            .map_err(|e| {
+                error!("Failed to bind UDP socket to {}: {}", addr, e);
+                TorrentError::network_error_full("Failed to bind UDP socket", addr.to_string(), e.to_string())
+            })?;
+        let local_addr = socket.local_addr()
+            .map_err(|e| {
+                error!("Failed to get local address: {}", e);
+                TorrentError::network_error_full("Failed to get local address", "unknown".to_string(), e.to_string())
+            })?;
+        let socket = Arc::new(socket);
+        let connections: ConnectionMap = Arc::new(Mutex::new(HashMap::new()));
+        let (dht_sender, dht_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (accept_sender, accept_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+        let utp_enabled = Arc::new(AtomicBool::new(true));
+
+        let task = tokio::spawn(run_demux(
+            socket.clone(),
+            connections.clone(),
+            dht_sender,
+            accept_sender,
+            utp_enabled.clone(),
+        ));
+        info!("UDP demultiplexer listening on {}", local_addr);
+
+        Ok(Self {
+            socket,
+            connections,
+            dht_receiver: Mutex::new(Some(dht_receiver)),
+            accept_receiver: tokio::sync::Mutex::new(accept_receiver),
+            local_addr,
+            utp_enabled,
+            task,
+        })
+    }
+
+    /// Get the local address of the shared socket
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the shared socket, used for sending DHT messages
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.clone()
+    }
+
+    /// Enable or disable uTP; when disabled incoming SYNs are ignored
+    pub fn set_utp_enabled(&self, enabled: bool) {
+        debug!("uTP {} on {}", if enabled { "enabled" } else { "disabled" }, self.local_addr);
+        self.utp_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Check if uTP is enabled
+    pub fn is_utp_enabled(&self) -> bool {
+        self.utp_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Take the receiver of DHT datagrams (only once)
+    pub fn take_dht_receiver(&self) -> Option<mpsc::Receiver<Datagram>> {
+        self.dht_receiver.lock().expect("DHT receiver poisoned").take()
+    }
+
+    /// Open a uTP connection to a remote peer
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream> {
+        if !self.is_utp_enabled() {
+            return Err(TorrentError::network_error_full("uTP is disabled", addr.to_string(), "uTP disabled".to_string()).into());
+        }
+        debug!("Opening uTP connection to {}", addr);
+        let recv_id = {
+            let connections = self.connections.lock().expect("uTP registry poisoned");
+            // The remote answers on recv_id, and accepts on recv_id + 1
+            loop {
+                let id: u16 = rand::random();
+                if !connections.contains_key(&(addr, id)) && !connections.contains_key(&(addr, id.wrapping_add(1))) {
+                    break id;
+                }
+            }
+        };
+
+        let conn = Connection::connect(addr, recv_id, Instant::now());
+        let stream = UtpStream::spawn(conn, self.socket.clone(), self.connections.clone());
+        stream.wait_connected().await
+            .map_err(|e| {
+                debug!("uTP connection to {} failed: {}", addr, e);
+                TorrentError::network_error_full("Failed to connect over uTP", addr.to_string(), e.to_string())
+            })?;
+        info!("uTP connection established with {}", addr);
+        Ok(stream)
+    }
+
+    /// Wait for an incoming uTP connection
+    pub async fn accept(&self) -> Result<UtpStream> {
+        let mut receiver = self.accept_receiver.lock().await;
+        receiver.recv().await.ok_or_else(|| {
+            TorrentError::network_error_full("uTP listener closed", self.local_addr.to_string(), "demultiplexer stopped".to_string()).into()
+        })
+    }
+
+    /// Number of live uTP connections
+    pub fn connection_count(&self) -> usize {
+        self.connections.lock().expect("uTP registry poisoned").len()
+    }
+}
+
+impl Drop for UdpDemux {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Receive loop routing datagrams to the DHT or to uTP connections
+async fn run_demux(
+    socket: Arc<UdpSocket>,
+    connections: ConnectionMap,
+    dht_sender: mpsc::Sender<Datagram>,
+    accept_sender: mpsc::Sender<UtpStream>,
+    utp_enabled: Arc<AtomicBool>,
+) {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buffer).await {
+            Ok(result) => result,
+            Err(e) => {
+                // ICMP errors surface here on some platforms; keep going
+                trace!("UDP receive error: {}", e);
+                continue;
+            }
+        };
+        let data = &buffer[..len];
+
+        if data.first() == Some(&b'd') {
+            if dht_sender.try_send((data.to_vec(), from)).is_err() {
+                trace!("Dropping DHT datagram from {}: queue full or closed", from);
+            }
+            continue;
+        }
+
+        if !Packet::is_utp(data) {
+            trace!("Ignoring unknown datagram from {} ({} bytes)", from, len);
+            continue;
+        }
+        let packet = match Packet::decode(data) {
+            Ok(packet) => packet,
+            Err(e) => {
+                trace!("Invalid uTP packet from {}: {}", from, e);
+                continue;
+            }
+        };
+
+        let key = if packet.packet_type == PacketType::Syn {
+            (from, packet.connection_id.wrapping_add(1))
+        } else {
+            (from, packet.connection_id)
+        };
+        let existing = connections.lock().expect("uTP registry poisoned").get(&key).cloned();
+
+        match existing {
+            Some(conn) => {
+                let mut conn = conn.lock().expect("uTP connection poisoned");
+                conn.on_packet(packet, Instant::now());
+                flush_transmit(&socket, &mut conn);
+            }
+            None if packet.packet_type == PacketType::Syn && utp_enabled.load(Ordering::Relaxed) => {
+                debug!("Incoming uTP connection from {}", from);
+                let conn = Connection::accept(from, &packet, Instant::now());
+                let stream = UtpStream::spawn(conn, socket.clone(), connections.clone());
+                if let Err(e) = accept_sender.try_send(stream) {
+                    // Dropping the stream closes the connection
+                    warn!("Rejecting uTP connection from {}: {}", from, e);
+                }
+            }
+            None => {
+                trace!("uTP packet for unknown connection {} from {}", packet.connection_id, from);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn local_demux() -> UdpDemux {
+        UdpDemux::bind("127.0.0.1:0".parse().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_utp_stream_transfer() {
+        let server = local_demux().await;
+        let client = local_demux().await;
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+        let (outgoing, incoming) = tokio::join!(client.connect(server.local_addr()), server.accept());
+        let mut outgoing = outgoing.unwrap();
+        let mut incoming = incoming.unwrap();
+        assert_eq!(incoming.peer_addr(), client.local_addr());
+
+        let expected = data.clone();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            incoming.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+            incoming.write_all(b"done").await.unwrap();
+            incoming.shutdown().await.unwrap();
+            incoming
+        });
+
+        outgoing.write_all(&data).await.unwrap();
+        outgoing.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        outgoing.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"done");
+        reader.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dht_datagrams_are_routed() {
+        let demux = local_demux().await;
+        let mut dht = demux.take_dht_receiver().unwrap();
+        assert!(demux.take_dht_receiver().is_none());
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(b"d1:y1:qe", demux.local_addr()).await.unwrap();
+        let (data, from) = dht.recv().await.unwrap();
+        assert_eq!(data, b"d1:y1:qe");
+        assert_eq!(from, sender.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,218 @@
+//! LEDBAT congestion control
+//!
+//! Delay-based congestion controller used by uTP (RFC 6817). The window grows
+//! while the measured queuing delay stays below the target and shrinks when it
+//! exceeds it, so uTP yields to other traffic on the link.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+/// Target queuing delay in microseconds
+pub const TARGET_DELAY_US: u32 = 100_000;
+
+/// Maximum segment size used for window arithmetic
+pub const MSS: usize = 1400;
+
+/// Window growth gain
+const GAIN: f64 = 1.0;
+
+/// Smallest congestion window
+const MIN_CWND: usize = 2 * MSS;
+
+/// Largest congestion window
+const MAX_CWND: usize = 1024 * 1024;
+
+/// Initial congestion window
+const INITIAL_CWND: usize = 4 * MSS;
+
+/// Number of one-minute base delay buckets kept (RFC 6817 BASE_HISTORY)
+const BASE_HISTORY: usize = 10;
+
+/// Lower bound for the retransmission timeout
+pub const MIN_RTO: Duration = Duration::from_millis(500);
+
+/// Upper bound for the retransmission timeout
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Retransmission timeout before any RTT sample
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// LEDBAT congestion controller with RTT estimation
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    /// Congestion window in bytes
+    cwnd: usize,
+    /// Minimum delay observed per minute, newest last
+    base_delays: VecDeque<u32>,
+    /// Start of the current base delay bucket
+    bucket_start: Option<Instant>,
+    /// Smoothed round trip time
+    rtt: Option<Duration>,
+    /// Round trip time variance
+    rtt_var: Duration,
+    /// Current retransmission timeout
+    rto: Duration,
+}
+
+impl Ledbat {
+    /// Create a new controller
+    pub fn new() -> Self {
+        Self {
+            cwnd: INITIAL_CWND,
+            base_delays: VecDeque::with_capacity(BASE_HISTORY),
+            bucket_start: None,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    /// Get the congestion window in bytes
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Get the retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Get the smoothed round trip time, if measured
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Get the lowest one-way delay seen in the history window
+    pub fn base_delay(&self) -> Option<u32> {
+        self.base_delays.iter().copied().min()
+    }
+
+    /// Record a one-way delay sample
+    fn update_base_delay(&mut self, delay_us: u32, now: Instant) {
+        let new_bucket = match self.bucket_start {
+            Some(start) => now.duration_since(start) >= Duration::from_secs(60),
+            None => true,
+        };
+
+        if new_bucket {
+            if self.base_delays.len() == BASE_HISTORY {
+                self.base_delays.pop_front();
+            }
+            self.base_delays.push_back(delay_us);
+            self.bucket_start = Some(now);
+        } else if let Some(last) = self.base_delays.back_mut() {
+            *last = (*last).min(delay_us);
+        }
+    }
+
+    /// Handle newly acknowledged bytes
+    ///
+    /// `delay_us` is the one-way delay the remote measured for our packets,
+    /// or `None` if it has not reported one yet.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay_us: Option<u32>, now: Instant) {
+        let Some(delay_us) = delay_us else {
+            return;
+        };
+
+        self.update_base_delay(delay_us, now);
+        let base = self.base_delay().unwrap_or(delay_us);
+        let queuing_delay = delay_us.saturating_sub(base);
+
+        let off_target = (TARGET_DELAY_US as f64 - queuing_delay as f64) / TARGET_DELAY_US as f64;
+        let delta = GAIN * off_target * bytes_acked as f64 * MSS as f64 / self.cwnd as f64;
+        let cwnd = (self.cwnd as f64 + delta).clamp(MIN_CWND as f64, MAX_CWND as f64);
+        self.cwnd = cwnd as usize;
+
+        trace!("LEDBAT: queuing delay {}us, cwnd {} bytes", queuing_delay, self.cwnd);
+    }
+
+    /// Update the RTT estimate and retransmission timeout from a sample
+    pub fn on_rtt_sample(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+
+        let rtt = self.rtt.unwrap_or(sample);
+        self.rto = (rtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// React to packet loss detected through duplicate or selective ACKs
+    pub fn on_loss(&mut self) {
+        self.cwnd = (self.cwnd / 2).max(MIN_CWND);
+        trace!("LEDBAT: loss, cwnd {} bytes", self.cwnd);
+    }
+
+    /// React to a retransmission timeout
+    pub fn on_timeout(&mut self) {
+        self.cwnd = MSS;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        trace!("LEDBAT: timeout, cwnd {} bytes, rto {:?}", self.cwnd, self.rto);
+    }
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_grows_below_target() {
+        let mut cc = Ledbat::new();
+        let now = Instant::now();
+        let initial = cc.cwnd();
+        for _ in 0..10 {
+            cc.on_ack(MSS, Some(20_000), now);
+        }
+        assert!(cc.cwnd() > initial);
+    }
+
+    #[test]
+    fn test_window_shrinks_above_target() {
+        let mut cc = Ledbat::new();
+        let now = Instant::now();
+        cc.on_ack(MSS, Some(10_000), now);
+        let before = cc.cwnd();
+        for _ in 0..10 {
+            cc.on_ack(MSS, Some(10_000 + 3 * TARGET_DELAY_US), now);
+        }
+        assert!(cc.cwnd() < before);
+        assert!(cc.cwnd() >= MIN_CWND);
+    }
+
+    #[test]
+    fn test_loss_and_timeout() {
+        let mut cc = Ledbat::new();
+        let before = cc.cwnd();
+        cc.on_loss();
+        assert_eq!(cc.cwnd(), (before / 2).max(MIN_CWND));
+
+        let rto = cc.rto();
+        cc.on_timeout();
+        assert_eq!(cc.cwnd(), MSS);
+        assert_eq!(cc.rto(), rto * 2);
+    }
+
+    #[test]
+    fn test_rto_has_lower_bound() {
+        let mut cc = Ledbat::new();
+        for _ in 0..20 {
+            cc.on_rtt_sample(Duration::from_millis(5));
+        }
+        assert_eq!(cc.rto(), MIN_RTO);
+        assert!(cc.rtt().unwrap() <= Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,61 @@
+//! In-memory transport
+//!
+//! A connected pair of byte streams that never touches the network, used to
+//! run complete peer sessions inside one process.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+/// Buffer size of each direction of the pair
+const DEFAULT_BUFFER: usize = 256 * 1024;
+
+/// One end of an in-memory connection
+#[derive(Debug)]
+pub struct MemoryTransport {
+    stream: DuplexStream,
+    peer_addr: SocketAddr,
+}
+
+impl MemoryTransport {
+    /// Create a connected pair; each end reports the other's address as its peer
+    pub fn pair(addr_a: SocketAddr, addr_b: SocketAddr) -> (Self, Self) {
+        Self::pair_with_capacity(addr_a, addr_b, DEFAULT_BUFFER)
+    }
+
+    /// Create a connected pair with a specific buffer size per direction
+    pub fn pair_with_capacity(addr_a: SocketAddr, addr_b: SocketAddr, capacity: usize) -> (Self, Self) {
+        let (a, b) = tokio::io::duplex(capacity);
+        (
+            Self { stream: a, peer_addr: addr_b },
+            Self { stream: b, peer_addr: addr_a },
+        )
+    }
+
+    /// Get the address of the other end
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for MemoryTransport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryTransport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,149 @@
+//! Transport module
+//!
+//! Byte-stream transports for peer connections. TCP, uTP (over a UDP socket
+//! shared with the DHT), in-memory pairs, encryption and rate limiting layers
+//! all implement [`Transport`], so a `PeerConnection` can run over any of them.
+
+pub mod ledbat;
+pub mod utp;
+pub mod demux;
+pub mod memory;
+pub mod rate_limit;
+pub mod socket;
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::protocol::MseStream;
+
+// Re-export main types
+pub use demux::{Datagram, UdpDemux};
+pub use ledbat::Ledbat;
+pub use memory::MemoryTransport;
+pub use rate_limit::{RateLimiter, RateLimits, Throttled};
+pub use socket::{bind_tcp, bind_udp};
+pub use utp::{ConnectionState as UtpConnectionState, Packet as UtpPacket, PacketType as UtpPacketType, UtpStream};
+
+/// Kind of the underlying connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// TCP connection
+    Tcp,
+    /// uTP connection
+    Utp,
+    /// In-memory connection
+    Memory,
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportKind::Tcp => write!(f, "tcp"),
+            TransportKind::Utp => write!(f, "utp"),
+            TransportKind::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// A byte stream a peer connection can run over
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync {
+    /// Get the remote address
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Get the kind of the underlying connection
+    fn kind(&self) -> TransportKind;
+
+    /// Check if the payload is encrypted by this or a lower layer
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+}
+
+/// A type-erased transport
+pub type BoxedTransport = Box<dyn Transport>;
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn kind(&self) -> TransportKind {
+        TransportKind::Tcp
+    }
+}
+
+impl Transport for UtpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(UtpStream::peer_addr(self))
+    }
+
+    fn kind(&self) -> TransportKind {
+        TransportKind::Utp
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(MemoryTransport::peer_addr(self))
+    }
+
+    fn kind(&self) -> TransportKind {
+        TransportKind::Memory
+    }
+}
+
+impl<S: Transport> Transport for MseStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn kind(&self) -> TransportKind {
+        self.get_ref().kind()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        MseStream::is_encrypted(self) || self.get_ref().is_encrypted()
+    }
+}
+
+impl Transport for BoxedTransport {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn kind(&self) -> TransportKind {
+        (**self).kind()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        (**self).is_encrypted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_memory_pair_is_a_transport() {
+        let a_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let (a, b) = MemoryTransport::pair(a_addr, b_addr);
+        let mut a: BoxedTransport = Box::new(a);
+        let mut b: BoxedTransport = Box::new(MseStream::plaintext(b));
+
+        assert_eq!(a.peer_addr().unwrap(), b_addr);
+        assert_eq!(b.peer_addr().unwrap(), a_addr);
+        assert_eq!(b.kind(), TransportKind::Memory);
+        assert!(!b.is_encrypted());
+
+        a.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
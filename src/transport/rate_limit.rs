@@ -0,0 +1,407 @@
+//! Rate limiting
+//!
+//! Token buckets for download and upload rates, and a stream wrapper that
+//! charges every byte read or written against a chain of buckets (global,
+//! per-torrent and per-peer). Rates can be changed at runtime; a rate of 0
+//! means unlimited.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tracing::{debug, trace};
+
+use super::{Transport, TransportKind};
+
+/// Smallest grant worth waking up for, so slow links still move whole chunks
+const MIN_GRANT: usize = 1024;
+
+/// Shortest wait before retrying an empty bucket
+const MIN_WAIT: Duration = Duration::from_millis(5);
+
+/// Token bucket state
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, 0 = unlimited
+    rate: u64,
+    /// Available bytes; negative when shared users overdraw
+    tokens: f64,
+    /// Last refill
+    last_refill: Instant,
+}
+
+impl Bucket {
+    /// Largest number of tokens the bucket holds (one second worth)
+    fn capacity(&self) -> f64 {
+        self.rate.max(MIN_GRANT as u64) as f64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity());
+        self.last_refill = now;
+    }
+}
+
+/// Token bucket rate limiter, shared between the streams it applies to
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` bytes per second (0 = unlimited)
+    pub fn new(rate: u64) -> Self {
+        let bucket = Bucket { rate, tokens: 0.0, last_refill: Instant::now() };
+        let tokens = bucket.capacity();
+        Self { bucket: Mutex::new(Bucket { tokens, ..bucket }) }
+    }
+
+    /// Create a limiter that never throttles
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    /// Get the rate in bytes per second (0 = unlimited)
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().expect("rate limiter poisoned").rate
+    }
+
+    /// Check if the limiter never throttles
+    pub fn is_unlimited(&self) -> bool {
+        self.rate() == 0
+    }
+
+    /// Change the rate in bytes per second (0 = unlimited)
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+        let now = Instant::now();
+        if bucket.rate == 0 {
+            // Start a newly limited bucket full
+            bucket.rate = rate;
+            bucket.tokens = bucket.capacity();
+            bucket.last_refill = now;
+        } else {
+            bucket.refill(now);
+            bucket.rate = rate;
+            bucket.tokens = bucket.tokens.min(bucket.capacity());
+        }
+        debug!("Rate limit set to {} bytes/s", rate);
+    }
+
+    /// Bytes that may be transferred now, or how long to wait before `wanted` bytes are worth asking for
+    fn available(&self, wanted: usize, now: Instant) -> Result<usize, Duration> {
+        let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+        if bucket.rate == 0 {
+            return Ok(usize::MAX);
+        }
+        bucket.refill(now);
+
+        let threshold = wanted.min(MIN_GRANT) as f64;
+        if bucket.tokens >= threshold {
+            return Ok(bucket.tokens as usize);
+        }
+        let wait = (threshold - bucket.tokens) / bucket.rate as f64;
+        Err(Duration::from_secs_f64(wait).max(MIN_WAIT))
+    }
+
+    /// Charge transferred bytes
+    pub fn consume(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+        if bucket.rate != 0 {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    /// Give back bytes that should not count against the limit
+    pub fn refund(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+        if bucket.rate != 0 {
+            bucket.tokens = (bucket.tokens + bytes as f64).min(bucket.capacity());
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+/// A pair of download and upload limiters for one level (global, torrent or peer)
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// Download limiter
+    pub download: Arc<RateLimiter>,
+    /// Upload limiter
+    pub upload: Arc<RateLimiter>,
+}
+
+impl RateLimits {
+    /// Create limits in bytes per second (0 = unlimited)
+    pub fn new(download: u64, upload: u64) -> Self {
+        Self {
+            download: Arc::new(RateLimiter::new(download)),
+            upload: Arc::new(RateLimiter::new(upload)),
+        }
+    }
+
+    /// Create limits that never throttle
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Change both rates in bytes per second (0 = unlimited)
+    pub fn set(&self, download: u64, upload: u64) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+}
+
+/// Ask every limiter in a chain for bytes; the smallest grant wins
+fn grant(limiters: &[Arc<RateLimiter>], wanted: usize) -> Result<usize, Duration> {
+    let now = Instant::now();
+    let mut granted = wanted;
+    let mut wait = Duration::ZERO;
+    for limiter in limiters {
+        match limiter.available(wanted, now) {
+            Ok(available) => granted = granted.min(available),
+            Err(delay) => wait = wait.max(delay),
+        }
+    }
+    if wait > Duration::ZERO {
+        Err(wait)
+    } else {
+        Ok(granted)
+    }
+}
+
+/// Wait on `sleep` if set, then retry the chain until it grants some bytes
+fn poll_grant(
+    limiters: &[Arc<RateLimiter>],
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+    wanted: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(timer) = sleep.as_mut() {
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *sleep = None;
+        }
+        match grant(limiters, wanted) {
+            Ok(granted) => return Poll::Ready(granted),
+            Err(wait) => {
+                trace!("Rate limited for {:?}", wait);
+                *sleep = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+    }
+}
+
+/// Stream wrapper charging reads and writes against chains of rate limiters
+pub struct Throttled<S> {
+    inner: S,
+    download: Vec<Arc<RateLimiter>>,
+    upload: Vec<Arc<RateLimiter>>,
+    count_overhead: bool,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// Wrap a stream without any limits
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            download: Vec::new(),
+            upload: Vec::new(),
+            count_overhead: true,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+
+    /// Replace the limiter chain, outermost level (global) first
+    pub fn set_limits(&mut self, levels: &[RateLimits]) {
+        self.download = levels.iter().map(|l| l.download.clone()).collect();
+        self.upload = levels.iter().map(|l| l.upload.clone()).collect();
+    }
+
+    /// Set whether protocol overhead counts against the limits
+    ///
+    /// The stream itself charges every byte; callers that know which bytes
+    /// are overhead refund them when this is off.
+    pub fn set_count_overhead(&mut self, count_overhead: bool) {
+        self.count_overhead = count_overhead;
+    }
+
+    /// Check if protocol overhead counts against the limits
+    pub fn counts_overhead(&self) -> bool {
+        self.count_overhead
+    }
+
+    /// Give back downloaded bytes that should not count
+    pub fn refund_download(&self, bytes: usize) {
+        self.download.iter().for_each(|limiter| limiter.refund(bytes));
+    }
+
+    /// Give back uploaded bytes that should not count
+    pub fn refund_upload(&self, bytes: usize) {
+        self.upload.iter().for_each(|limiter| limiter.refund(bytes));
+    }
+
+    /// Get a reference to the wrapped stream
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.download.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let granted = match poll_grant(&this.download, &mut this.read_sleep, buf.remaining(), cx) {
+            Poll::Ready(granted) => granted.min(buf.remaining()),
+            Poll::Pending => return Poll::Pending,
+        };
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(granted));
+        match Pin::new(&mut this.inner).poll_read(cx, &mut limited) {
+            Poll::Ready(Ok(())) => {
+                let read = limited.filled().len();
+                this.download.iter().for_each(|limiter| limiter.consume(read));
+                buf.advance(read);
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.upload.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        let granted = match poll_grant(&this.upload, &mut this.write_sleep, buf.len(), cx) {
+            Poll::Ready(granted) => granted.min(buf.len()),
+            Poll::Pending => return Poll::Pending,
+        };
+        match Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]) {
+            Poll::Ready(Ok(written)) => {
+                this.upload.iter().for_each(|limiter| limiter.consume(written));
+                Poll::Ready(Ok(written))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: Transport> Transport for Throttled<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn kind(&self) -> TransportKind {
+        self.inner.kind()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.inner.is_encrypted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_bucket_grants_and_waits() {
+        let limiter = RateLimiter::new(10_000);
+        let now = Instant::now();
+        assert_eq!(limiter.available(50_000, now).unwrap(), 10_000);
+
+        limiter.consume(10_000);
+        let wait = limiter.available(50_000, now).unwrap_err();
+        // MIN_GRANT bytes at 10 kB/s
+        assert!(wait >= Duration::from_millis(90) && wait <= Duration::from_millis(110));
+
+        limiter.refund(4_000);
+        assert_eq!(limiter.available(50_000, now).unwrap(), 4_000);
+    }
+
+    #[test]
+    fn test_rate_changes_at_runtime() {
+        let limiter = RateLimiter::unlimited();
+        assert_eq!(limiter.available(1 << 30, Instant::now()).unwrap(), usize::MAX);
+        limiter.consume(1 << 30);
+
+        limiter.set_rate(2048);
+        assert_eq!(limiter.rate(), 2048);
+        assert_eq!(limiter.available(4096, Instant::now()).unwrap(), 2048);
+
+        limiter.set_rate(0);
+        assert!(limiter.is_unlimited());
+    }
+
+    #[test]
+    fn test_chain_takes_smallest_grant() {
+        let global = RateLimits::new(100_000, 0);
+        let peer = RateLimits::new(5_000, 0);
+        let chain = vec![global.download.clone(), peer.download.clone()];
+        assert_eq!(grant(&chain, 64 * 1024).unwrap(), 5_000);
+
+        peer.download.consume(5_000);
+        assert!(grant(&chain, 64 * 1024).is_err());
+        assert_eq!(grant(std::slice::from_ref(&global.upload), 64 * 1024).unwrap(), 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_stream_respects_rate() {
+        let (a, b) = MemoryTransport::pair("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+        let mut sender = Throttled::new(a);
+        sender.set_limits(&[RateLimits::new(0, 40_000)]);
+        let mut receiver = b;
+
+        let start = tokio::time::Instant::now();
+        let writer = tokio::spawn(async move {
+            sender.write_all(&[7u8; 60_000]).await.unwrap();
+            sender.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        receiver.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+
+        assert_eq!(received.len(), 60_000);
+        // One second of burst, then 20 kB at 40 kB/s
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "finished after {:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(1500), "finished after {:?}", elapsed);
+    }
+}
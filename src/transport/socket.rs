@@ -0,0 +1,59 @@
+//! Socket binding
+//!
+//! IPv6 sockets are bound v6-only so an IPv4 and an IPv6 socket can listen
+//! on the same port side by side.
+
+use std::io;
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Number of pending connections the TCP listener queues
+const LISTEN_BACKLOG: i32 = 1024;
+
+fn new_socket(addr: &SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Bind a UDP socket, v6-only for IPv6 addresses
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = new_socket(&addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Bind a TCP listener, v6-only for IPv6 addresses
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = new_socket(&addr, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ipv4_and_ipv6_share_a_port() {
+        let udp = bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = udp.local_addr().unwrap().port();
+        // Hosts without IPv6 cannot bind the second socket at all
+        let Ok(udp6) = bind_udp(SocketAddr::from(([0u16; 8], port))) else {
+            return;
+        };
+        assert_eq!(udp6.local_addr().unwrap().port(), port);
+
+        let tcp = bind_tcp("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let tcp6 = bind_tcp(SocketAddr::from(([0u16; 8], port))).unwrap();
+        assert!(tcp6.local_addr().unwrap().is_ipv6());
+    }
+}
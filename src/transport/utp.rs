@@ -0,0 +1,1068 @@
+//! uTP transport (BEP 29)
+//!
+//! Reliable, ordered byte streams over UDP with LEDBAT congestion control and
+//! selective ACKs. The connection state machine is kept free of I/O; the
+//! [`UtpStream`] wrapper drives it from the shared UDP socket.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace, warn};
+
+use super::ledbat::{Ledbat, MSS};
+use crate::error::TorrentError;
+
+/// uTP protocol version
+pub const VERSION: u8 = 1;
+
+/// Size of the fixed packet header
+pub const HEADER_SIZE: usize = 20;
+
+/// Largest payload carried by a single data packet
+pub const MAX_PAYLOAD: usize = MSS - HEADER_SIZE;
+
+/// Extension type of the selective ACK extension
+const EXT_SELECTIVE_ACK: u8 = 1;
+
+/// Receive buffer size advertised to the remote
+const RECV_WINDOW: usize = 1024 * 1024;
+
+/// Maximum amount of unsent data buffered per connection
+const SEND_BUFFER: usize = 256 * 1024;
+
+/// Out-of-order packets further ahead than this are dropped
+const MAX_REORDER: u16 = 1024;
+
+/// Number of SYN transmissions before the connect attempt fails
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+
+/// Number of transmissions of a data packet before the connection fails
+const MAX_TRANSMISSIONS: u32 = 8;
+
+/// Duplicate ACKs (or later SACKed packets) that trigger a fast retransmit
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+
+/// Idle time after which a keep-alive ACK is sent
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(29);
+
+/// Interval of the per-connection timer
+pub(crate) const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// uTP packet types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    /// Regular data packet
+    Data = 0,
+    /// Finalize the connection
+    Fin = 1,
+    /// State packet (ACK without data)
+    State = 2,
+    /// Terminate the connection forcefully
+    Reset = 3,
+    /// Connect request
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = TorrentError;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(TorrentError::protocol_error_with_source("Invalid uTP packet", format!("unknown packet type {}", value))),
+        }
+    }
+}
+
+/// A uTP packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// Packet type
+    pub packet_type: PacketType,
+    /// Connection ID
+    pub connection_id: u16,
+    /// Send time in microseconds
+    pub timestamp_us: u32,
+    /// Last measured one-way delay from the remote
+    pub timestamp_diff_us: u32,
+    /// Advertised receive window in bytes
+    pub wnd_size: u32,
+    /// Sequence number
+    pub seq_nr: u16,
+    /// Last in-order sequence number received
+    pub ack_nr: u16,
+    /// Selective ACK bitmask, bit 0 refers to `ack_nr + 2`
+    pub selective_ack: Option<Vec<u8>>,
+    /// Payload
+    pub payload: Bytes,
+}
+
+impl Packet {
+    /// Check if a datagram looks like a uTP packet
+    pub fn is_utp(data: &[u8]) -> bool {
+        data.len() >= HEADER_SIZE && data[0] & 0x0f == VERSION && data[0] >> 4 <= PacketType::Syn as u8
+    }
+
+    /// Serialize the packet
+    pub fn encode(&self) -> Vec<u8> {
+        let sack_len = self.selective_ack.as_ref().map(|s| 2 + s.len()).unwrap_or(0);
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE + sack_len + self.payload.len());
+        buf.put_u8(((self.packet_type as u8) << 4) | VERSION);
+        buf.put_u8(if self.selective_ack.is_some() { EXT_SELECTIVE_ACK } else { 0 });
+        buf.put_u16(self.connection_id);
+        buf.put_u32(self.timestamp_us);
+        buf.put_u32(self.timestamp_diff_us);
+        buf.put_u32(self.wnd_size);
+        buf.put_u16(self.seq_nr);
+        buf.put_u16(self.ack_nr);
+        if let Some(mask) = &self.selective_ack {
+            buf.put_u8(0);
+            buf.put_u8(mask.len() as u8);
+            buf.put_slice(mask);
+        }
+        buf.put_slice(&self.payload);
+        buf.to_vec()
+    }
+
+    /// Parse a packet
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(TorrentError::protocol_error_with_source(
+                "Invalid uTP packet",
+                format!("packet too short: {} bytes", data.len()),
+            ).into());
+        }
+        if data[0] & 0x0f != VERSION {
+            return Err(TorrentError::protocol_error_with_source(
+                "Invalid uTP packet",
+                format!("unsupported version {}", data[0] & 0x0f),
+            ).into());
+        }
+
+        let mut buf = data;
+        let packet_type = PacketType::try_from(buf.get_u8() >> 4)?;
+        let mut extension = buf.get_u8();
+        let connection_id = buf.get_u16();
+        let timestamp_us = buf.get_u32();
+        let timestamp_diff_us = buf.get_u32();
+        let wnd_size = buf.get_u32();
+        let seq_nr = buf.get_u16();
+        let ack_nr = buf.get_u16();
+
+        let mut selective_ack = None;
+        while extension != 0 {
+            if buf.remaining() < 2 {
+                return Err(TorrentError::protocol_error_with_source("Invalid uTP packet", "truncated extension header").into());
+            }
+            let next = buf.get_u8();
+            let length = buf.get_u8() as usize;
+            if buf.remaining() < length {
+                return Err(TorrentError::protocol_error_with_source("Invalid uTP packet", "truncated extension").into());
+            }
+            if extension == EXT_SELECTIVE_ACK {
+                selective_ack = Some(buf[..length].to_vec());
+            }
+            buf.advance(length);
+            extension = next;
+        }
+
+        Ok(Self {
+            packet_type,
+            connection_id,
+            timestamp_us,
+            timestamp_diff_us,
+            wnd_size,
+            seq_nr,
+            ack_nr,
+            selective_ack,
+            payload: Bytes::copy_from_slice(buf),
+        })
+    }
+}
+
+/// Compare sequence numbers with wrap-around: is `a` before `b`?
+fn seq_before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+/// Microsecond timestamp for packet headers
+pub(crate) fn timestamp_us(now: Instant) -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    let epoch = *EPOCH.get_or_init(Instant::now);
+    now.saturating_duration_since(epoch).as_micros() as u32
+}
+
+/// uTP connection states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// SYN sent, waiting for the remote's STATE
+    SynSent,
+    /// Connection established
+    Connected,
+    /// Our FIN has been sent
+    FinSent,
+    /// Connection closed or failed
+    Closed,
+}
+
+/// A sent packet waiting to be acknowledged
+#[derive(Debug)]
+struct Outgoing {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    need_resend: bool,
+}
+
+/// uTP connection state machine, free of I/O
+pub(crate) struct Connection {
+    state: ConnectionState,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    /// Next sequence number to send
+    seq_nr: u16,
+    /// Last in-order sequence number received
+    ack_nr: u16,
+    /// Delay measured for the last packet received, echoed to the remote
+    reply_micro: u32,
+    /// Receive window advertised by the remote
+    peer_wnd: usize,
+    /// Sent but unacknowledged packets, in sequence order
+    outstanding: VecDeque<Outgoing>,
+    /// Data written by the application but not yet packetized
+    send_buf: BytesMut,
+    /// In-order data ready for the application
+    recv_buf: BytesMut,
+    /// Packets received ahead of `ack_nr`
+    reorder: HashMap<u16, Bytes>,
+    /// Sequence number of the remote's FIN
+    eof_seq: Option<u16>,
+    /// Application asked to close the write side
+    fin_requested: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    /// The application dropped its handle
+    released: bool,
+    duplicate_acks: u32,
+    last_ack: u16,
+    last_loss: Option<Instant>,
+    last_sent: Instant,
+    error: Option<io::ErrorKind>,
+    cc: Ledbat,
+    /// Packets ready to go out on the socket
+    transmit: VecDeque<Packet>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connect_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn base(remote: SocketAddr, recv_id: u16, send_id: u16, now: Instant) -> Self {
+        Self {
+            state: ConnectionState::SynSent,
+            remote,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            reply_micro: 0,
+            peer_wnd: RECV_WINDOW,
+            outstanding: VecDeque::new(),
+            send_buf: BytesMut::new(),
+            recv_buf: BytesMut::new(),
+            reorder: HashMap::new(),
+            eof_seq: None,
+            fin_requested: false,
+            fin_sent: false,
+            fin_acked: false,
+            released: false,
+            duplicate_acks: 0,
+            last_ack: 0,
+            last_loss: None,
+            last_sent: now,
+            error: None,
+            cc: Ledbat::new(),
+            transmit: VecDeque::new(),
+            read_waker: None,
+            write_waker: None,
+            connect_waker: None,
+        }
+    }
+
+    /// Create an outgoing connection and queue its SYN
+    pub(crate) fn connect(remote: SocketAddr, recv_id: u16, now: Instant) -> Self {
+        let mut conn = Self::base(remote, recv_id, recv_id.wrapping_add(1), now);
+        let syn = conn.make_packet(PacketType::Syn, Bytes::new(), now);
+        conn.send_new(syn, now);
+        conn
+    }
+
+    /// Create an incoming connection from a SYN and queue the reply
+    pub(crate) fn accept(remote: SocketAddr, syn: &Packet, now: Instant) -> Self {
+        let mut conn = Self::base(remote, syn.connection_id.wrapping_add(1), syn.connection_id, now);
+        conn.state = ConnectionState::Connected;
+        conn.seq_nr = rand::random();
+        conn.ack_nr = syn.seq_nr;
+        conn.last_ack = conn.seq_nr.wrapping_sub(1);
+        conn.reply_micro = timestamp_us(now).wrapping_sub(syn.timestamp_us);
+        conn.send_ack(now);
+        conn
+    }
+
+    pub(crate) fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub(crate) fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    pub(crate) fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Check if the connection can be forgotten
+    pub(crate) fn is_finished(&self) -> bool {
+        self.state == ConnectionState::Closed
+            || (self.released && self.fin_acked)
+    }
+
+    /// Take packets ready for transmission
+    pub(crate) fn take_transmit(&mut self) -> VecDeque<Packet> {
+        std::mem::take(&mut self.transmit)
+    }
+
+    /// Put back packets that could not be sent
+    pub(crate) fn requeue_transmit(&mut self, packets: VecDeque<Packet>) {
+        let mut packets = packets;
+        packets.append(&mut self.transmit);
+        self.transmit = packets;
+    }
+
+    fn advertised_window(&self) -> u32 {
+        let buffered = self.recv_buf.len() + self.reorder.values().map(|p| p.len()).sum::<usize>();
+        RECV_WINDOW.saturating_sub(buffered) as u32
+    }
+
+    fn make_packet(&self, packet_type: PacketType, payload: Bytes, now: Instant) -> Packet {
+        Packet {
+            packet_type,
+            connection_id: if packet_type == PacketType::Syn { self.recv_id } else { self.send_id },
+            timestamp_us: timestamp_us(now),
+            timestamp_diff_us: self.reply_micro,
+            wnd_size: self.advertised_window(),
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    /// Send a packet that consumes a sequence number
+    fn send_new(&mut self, packet: Packet, now: Instant) {
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit.push_back(packet.clone());
+        self.outstanding.push_back(Outgoing {
+            packet,
+            sent_at: now,
+            transmissions: 1,
+            need_resend: false,
+        });
+        self.last_sent = now;
+    }
+
+    /// Queue a STATE packet acknowledging what we have received
+    fn send_ack(&mut self, now: Instant) {
+        let mut packet = self.make_packet(PacketType::State, Bytes::new(), now);
+        packet.selective_ack = self.selective_ack_mask();
+        self.transmit.push_back(packet);
+        self.last_sent = now;
+    }
+
+    fn send_reset(&mut self, now: Instant) {
+        let packet = self.make_packet(PacketType::Reset, Bytes::new(), now);
+        self.transmit.push_back(packet);
+    }
+
+    /// Build the selective ACK bitmask for out-of-order packets
+    fn selective_ack_mask(&self) -> Option<Vec<u8>> {
+        let furthest = self.reorder.keys()
+            .map(|seq| seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .max()?;
+        let mut mask = vec![0u8; (furthest / 32 + 1) * 4];
+        for seq in self.reorder.keys() {
+            let bit = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            mask[bit / 8] |= 1 << (bit % 8);
+        }
+        Some(mask)
+    }
+
+    /// Resend an outstanding packet with fresh header fields
+    fn resend(&mut self, index: usize, now: Instant) {
+        let timestamp = timestamp_us(now);
+        let reply_micro = self.reply_micro;
+        let wnd_size = self.advertised_window();
+        let ack_nr = self.ack_nr;
+        let entry = &mut self.outstanding[index];
+        entry.packet.timestamp_us = timestamp;
+        entry.packet.timestamp_diff_us = reply_micro;
+        entry.packet.wnd_size = wnd_size;
+        if entry.packet.packet_type != PacketType::Syn {
+            entry.packet.ack_nr = ack_nr;
+        }
+        entry.sent_at = now;
+        entry.transmissions += 1;
+        entry.need_resend = false;
+        trace!("uTP resending seq {} to {} (transmission {})", entry.packet.seq_nr, self.remote, entry.transmissions);
+        self.transmit.push_back(entry.packet.clone());
+        self.last_sent = now;
+    }
+
+    /// Bytes sent but not yet acknowledged
+    fn bytes_in_flight(&self) -> usize {
+        self.outstanding.iter().map(|o| o.packet.payload.len()).sum()
+    }
+
+    /// Turn buffered application data into packets, as far as the window allows
+    fn packetize(&mut self, now: Instant) {
+        if !matches!(self.state, ConnectionState::Connected) {
+            return;
+        }
+
+        let window = self.cc.cwnd().min(self.peer_wnd);
+        while !self.send_buf.is_empty() {
+            let in_flight = self.bytes_in_flight();
+            // Always allow one packet in flight so a zero window gets probed
+            let available = if self.outstanding.is_empty() {
+                MAX_PAYLOAD
+            } else {
+                window.saturating_sub(in_flight).min(MAX_PAYLOAD)
+            };
+            if available == 0 {
+                break;
+            }
+            let length = available.min(self.send_buf.len());
+            let payload = self.send_buf.split_to(length).freeze();
+            let packet = self.make_packet(PacketType::Data, payload, now);
+            self.send_new(packet, now);
+        }
+
+        if self.fin_requested && !self.fin_sent && self.send_buf.is_empty() {
+            let fin = self.make_packet(PacketType::Fin, Bytes::new(), now);
+            self.send_new(fin, now);
+            self.fin_sent = true;
+            self.state = ConnectionState::FinSent;
+            debug!("uTP FIN sent to {}", self.remote);
+        }
+
+        if self.send_buf.len() < SEND_BUFFER {
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Fail the connection
+    fn fail(&mut self, kind: io::ErrorKind) {
+        if self.error.is_none() {
+            self.error = Some(kind);
+        }
+        self.state = ConnectionState::Closed;
+        self.outstanding.clear();
+        self.wake_all();
+    }
+
+    fn wake_all(&mut self) {
+        for waker in [self.read_waker.take(), self.write_waker.take(), self.connect_waker.take()].into_iter().flatten() {
+            waker.wake();
+        }
+    }
+
+    /// Handle an incoming packet for this connection
+    pub(crate) fn on_packet(&mut self, packet: Packet, now: Instant) {
+        if self.state == ConnectionState::Closed {
+            return;
+        }
+
+        match packet.packet_type {
+            PacketType::Reset => {
+                debug!("uTP connection reset by {}", self.remote);
+                self.fail(io::ErrorKind::ConnectionReset);
+                return;
+            }
+            PacketType::Syn => {
+                // Our STATE reply was lost
+                if packet.seq_nr == self.ack_nr {
+                    self.send_ack(now);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        self.reply_micro = timestamp_us(now).wrapping_sub(packet.timestamp_us);
+        self.peer_wnd = packet.wnd_size as usize;
+
+        if self.state == ConnectionState::SynSent {
+            self.state = ConnectionState::Connected;
+            // STATE carries the next sequence number the remote will use
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            debug!("uTP connection established with {}", self.remote);
+            if let Some(waker) = self.connect_waker.take() {
+                waker.wake();
+            }
+        }
+
+        let delay = (packet.timestamp_diff_us != 0).then_some(packet.timestamp_diff_us);
+        self.process_ack(packet.ack_nr, packet.selective_ack.as_deref(), delay, packet.packet_type == PacketType::State, now);
+
+        match packet.packet_type {
+            PacketType::Data => {
+                self.on_data(packet.seq_nr, packet.payload);
+                self.send_ack(now);
+            }
+            PacketType::Fin => {
+                debug!("uTP FIN received from {}", self.remote);
+                self.eof_seq = Some(packet.seq_nr);
+                self.on_data(packet.seq_nr, Bytes::new());
+                self.send_ack(now);
+            }
+            _ => {}
+        }
+
+        self.packetize(now);
+
+        if self.fin_acked && self.reached_eof() {
+            debug!("uTP connection with {} closed", self.remote);
+            self.state = ConnectionState::Closed;
+            self.wake_all();
+        }
+    }
+
+    /// Store received data, delivering whatever is now in order
+    fn on_data(&mut self, seq: u16, payload: Bytes) {
+        let expected = self.ack_nr.wrapping_add(1);
+        if seq == expected {
+            self.recv_buf.extend_from_slice(&payload);
+            self.ack_nr = seq;
+            while let Some(next) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+                self.recv_buf.extend_from_slice(&next);
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+            }
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        } else if seq_before(self.ack_nr, seq) && seq.wrapping_sub(self.ack_nr) <= MAX_REORDER {
+            trace!("uTP out-of-order packet {} (expected {}) from {}", seq, expected, self.remote);
+            self.reorder.entry(seq).or_insert(payload);
+        } else {
+            trace!("uTP duplicate packet {} from {}", seq, self.remote);
+        }
+    }
+
+    fn reached_eof(&self) -> bool {
+        match self.eof_seq {
+            Some(eof) => !seq_before(self.ack_nr, eof),
+            None => false,
+        }
+    }
+
+    /// Process the cumulative and selective acknowledgements of a packet
+    fn process_ack(&mut self, ack_nr: u16, selective_ack: Option<&[u8]>, delay: Option<u32>, pure_ack: bool, now: Instant) {
+        let mut acked_bytes = 0;
+        let mut acked_packets = 0;
+        let mut rtt_sample = None;
+
+        let mut record = |entry: Outgoing, fin_acked: &mut bool| {
+            acked_bytes += entry.packet.payload.len();
+            acked_packets += 1;
+            if entry.transmissions == 1 {
+                rtt_sample = Some(now.saturating_duration_since(entry.sent_at));
+            }
+            if entry.packet.packet_type == PacketType::Fin {
+                *fin_acked = true;
+            }
+        };
+
+        let mut fin_acked = self.fin_acked;
+        while let Some(front) = self.outstanding.front() {
+            if seq_before(ack_nr, front.packet.seq_nr) {
+                break;
+            }
+            let entry = self.outstanding.pop_front().expect("front exists");
+            record(entry, &mut fin_acked);
+        }
+
+        let mut lost = false;
+        if let Some(mask) = selective_ack {
+            let is_sacked = |seq: u16| {
+                let bit = seq.wrapping_sub(ack_nr).wrapping_sub(2) as usize;
+                bit / 8 < mask.len() && mask[bit / 8] & (1 << (bit % 8)) != 0
+            };
+
+            let mut index = 0;
+            while index < self.outstanding.len() {
+                if is_sacked(self.outstanding[index].packet.seq_nr) {
+                    let entry = self.outstanding.remove(index).expect("index in range");
+                    record(entry, &mut fin_acked);
+                } else {
+                    index += 1;
+                }
+            }
+
+            // A packet with enough SACKed packets after it is considered lost
+            let sacked_total = mask.iter().map(|b| b.count_ones()).sum::<u32>();
+            if sacked_total >= DUPLICATE_ACK_THRESHOLD {
+                for entry in self.outstanding.iter_mut() {
+                    let later = (0..mask.len() * 8)
+                        .filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+                        .map(|bit| ack_nr.wrapping_add(2).wrapping_add(bit as u16))
+                        .filter(|seq| seq_before(entry.packet.seq_nr, *seq))
+                        .count() as u32;
+                    if later >= DUPLICATE_ACK_THRESHOLD && !entry.need_resend {
+                        entry.need_resend = true;
+                        lost = true;
+                    }
+                }
+            }
+        }
+        self.fin_acked = fin_acked;
+
+        if acked_packets > 0 {
+            self.duplicate_acks = 0;
+        } else if pure_ack && ack_nr == self.last_ack && !self.outstanding.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACK_THRESHOLD {
+                if let Some(front) = self.outstanding.front_mut() {
+                    front.need_resend = true;
+                    lost = true;
+                }
+            }
+        }
+        self.last_ack = ack_nr;
+
+        if acked_bytes > 0 {
+            self.cc.on_ack(acked_bytes, delay, now);
+        }
+        if let Some(sample) = rtt_sample {
+            self.cc.on_rtt_sample(sample);
+        }
+
+        if lost {
+            // React to loss at most once per round trip
+            let rtt = self.cc.rtt().unwrap_or(self.cc.rto());
+            if self.last_loss.is_none_or(|at| now.saturating_duration_since(at) >= rtt) {
+                self.cc.on_loss();
+                self.last_loss = Some(now);
+            }
+        }
+
+        for index in 0..self.outstanding.len() {
+            if self.outstanding[index].need_resend {
+                self.resend(index, now);
+            }
+        }
+
+        if acked_packets > 0 {
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Handle timeouts and keep-alives
+    pub(crate) fn on_tick(&mut self, now: Instant) {
+        if self.state == ConnectionState::Closed {
+            return;
+        }
+
+        if let Some(front) = self.outstanding.front() {
+            if now.saturating_duration_since(front.sent_at) >= self.cc.rto() {
+                let limit = if self.state == ConnectionState::SynSent { MAX_SYN_TRANSMISSIONS } else { MAX_TRANSMISSIONS };
+                if front.transmissions >= limit {
+                    warn!("uTP connection to {} timed out", self.remote);
+                    self.fail(io::ErrorKind::TimedOut);
+                    return;
+                }
+                self.cc.on_timeout();
+                self.resend(0, now);
+            }
+        } else if self.state != ConnectionState::SynSent
+            && now.saturating_duration_since(self.last_sent) >= KEEPALIVE_INTERVAL
+        {
+            self.send_ack(now);
+        }
+
+        self.packetize(now);
+    }
+
+    /// Buffer application data for sending, returns the number of bytes accepted
+    pub(crate) fn write(&mut self, data: &[u8], now: Instant) -> usize {
+        let accepted = data.len().min(SEND_BUFFER.saturating_sub(self.send_buf.len()));
+        self.send_buf.extend_from_slice(&data[..accepted]);
+        self.packetize(now);
+        accepted
+    }
+
+    /// Close the write side once all buffered data is sent
+    pub(crate) fn close(&mut self, now: Instant) {
+        if !self.fin_requested && self.state != ConnectionState::Closed {
+            self.fin_requested = true;
+            if self.state == ConnectionState::SynSent {
+                self.send_reset(now);
+                self.fail(io::ErrorKind::ConnectionAborted);
+                return;
+            }
+            self.packetize(now);
+        }
+    }
+
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.recv_buf.is_empty() {
+            let n = self.recv_buf.len().min(buf.remaining());
+            buf.put_slice(&self.recv_buf[..n]);
+            self.recv_buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        if self.reached_eof() {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.state == ConnectionState::Closed {
+            return Poll::Ready(Ok(()));
+        }
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8], now: Instant) -> Poll<io::Result<usize>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.fin_requested || self.state == ConnectionState::Closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let accepted = self.write(buf, now);
+        if accepted == 0 && !buf.is_empty() {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(accepted))
+    }
+
+    fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.state == ConnectionState::SynSent {
+            self.connect_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Registry of live connections keyed by remote address and receive ID
+pub(crate) type ConnectionMap = Arc<Mutex<HashMap<(SocketAddr, u16), Arc<Mutex<Connection>>>>>;
+
+/// Send queued packets of a connection
+pub(crate) fn flush_transmit(socket: &UdpSocket, conn: &mut Connection) {
+    let mut packets = conn.take_transmit();
+    while let Some(packet) = packets.pop_front() {
+        match socket.try_send_to(&packet.encode(), conn.remote()) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                packets.push_front(packet);
+                conn.requeue_transmit(packets);
+                return;
+            }
+            Err(e) => {
+                debug!("Failed to send uTP packet to {}: {}", conn.remote(), e);
+            }
+        }
+    }
+}
+
+/// A uTP connection usable as an async byte stream
+pub struct UtpStream {
+    conn: Arc<Mutex<Connection>>,
+    socket: Arc<UdpSocket>,
+    remote: SocketAddr,
+}
+
+impl UtpStream {
+    /// Register a connection and start its timer task
+    pub(crate) fn spawn(conn: Connection, socket: Arc<UdpSocket>, connections: ConnectionMap) -> Self {
+        let remote = conn.remote();
+        let key = (remote, conn.recv_id());
+        let conn = Arc::new(Mutex::new(conn));
+        connections.lock().expect("uTP registry poisoned").insert(key, conn.clone());
+
+        {
+            let mut guard = conn.lock().expect("uTP connection poisoned");
+            flush_transmit(&socket, &mut guard);
+        }
+
+        let timer_conn = conn.clone();
+        let timer_socket = socket.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TICK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let finished = {
+                    let mut guard = timer_conn.lock().expect("uTP connection poisoned");
+                    guard.on_tick(Instant::now());
+                    flush_transmit(&timer_socket, &mut guard);
+                    guard.is_finished()
+                };
+                if finished {
+                    connections.lock().expect("uTP registry poisoned").remove(&key);
+                    trace!("uTP connection to {} removed", key.0);
+                    break;
+                }
+            }
+        });
+
+        Self { conn, socket, remote }
+    }
+
+    /// Wait until the connection is established
+    pub(crate) async fn wait_connected(&self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.lock().poll_connected(cx)).await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().expect("uTP connection poisoned")
+    }
+
+    /// Get the remote address
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Get the connection state
+    pub fn state(&self) -> ConnectionState {
+        self.lock().state()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut conn = self.lock();
+        let result = conn.poll_read(cx, buf);
+        flush_transmit(&self.socket, &mut conn);
+        result
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut conn = self.lock();
+        let result = conn.poll_write(cx, buf, Instant::now());
+        flush_transmit(&self.socket, &mut conn);
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Data is handed to the socket as soon as the window allows
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut conn = self.lock();
+        conn.close(Instant::now());
+        flush_transmit(&self.socket, &mut conn);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        if let Ok(mut conn) = self.conn.lock() {
+            conn.released = true;
+            conn.close(Instant::now());
+            flush_transmit(&self.socket, &mut conn);
+        }
+    }
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream")
+            .field("remote", &self.remote)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Deliver queued packets from one connection to the other
+    fn deliver(from: &mut Connection, to: &mut Connection, now: Instant, drop_seq: Option<u16>) -> usize {
+        let packets = from.take_transmit();
+        let count = packets.len();
+        for packet in packets {
+            if Some(packet.seq_nr) == drop_seq && packet.packet_type == PacketType::Data {
+                continue;
+            }
+            let decoded = Packet::decode(&packet.encode()).unwrap();
+            to.on_packet(decoded, now);
+        }
+        count
+    }
+
+    fn handshake(now: Instant) -> (Connection, Connection) {
+        let mut client = Connection::connect(addr(1), 100, now);
+        let syn = client.take_transmit().pop_front().unwrap();
+        assert_eq!(syn.packet_type, PacketType::Syn);
+        let mut server = Connection::accept(addr(2), &syn, now);
+        deliver(&mut server, &mut client, now, None);
+        assert_eq!(client.state(), ConnectionState::Connected);
+        (client, server)
+    }
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let packet = Packet {
+            packet_type: PacketType::State,
+            connection_id: 4242,
+            timestamp_us: 123456,
+            timestamp_diff_us: 789,
+            wnd_size: 65536,
+            seq_nr: 10,
+            ack_nr: 9,
+            selective_ack: Some(vec![0b0000_0101, 0, 0, 0]),
+            payload: Bytes::new(),
+        };
+        let encoded = packet.encode();
+        assert_eq!(encoded.len(), HEADER_SIZE + 6);
+        assert!(Packet::is_utp(&encoded));
+        assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_packet_decode_rejects_garbage() {
+        assert!(Packet::decode(&[0x41, 0, 0]).is_err());
+        assert!(!Packet::is_utp(b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaae1:q4:ping1:t2:aa1:y1:qe"));
+        let mut bad_version = vec![0u8; HEADER_SIZE];
+        bad_version[0] = 0x02;
+        assert!(Packet::decode(&bad_version).is_err());
+    }
+
+    #[test]
+    fn test_connection_ids() {
+        let now = Instant::now();
+        let (client, server) = handshake(now);
+        assert_eq!(client.recv_id, 100);
+        assert_eq!(client.send_id, 101);
+        assert_eq!(server.recv_id, 101);
+        assert_eq!(server.send_id, 100);
+    }
+
+    #[test]
+    fn test_in_order_transfer() {
+        let now = Instant::now();
+        let (mut client, mut server) = handshake(now);
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        assert_eq!(client.write(&data, now), data.len());
+
+        loop {
+            let sent = deliver(&mut client, &mut server, now, None) + deliver(&mut server, &mut client, now, None);
+            if sent == 0 {
+                break;
+            }
+        }
+
+        assert_eq!(&server.recv_buf[..], &data[..]);
+        assert!(client.outstanding.is_empty());
+    }
+
+    #[test]
+    fn test_selective_ack_triggers_fast_retransmit() {
+        let now = Instant::now();
+        let (mut client, mut server) = handshake(now);
+        let data = vec![7u8; MAX_PAYLOAD * 4];
+        client.write(&data, now);
+        let first_seq = client.outstanding.front().unwrap().packet.seq_nr;
+
+        // Lose the first data packet; the rest arrive and get SACKed
+        deliver(&mut client, &mut server, now, Some(first_seq));
+        assert!(server.recv_buf.is_empty());
+        assert_eq!(server.reorder.len(), 3);
+
+        deliver(&mut server, &mut client, now, None);
+        assert_eq!(client.outstanding.len(), 1);
+        assert_eq!(client.outstanding.front().unwrap().transmissions, 2);
+
+        deliver(&mut client, &mut server, now, None);
+        assert_eq!(server.recv_buf.len(), data.len());
+    }
+
+    #[test]
+    fn test_timeout_retransmits_and_fails() {
+        let now = Instant::now();
+        let mut client = Connection::connect(addr(1), 7, now);
+        client.take_transmit();
+
+        let mut later = now;
+        for _ in 1..MAX_SYN_TRANSMISSIONS {
+            later += client.cc.rto();
+            client.on_tick(later);
+            assert_eq!(client.take_transmit().len(), 1);
+        }
+        later += client.cc.rto();
+        client.on_tick(later);
+        assert_eq!(client.state(), ConnectionState::Closed);
+        assert_eq!(client.error, Some(io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn test_fin_closes_both_sides() {
+        let now = Instant::now();
+        let (mut client, mut server) = handshake(now);
+        client.write(b"bye", now);
+        client.close(now);
+        deliver(&mut client, &mut server, now, None);
+        assert!(server.reached_eof());
+        deliver(&mut server, &mut client, now, None);
+        assert!(client.fin_acked);
+
+        server.close(now);
+        deliver(&mut server, &mut client, now, None);
+        deliver(&mut client, &mut server, now, None);
+        assert_eq!(client.state(), ConnectionState::Closed);
+        assert_eq!(server.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn test_reset_fails_connection() {
+        let now = Instant::now();
+        let (mut client, mut server) = handshake(now);
+        server.send_reset(now);
+        deliver(&mut server, &mut client, now, None);
+        assert_eq!(client.state(), ConnectionState::Closed);
+        assert_eq!(client.error, Some(io::ErrorKind::ConnectionReset));
+    }
+}